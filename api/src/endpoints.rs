@@ -13,18 +13,28 @@
 // limitations under the License.
 
 
+use std::cmp;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::thread;
 
 use chain;
 use core::core::Transaction;
+use core::core::hash::Hashed;
 use core::ser;
+use p2p;
 use pool;
 use rest::*;
 use types::*;
+use secp;
 use secp::pedersen::Commitment;
 use util;
 
+/// Maximum number of buckets a fee histogram can be requested with.
+const MAX_FEE_HISTOGRAM_BUCKETS: usize = 1000;
+/// Maximum number of outputs in a page of unspent outputs at a height.
+const MAX_OUTPUTS_PAGE: u64 = 1000;
+
 /// ApiEndpoint implementation for the blockchain. Exposes the current chain
 /// state as a simple JSON object.
 #[derive(Clone)]
@@ -51,6 +61,33 @@ impl ApiEndpoint for ChainApi {
 	}
 }
 
+/// ApiEndpoint implementation for the chain statistics, the number of unspent
+/// outputs and kernels, total supply and fees. Like the chain state, the id
+/// is ignored.
+#[derive(Clone)]
+pub struct ChainStatsApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl ApiEndpoint for ChainStatsApi {
+	type ID = String;
+	type T = ChainStats;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, _: String) -> ApiResult<ChainStats> {
+		match self.chain.get_stats() {
+			Ok(stats) => Ok(ChainStats::from_stats(stats)),
+			Err(e) => Err(Error::Internal(format!("{:?}", e)))
+		}
+	}
+}
+
 /// ApiEndpoint implementation for outputs that have been included in the chain.
 #[derive(Clone)]
 pub struct OutputApi {
@@ -82,6 +119,169 @@ impl ApiEndpoint for OutputApi {
 	}
 }
 
+/// ApiEndpoint implementation listing the outputs created by the block at a
+/// given height on the current chain.
+#[derive(Clone)]
+pub struct BlockOutputsApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl ApiEndpoint for BlockOutputsApi {
+	type ID = u64;
+	type T = BlockOutputs;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, height: u64) -> ApiResult<BlockOutputs> {
+		debug!("GET outputs at height {}", height);
+		let header = self.chain.get_header_by_height(height)
+			.map_err(|_| Error::NotFound)?;
+		let block = self.chain.get_block(&header.hash())
+			.map_err(|_| Error::NotFound)?;
+
+		Ok(BlockOutputs {
+			height: header.height,
+			outputs: block.outputs
+				.iter()
+				.map(|out| Output::from_output(out, &header))
+				.collect(),
+		})
+	}
+}
+
+/// ApiEndpoint implementation listing the outputs that were unspent at a
+/// given height on the current chain, whether they've been spent or pruned
+/// since. Getting a height returns the first page of outputs, the following
+/// ones are requested with the page operation.
+#[derive(Clone)]
+pub struct OutputsAtApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl OutputsAtApi {
+	fn page(&self, height: u64, start_index: u64, max: u64) -> ApiResult<OutputsAt> {
+		debug!("GET unspent outputs at height {} from {}", height, start_index);
+		let root = self.chain.output_pmmr_root_at(height)
+			.map_err(|_| Error::NotFound)?;
+		let (outputs, next_index) = self.chain
+			.get_outputs_at(height, start_index, cmp::min(max, MAX_OUTPUTS_PAGE))
+			.map_err(|e| Error::Internal(format!("{:?}", e)))?;
+
+		let mut api_outputs = Vec::with_capacity(outputs.len());
+		for out in outputs {
+			let header = self.chain.get_block_header_by_output_commit(&out.commitment())
+				.map_err(|e| Error::Internal(format!("{:?}", e)))?;
+			api_outputs.push(Output::from_output(&out, &header));
+		}
+		Ok(OutputsAt {
+			height: height,
+			output_root: util::to_hex(root.to_vec()),
+			outputs: api_outputs,
+			next_index: next_index,
+		})
+	}
+}
+
+impl ApiEndpoint for OutputsAtApi {
+	type ID = u64;
+	type T = OutputsAt;
+	type OP_IN = OutputsAtRequest;
+	type OP_OUT = OutputsAt;
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get, Operation::Custom("page".to_string())]
+	}
+
+	fn get(&self, height: u64) -> ApiResult<OutputsAt> {
+		self.page(height, 1, MAX_OUTPUTS_PAGE)
+	}
+
+	fn operation(&self, _: String, input: OutputsAtRequest) -> ApiResult<OutputsAt> {
+		self.page(input.height, input.start_index, input.max)
+	}
+}
+
+/// ApiEndpoint implementation verifying payment proofs against the chain, so
+/// merchants don't need to run a full wallet to check they've been paid.
+#[derive(Clone)]
+pub struct PaymentApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl ApiEndpoint for PaymentApi {
+	type ID = String;
+	type T = ();
+	type OP_IN = VerifyPaymentRequest;
+	type OP_OUT = PaymentVerification;
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Custom("verify_payment".to_string())]
+	}
+
+	fn operation(&self, _: String, input: VerifyPaymentRequest) -> ApiResult<PaymentVerification> {
+		let proof = input.proof;
+		debug!("Verifying payment proof for kernel {:?}", proof.excess);
+
+		if proof.amount != input.expected_amount {
+			return Ok(PaymentVerification::invalid("amount mismatch"));
+		}
+		let sig = util::from_hex(proof.excess_sig.clone()).map_err(|_| {
+			Error::Argument(format!("Invalid hex in kernel signature."))
+		})?;
+
+		let header = match self.chain.get_block_header_by_kernel_excess(&proof.excess) {
+			Ok(header) => header,
+			Err(_) => return Ok(PaymentVerification::invalid("kernel not found on chain")),
+		};
+		let block = self.chain.get_block(&header.hash())
+			.map_err(|e| Error::Internal(format!("{:?}", e)))?;
+
+		let kernel = match block.kernels.iter().find(|k| k.excess == proof.excess) {
+			Some(kernel) => kernel,
+			None => return Ok(PaymentVerification::invalid("kernel not found on chain")),
+		};
+		if kernel.excess_sig != sig || kernel.fee != proof.fee {
+			return Ok(PaymentVerification::invalid("kernel does not match proof"));
+		}
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		if let Err(_) = kernel.verify(&secp) {
+			return Ok(PaymentVerification::invalid("invalid kernel signature"));
+		}
+		if !block.outputs.iter().any(|out| out.commitment() == proof.output) {
+			return Ok(PaymentVerification::invalid("output not found in kernel block"));
+		}
+		let amount_sig = util::from_hex(proof.amount_sig.clone()).map_err(|_| {
+			Error::Argument(format!("Invalid hex in amount signature."))
+		})?;
+		if let Err(_) = verify_amount(&secp, &proof, &amount_sig) {
+			return Ok(PaymentVerification::invalid("output does not commit to the amount"));
+		}
+
+		Ok(PaymentVerification::valid(proof.amount, header.height))
+	}
+}
+
+// Checks the amount signature verifies against the output minus a commitment
+// to the amount, a commitment to zero whose blinding factor only the payee
+// knows, which can only be if the output commits to the amount.
+fn verify_amount(
+	secp: &secp::Secp256k1,
+	proof: &PaymentProof,
+	sig: &[u8],
+) -> Result<(), secp::Error> {
+	let value = secp.commit_value(proof.amount)?;
+	let blind = secp.commit_sum(vec![proof.output], vec![value])?;
+	let sig = secp::Signature::from_der(secp, sig)?;
+	secp.verify_from_commit(&proof.amount_msg(), &sig, &blind)
+}
+
 /// ApiEndpoint implementation for the transaction pool, to check its status
 /// and size as well as push new transactions.
 #[derive(Clone)]
@@ -107,6 +307,8 @@ impl<T> ApiEndpoint for PoolApi<T>
 			pool_size: pool.pool_size(),
 			orphans_size: pool.orphans_size(),
 			total_size: pool.total_size(),
+			min_fee_rate: pool.config.min_fee_rate,
+			evicted_count: pool.evicted_count,
 		})
 	}
 
@@ -140,6 +342,85 @@ impl<T> ApiEndpoint for PoolApi<T>
 	}
 }
 
+/// ApiEndpoint implementation for the histogram of the fee rates paid by
+/// the pool transactions, split in the requested number of buckets.
+#[derive(Clone)]
+pub struct FeeHistogramApi<T> {
+	tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
+}
+
+impl<T> ApiEndpoint for FeeHistogramApi<T>
+    where T: pool::BlockChain + Clone + Send + Sync + 'static
+{
+	type ID = usize;
+	type T = FeeHistogram;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, buckets: usize) -> ApiResult<FeeHistogram> {
+		if buckets > MAX_FEE_HISTOGRAM_BUCKETS {
+			return Err(Error::Argument(format!("At most {} buckets.", MAX_FEE_HISTOGRAM_BUCKETS)));
+		}
+		Ok(FeeHistogram { buckets: self.tx_pool.read().unwrap().fee_histogram(buckets) })
+	}
+}
+
+/// ApiEndpoint implementation estimating the fee rate a transaction should
+/// pay to be mined within the requested number of blocks.
+#[derive(Clone)]
+pub struct FeeEstimateApi<T> {
+	tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
+}
+
+impl<T> ApiEndpoint for FeeEstimateApi<T>
+    where T: pool::BlockChain + Clone + Send + Sync + 'static
+{
+	type ID = u64;
+	type T = FeeEstimate;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, target_blocks: u64) -> ApiResult<FeeEstimate> {
+		Ok(FeeEstimate {
+			target_blocks: target_blocks,
+			fee_rate: self.tx_pool.read().unwrap().estimate_fee(target_blocks),
+		})
+	}
+}
+
+/// ApiEndpoint implementation for our connected peers. Exposes the traffic
+/// statistics of each peer at /peers/:addr/stats.
+#[derive(Clone)]
+pub struct PeersApi {
+	p2p_server: Arc<p2p::Server>,
+}
+
+impl ApiEndpoint for PeersApi {
+	type ID = SocketAddr;
+	type T = ();
+	type OP_IN = ();
+	type OP_OUT = PeerStats;
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::GetSub("stats".to_string())]
+	}
+
+	fn get_sub(&self, addr: SocketAddr, _: String) -> ApiResult<PeerStats> {
+		match self.p2p_server.get_peer(addr) {
+			Some(peer) => Ok(PeerStats::from_peer(&peer)),
+			None => Err(Error::NotFound),
+		}
+	}
+}
+
 /// Dummy wrapper for the hex-encoded serialized transaction.
 #[derive(Serialize, Deserialize)]
 pub struct TxWrapper {
@@ -150,7 +431,8 @@ pub struct TxWrapper {
 /// instance and runs the corresponding HTTP server.
 pub fn start_rest_apis<T>(addr: String,
                           chain: Arc<chain::Chain>,
-                          tx_pool: Arc<RwLock<pool::TransactionPool<T>>>)
+                          tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
+                          p2p_server: Arc<p2p::Server>)
 	where T: pool::BlockChain + Clone + Send + Sync + 'static
 {
 
@@ -158,14 +440,59 @@ pub fn start_rest_apis<T>(addr: String,
 		let mut apis = ApiServer::new("/v1".to_string());
 		apis.register_endpoint("/chain".to_string(),
 		                       ChainApi { chain: chain.clone() });
+		apis.register_endpoint("/chain/stats".to_string(),
+		                       ChainStatsApi { chain: chain.clone() });
 		apis.register_endpoint("/chain/utxo".to_string(),
 		                       OutputApi {
 			                       chain: chain.clone(),
 		                       });
+		apis.register_endpoint("/chain/outputs".to_string(),
+		                       BlockOutputsApi {
+			                       chain: chain.clone(),
+		                       });
+		apis.register_endpoint("/chain/outputs/at".to_string(),
+		                       OutputsAtApi {
+			                       chain: chain.clone(),
+		                       });
+		apis.register_endpoint("/wallet".to_string(),
+		                       PaymentApi {
+			                       chain: chain.clone(),
+		                       });
+		apis.register_endpoint("/pool/fee_histogram".to_string(),
+		                       FeeHistogramApi { tx_pool: tx_pool.clone() });
+		apis.register_endpoint("/pool/fee_estimate".to_string(),
+		                       FeeEstimateApi { tx_pool: tx_pool.clone() });
 		apis.register_endpoint("/pool".to_string(), PoolApi { tx_pool: tx_pool });
+		apis.register_endpoint("/peers".to_string(), PeersApi { p2p_server: p2p_server });
 
 		apis.start(&addr[..]).unwrap_or_else(|e| {
 			error!("Failed to start API HTTP server: {}.", e);
 		});
 	});
 }
+
+#[cfg(test)]
+mod test {
+	use secp::key::SecretKey;
+	use super::*;
+
+	#[test]
+	fn amount_bound_to_output() {
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let blind = SecretKey::from_slice(&secp, &[3; 32]).unwrap();
+		let mut proof = PaymentProof {
+			amount: 60,
+			excess: secp.commit(0, SecretKey::from_slice(&secp, &[4; 32]).unwrap()).unwrap(),
+			excess_sig: String::new(),
+			fee: 1,
+			output: secp.commit(60, blind).unwrap(),
+			amount_sig: String::new(),
+		};
+		let sig = secp.sign(&proof.amount_msg(), &blind).unwrap().serialize_der(&secp);
+		assert!(verify_amount(&secp, &proof, &sig).is_ok());
+
+		// the output doesn't commit to any other amount
+		proof.amount = 61;
+		assert!(verify_amount(&secp, &proof, &sig).is_err());
+	}
+}
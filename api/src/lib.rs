@@ -14,6 +14,7 @@
 
 extern crate grin_core as core;
 extern crate grin_chain as chain;
+extern crate grin_p2p as p2p;
 extern crate grin_pool as pool;
 extern crate grin_store as store;
 extern crate grin_util as util;
@@ -91,6 +91,8 @@ pub enum Operation {
 	Delete,
 	Update,
 	Get,
+	/// Get of a sub-resource of the resource with the provided id
+	GetSub(String),
 	Custom(String),
 }
 
@@ -101,6 +103,7 @@ impl Operation {
 			Operation::Delete => Method::Delete,
 			Operation::Update => Method::Put,
 			Operation::Get => Method::Get,
+			Operation::GetSub(_) => Method::Get,
 			Operation::Custom(_) => Method::Post,
 		}
 	}
@@ -116,6 +119,7 @@ pub type ApiResult<T> = ::std::result::Result<T, Error>;
 /// * get:    GET /:id
 /// * update: PUT /:id
 /// * delete: DELETE /:id
+/// * get_sub: GET /:id/sub
 ///
 /// The methods method defines which operation the endpoint implements, they're
 /// all optional by default. It also allows the framework to automatically
@@ -152,6 +156,11 @@ pub trait ApiEndpoint: Clone + Send + Sync + 'static {
 		unimplemented!()
 	}
 
+	#[allow(unused_variables)]
+	fn get_sub(&self, id: Self::ID, sub: String) -> ApiResult<Self::OP_OUT> {
+		unimplemented!()
+	}
+
 	#[allow(unused_variables)]
 	fn operation(&self, op: String, input: Self::OP_IN) -> ApiResult<Self::OP_OUT> {
 		unimplemented!()
@@ -215,6 +224,23 @@ impl<E> Handler for OpWrapper<E>
 	}
 }
 
+struct SubWrapper<E> {
+	sub: String,
+	endpoint: E,
+}
+
+impl<E> Handler for SubWrapper<E>
+	where E: ApiEndpoint,
+	      <<E as ApiEndpoint>::ID as FromStr>::Err: Debug + Send + error::Error
+{
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let res = self.endpoint.get_sub(extract_param(req, "id")?, self.sub.clone())?;
+		let res_json = serde_json::to_string(&res)
+      .map_err(|e| IronError::new(e, status::InternalServerError))?;
+		Ok(Response::with((status::Ok, res_json)))
+	}
+}
+
 fn extract_param<ID>(req: &mut Request, param: &'static str) -> IronResult<ID>
 	where ID: ToString + FromStr,
 	      <ID as FromStr>::Err: Debug + Send + error::Error + 'static
@@ -283,6 +309,14 @@ impl ApiServer {
 				let full_path = format!("{}/{}", root.clone(), op_s.clone());
 				self.router.route(op.to_method(), full_path.clone(), wrapper, route_name);
 				info!("route: POST {}", full_path);
+			} else if let Operation::GetSub(sub) = op.clone() {
+				let full_path = format!("{}/:id/{}", root.clone(), sub.clone());
+				let wrapper = SubWrapper {
+					sub: sub,
+					endpoint: endpoint.clone(),
+				};
+				self.router.route(op.to_method(), full_path.clone(), wrapper, route_name);
+				info!("route: GET {}", full_path);
 			} else {
 
 				// regular REST operations
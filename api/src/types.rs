@@ -14,6 +14,9 @@
 
 use core::{core, consensus};
 use chain;
+use p2p;
+use core::core::hash::Hashed;
+use secp::Message;
 use secp::pedersen;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,6 +39,30 @@ impl Tip {
 	}
 }
 
+/// Statistics of the chain up to its head.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainStats {
+	/// Number of unspent outputs
+	pub utxo_count: u64,
+	/// Number of kernels
+	pub kernel_count: u64,
+	/// Sum of all block rewards, in nanogrins
+	pub total_supply_nanogrin: u64,
+	/// Sum of all transaction fees paid, in nanogrins
+	pub total_fees_nanogrin: u64,
+}
+
+impl ChainStats {
+	pub fn from_stats(stats: chain::ChainStats) -> ChainStats {
+		ChainStats {
+			utxo_count: stats.utxo_count,
+			kernel_count: stats.kernel_count,
+			total_supply_nanogrin: stats.total_supply_nanogrin,
+			total_fees_nanogrin: stats.total_fees_nanogrin,
+		}
+	}
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum OutputType {
 	Coinbase,
@@ -74,6 +101,45 @@ impl Output {
 	}
 }
 
+/// All the outputs created by the block at a given height, along with the
+/// height itself. Lets wallets scan the chain for outputs they own without
+/// knowing their commitments beforehand.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockOutputs {
+	/// The height of the block
+	pub height: u64,
+	/// The outputs created by the block
+	pub outputs: Vec<Output>,
+}
+
+/// A page of the outputs that were unspent after the block at a given
+/// height, along with the root of the output MMR its header committed to, as
+/// hex.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutputsAt {
+	/// The height of the block
+	pub height: u64,
+	/// Root of the output MMR after the block
+	pub output_root: String,
+	/// The outputs unspent after the block, in MMR order
+	pub outputs: Vec<Output>,
+	/// Insertion index in the output MMR to request the next page from, if
+	/// any output is left
+	pub next_index: Option<u64>,
+}
+
+/// Request for a page of the outputs unspent after the block at a given
+/// height.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutputsAtRequest {
+	/// The height of the block
+	pub height: u64,
+	/// Insertion index in the output MMR to start from, 1 for the first page
+	pub start_index: u64,
+	/// Maximum number of outputs in the page
+	pub max: u64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PoolInfo {
 	/// Size of the pool
@@ -82,4 +148,138 @@ pub struct PoolInfo {
 	pub orphans_size: usize,
 	/// Total size of pool + orphans
 	pub total_size: usize,
+	/// Minimum fee per unit of weight required by the pool, what wallets
+	/// should estimate their fees with
+	pub min_fee_rate: u64,
+	/// Number of transactions evicted from the pool after their time to live
+	pub evicted_count: u64,
+}
+
+/// Histogram of the fee rates paid by the transactions in the pool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeHistogram {
+	/// Lowest fee rate of each bucket along with the number of transactions
+	/// in it, from the lowest to the highest fee rate
+	pub buckets: Vec<(u64, u64)>,
+}
+
+/// Fee rate a transaction should pay to be mined within a number of blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeEstimate {
+	/// Number of blocks the transaction should be mined within
+	pub target_blocks: u64,
+	/// Fee per unit of weight to pay
+	pub fee_rate: u64,
+}
+
+/// Proof that a payment has been made, referencing the kernel of the
+/// transaction that carried it as well as the output the payee received.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentProof {
+	/// The amount paid
+	pub amount: u64,
+	/// Excess commitment of the payment transaction kernel
+	pub excess: pedersen::Commitment,
+	/// Signature of the payment transaction kernel, hex encoded
+	pub excess_sig: String,
+	/// Fee of the payment transaction
+	pub fee: u64,
+	/// Commitment of the output received by the payee
+	pub output: pedersen::Commitment,
+	/// Signature of the amount message by the payee with the blinding factor
+	/// of the output, hex encoded. Only verifies against the output minus a
+	/// commitment to the amount, binding the amount to the output.
+	pub amount_sig: String,
+}
+
+impl PaymentProof {
+	/// The message the payee signs to bind the amount to the output, the
+	/// hash of the kernel excess so the signature can't be reused for
+	/// another payment.
+	pub fn amount_msg(&self) -> Message {
+		Message::from_slice(&self.excess.hash().to_vec()).unwrap()
+	}
+}
+
+/// Request to verify a payment proof against an expected amount.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyPaymentRequest {
+	/// The proof to verify
+	pub proof: PaymentProof,
+	/// The amount the payment should be for
+	pub expected_amount: u64,
+}
+
+/// Result of a payment proof verification. Either valid, with the amount and
+/// the height at which the payment got confirmed, or invalid with a reason.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentVerification {
+	/// Whether the proof is valid
+	pub valid: bool,
+	/// Amount of the payment, if valid
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub amount: Option<u64>,
+	/// Height of the block including the payment, if valid
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub block_height: Option<u64>,
+	/// Why the proof is invalid
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub reason: Option<String>,
+}
+
+impl PaymentVerification {
+	/// A successful verification
+	pub fn valid(amount: u64, block_height: u64) -> PaymentVerification {
+		PaymentVerification {
+			valid: true,
+			amount: Some(amount),
+			block_height: Some(block_height),
+			reason: None,
+		}
+	}
+
+	/// A failed verification, with the reason
+	pub fn invalid(reason: &str) -> PaymentVerification {
+		PaymentVerification {
+			valid: false,
+			amount: None,
+			block_height: None,
+			reason: Some(reason.to_string()),
+		}
+	}
+}
+
+/// Traffic statistics of a connected peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerStats {
+	/// Address of the peer
+	pub addr: String,
+	/// User agent advertised by the peer
+	pub user_agent: String,
+	/// Bytes sent to the peer
+	pub sent_bytes: u64,
+	/// Bytes received from the peer
+	pub received_bytes: u64,
+	/// Messages queued for the peer and not sent yet
+	pub current_queue_depth: usize,
+	/// Milliseconds the last message sent waited in the queue, for block,
+	/// transaction and peer address relay respectively
+	pub queue_delay_ms: [u64; 3],
+	/// Round trip time of the last ping answered by the peer
+	pub last_rtt_ms: Option<u64>,
+}
+
+impl PeerStats {
+	pub fn from_peer(peer: &p2p::Peer) -> PeerStats {
+		let (sent, received) = peer.transmitted_bytes();
+		PeerStats {
+			addr: peer.info.addr.to_string(),
+			user_agent: peer.info.user_agent.clone(),
+			sent_bytes: sent,
+			received_bytes: received,
+			current_queue_depth: peer.queue_depth(),
+			queue_delay_ms: peer.queue_delays(),
+			last_rtt_ms: peer.last_rtt_ms(),
+		}
+	}
 }
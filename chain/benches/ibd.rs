@@ -0,0 +1,117 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Throughput of the initial block download of a 100,000 blocks chain, with
+//! a single block downloaded ahead of validation and with the default
+//! lookahead. Fetching a block has a fixed latency, standing for the network.
+//! A single IBD takes minutes, far too long for the standard bench harness
+//! running it dozens of times, so each gets timed once.
+
+extern crate rand;
+extern crate grin_chain as chain;
+extern crate grin_core as core;
+extern crate grin_pow as pow;
+extern crate secp256k1zkp as secp;
+extern crate time;
+
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chain::{BlockFetcher, BodySync};
+use chain::sync::DEFAULT_LOOKAHEAD;
+use chain::types::*;
+use core::core::Block;
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+use secp::Secp256k1;
+use secp::key::SecretKey;
+
+const CHAIN_LEN: u64 = 100_000;
+
+// Simulated time to receive a block from a peer.
+const FETCH_LATENCY_MS: u64 = 1;
+
+struct ChainFetcher {
+	source: Arc<chain::Chain>,
+}
+
+impl BlockFetcher for ChainFetcher {
+	fn fetch_block(&self, h: &Hash) -> Option<Block> {
+		thread::sleep(Duration::from_millis(FETCH_LATENCY_MS));
+		self.source.get_block(h).ok()
+	}
+}
+
+fn init_chain(dir_name: &str, genesis: Option<Block>) -> Arc<chain::Chain> {
+	let _ = fs::remove_dir_all(dir_name);
+	Arc::new(chain::Chain::init(dir_name.to_string(), Arc::new(NoopAdapter {}),
+	                            genesis, pow::verify_size, ChainConfig::default(),
+	                            SyncMode::Full, chain::Checkpoints::none()).unwrap())
+}
+
+// Test chain of empty blocks, all with the same coinbase so building it
+// doesn't need a new range proof for each.
+fn source_chain() -> Arc<chain::Chain> {
+	let source = init_chain(".grin_bench_ibd_src", pow::mine_genesis_block(None));
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let key = SecretKey::new(&secp, &mut rand::thread_rng());
+	let (reward_out, reward_kern) = Block::reward_output(key, &secp).unwrap();
+
+	let mut prev = source.head_header().unwrap();
+	for n in 1..(CHAIN_LEN + 1) {
+		let mut b = Block::with_reward(&prev, vec![], reward_out.clone(), reward_kern.clone())
+			.unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = Difficulty::from_num(n);
+		source.set_mmr_roots(&mut b).unwrap();
+		prev = b.header.clone();
+		source.process_block(b, SKIP_POW).unwrap();
+	}
+	source
+}
+
+fn ibd(source: &Arc<chain::Chain>, lookahead: usize) -> Duration {
+	let genesis = source.get_block(&source.get_header_by_height(1).unwrap().previous).ok();
+	let target = init_chain(".grin_bench_ibd_dst", genesis);
+	for height in 1..(CHAIN_LEN + 1) {
+		let bh = source.get_header_by_height(height).unwrap();
+		target.process_block_header(&bh, SKIP_POW).unwrap();
+	}
+
+	let fetcher = Arc::new(ChainFetcher { source: source.clone() });
+	let sync = BodySync::new(target.clone(), fetcher, lookahead, SYNC);
+	let start = Instant::now();
+	sync.run().unwrap();
+	let elapsed = start.elapsed();
+	assert_eq!(target.head().unwrap().height, CHAIN_LEN);
+	elapsed
+}
+
+fn main() {
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+	let source = source_chain();
+	for &lookahead in &[1, DEFAULT_LOOKAHEAD] {
+		let elapsed = ibd(&source, lookahead);
+		let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+		println!("ibd lookahead {}: {} blocks in {:.1}s, {:.0} blocks/s",
+		         lookahead,
+		         CHAIN_LEN,
+		         secs,
+		         CHAIN_LEN as f64 / secs);
+	}
+}
@@ -15,17 +15,22 @@
 //! Facade and handler for the rest of the blockchain implementation
 //! and mostly the chain pipeline.
 
+use std::cmp;
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 use secp::pedersen::Commitment;
 
-use core::core::{Block, BlockHeader, Output};
+use core::core::{Block, BlockHeader, Output, TxKernel};
 use core::core::target::Difficulty;
 use core::core::hash::Hash;
+use checkpoints::Checkpoints;
+use fork_graph::ForkGraph;
 use grin_store::Error::NotFoundErr;
+use header_chain::{HeaderChain, HEADER_CHAIN_FILE};
 use pipe;
 use store;
+use sumtree::SumTrees;
 use types::*;
 
 use core::global::{MiningParameterMode,MINING_PARAMETER_MODE};
@@ -42,6 +47,14 @@ pub struct Chain {
 	head: Arc<Mutex<Tip>>,
 	block_process_lock: Arc<Mutex<bool>>,
 	orphans: Arc<Mutex<VecDeque<(Options, Block)>>>,
+	sumtrees: Arc<Mutex<SumTrees>>,
+	fork_graph: Arc<Mutex<ForkGraph>>,
+
+	config: ChainConfig,
+	sync_mode: SyncMode,
+	checkpoints: Checkpoints,
+	// only maintained when syncing headers only
+	header_chain: Option<Arc<Mutex<HeaderChain>>>,
 
 	//POW verification function
 	pow_verifier: fn(&BlockHeader, u32) -> bool,
@@ -68,14 +81,21 @@ impl Chain {
 	/// check
 	/// on the current chain head to make sure it exists and creates one based
 	/// on
-	/// the genesis block if necessary.
+	/// the genesis block if necessary. When only syncing headers, the header
+	/// chain is also opened. The genesis block has to match the provided
+	/// checkpoints, if they include one. The chain MMRs are opened and
+	/// brought to the state of the head, spent outputs getting pruned from
+	/// them following the configured pruning policy.
 	pub fn init(
 		db_root: String,
 		adapter: Arc<ChainAdapter>,
 		gen_block: Option<Block>,
 		pow_verifier: fn(&BlockHeader, u32) -> bool,
+		config: ChainConfig,
+		sync_mode: SyncMode,
+		checkpoints: Checkpoints,
 	) -> Result<Chain, Error> {
-		let chain_store = store::ChainKVStore::new(db_root)?;
+		let chain_store = store::ChainKVStore::new(db_root.clone())?;
 
 		// check if we have a head in store, otherwise the genesis block is it
 		let head = match chain_store.head() {
@@ -86,6 +106,7 @@ impl Chain {
 				}
 
 				let gen = gen_block.unwrap();
+				checkpoints.verify(&gen.header)?;
 				chain_store.save_block(&gen)?;
 
 				// saving a new tip based on genesis
@@ -97,9 +118,40 @@ impl Chain {
 			Err(e) => return Err(Error::StoreErr(e)),
 		};
 
+		let indexed = chain_store.index_kernels()?;
+		if indexed > 0 {
+			info!("Indexed the kernels of {} blocks by excess.", indexed);
+		}
+
         // TODO - confirm this was safe to remove based on code above?
 		// let head = chain_store.head()?;
 
+		let sumtrees = SumTrees::open(&db_root, &head, &chain_store, config.pruning, config.archive_pruned)?;
+
+		// the fork graph starts with the last max_reorg_depth headers of the
+		// chain, blocks forking off any of them still being linked after a
+		// restart
+		let mut headers = vec![chain_store.get_block_header(&head.last_block_h)?];
+		while (headers.len() as u64) <= config.max_reorg_depth {
+			let previous = {
+				let last = &headers[headers.len() - 1];
+				if last.height == 0 {
+					break;
+				}
+				last.previous
+			};
+			headers.push(chain_store.get_block_header(&previous)?);
+		}
+		headers.reverse();
+		let fork_graph = ForkGraph::from_chain(headers, config.max_reorg_depth)?;
+
+		let header_chain = match sync_mode {
+			SyncMode::Full => None,
+			SyncMode::HeadersOnly => {
+				let header_chain = open_header_chain(&db_root, &chain_store, &config, pow_verifier)?;
+				Some(Arc::new(Mutex::new(header_chain)))
+			}
+		};
 
 		Ok(Chain {
 			store: Arc::new(chain_store),
@@ -107,14 +159,26 @@ impl Chain {
 			head: Arc::new(Mutex::new(head)),
 			block_process_lock: Arc::new(Mutex::new(true)),
 			orphans: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_ORPHANS + 1))),
+			sumtrees: Arc::new(Mutex::new(sumtrees)),
+			fork_graph: Arc::new(Mutex::new(fork_graph)),
+			config: config,
+			sync_mode: sync_mode,
+			checkpoints: checkpoints,
+			header_chain: header_chain,
 			pow_verifier: pow_verifier,
 		})
 	}
 
 	/// Attempt to add a new block to the chain. Returns the new chain tip if it
 	/// has been added to the longest chain, None if it's added to an (as of
-	/// now) orphan chain.
+	/// now) orphan chain. Accepted blocks are added to the fork graph, which
+	/// tracks the tips of all the branches that could still take over and
+	/// picks the head among them.
 	pub fn process_block(&self, b: Block, opts: Options) -> Result<Option<Tip>, Error> {
+		if self.sync_mode == SyncMode::HeadersOnly {
+			return Err(Error::Unfit("only syncing headers".to_string()));
+		}
+		let opts = self.check_checkpoints(&b.header, opts)?;
 		let head = self.store.head().map_err(&Error::StoreErr)?;
 		let ctx = self.ctx_from_head(head, opts);
 
@@ -142,35 +206,80 @@ impl Chain {
 		res
 	}
 
+	/// The tips of all the forks we know of that are recent enough to still
+	/// be reorganized to, the one of the chain head first.
+	pub fn fork_tips(&self) -> Vec<BlockHeader> {
+		let fork_graph = self.fork_graph.lock().unwrap();
+		fork_graph.tips().into_iter().cloned().collect()
+	}
+
 	/// Attempt to add a new header to the header chain. Only necessary during
-	/// sync.
+	/// sync. When only syncing headers, the header is first validated and
+	/// appended by the header chain, the store keeping a copy for lookups.
 	pub fn process_block_header(
 		&self,
 		bh: &BlockHeader,
 		opts: Options,
 	) -> Result<Option<Tip>, Error> {
 
+		let opts = self.check_checkpoints(bh, opts)?;
 		let head = self.store.get_header_head().map_err(&Error::StoreErr)?;
-		let ctx = self.ctx_from_head(head, opts);
+		let ctx = match self.header_chain {
+			Some(ref header_chain) => {
+				header_chain.lock().unwrap().process_header(bh)?;
+				// no need to verify the cuckoo cycle twice
+				self.ctx_from_head(head, opts | KNOWN_POW)
+			}
+			None => self.ctx_from_head(head, opts),
+		};
 
 		pipe::process_block_header(bh, ctx)
 	}
 
-	fn ctx_from_head(&self, head: Tip, opts: Options) -> pipe::BlockContext {
-		let opts_in = opts;
-		let param_ref=MINING_PARAMETER_MODE.read().unwrap();
-		let opts_in = match *param_ref {
-			MiningParameterMode::AutomatedTesting => opts_in | EASY_POW,
-			MiningParameterMode::UserTesting => opts_in | EASY_POW,
-			MiningParameterMode::Production => opts_in,
-		};
+	/// Syncs to disk the headers the header chain appended since the last
+	/// sync, to be called once done processing a batch of headers. Nothing
+	/// to do unless only syncing headers.
+	pub fn sync_header_chain(&self) -> Result<(), Error> {
+		match self.header_chain {
+			Some(ref header_chain) => header_chain.lock().unwrap().sync(),
+			None => Ok(()),
+		}
+	}
 
+	// Checks the header against our checkpoints. Once the synced headers
+	// matched the highest checkpoint, the ones below it we already have are
+	// committed to by the checkpoint and don't need their cuckoo cycle
+	// verified again when their block comes. Difficulties still are.
+	fn check_checkpoints(&self, bh: &BlockHeader, opts: Options) -> Result<Options, Error> {
+		self.checkpoints.verify(bh)?;
+		if !self.checkpoints.covers(bh.height) || !self.is_synced_header(&bh.hash()) {
+			return Ok(opts);
+		}
+		match self.checkpoints.highest() {
+			Some((_, h)) if self.is_synced_header(&h) => Ok(opts | KNOWN_POW),
+			_ => Ok(opts),
+		}
+	}
+
+	// Whether we already validated the header with the provided hash while
+	// syncing headers.
+	fn is_synced_header(&self, h: &Hash) -> bool {
+		match self.header_chain {
+			Some(ref header_chain) => header_chain.lock().unwrap().get_header(h).is_some(),
+			None => self.store.get_block_header(h).is_ok(),
+		}
+	}
+
+	fn ctx_from_head(&self, head: Tip, opts: Options) -> pipe::BlockContext {
 		pipe::BlockContext {
-			opts: opts_in,
+			opts: mining_mode_opts(opts),
 			store: self.store.clone(),
 			adapter: self.adapter.clone(),
 			head: head,
 			pow_verifier: self.pow_verifier,
+			max_reorg_depth: self.config.max_reorg_depth,
+			sumtrees: self.sumtrees.clone(),
+			fork_graph: self.fork_graph.clone(),
 			lock: self.block_process_lock.clone(),
 		}
 	}
@@ -251,8 +360,14 @@ impl Chain {
 		self.store.get_block(h).map_err(&Error::StoreErr)
 	}
 
-	/// Gets a block header by hash
+	/// Gets a block header by hash. When only syncing headers, the header
+	/// chain index is tried first.
 	pub fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
+		if let Some(ref header_chain) = self.header_chain {
+			if let Some(header) = header_chain.lock().unwrap().get_header(h) {
+				return Ok(header);
+			}
+		}
 		self.store.get_block_header(h).map_err(&Error::StoreErr)
 	}
 
@@ -270,11 +385,170 @@ impl Chain {
 		)
 	}
 
+	/// Gets the block header including the kernel with the provided excess
+	pub fn get_block_header_by_kernel_excess(&self, excess: &Commitment) -> Result<BlockHeader, Error> {
+		self.store.get_block_header_by_kernel_excess(excess).map_err(
+			&Error::StoreErr,
+		)
+	}
+
+	/// Gets all the kernels included in the blocks between the provided
+	/// heights, inclusive, in chain order. Heights past the current head are
+	/// ignored. Allows wallets verifying payment proofs to go through kernels
+	/// without downloading full blocks.
+	pub fn scan_kernels(&self, from_height: u64, to_height: u64) -> Result<KernelScan, Error> {
+		let head_height = self.head.lock().unwrap().height;
+		let to_height = cmp::min(to_height, head_height);
+
+		let mut scan = KernelScan {
+			kernels: vec![],
+			kernels_per_block: vec![],
+		};
+		for height in from_height..(to_height + 1) {
+			let header = self.get_header_by_height(height)?;
+			let block = self.get_block(&header.hash())?;
+			scan.kernels_per_block.push(block.kernels.len());
+			scan.kernels.extend(block.kernels);
+		}
+		Ok(scan)
+	}
+
+	/// Gets the kernel with the provided excess, if it's included in the
+	/// kernel MMR of the chain.
+	pub fn get_kernel_by_excess(&self, excess: &Commitment) -> Option<TxKernel> {
+		let sumtrees = self.sumtrees.lock().unwrap();
+		sumtrees.get_kernel(excess, &*self.store)
+	}
+
+	/// Whether the output with the provided commitment is in the output MMR
+	/// of the chain and hasn't been pruned. Spent outputs are retained for as
+	/// long as the pruning policy says.
+	pub fn is_output_retained(&self, commit: &Commitment) -> bool {
+		let sumtrees = self.sumtrees.lock().unwrap();
+		sumtrees.is_output_retained(commit, &*self.store)
+	}
+
+	/// Gets the output with the provided commitment, along with its range
+	/// proof, from the archive of the outputs pruned from the MMRs. Outputs
+	/// only get archived when the chain is configured to.
+	pub fn get_archived_output(&self, commit: &Commitment) -> Result<Option<Output>, Error> {
+		let sumtrees = self.sumtrees.lock().unwrap();
+		sumtrees.get_archived_output(commit, &*self.store)
+	}
+
+	/// Root of the output MMR as it was after the block at the provided
+	/// height on the current chain, as committed to by its header.
+	pub fn output_pmmr_root_at(&self, height: u64) -> Result<Hash, Error> {
+		let head = self.store.head().map_err(&Error::StoreErr)?;
+		Ok(self.header_at(height, &head)?.output_mmr_root)
+	}
+
+	/// Gets the output with the provided commitment if it was unspent after
+	/// the block at the provided height on the current chain, even if it
+	/// has been spent and pruned since.
+	pub fn get_output_at(&self, commit: &Commitment, height: u64) -> Result<Option<Output>, Error> {
+		let sumtrees = self.sumtrees.lock().unwrap();
+		let head = self.store.head().map_err(&Error::StoreErr)?;
+		let header = self.header_at(height, &head)?;
+		let sizes = self.store.get_block_mmr_sizes(&header.hash())?;
+
+		if !sumtrees.is_unspent_at(commit, height, sizes.output_size, &*self.store)? {
+			return Ok(None);
+		}
+		match self.store.get_output_by_commit(commit) {
+			Ok(out) => Ok(Some(out)),
+			Err(NotFoundErr) => Ok(None),
+			Err(e) => Err(Error::StoreErr(e)),
+		}
+	}
+
+	/// Gets the outputs that were unspent after the block at the provided
+	/// height on the current chain, in the order they were added to it. At
+	/// most max outputs are returned, from the provided insertion index in
+	/// the output MMR, starting at 1, along with the index to get the next
+	/// ones from if any are left.
+	pub fn get_outputs_at(
+		&self,
+		height: u64,
+		start_index: u64,
+		max: u64,
+	) -> Result<(Vec<Output>, Option<u64>), Error> {
+		let sumtrees = self.sumtrees.lock().unwrap();
+		let head = self.store.head().map_err(&Error::StoreErr)?;
+		let header = self.header_at(height, &head)?;
+		let sizes = self.store.get_block_mmr_sizes(&header.hash())?;
+
+		let (commits, next) = sumtrees.unspent_at(height, sizes.output_size, start_index, max, &*self.store)?;
+		let mut outputs = vec![];
+		for commit in commits {
+			outputs.push(self.store.get_output_by_commit(&commit)?);
+		}
+		Ok((outputs, next))
+	}
+
+	// Header of the block at the provided height on the chain ending with the
+	// provided head. The height index doesn't include genesis.
+	fn header_at(&self, height: u64, head: &Tip) -> Result<BlockHeader, Error> {
+		if height > head.height {
+			return Err(Error::Other(format!("height {} past the chain head", height)));
+		}
+		if height == head.height {
+			return self.get_block_header(&head.last_block_h);
+		}
+		if height == 0 {
+			let first = self.get_header_by_height(1)?;
+			return self.get_block_header(&first.previous);
+		}
+		self.get_header_by_height(height)
+	}
+
+	/// Compacts the output and range proof MMRs, cutting the data of spent
+	/// outputs from storage. Reorganizations can't go past the compacted data
+	/// anymore.
+	pub fn compact(&self) -> Result<(), Error> {
+		let mut sumtrees = self.sumtrees.lock().unwrap();
+		sumtrees.compact()
+	}
+
+	/// Sets the output, range proof and kernel MMR roots of the block header
+	/// to the roots of the chain MMRs once the block is applied to them,
+	/// usually on top of the head. Used when building a new block, before
+	/// mining it. The MMRs are left as they were.
+	pub fn set_mmr_roots(&self, b: &mut Block) -> Result<(), Error> {
+		let mut sumtrees = self.sumtrees.lock().unwrap();
+		let head = self.store.head().map_err(&Error::StoreErr)?;
+
+		let (fork_point, _) = sumtrees.apply_fork(b, &head, &*self.store, false)?;
+		let roots = sumtrees.roots();
+		sumtrees.restore(&fork_point, &head, &*self.store)?;
+
+		b.header.set_mmr_roots(&roots);
+		Ok(())
+	}
+
+	/// Statistics of the chain up to its head: number of unspent outputs and
+	/// kernels, total supply and fees. Kept for each block as it's saved, so
+	/// they follow the head through reorganizations and restarts.
+	pub fn get_stats(&self) -> Result<ChainStats, Error> {
+		let head = self.head.lock().unwrap().clone();
+		self.store.get_block_stats(&head.last_block_h).map_err(&Error::StoreErr)
+	}
+
 	/// Get the tip of the header chain
 	pub fn get_header_head(&self) -> Result<Tip, Error> {
 		self.store.get_header_head().map_err(&Error::StoreErr)
 	}
 
+	/// Whether full blocks or only headers are synced, with the heights
+	/// reached by both.
+	pub fn sync_status(&self) -> Result<SyncStatus, Error> {
+		Ok(SyncStatus {
+			mode: self.sync_mode,
+			header_height: self.get_header_head()?.height,
+			body_height: self.head.lock().unwrap().height,
+		})
+	}
+
 	/// Builds an iterator on blocks starting from the current chain head and
 	/// running backward. Specialized to return information pertaining to block
 	/// difficulty calculation (timestamp and previous difficulties).
@@ -283,3 +557,59 @@ impl Chain {
 		store::DifficultyIter::from(head.last_block_h, self.store.clone())
 	}
 }
+
+// Adds the options implied by the mining parameter mode, testing modes using
+// smaller proofs of work.
+fn mining_mode_opts(opts: Options) -> Options {
+	let param_ref = MINING_PARAMETER_MODE.read().unwrap();
+	match *param_ref {
+		MiningParameterMode::AutomatedTesting => opts | EASY_POW,
+		MiningParameterMode::UserTesting => opts | EASY_POW,
+		MiningParameterMode::Production => opts,
+	}
+}
+
+// Opens the header chain file and catches up with the headers the store has
+// past its head. That's all of them on first start, but it also covers a node
+// that was syncing full blocks before.
+fn open_header_chain(
+	db_root: &str,
+	store: &ChainStore,
+	config: &ChainConfig,
+	pow_verifier: fn(&BlockHeader, u32) -> bool,
+) -> Result<HeaderChain, Error> {
+	let mut header_chain = HeaderChain::open(
+		format!("{}/{}", db_root, HEADER_CHAIN_FILE),
+		mining_mode_opts(NONE),
+		config.max_reorg_depth,
+		pow_verifier,
+	)?;
+
+	// walk back the store header chain until we reach a header we have
+	let mut missing = vec![];
+	let mut header = store.get_block_header(&store.get_header_head()?.last_block_h)?;
+	loop {
+		let known = header_chain.get_header_by_height(header.height).map(|h| h.hash());
+		if known == Some(header.hash()) {
+			break;
+		}
+		let (height, previous) = (header.height, header.previous);
+		missing.push(header);
+		if height == 0 {
+			break;
+		}
+		header = store.get_block_header(&previous)?;
+	}
+
+	for header in missing.iter().rev() {
+		if let Err(e) = header_chain.process_header(header) {
+			warn!("Could not add header {} at {} to the header chain: {:?}",
+			      header.hash(),
+			      header.height,
+			      e);
+			break;
+		}
+	}
+	header_chain.sync()?;
+	Ok(header_chain)
+}
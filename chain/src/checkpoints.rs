@@ -0,0 +1,100 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hardcoded checkpoints, block hashes at regular heights of the production
+//! chain. Headers at those heights have to match and, as headers commit to
+//! all the ones before them, once the synced headers reached the highest
+//! checkpoint the blocks below it don't need their cuckoo cycle verified
+//! again, speeding up the initial sync. Updated with each release.
+
+use core::core::BlockHeader;
+use core::core::hash::Hash;
+use core::global::{self, MiningParameterMode};
+use types::Error;
+use util;
+
+/// Number of blocks between two checkpoints.
+pub const CHECKPOINT_INTERVAL: u64 = 10_000;
+
+// Heights and hex encoded block hashes of the production chain checkpoints,
+// in increasing height order. The genesis block is still mined on startup
+// so there's no chain to take checkpoints from yet.
+const CHECKPOINTS: &'static [(u64, &'static str)] = &[];
+
+/// Set of checkpoints the chain is checked against.
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+	points: Vec<(u64, Hash)>,
+}
+
+impl Checkpoints {
+	/// Checkpoints from the provided heights and block hashes.
+	pub fn new(mut points: Vec<(u64, Hash)>) -> Checkpoints {
+		points.sort_by_key(|&(height, _)| height);
+		Checkpoints { points: points }
+	}
+
+	/// No checkpoint, all headers are fully verified.
+	pub fn none() -> Checkpoints {
+		Checkpoints { points: vec![] }
+	}
+
+	/// The checkpoints shipped with this release. Only apply to the
+	/// production chain, testing modes mine their own chains.
+	pub fn hardcoded() -> Checkpoints {
+		let production = match *global::MINING_PARAMETER_MODE.read().unwrap() {
+			MiningParameterMode::Production => true,
+			_ => false,
+		};
+		if !production {
+			return Checkpoints::none();
+		}
+		let points = CHECKPOINTS
+			.iter()
+			.map(|&(height, hex)| {
+				let bytes = util::from_hex(hex.to_string()).expect("invalid checkpoint hash");
+				(height, Hash::from_vec(bytes))
+			})
+			.collect();
+		Checkpoints::new(points)
+	}
+
+	/// All the checkpoints, by increasing height.
+	pub fn all(&self) -> &[(u64, Hash)] {
+		&self.points
+	}
+
+	/// The highest checkpoint, if any.
+	pub fn highest(&self) -> Option<(u64, Hash)> {
+		self.points.last().cloned()
+	}
+
+	/// Whether the header at the provided height is below the highest
+	/// checkpoint, committed to by it.
+	pub fn covers(&self, height: u64) -> bool {
+		match self.points.last() {
+			Some(&(highest, _)) => height < highest,
+			None => false,
+		}
+	}
+
+	/// Checks the header against the checkpoint at its height, if any.
+	pub fn verify(&self, header: &BlockHeader) -> Result<(), Error> {
+		let checkpoint = self.points.iter().find(|&&(height, _)| height == header.height);
+		match checkpoint {
+			Some(&(height, hash)) if hash != header.hash() => Err(Error::CheckpointMismatch(height)),
+			_ => Ok(()),
+		}
+	}
+}
@@ -0,0 +1,158 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Graph of the recent block headers of all the forks we know of, tracking
+//! the tip of each of them. Branches too far behind the best tip to ever be
+//! reorganized to are forgotten.
+
+use std::collections::{HashMap, HashSet};
+
+use core::core::BlockHeader;
+use core::core::hash::Hash;
+use types::Error;
+
+/// DAG of block headers keyed by hash, each linked to its parent by its
+/// previous hash. Starts from a single root header, usually the chain head,
+/// and only keeps the headers at most max_reorg_depth blocks below the best
+/// tip.
+pub struct ForkGraph {
+	headers: HashMap<Hash, BlockHeader>,
+	tips: HashSet<Hash>,
+	best: Hash,
+	max_reorg_depth: u64,
+}
+
+impl ForkGraph {
+	/// New graph holding only the provided root header.
+	pub fn new(root: BlockHeader, max_reorg_depth: u64) -> ForkGraph {
+		let hash = root.hash();
+		let mut headers = HashMap::new();
+		headers.insert(hash, root);
+		let mut tips = HashSet::new();
+		tips.insert(hash);
+		ForkGraph {
+			headers: headers,
+			tips: tips,
+			best: hash,
+			max_reorg_depth: max_reorg_depth,
+		}
+	}
+
+	/// New graph holding the provided headers of a chain, in increasing
+	/// height, its tip being the last one.
+	pub fn from_chain(headers: Vec<BlockHeader>, max_reorg_depth: u64) -> Result<ForkGraph, Error> {
+		let mut headers = headers.into_iter();
+		let root = match headers.next() {
+			Some(root) => root,
+			None => return Err(Error::Other("no header to start the fork graph from".to_string())),
+		};
+		let mut graph = ForkGraph::new(root, max_reorg_depth);
+		for h in headers {
+			graph.add_header(h)?;
+		}
+		Ok(graph)
+	}
+
+	/// Inserts the header, linking it to its parent. A header whose parent
+	/// isn't in the graph is only accepted if it has more work than the best
+	/// tip (its fork starting before the graph does), becoming the root of a
+	/// new branch. The header becomes the best tip if it has more work than
+	/// the current one, in which case branches now too far behind are
+	/// pruned.
+	pub fn add_header(&mut self, h: BlockHeader) -> Result<(), Error> {
+		let hash = h.hash();
+		if self.headers.contains_key(&hash) {
+			return Ok(());
+		}
+		let better = self.is_better(&h);
+		if !better && !self.headers.contains_key(&h.previous) {
+			return Err(Error::Orphan);
+		}
+
+		self.tips.remove(&h.previous);
+		self.tips.insert(hash);
+		self.headers.insert(hash, h);
+		if better {
+			self.best = hash;
+			self.prune();
+		}
+		Ok(())
+	}
+
+	/// Whether the header would become the best tip once added, having more
+	/// work than the current one.
+	pub fn is_better(&self, h: &BlockHeader) -> bool {
+		h.total_difficulty > self.best_tip().total_difficulty
+	}
+
+	/// The tip with the highest total difficulty, the first one seen among
+	/// equals.
+	pub fn best_tip(&self) -> &BlockHeader {
+		&self.headers[&self.best]
+	}
+
+	/// All the tips of the graph, best first then by decreasing total
+	/// difficulty.
+	pub fn tips(&self) -> Vec<&BlockHeader> {
+		let mut tips = self.tips.iter().map(|h| &self.headers[h]).collect::<Vec<_>>();
+		tips.sort_by(|a, b| b.total_difficulty.cmp(&a.total_difficulty));
+		if let Some(pos) = tips.iter().position(|h| h.hash() == self.best) {
+			let best = tips.remove(pos);
+			tips.insert(0, best);
+		}
+		tips
+	}
+
+	/// Whether the graph includes the header with the provided hash.
+	pub fn contains(&self, h: &Hash) -> bool {
+		self.headers.contains_key(h)
+	}
+
+	// Drops all the headers more than max_reorg_depth blocks below the best
+	// tip, along with the branches forking off the best chain there.
+	fn prune(&mut self) {
+		let min_height = self.best_tip().height.saturating_sub(self.max_reorg_depth);
+		self.headers.retain(|_, h| h.height >= min_height);
+
+		let mut best_chain = HashSet::new();
+		let mut current = self.best;
+		while let Some(h) = self.headers.get(&current) {
+			best_chain.insert(current);
+			current = h.previous;
+		}
+
+		let mut dropped = vec![];
+		for tip in &self.tips {
+			let mut branch = vec![];
+			let mut current = *tip;
+			while !best_chain.contains(&current) {
+				match self.headers.get(&current) {
+					Some(h) => {
+						branch.push(current);
+						current = h.previous;
+					}
+					None => {
+						dropped.extend(branch);
+						break;
+					}
+				}
+			}
+		}
+		for h in dropped {
+			self.headers.remove(&h);
+		}
+		let headers = &self.headers;
+		self.tips.retain(|h| headers.contains_key(h));
+	}
+}
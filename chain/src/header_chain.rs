@@ -0,0 +1,275 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Chain of block headers only, for light clients that need to verify the
+//! cumulative difficulty of the chain without downloading full blocks.
+//! Headers are validated against the ones preceding them and appended to a
+//! single file, in fixed size records. Headers forking off the chain are
+//! kept aside until their branch has more work, at which point the file is
+//! truncated back to the fork point and the branch appended instead.
+
+use std::collections::HashMap;
+
+use core::consensus::{self, TargetError};
+use core::core::BlockHeader;
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
+use core::ser;
+use grin_store::block_index::BlockHashIndex;
+use grin_store::sumtree::AppendOnlyFile;
+use pipe;
+use types::*;
+
+/// Name of the header chain file, under the chain db root.
+pub const HEADER_CHAIN_FILE: &'static str = "headers.bin";
+
+// Number of headers appended between two saves of the hash index, the ones
+// appended since the last save get indexed again on restart
+const INDEX_SAVE_INTERVAL: usize = 1000;
+
+/// Header chain, kept in memory and backed by an append-only file. Headers
+/// are indexed by hash, the index being saved next to the file. Headers of
+/// the branches forking off the chain at most max_reorg_depth below its head
+/// are only kept in memory, until a branch gets more work than the chain.
+pub struct HeaderChain {
+	file: AppendOnlyFile,
+	headers: Vec<BlockHeader>,
+	index: BlockHashIndex,
+	forks: HashMap<Hash, BlockHeader>,
+	record_len: usize,
+	max_reorg_depth: u64,
+	opts: Options,
+	pow_verifier: fn(&BlockHeader, u32) -> bool,
+}
+
+impl HeaderChain {
+	/// Opens the header chain saved in the provided file, creating the file
+	/// if necessary. A trailing partial record, left by an interrupted write,
+	/// is dropped. The hash index is rebuilt if it doesn't cover all the
+	/// headers.
+	pub fn open(
+		path: String,
+		opts: Options,
+		max_reorg_depth: u64,
+		pow_verifier: fn(&BlockHeader, u32) -> bool,
+	) -> Result<HeaderChain, Error> {
+		// all headers serialize to the same size for a given proof size
+		let record_len = ser::ser_vec(&BlockHeader::default())?.len();
+		let index_path = format!("{}.idx", path);
+		let mut file = AppendOnlyFile::with_segment_size(path, 0, false)?;
+
+		let size = file.size()?;
+		let complete = size - size % (record_len as u64);
+		if complete < size {
+			file.rewind(complete)?;
+			file.sync()?;
+		}
+
+		let count = (complete / record_len as u64) as usize;
+		let mut headers = Vec::with_capacity(count);
+		let mut buf = vec![0; record_len];
+		for n in 0..count {
+			file.read_into(n * record_len, &mut buf)?;
+			headers.push(ser::deserialize(&mut &buf[..])?);
+		}
+		let index = BlockHashIndex::open(index_path, count, || {
+			Ok(headers
+				.iter()
+				.enumerate()
+				.map(|(n, h)| (h.hash(), (n * record_len) as u64))
+				.collect())
+		})?;
+
+		Ok(HeaderChain {
+			file: file,
+			headers: headers,
+			index: index,
+			forks: HashMap::new(),
+			record_len: record_len,
+			max_reorg_depth: max_reorg_depth,
+			opts: opts,
+			pow_verifier: pow_verifier,
+		})
+	}
+
+	/// Validates the header and adds it to the chain. Its timestamp, total
+	/// difficulty and proof of work are checked against the headers before
+	/// it. A header extending the head is appended, one forking off the chain
+	/// is kept aside, unless its branch now has more work than the chain: the
+	/// chain is then rewound to the fork point and the branch appended. On an
+	/// empty chain the header is taken as genesis, as is. Appended headers
+	/// are only synced to disk by sync.
+	pub fn process_header(&mut self, header: &BlockHeader) -> Result<(), Error> {
+		if self.headers.is_empty() {
+			if header.height != 0 {
+				return Err(Error::GenesisBlockRequired);
+			}
+			return self.append(header);
+		}
+
+		let hash = header.hash();
+		if self.index.get(&hash).is_some() || self.forks.contains_key(&hash) {
+			return Err(Error::Unfit("already in the header chain".to_string()));
+		}
+		let (fork_height, branch) = self.branch_of(header)?;
+		let head = self.headers[self.headers.len() - 1].clone();
+		let depth = head.height - fork_height;
+		if depth > self.max_reorg_depth {
+			return Err(Error::ReorgTooDeep { depth: depth });
+		}
+		self.validate_header(header, fork_height, &branch)?;
+
+		if depth == 0 {
+			self.append(header)
+		} else if header.total_difficulty > head.total_difficulty {
+			self.reorg(fork_height, branch, header)
+		} else {
+			self.forks.insert(hash, header.clone());
+			Ok(())
+		}
+	}
+
+	/// Syncs the headers appended since the last sync to disk. Processing a
+	/// batch of headers and syncing once is much cheaper than syncing each.
+	pub fn sync(&mut self) -> Result<(), Error> {
+		self.file.sync()?;
+		Ok(())
+	}
+
+	// Height of the header of the chain the provided header's branch forks
+	// off from, along with the headers of the branch up to the provided
+	// header's previous, in height order. The branch is empty for a header
+	// extending the chain head. Fails with Orphan if the previous header is
+	// unknown.
+	fn branch_of(&self, header: &BlockHeader) -> Result<(u64, Vec<BlockHeader>), Error> {
+		let mut branch = vec![];
+		let mut previous = header.previous;
+		loop {
+			if let Some(offset) = self.index.get(&previous) {
+				branch.reverse();
+				return Ok(((offset / self.record_len as u64), branch));
+			}
+			match self.forks.get(&previous) {
+				Some(h) => {
+					previous = h.previous;
+					branch.push(h.clone());
+				}
+				None => return Err(Error::Orphan),
+			}
+		}
+	}
+
+	fn validate_header(
+		&self,
+		header: &BlockHeader,
+		fork_height: u64,
+		branch: &[BlockHeader],
+	) -> Result<(), Error> {
+		// headers of the chain up to the fork point, then of the branch
+		let ancestors = &self.headers[..(fork_height as usize + 1)];
+		let prev = branch.last().unwrap_or(&ancestors[ancestors.len() - 1]);
+		if header.height != prev.height + 1 {
+			return Err(Error::Unfit("doesn't follow its previous header".to_string()));
+		}
+		header.check_version(header.height, &consensus::ConsensusParams::default())
+			.map_err(|e| Error::InvalidBlockVersion(e))?;
+		pipe::validate_header_time(header, prev)?;
+		pipe::validate_total_difficulty(header)?;
+
+		if !self.opts.intersects(SKIP_POW) {
+			// same as the store difficulty iterator, running back to genesis
+			// (excluded) from the previous header
+			let diff_iter = ancestors[1..].iter().chain(branch.iter()).rev().map(
+				|h| -> Result<(u64, Difficulty), TargetError> {
+					Ok((h.timestamp.to_timespec().sec as u64, h.difficulty.clone()))
+				},
+			);
+			pipe::validate_header_difficulty(header, prev, diff_iter)?;
+			pipe::validate_header_pow(header, self.opts, self.pow_verifier)?;
+		}
+		Ok(())
+	}
+
+	// Appends the header to the file, indexing it. Side branches forking too
+	// far below the new head are dropped.
+	fn append(&mut self, header: &BlockHeader) -> Result<(), Error> {
+		let data = ser::ser_vec(header)?;
+		if data.len() != self.record_len {
+			return Err(Error::Other(format!("Header of {} bytes, expected {}", data.len(), self.record_len)));
+		}
+		self.file.append(&data)?;
+		let offset = (self.headers.len() * self.record_len) as u64;
+		self.index.insert(header.hash(), offset);
+		self.headers.push(header.clone());
+		if self.headers.len() % INDEX_SAVE_INTERVAL == 0 {
+			// the index doesn't get ahead of the file
+			self.sync()?;
+			self.index.save()?;
+		}
+
+		if !self.forks.is_empty() {
+			let max_reorg_depth = self.max_reorg_depth;
+			self.forks.retain(|_, h| h.height.saturating_add(max_reorg_depth) >= header.height);
+		}
+		Ok(())
+	}
+
+	// Rewinds the chain to the header at fork_height and appends the provided
+	// branch, ending with the header. The headers rewound are kept aside like
+	// any other branch.
+	fn reorg(&mut self, fork_height: u64, branch: Vec<BlockHeader>, header: &BlockHeader) -> Result<(), Error> {
+		info!("Header chain reorg from {} back to {}, {} headers on the new branch.",
+		      self.headers.len() - 1,
+		      fork_height,
+		      branch.len() + 1);
+
+		let rewound = self.headers.split_off(fork_height as usize + 1);
+		self.file.rewind(self.headers.len() as u64 * self.record_len as u64)?;
+		for h in rewound {
+			let hash = h.hash();
+			self.index.remove(&hash);
+			self.forks.insert(hash, h);
+		}
+		for h in branch.iter().chain(Some(header)) {
+			self.forks.remove(&h.hash());
+			self.append(h)?;
+		}
+
+		// the saved index may have entries for rewound headers, while still
+		// covering as many headers
+		self.sync()?;
+		self.index.save()?;
+		Ok(())
+	}
+
+	/// Tip of the header chain, None if it doesn't even have a genesis yet.
+	pub fn head(&self) -> Option<Tip> {
+		self.headers.last().map(Tip::from_block)
+	}
+
+	/// Gets the header at the provided height
+	pub fn get_header_by_height(&self, height: u64) -> Option<BlockHeader> {
+		self.headers.get(height as usize).cloned()
+	}
+
+	/// Gets the header with the provided hash, looked up in the hash index
+	/// or in the branches forking off the chain
+	pub fn get_header(&self, h: &Hash) -> Option<BlockHeader> {
+		self.index
+			.get(h)
+			.and_then(|offset| self.headers.get(offset as usize / self.record_len))
+			.or_else(|| self.forks.get(h))
+			.cloned()
+	}
+}
@@ -32,14 +32,24 @@ extern crate time;
 
 extern crate grin_core as core;
 extern crate grin_store;
+extern crate grin_util as util;
 extern crate secp256k1zkp as secp;
 
 mod chain;
+pub mod checkpoints;
+pub mod fork_graph;
+pub mod header_chain;
 pub mod pipe;
 pub mod store;
+pub mod sumtree;
+pub mod sync;
 pub mod types;
 
 // Re-export the base interface
 
 pub use chain::Chain;
-pub use types::{ChainStore, Tip, ChainAdapter, SYNC, NONE, SKIP_POW, EASY_POW, Options, Error};
+pub use checkpoints::Checkpoints;
+pub use fork_graph::ForkGraph;
+pub use header_chain::HeaderChain;
+pub use sync::{BlockFetcher, BodySync};
+pub use types::{ChainConfig, ChainStats, ChainStore, PruningPolicy, Tip, KernelScan, ChainAdapter, SyncMode, SyncStatus, SYNC, NONE, SKIP_POW, EASY_POW, KNOWN_POW, Options, Error};
@@ -14,6 +14,7 @@
 
 //! Implementation of the chain block acceptance (or refusal) pipeline.
 
+use std::cmp;
 use std::sync::{Arc, Mutex};
 
 use secp;
@@ -21,10 +22,14 @@ use time;
 
 use core::consensus;
 use core::core::hash::{Hash, Hashed};
-use core::core::{BlockHeader, Block};
+use core::core::{BlockHeader, Block, BlockError};
+use core::consensus::TargetError;
+use core::core::target::Difficulty;
 use core::core::transaction;
 use types::*;
 use store;
+use fork_graph::ForkGraph;
+use sumtree::SumTrees;
 use core::global;
 
 /// Contextual information required to process a new block and either reject or
@@ -40,6 +45,12 @@ pub struct BlockContext {
 	pub head: Tip,
 	/// The POW verification function
 	pub pow_verifier: fn(&BlockHeader, u32) -> bool,
+	/// Maximum depth of a reorganization
+	pub max_reorg_depth: u64,
+	/// The chain MMRs
+	pub sumtrees: Arc<Mutex<SumTrees>>,
+	/// The tips of the recent forks, picking the head
+	pub fork_graph: Arc<Mutex<ForkGraph>>,
 	/// The lock
 	pub lock: Arc<Mutex<bool>>,
 }
@@ -66,6 +77,7 @@ pub fn process_block(b: &Block, mut ctx: BlockContext) -> Result<Option<Tip>, Er
 	}
 
 	validate_block(b, &mut ctx)?;
+	let reorg_depth = check_reorg_depth(&b.header, &ctx)?;
 	debug!(
 		"Block at {} with hash {} is valid, going to save and append.",
 		b.header.height,
@@ -73,8 +85,18 @@ pub fn process_block(b: &Block, mut ctx: BlockContext) -> Result<Option<Tip>, Er
 	);
 
 	let _ = ctx.lock.lock().unwrap();
-	add_block(b, &mut ctx)?;
-	update_head(b, &mut ctx)
+	let sumtrees_ref = ctx.sumtrees.clone();
+	let mut sumtrees = sumtrees_ref.lock().unwrap();
+	// the MMRs are at the state of the head once we hold them
+	ctx.head = ctx.store.head().map_err(&Error::StoreErr)?;
+	let res = add_to_chain(b, &mut sumtrees, &mut ctx);
+	if let (&Ok(Some(_)), Some(depth)) = (&res, reorg_depth) {
+		warn!("Reorganized the chain to {} at {}, {} blocks deep.",
+		      b.hash(),
+		      b.header.height,
+		      depth);
+	}
+	res
 }
 
 /// Process the block header
@@ -126,6 +148,25 @@ fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), E
 	if header.height != prev.height + 1 {
 		return Err(Error::InvalidBlockHeight);
 	}
+	header.check_version(header.height, &consensus::ConsensusParams::default())
+		.map_err(|e| Error::InvalidBlockVersion(e))?;
+	validate_header_time(header, &prev)?;
+	validate_total_difficulty(header)?;
+
+	if !ctx.opts.intersects(SKIP_POW) {
+		let diff_iter = store::DifficultyIter::from(header.previous, ctx.store.clone());
+		validate_header_difficulty(header, &prev, diff_iter)?;
+		if !ctx.opts.intersects(KNOWN_POW) {
+			validate_header_pow(header, ctx.opts, ctx.pow_verifier)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks the header timestamp is past the one of the previous header and not
+/// too far in the future.
+pub fn validate_header_time(header: &BlockHeader, prev: &BlockHeader) -> Result<(), Error> {
 	if header.timestamp <= prev.timestamp && !global::is_automated_testing_mode(){
 		// prevent time warp attacks and some timestamp manipulations by forcing strict
 		// time progression (but not in CI mode)
@@ -138,34 +179,95 @@ fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), E
 		// TODO add warning in p2p code if local time is too different from peers
 		return Err(Error::InvalidBlockTime);
 	}
+	Ok(())
+}
 
-	if !ctx.opts.intersects(SKIP_POW) {
-		// verify the proof of work and related parameters
+/// Checks the total difficulty claimed by the header is within the maximum,
+/// past which additions could overflow.
+pub fn validate_total_difficulty(header: &BlockHeader) -> Result<(), Error> {
+	if header.total_difficulty > Difficulty::MAX {
+		return Err(Error::DifficultyOverflow);
+	}
+	Ok(())
+}
 
-		if header.total_difficulty != prev.total_difficulty.clone() + prev.pow.to_difficulty() {
-			return Err(Error::WrongTotalDifficulty);
-		}
+/// Verifies the difficulty of the header, both the total one linking it to
+/// the previous header and the one of the header itself, against the
+/// difficulty expected from the provided difficulty iterator (running
+/// backward from the previous header).
+pub fn validate_header_difficulty<T>(
+	header: &BlockHeader,
+	prev: &BlockHeader,
+	diff_iter: T,
+) -> Result<(), Error>
+	where T: IntoIterator<Item = Result<(u64, Difficulty), TargetError>>
+{
+	let total_difficulty = prev.total_difficulty
+		.checked_add(&prev.pow.to_difficulty())
+		.ok_or(Error::DifficultyOverflow)?;
+	if header.total_difficulty != total_difficulty {
+		return Err(Error::WrongTotalDifficulty);
+	}
 
-		let diff_iter = store::DifficultyIter::from(header.previous, ctx.store.clone());
-		let difficulty = consensus::next_difficulty(diff_iter).map_err(|e| {
-			Error::Other(e.to_string())
-		})?;
-		if header.difficulty < difficulty {
-			return Err(Error::DifficultyTooLow);
-		}
+	let difficulty = consensus::next_difficulty(diff_iter).map_err(|e| {
+		Error::Other(e.to_string())
+	})?;
+	if header.difficulty < difficulty {
+		return Err(Error::DifficultyTooLow);
+	}
+	Ok(())
+}
 
-		let cycle_size = if ctx.opts.intersects(EASY_POW) {
-			global::sizeshift()
-		} else {
-			consensus::DEFAULT_SIZESHIFT
-		};
-		debug!("Validating block with cuckoo size {}", cycle_size);
-		if !(ctx.pow_verifier)(header, cycle_size as u32) {
-			return Err(Error::InvalidPow);
+/// Verifies the cuckoo cycle of the header proof of work.
+pub fn validate_header_pow(
+	header: &BlockHeader,
+	opts: Options,
+	pow_verifier: fn(&BlockHeader, u32) -> bool,
+) -> Result<(), Error> {
+	let cycle_size = if opts.intersects(EASY_POW) {
+		global::sizeshift()
+	} else {
+		consensus::DEFAULT_SIZESHIFT
+	};
+	debug!("Validating block with cuckoo size {}", cycle_size);
+	if !pow_verifier(header, cycle_size as u32) {
+		return Err(Error::InvalidPow);
+	}
+	Ok(())
+}
+
+/// Checks whether the block would move the head to another fork, returning
+/// the number of blocks the head would be rolled back by if so. Refuses forks
+/// deeper than the maximum reorg depth.
+fn check_reorg_depth(header: &BlockHeader, ctx: &BlockContext) -> Result<Option<u64>, Error> {
+	if header.total_difficulty <= ctx.head.total_difficulty ||
+		header.previous == ctx.head.last_block_h
+	{
+		return Ok(None);
+	}
+
+	// walk back the fork until we find its first block on our chain, using
+	// the height index (that doesn't include genesis)
+	let mut fork = ctx.store.get_block_header(&header.previous)?;
+	while fork.height > 0 {
+		if fork.height <= ctx.head.height {
+			if let Ok(main) = ctx.store.get_header_by_height(fork.height) {
+				if main.hash() == fork.hash() {
+					break;
+				}
+			}
+			if ctx.head.height - fork.height > ctx.max_reorg_depth {
+				break;
+			}
 		}
+		fork = ctx.store.get_block_header(&fork.previous)?;
 	}
 
-	Ok(())
+	let depth = ctx.head.height - cmp::min(fork.height, ctx.head.height);
+	if depth > ctx.max_reorg_depth {
+		return Err(Error::ReorgTooDeep { depth: depth });
+	}
+	Ok(Some(depth))
 }
 
 /// Fully validate the block content.
@@ -175,7 +277,11 @@ fn validate_block(block: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	}
 
 	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	try!(block.validate(&curve).map_err(&Error::InvalidBlockProof));
+	block.validate_full(&curve).map_err(|e| match e {
+		BlockError::Secp(e) => Error::InvalidBlockProof(e),
+		BlockError::WeightExceeded { weight, .. } => Error::BlockWeightExceeded(weight),
+		BlockError::MmrRootMismatch { field } => Error::MmrRootMismatch(field),
+	})?;
 
 	// check that all the outputs of the block are "new" -
 	// that they do not clobber any existing unspent outputs (by their commitment)
@@ -207,6 +313,49 @@ fn validate_block(block: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	Ok(())
 }
 
+/// Adds the block to the chain MMRs when it's going to become the new head,
+/// as decided by the fork graph, checking the roots committed to by its
+/// header as well as by the other blocks of its fork, before saving it and
+/// updating the head. Blocks on a fork with less work than the best tip leave
+/// the MMRs alone, they get checked if their fork ever takes over.
+fn add_to_chain(b: &Block, sumtrees: &mut SumTrees, ctx: &mut BlockContext) -> Result<Option<Tip>, Error> {
+	let becomes_head = ctx.fork_graph.lock().unwrap().is_better(&b.header);
+	if !becomes_head {
+		add_block(b, ctx)?;
+		return update_head(b, ctx);
+	}
+
+	let (fork_point, applied) = sumtrees.apply_fork(b, &ctx.head, &*ctx.store, true)?;
+	let res = save_applied(b, sumtrees, &applied, ctx);
+	match res {
+		Ok(Some(_)) => {}
+		// the MMRs have to follow the head, whatever happened
+		_ => {
+			if let Err(e) = sumtrees.restore(&fork_point, &ctx.head, &*ctx.store) {
+				error!("Could not restore the chain MMRs: {:?}", e);
+			}
+		}
+	}
+	res
+}
+
+/// Saves the block once applied to the MMRs, along with the MMR sizes after
+/// each applied block, and updates the head. The MMRs are synced before the
+/// head is saved, on restart they get rewound to the head if ahead of it.
+fn save_applied(
+	b: &Block,
+	sumtrees: &mut SumTrees,
+	applied: &[(Hash, MmrSizes)],
+	ctx: &mut BlockContext,
+) -> Result<Option<Tip>, Error> {
+	add_block(b, ctx)?;
+	for &(ref h, ref sizes) in applied {
+		ctx.store.save_block_mmr_sizes(h, sizes).map_err(&Error::StoreErr)?;
+	}
+	sumtrees.sync(&*ctx.store)?;
+	update_head(b, ctx)
+}
+
 /// Officially adds the block to our chain.
 fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	ctx.store.save_block(b).map_err(&Error::StoreErr)?;
@@ -226,12 +375,23 @@ fn add_block_header(bh: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Erro
 
 /// Directly updates the head if we've just appended a new block to it or handle
 /// the situation where we've just added enough work to have a fork with more
-/// work than the head.
+/// work than the head. The fork graph tracking all the recent tips decides,
+/// the block becoming the head if it's its best tip.
 fn update_head(b: &Block, ctx: &mut BlockContext) -> Result<Option<Tip>, Error> {
-	// if we made a fork with more work than the head (which should also be true
-	// when extending the head), update it
 	let tip = Tip::from_block(&b.header);
-	if tip.total_difficulty > ctx.head.total_difficulty {
+	let best = {
+		let mut fork_graph = ctx.fork_graph.lock().unwrap();
+		match fork_graph.add_header(b.header.clone()) {
+			Ok(()) => fork_graph.best_tip().hash() == tip.last_block_h,
+			Err(e) => {
+				// forking off below the graph root with less work than the
+				// head, so only kept in store
+				debug!("Block {} not added to the fork graph: {:?}", tip.last_block_h, e);
+				false
+			}
+		}
+	};
+	if best {
 
 		// update the block height index
 		ctx.store.setup_height(&b.header).map_err(&Error::StoreErr)?;
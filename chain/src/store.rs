@@ -34,6 +34,17 @@ const HEADER_HEAD_PREFIX: u8 = 'I' as u8;
 const HEADER_HEIGHT_PREFIX: u8 = '8' as u8;
 const OUTPUT_COMMIT_PREFIX: u8 = 'o' as u8;
 const HEADER_BY_OUTPUT_PREFIX: u8 = 'p' as u8;
+const HEADER_BY_KERNEL_PREFIX: u8 = 'k' as u8;
+const KERNEL_INDEX_PREFIX: u8 = 'K' as u8;
+const BLOCK_STATS_PREFIX: u8 = 's' as u8;
+const BLOCK_MMR_SIZES_PREFIX: u8 = 'm' as u8;
+const OUTPUT_POS_PREFIX: u8 = 'P' as u8;
+const OUTPUT_AT_POS_PREFIX: u8 = 'O' as u8;
+const KERNEL_POS_PREFIX: u8 = 'q' as u8;
+const KERNEL_AT_POS_PREFIX: u8 = 'Q' as u8;
+const BLOCK_SPENT_PREFIX: u8 = 'x' as u8;
+const MMR_INDEXES_TIP_PREFIX: u8 = 'T' as u8;
+const SPENT_BY_PREFIX: u8 = 'S' as u8;
 
 /// An implementation of the ChainStore trait backed by a simple key-value
 /// store.
@@ -91,11 +102,19 @@ impl ChainStore for ChainKVStore {
 	}
 
 	fn save_block(&self, b: &Block) -> Result<(), Error> {
-		// saving the block and its header
+		// the chain statistics build on the ones of the previous block
+		let prev_stats = if b.header.height == 0 {
+			ChainStats::default()
+		} else {
+			self.get_block_stats(&b.header.previous)?
+		};
+
+		// saving the block, its header and the chain statistics up to it
 		let mut batch = self.db
 			.batch()
 			.put_ser(&to_key(BLOCK_PREFIX, &mut b.hash().to_vec())[..], b)?
-			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec())[..], &b.header)?;
+			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec())[..], &b.header)?
+			.put_ser(&to_key(BLOCK_STATS_PREFIX, &mut b.hash().to_vec())[..], &prev_stats.apply(b))?;
 
 		// saving the full output under its hash, as well as a commitment to hash index
 		for out in &b.outputs {
@@ -103,9 +122,26 @@ impl ChainStore for ChainKVStore {
 				.put_ser(&to_key(OUTPUT_COMMIT_PREFIX, &mut out.commitment().as_ref().to_vec())[..], out)?
 				.put_ser(&to_key(HEADER_BY_OUTPUT_PREFIX, &mut out.commitment().as_ref().to_vec())[..], &b.hash())?;
 		}
+		// and a kernel excess to hash index
+		for kernel in &b.kernels {
+			batch = batch
+				.put_ser(&to_key(HEADER_BY_KERNEL_PREFIX, &mut kernel.excess.as_ref().to_vec())[..], &b.hash())?;
+		}
 		batch.write()
 	}
 
+	fn get_block_stats(&self, h: &Hash) -> Result<ChainStats, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(BLOCK_STATS_PREFIX, &mut h.to_vec())))
+	}
+
+	fn save_block_mmr_sizes(&self, h: &Hash, sizes: &MmrSizes) -> Result<(), Error> {
+		self.db.put_ser(&to_key(BLOCK_MMR_SIZES_PREFIX, &mut h.to_vec())[..], sizes)
+	}
+
+	fn get_block_mmr_sizes(&self, h: &Hash) -> Result<MmrSizes, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(BLOCK_MMR_SIZES_PREFIX, &mut h.to_vec())))
+	}
+
 	// lookup the block header hash by output commitment
 	// lookup the block header based on this hash
 	// to check the chain is correct compare this block header to
@@ -136,6 +172,22 @@ impl ChainStore for ChainKVStore {
 		}
 	}
 
+	// same as for outputs, only returns the header if the block is on the
+	// current chain
+	fn get_block_header_by_kernel_excess(&self, excess: &Commitment) -> Result<BlockHeader, Error> {
+		let block_hash = option_to_not_found(self.db.get_ser(&to_key(
+			HEADER_BY_KERNEL_PREFIX,
+			&mut excess.as_ref().to_vec(),
+		)))?;
+		let block_header = self.get_block_header(&block_hash)?;
+		let header_at_height = self.get_header_by_height(block_header.height)?;
+		if block_header.hash() == header_at_height.hash() {
+			Ok(block_header)
+		} else {
+			Err(Error::NotFoundErr)
+		}
+	}
+
 	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
 		self.db.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut bh.hash().to_vec())[..], bh)
 	}
@@ -160,6 +212,73 @@ impl ChainStore for ChainKVStore {
 		)))
 	}
 
+	fn index_kernels(&self) -> Result<u64, Error> {
+		if self.db.exists(&vec![KERNEL_INDEX_PREFIX])? {
+			return Ok(0);
+		}
+		let mut count = 0;
+		for b in self.db.iter::<Block>(&vec![BLOCK_PREFIX]) {
+			let mut batch = self.db.batch();
+			for kernel in &b.kernels {
+				batch = batch
+					.put_ser(&to_key(HEADER_BY_KERNEL_PREFIX, &mut kernel.excess.as_ref().to_vec())[..], &b.hash())?;
+			}
+			batch.write()?;
+			count += 1;
+		}
+		self.db.put_ser(&vec![KERNEL_INDEX_PREFIX], &count)?;
+		Ok(count)
+	}
+
+	fn save_mmr_indexes(&self, indexes: &MmrIndexes, tip: &Hash) -> Result<(), Error> {
+		let mut batch = self.db.batch();
+		for (commit, pos) in &indexes.outputs {
+			batch = batch
+				.put_ser(&to_key(OUTPUT_POS_PREFIX, &mut commit.as_ref().to_vec())[..], pos)?
+				.put_ser(&u64_to_key(OUTPUT_AT_POS_PREFIX, *pos)[..], commit)?;
+		}
+		for (excess, pos) in &indexes.kernels {
+			batch = batch
+				.put_ser(&to_key(KERNEL_POS_PREFIX, &mut excess.as_ref().to_vec())[..], pos)?
+				.put_ser(&u64_to_key(KERNEL_AT_POS_PREFIX, *pos)[..], excess)?;
+		}
+		for (h, spent) in &indexes.spent {
+			batch = batch.put_ser(&to_key(BLOCK_SPENT_PREFIX, &mut h.to_vec())[..], spent)?;
+			for pos in spent {
+				batch = batch.put_ser(&u64_to_key(SPENT_BY_PREFIX, *pos)[..], h)?;
+			}
+		}
+		batch.put_ser(&vec![MMR_INDEXES_TIP_PREFIX], tip)?.write()
+	}
+
+	fn get_mmr_indexes_tip(&self) -> Result<Hash, Error> {
+		option_to_not_found(self.db.get_ser(&vec![MMR_INDEXES_TIP_PREFIX]))
+	}
+
+	fn get_output_pos(&self, commit: &Commitment) -> Result<u64, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(OUTPUT_POS_PREFIX, &mut commit.as_ref().to_vec())))
+	}
+
+	fn get_output_at_pos(&self, pos: u64) -> Result<Commitment, Error> {
+		option_to_not_found(self.db.get_ser(&u64_to_key(OUTPUT_AT_POS_PREFIX, pos)))
+	}
+
+	fn get_kernel_pos(&self, excess: &Commitment) -> Result<u64, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(KERNEL_POS_PREFIX, &mut excess.as_ref().to_vec())))
+	}
+
+	fn get_kernel_at_pos(&self, pos: u64) -> Result<Commitment, Error> {
+		option_to_not_found(self.db.get_ser(&u64_to_key(KERNEL_AT_POS_PREFIX, pos)))
+	}
+
+	fn get_block_spent(&self, h: &Hash) -> Result<Vec<u64>, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(BLOCK_SPENT_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_spent_by(&self, pos: u64) -> Result<Hash, Error> {
+		option_to_not_found(self.db.get_ser(&u64_to_key(SPENT_BY_PREFIX, pos)))
+	}
+
 	/// Maintain consistency of the "header_by_height" index by traversing back through the
 	/// current chain and updating "header_by_height" until we reach a block_header
 	/// that is consistent with its height (everything prior to this will be consistent)
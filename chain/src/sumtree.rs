@@ -0,0 +1,747 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The MMRs of all the outputs, range proofs and kernels of the chain, whose
+//! roots block headers commit to. Blocks are appended to them as they extend
+//! the chain. When the chain forks, they get rewound to the fork point and
+//! the blocks of the fork applied instead.
+//!
+//! Each MMR has its own backend. Outputs and their range proofs get pruned
+//! once spent, as the pruning policy permits, while kernels are the permanent
+//! record of all transactions and are never pruned. Archival nodes can keep
+//! the pruned outputs and range proofs in an archive next to their MMR.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+
+use secp::pedersen::{Commitment, RangeProof};
+
+use core::core::{Block, BlockError, BlockHeader, MmrRoots, Output, OutputFeatures, TxKernel};
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::pmmr::{self, Backend, HashSum, NoSum, PMMR, PMMRReader, Summable};
+use core::ser::{self, Writeable};
+use grin_store::cold_store::ColdStore;
+use grin_store::sumtree::PMMRBackend;
+use types::*;
+
+const OUTPUT_SUBDIR: &'static str = "output";
+const RANGEPROOF_SUBDIR: &'static str = "rangeproof";
+const KERNEL_SUBDIR: &'static str = "kernel";
+const PRUNED_ARCHIVE_FILE: &'static str = "pruned_archive.bin";
+
+/// The output, range proof and kernel MMRs of the chain, each stored in its
+/// own backend. The positions of outputs and kernels, by commitment, and the
+/// outputs spent by each block are indexed in the chain store.
+pub struct SumTrees {
+	output_pmmr: PMMRBackend<NoSum<Output>>,
+	rproof_pmmr: PMMRBackend<NoSum<RangeProof>>,
+	kernel_pmmr: PMMRBackend<NoSum<TxKernel>>,
+
+	// header of the last block applied to the MMRs
+	tip: BlockHeader,
+	// indexes of the blocks applied since the MMRs were last synced
+	pending: MmrIndexes,
+	pruning: PruningPolicy,
+	// archives of the pruned outputs, without range proof, and of the pruned
+	// range proofs, if enabled
+	output_archive: Option<ColdStore>,
+	rproof_archive: Option<ColdStore>,
+}
+
+impl SumTrees {
+	/// Opens the MMRs stored under the provided root directory and brings
+	/// them to the state of the chain head, which they can be past if the
+	/// node stopped while adding a block. The genesis block is applied to
+	/// the MMRs of a new chain, checking the roots its header commits to.
+	/// The output and kernel positions are only indexed going through the
+	/// whole chain once, for chains saved before the index was kept in
+	/// store. Spent outputs are pruned following the provided policy, and
+	/// archived before if archive_pruned is set.
+	pub fn open(
+		root_dir: &str,
+		head: &Tip,
+		store: &ChainStore,
+		pruning: PruningPolicy,
+		archive_pruned: bool,
+	) -> Result<SumTrees, Error> {
+		let (output_archive, rproof_archive) = if archive_pruned {
+			(
+				Some(open_archive(root_dir, OUTPUT_SUBDIR)?),
+				Some(open_archive(root_dir, RANGEPROOF_SUBDIR)?),
+			)
+		} else {
+			(None, None)
+		};
+		let mut trees = SumTrees {
+			output_pmmr: open_backend(root_dir, OUTPUT_SUBDIR)?,
+			rproof_pmmr: open_backend(root_dir, RANGEPROOF_SUBDIR)?,
+			kernel_pmmr: open_backend(root_dir, KERNEL_SUBDIR)?,
+			tip: store.get_block_header(&head.last_block_h)?,
+			pending: MmrIndexes::default(),
+			pruning: pruning,
+			output_archive: output_archive,
+			rproof_archive: rproof_archive,
+		};
+
+		if head.height == 0 && store.get_block_mmr_sizes(&head.last_block_h).is_err() {
+			let genesis = store.get_block(&head.last_block_h)?;
+			trees.rewind_sizes(&MmrSizes::default())?;
+			let (_, sizes) = trees.apply_checked(&genesis, store, true)?;
+			trees.sync(store)?;
+			store.save_block_mmr_sizes(&genesis.hash(), &sizes)?;
+		}
+
+		let tip_h = match store.get_mmr_indexes_tip() {
+			Ok(h) => h,
+			Err(grin_store::Error::NotFoundErr) => {
+				let count = index_chain(head, store)?;
+				info!("Indexed the MMR positions of {} blocks.", count);
+				head.last_block_h
+			}
+			Err(e) => return Err(Error::StoreErr(e)),
+		};
+
+		let head_sizes = store.get_block_mmr_sizes(&head.last_block_h)?;
+		let sizes = trees.sizes();
+		if sizes.output_size < head_sizes.output_size ||
+			sizes.rangeproof_size < head_sizes.rangeproof_size ||
+			sizes.kernel_size < head_sizes.kernel_size
+		{
+			return Err(Error::SumTreeErr(format!(
+				"MMRs behind the chain head at {}, the chain needs to be resynced",
+				head.height
+			)));
+		}
+		if tip_h != head.last_block_h {
+			// blocks got indexed past the head before the node stopped
+			trees.tip = store.get_block_header(&tip_h)?;
+			let (fork_point, _) = find_fork_point(trees.tip.clone(), head, store)?;
+			trees.restore(&fork_point, head, store)?;
+		} else if sizes != head_sizes {
+			trees.rewind_sizes(&head_sizes)?;
+		}
+		Ok(trees)
+	}
+
+	/// Current sizes of the MMRs.
+	pub fn sizes(&self) -> MmrSizes {
+		MmrSizes {
+			output_size: self.output_pmmr.current_size(),
+			rangeproof_size: self.rproof_pmmr.current_size(),
+			kernel_size: self.kernel_pmmr.current_size(),
+		}
+	}
+
+	/// Current roots of the MMRs. Empty MMRs have a zero root.
+	pub fn roots(&self) -> MmrRoots {
+		MmrRoots {
+			output_root: root(&self.output_pmmr),
+			rangeproof_root: root(&self.rproof_pmmr),
+			kernel_root: root(&self.kernel_pmmr),
+		}
+	}
+
+	/// Appends the outputs, range proofs and kernels of the block to the
+	/// MMRs, returning their new sizes. Outputs spent, by this block or an
+	/// earlier one depending on the pruning policy, get pruned along with
+	/// their range proofs.
+	pub fn apply_block(&mut self, b: &Block, store: &ChainStore) -> Result<MmrSizes, Error> {
+		// everything is appended first, for a block that didn't make it to
+		// the chain head to be rewound when reopening
+		{
+			let size = self.output_pmmr.current_size();
+			let mut pmmr = PMMR::at(&mut self.output_pmmr, size);
+			for out in &b.outputs {
+				let pos = pmmr.push(NoSum(*out));
+				self.pending.outputs.insert(out.commitment(), pos);
+			}
+		}
+		{
+			let size = self.rproof_pmmr.current_size();
+			let mut pmmr = PMMR::at(&mut self.rproof_pmmr, size);
+			for out in &b.outputs {
+				pmmr.push(NoSum(out.proof));
+			}
+		}
+		{
+			let size = self.kernel_pmmr.current_size();
+			let mut pmmr = PMMR::at(&mut self.kernel_pmmr, size);
+			for kernel in &b.kernels {
+				let pos = pmmr.push(NoSum(kernel.clone()));
+				self.pending.kernels.insert(kernel.excess, pos);
+			}
+		}
+
+		// inputs aren't checked against the MMR yet, the ones we don't know
+		// have nothing to prune
+		let mut spent = Vec::with_capacity(b.inputs.len());
+		for input in &b.inputs {
+			if let Some(pos) = self.output_pos(&input.commitment(), store) {
+				spent.push(pos);
+			}
+		}
+		self.pending.spent.insert(b.hash(), spent);
+		self.tip = b.header.clone();
+		if let Some(positions) = self.prunable(b.header.height, store)? {
+			self.archive(&positions, store)?;
+			self.prune(&positions);
+		}
+		Ok(self.sizes())
+	}
+
+	/// The kernel with the provided excess, if included in the kernel MMR.
+	/// The kernel itself is read from the block that includes it.
+	pub fn get_kernel(&self, excess: &Commitment, store: &ChainStore) -> Option<TxKernel> {
+		let pos = match self.kernel_pos(excess, store) {
+			Some(pos) => pos,
+			None => return None,
+		};
+		let stored = match self.kernel_pmmr.get(pos) {
+			Some(hs) => hs,
+			None => return None,
+		};
+		let kernel = store
+			.get_block_header_by_kernel_excess(excess)
+			.and_then(|header| store.get_block(&header.hash()))
+			.ok()
+			.and_then(|b| b.kernels.into_iter().find(|k| k.excess == *excess));
+		kernel.and_then(|k| if HashSum::from_summable(pos, &NoSum(k.clone())).hash == stored.hash {
+			Some(k)
+		} else {
+			None
+		})
+	}
+
+	/// Whether the output with the provided commitment is in the output MMR
+	/// and hasn't been pruned, unspent or retained after being spent.
+	pub fn is_output_retained(&self, commit: &Commitment, store: &ChainStore) -> bool {
+		match self.output_pos(commit, store) {
+			Some(pos) => self.output_pmmr.get(pos).is_some(),
+			None => false,
+		}
+	}
+
+	/// The output with the provided commitment, if pruned from the output
+	/// MMR and archived along with its range proof.
+	pub fn get_archived_output(&self, commit: &Commitment, store: &ChainStore) -> Result<Option<Output>, Error> {
+		let (output_archive, rproof_archive) = match (&self.output_archive, &self.rproof_archive) {
+			(&Some(ref o), &Some(ref r)) => (o, r),
+			_ => return Ok(None),
+		};
+		let pos = match self.output_pos(commit, store) {
+			Some(pos) => pos,
+			None => return Ok(None),
+		};
+		// archived again when pruned again, so the data may be older than
+		// the output now at this position
+		let (bits, archived_commit) = match output_archive.retrieve(pos)? {
+			Some(data) => ser::deserialize::<(u8, Commitment)>(&mut &data[..])?,
+			None => return Ok(None),
+		};
+		if archived_commit != *commit {
+			return Ok(None);
+		}
+		let proof = match rproof_archive.retrieve(pos)? {
+			Some(data) => ser::deserialize::<RangeProof>(&mut &data[..])?,
+			None => return Ok(None),
+		};
+		let features = OutputFeatures::from_bits(bits).ok_or_else(|| {
+			Error::SumTreeErr(format!("Invalid features of archived output at {}", pos))
+		})?;
+		Ok(Some(Output {
+			features: features,
+			commit: archived_commit,
+			proof: proof,
+		}))
+	}
+
+	/// Whether the output with the provided commitment was unspent after the
+	/// block at the provided height on the current chain, the output MMR then
+	/// having the provided size. Spent outputs are indexed with the block
+	/// spending them, so pruned outputs are known as well.
+	pub fn is_unspent_at(
+		&self,
+		commit: &Commitment,
+		height: u64,
+		output_size: u64,
+		store: &ChainStore,
+	) -> Result<bool, Error> {
+		match self.output_pos(commit, store) {
+			Some(pos) if pos <= output_size => Ok(!self.is_spent_at(pos, height, store)?),
+			_ => Ok(false),
+		}
+	}
+
+	/// Commitments of the outputs unspent after the block at the provided
+	/// height on the current chain, the output MMR then having the provided
+	/// size. Goes through the outputs in MMR order from the provided
+	/// insertion index, starting at 1, until max unspent ones are found.
+	/// Returns them along with the index to continue from, if any output is
+	/// left.
+	pub fn unspent_at(
+		&self,
+		height: u64,
+		output_size: u64,
+		start_index: u64,
+		max: u64,
+		store: &ChainStore,
+	) -> Result<(Vec<Commitment>, Option<u64>), Error> {
+		let mut unspent = vec![];
+		let mut n = cmp::max(start_index, 1);
+		loop {
+			let pos = pmmr::insertion_to_pmmr_index(n);
+			if pos > output_size {
+				return Ok((unspent, None));
+			}
+			if unspent.len() as u64 >= max {
+				return Ok((unspent, Some(n)));
+			}
+			if !self.is_spent_at(pos, height, store)? {
+				unspent.push(self.output_at(pos, store)?);
+			}
+			n += 1;
+		}
+	}
+
+	/// Compacts the output and range proof MMRs, actually cutting the pruned
+	/// data from their files once enough of it has accumulated. The MMRs
+	/// can't be rewound past compacted data anymore. The kernel MMR is never
+	/// pruned so never compacted.
+	pub fn compact(&mut self) -> Result<(), Error> {
+		self.output_pmmr.check_compact(0)?;
+		self.rproof_pmmr.check_compact(0)?;
+		Ok(())
+	}
+
+	/// Applies the block on top of the fork it belongs to. The MMRs are
+	/// first rewound to the fork point with our chain (the previous block
+	/// when it simply extends the head) and the blocks of the fork applied.
+	/// With check_roots, the roots committed to by the header of each applied
+	/// block are checked. Returns the fork point, for restore, and the MMR
+	/// sizes after each applied block. On error, the MMRs are restored.
+	pub fn apply_fork(
+		&mut self,
+		b: &Block,
+		head: &Tip,
+		store: &ChainStore,
+		check_roots: bool,
+	) -> Result<(BlockHeader, Vec<(Hash, MmrSizes)>), Error> {
+		let prev = store.get_block_header(&b.header.previous)?;
+		let (fork_point, fork) = find_fork_point(prev, head, store)?;
+
+		let res = self.apply_from(&fork_point, &fork, b, store, check_roots);
+		match res {
+			Ok(applied) => Ok((fork_point, applied)),
+			Err(e) => {
+				if let Err(re) = self.restore(&fork_point, head, store) {
+					error!("Could not restore the chain MMRs: {:?}", re);
+				}
+				Err(e)
+			}
+		}
+	}
+
+	/// Brings the MMRs back to the state of the chain head after a fork,
+	/// from the provided fork point, has been applied.
+	pub fn restore(&mut self, fork_point: &BlockHeader, head: &Tip, store: &ChainStore) -> Result<(), Error> {
+		self.rewind(fork_point, store)?;
+		for height in (fork_point.height + 1)..(head.height + 1) {
+			let header = store.get_header_by_height(height)?;
+			self.apply_block(&store.get_block(&header.hash())?, store)?;
+		}
+		self.sync(store)
+	}
+
+	/// Rewinds the MMRs to their state after the provided block, which has
+	/// to have been applied before. The outputs pruned by the blocks rewound
+	/// are restored.
+	pub fn rewind(&mut self, header: &BlockHeader, store: &ChainStore) -> Result<(), Error> {
+		let sizes = store.get_block_mmr_sizes(&header.hash())?;
+		let mut rewound = vec![];
+		let mut current = self.tip.clone();
+		while current.height > header.height {
+			rewound.push(current.hash());
+			current = store.get_block_header(&current.previous)?;
+		}
+		if current.hash() != header.hash() {
+			return Err(Error::SumTreeErr(
+				format!("Can't rewind to {}, not applied to the MMRs", header.hash()),
+			));
+		}
+
+		let mut unspent = vec![];
+		for height in (header.height + 1)..(self.tip.height + 1) {
+			if let Some(positions) = self.prunable(height, store)? {
+				unspent.extend(positions);
+			}
+		}
+		for h in rewound {
+			self.pending.spent.remove(&h);
+		}
+		self.tip = header.clone();
+		if sizes != self.sizes() {
+			self.rewind_sizes(&sizes)?;
+		}
+		self.pending.outputs.retain(|_, pos| *pos <= sizes.output_size);
+		self.pending.kernels.retain(|_, pos| *pos <= sizes.kernel_size);
+		self.unprune(unspent)
+	}
+
+	/// Saves the indexes of the blocks applied since the last sync to the
+	/// chain store, then syncs all the MMRs to disk. On restart, the MMRs
+	/// get rewound to the chain head from the last block indexed.
+	pub fn sync(&mut self, store: &ChainStore) -> Result<(), Error> {
+		store.save_mmr_indexes(&self.pending, &self.tip.hash())?;
+		self.pending = MmrIndexes::default();
+		self.sync_files()
+	}
+
+	fn sync_files(&mut self) -> Result<(), Error> {
+		if let Some(ref mut archive) = self.output_archive {
+			archive.sync()?;
+		}
+		if let Some(ref mut archive) = self.rproof_archive {
+			archive.sync()?;
+		}
+		self.output_pmmr.sync()?;
+		self.rproof_pmmr.sync()?;
+		self.kernel_pmmr.sync()?;
+		Ok(())
+	}
+
+	fn apply_from(
+		&mut self,
+		fork_point: &BlockHeader,
+		fork: &[Hash],
+		b: &Block,
+		store: &ChainStore,
+		check_roots: bool,
+	) -> Result<Vec<(Hash, MmrSizes)>, Error> {
+		self.rewind(fork_point, store)?;
+		let mut applied = vec![];
+		for h in fork {
+			let fb = store.get_block(h)?;
+			applied.push(self.apply_checked(&fb, store, check_roots)?);
+		}
+		applied.push(self.apply_checked(b, store, check_roots)?);
+		Ok(applied)
+	}
+
+	fn apply_checked(&mut self, b: &Block, store: &ChainStore, check_roots: bool) -> Result<(Hash, MmrSizes), Error> {
+		let sizes = self.apply_block(b, store)?;
+		if check_roots {
+			if let Err(BlockError::MmrRootMismatch { field }) = b.header.verify_mmr_roots(&self.roots()) {
+				return Err(Error::MmrRootMismatch(field));
+			}
+		}
+		Ok((b.hash(), sizes))
+	}
+
+	// Position of the output with the provided commitment, if added on the
+	// chain the MMRs are at.
+	fn output_pos(&self, commit: &Commitment, store: &ChainStore) -> Option<u64> {
+		indexed_pos(
+			commit,
+			&self.pending.outputs,
+			self.output_pmmr.current_size(),
+			|c| store.get_output_pos(c),
+			|pos| store.get_output_at_pos(pos),
+		)
+	}
+
+	// Position of the kernel with the provided excess, if added on the chain
+	// the MMRs are at.
+	fn kernel_pos(&self, excess: &Commitment, store: &ChainStore) -> Option<u64> {
+		indexed_pos(
+			excess,
+			&self.pending.kernels,
+			self.kernel_pmmr.current_size(),
+			|c| store.get_kernel_pos(c),
+			|pos| store.get_kernel_at_pos(pos),
+		)
+	}
+
+	// Commitment of the output at the provided position of the output MMR.
+	fn output_at(&self, pos: u64, store: &ChainStore) -> Result<Commitment, Error> {
+		match self.pending.outputs.iter().find(|&(_, p)| *p == pos) {
+			Some((commit, _)) => Ok(*commit),
+			None => Ok(store.get_output_at_pos(pos)?),
+		}
+	}
+
+	// Hash of the block at the provided height on the chain the MMRs are at,
+	// walking back from their tip until reaching the chain indexed by height.
+	fn hash_at(&self, height: u64, store: &ChainStore) -> Result<Hash, Error> {
+		if height > self.tip.height {
+			return Err(Error::Other(format!("height {} past the MMRs tip", height)));
+		}
+		let mut header = self.tip.clone();
+		loop {
+			if header.height == height {
+				return Ok(header.hash());
+			}
+			if let Ok(indexed) = store.get_header_by_height(header.height) {
+				if indexed.hash() == header.hash() {
+					// the genesis block isn't indexed by height
+					return if height == 0 {
+						Ok(store.get_header_by_height(1)?.previous)
+					} else {
+						Ok(store.get_header_by_height(height)?.hash())
+					};
+				}
+			}
+			header = store.get_block_header(&header.previous)?;
+		}
+	}
+
+	// Positions of the outputs spent by the block at the provided height.
+	fn spent_at(&self, height: u64, store: &ChainStore) -> Result<Vec<u64>, Error> {
+		let h = self.hash_at(height, store)?;
+		match self.pending.spent.get(&h) {
+			Some(spent) => Ok(spent.clone()),
+			None => Ok(store.get_block_spent(&h)?),
+		}
+	}
+
+	// Whether the output at the provided position got spent by the block at
+	// the provided height, or an earlier one, on the chain the MMRs are at.
+	// The block spending an output in store can have been rewound since.
+	fn is_spent_at(&self, pos: u64, height: u64, store: &ChainStore) -> Result<bool, Error> {
+		for (h, spent) in &self.pending.spent {
+			if spent.contains(&pos) {
+				let spent_height = if *h == self.tip.hash() {
+					self.tip.height
+				} else {
+					store.get_block_header(h)?.height
+				};
+				return Ok(spent_height <= height);
+			}
+		}
+		let h = match store.get_spent_by(pos) {
+			Ok(h) => h,
+			Err(grin_store::Error::NotFoundErr) => return Ok(false),
+			Err(e) => return Err(Error::StoreErr(e)),
+		};
+		let header = store.get_block_header(&h)?;
+		Ok(header.height <= height && self.hash_at(header.height, store)? == h)
+	}
+
+	// Positions of the outputs that get pruned when applying the block at
+	// the provided height.
+	fn prunable(&self, height: u64, store: &ChainStore) -> Result<Option<Vec<u64>>, Error> {
+		match self.pruning.prune_height(height) {
+			Some(h) => self.spent_at(h, store).map(Some),
+			None => Ok(None),
+		}
+	}
+
+	// Archives the outputs at the provided positions and their range proofs,
+	// when enabled, before they get pruned. Outputs are read from store.
+	fn archive(&mut self, positions: &[u64], store: &ChainStore) -> Result<(), Error> {
+		if self.output_archive.is_none() {
+			return Ok(());
+		}
+		for &pos in positions {
+			let out = store.get_output_by_commit(&self.output_at(pos, store)?)?;
+			let out_data = ser::ser_vec(&(out.features.bits(), out.commit))?;
+			let proof_data = ser::ser_vec(&out.proof)?;
+			if let Some(ref mut archive) = self.output_archive {
+				archive.archive(pos, &out_data)?;
+			}
+			if let Some(ref mut archive) = self.rproof_archive {
+				archive.archive(pos, &proof_data)?;
+			}
+		}
+		Ok(())
+	}
+
+	// Prunes the outputs at the provided positions, along with their range
+	// proofs.
+	fn prune(&mut self, positions: &[u64]) {
+		if positions.is_empty() {
+			return;
+		}
+		{
+			let size = self.output_pmmr.current_size();
+			let mut pmmr = PMMR::at(&mut self.output_pmmr, size);
+			for &pos in positions {
+				pmmr.prune(pos);
+			}
+		}
+		let size = self.rproof_pmmr.current_size();
+		let mut pmmr = PMMR::at(&mut self.rproof_pmmr, size);
+		for &pos in positions {
+			pmmr.prune(pos);
+		}
+	}
+
+	// Restores the pruned outputs at the provided positions, along with their
+	// range proofs. Parents pruned along with them have to be restored as
+	// well, so all the ancestors are.
+	fn unprune(&mut self, positions: Vec<u64>) -> Result<(), Error> {
+		let size = self.output_pmmr.current_size();
+		let mut to_restore = vec![];
+		for pos in positions {
+			let mut current = pos;
+			while current <= size {
+				to_restore.push(current);
+				current = pmmr::family(current).1;
+			}
+		}
+		if to_restore.is_empty() {
+			return Ok(());
+		}
+		self.sync_files()?;
+		self.output_pmmr.unremove(to_restore.clone()).map_err(&Error::SumTreeErr)?;
+		self.rproof_pmmr.unremove(to_restore).map_err(&Error::SumTreeErr)
+	}
+
+	fn rewind_sizes(&mut self, sizes: &MmrSizes) -> Result<(), Error> {
+		self.output_pmmr.rewind(sizes.output_size).map_err(&Error::SumTreeErr)?;
+		self.rproof_pmmr.rewind(sizes.rangeproof_size).map_err(&Error::SumTreeErr)?;
+		self.kernel_pmmr.rewind(sizes.kernel_size).map_err(&Error::SumTreeErr)
+	}
+}
+
+// Position in an MMR of the element with the provided commitment, looked up
+// in the indexes not yet saved, then in store. Positions in store can be left
+// stale by rewinds, they're only trusted if within the MMR and still holding
+// the same commitment.
+fn indexed_pos<P, A>(
+	commit: &Commitment,
+	pending: &HashMap<Commitment, u64>,
+	size: u64,
+	get_pos: P,
+	get_at_pos: A,
+) -> Option<u64>
+where
+	P: Fn(&Commitment) -> Result<u64, grin_store::Error>,
+	A: Fn(u64) -> Result<Commitment, grin_store::Error>,
+{
+	if let Some(&pos) = pending.get(commit) {
+		return Some(pos);
+	}
+	let pos = match get_pos(commit) {
+		Ok(pos) => pos,
+		Err(_) => return None,
+	};
+	if pos > size || pending.values().any(|&p| p == pos) {
+		return None;
+	}
+	match get_at_pos(pos) {
+		Ok(ref c) if c == commit => Some(pos),
+		_ => None,
+	}
+}
+
+// Indexes the outputs and kernels of all the blocks of the chain, from
+// genesis to the head, along with the outputs each block spends. Only needed
+// once, for chains saved before the indexes were kept in store. Returns the
+// number of blocks indexed.
+fn index_chain(head: &Tip, store: &ChainStore) -> Result<u64, Error> {
+	let mut outputs = 0;
+	let mut kernels = 0;
+	for height in 0..(head.height + 1) {
+		let b = if height == 0 {
+			let first = if head.height == 0 {
+				head.last_block_h
+			} else {
+				store.get_header_by_height(1)?.previous
+			};
+			store.get_block(&first)?
+		} else {
+			store.get_block(&store.get_header_by_height(height)?.hash())?
+		};
+
+		let mut indexes = MmrIndexes::default();
+		for out in &b.outputs {
+			outputs += 1;
+			indexes.outputs.insert(out.commitment(), pmmr::insertion_to_pmmr_index(outputs));
+		}
+		for kernel in &b.kernels {
+			kernels += 1;
+			indexes.kernels.insert(kernel.excess, pmmr::insertion_to_pmmr_index(kernels));
+		}
+		let mut spent = vec![];
+		for input in &b.inputs {
+			let commit = input.commitment();
+			match indexes.outputs.get(&commit) {
+				Some(&pos) => spent.push(pos),
+				None => if let Ok(pos) = store.get_output_pos(&commit) {
+					spent.push(pos);
+				},
+			}
+		}
+		indexes.spent.insert(b.hash(), spent);
+		store.save_mmr_indexes(&indexes, &b.hash())?;
+	}
+	Ok(head.height + 1)
+}
+
+// Walks back from the provided header until reaching a block of our chain,
+// returning it along with the hashes of the blocks walked through, oldest
+// first. The height index can hold stale entries past the head.
+fn find_fork_point(
+	header: BlockHeader,
+	head: &Tip,
+	store: &ChainStore,
+) -> Result<(BlockHeader, Vec<Hash>), Error> {
+	let mut header = header;
+	let mut fork = vec![];
+	loop {
+		// the genesis block isn't indexed but is shared by all forks
+		if header.height == 0 {
+			break;
+		}
+		if header.height <= head.height {
+			let indexed = store.get_header_by_height(header.height)?;
+			if indexed.hash() == header.hash() {
+				break;
+			}
+		}
+		fork.push(header.hash());
+		header = store.get_block_header(&header.previous)?;
+	}
+	fork.reverse();
+	Ok((header, fork))
+}
+
+fn open_backend<T>(root_dir: &str, subdir: &str) -> Result<PMMRBackend<T>, Error>
+where
+	T: Summable + Clone,
+{
+	let dir = format!("{}/{}", root_dir, subdir);
+	fs::create_dir_all(&dir)?;
+	Ok(PMMRBackend::new(dir, false)?)
+}
+
+fn open_archive(root_dir: &str, subdir: &str) -> Result<ColdStore, Error> {
+	let dir = format!("{}/{}", root_dir, subdir);
+	fs::create_dir_all(&dir)?;
+	Ok(ColdStore::open(format!("{}/{}", dir, PRUNED_ARCHIVE_FILE))?)
+}
+
+fn root<T>(backend: &PMMRBackend<T>) -> Hash
+where
+	T: Summable + Writeable + Debug + Clone,
+{
+	let size = backend.current_size();
+	if size == 0 {
+		ZERO_HASH
+	} else {
+		PMMRReader::at(backend, size).root().hash
+	}
+}
@@ -0,0 +1,286 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pipelined download and validation of the full blocks during the initial
+//! block download. A downloader thread fetches the block bodies along the
+//! header chain ahead of validation, so the validator doesn't stall waiting
+//! for each download.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use core::core::{Block, BlockHeader};
+use core::core::hash::{Hash, ZERO_HASH};
+use chain::Chain;
+use types::{Error, Options, Tip};
+
+/// Default number of block bodies downloaded ahead of validation.
+pub const DEFAULT_LOOKAHEAD: usize = 8;
+
+/// Source of the full blocks to sync, usually our peers.
+pub trait BlockFetcher: Send + Sync {
+	/// Fetches the full block with the provided hash, blocking until it's
+	/// received. None if it couldn't be obtained.
+	fn fetch_block(&self, h: &Hash) -> Option<Block>;
+}
+
+// Blocks downloaded but not validated yet, oldest first.
+struct Queue {
+	blocks: VecDeque<Block>,
+	// the downloader has nothing left to fetch
+	done: bool,
+	// the validator gave up, the downloader should too
+	stopped: bool,
+}
+
+/// Downloads and validates the full blocks missing between the chain head and
+/// the header chain head. The downloader keeps up to lookahead blocks in a
+/// bounded queue the validator pulls from. If the header chain forks off
+/// below the blocks already downloaded, the queue is flushed and the
+/// download restarts from the new branch.
+pub struct BodySync {
+	chain: Arc<Chain>,
+	fetcher: Arc<BlockFetcher>,
+	lookahead: usize,
+	opts: Options,
+	queue: Arc<(Mutex<Queue>, Condvar)>,
+}
+
+impl BodySync {
+	/// New pipeline downloading blocks from the fetcher to add them to the
+	/// chain with the provided options, up to lookahead blocks ahead.
+	pub fn new(
+		chain: Arc<Chain>,
+		fetcher: Arc<BlockFetcher>,
+		lookahead: usize,
+		opts: Options,
+	) -> BodySync {
+		BodySync {
+			chain: chain,
+			fetcher: fetcher,
+			lookahead: lookahead,
+			opts: opts,
+			queue: Arc::new((
+				Mutex::new(Queue {
+					blocks: VecDeque::with_capacity(lookahead),
+					done: false,
+					stopped: false,
+				}),
+				Condvar::new(),
+			)),
+		}
+	}
+
+	/// Runs the pipeline until all the blocks of the header chain, as it is
+	/// when the downloader runs out of blocks to fetch, are validated.
+	/// Returns the last tip the chain head got updated to, if any.
+	pub fn run(&self) -> Result<Option<Tip>, Error> {
+		{
+			let mut queue = self.queue.0.lock().unwrap();
+			queue.blocks.clear();
+			queue.done = false;
+			queue.stopped = false;
+		}
+
+		let chain = self.chain.clone();
+		let fetcher = self.fetcher.clone();
+		let queue = self.queue.clone();
+		let lookahead = self.lookahead;
+		let downloader = thread::Builder::new()
+			.name("body_download".to_string())
+			.spawn(move || {
+				let res = download(&chain, &*fetcher, &queue, lookahead);
+				let &(ref lock, ref cvar) = &*queue;
+				lock.lock().unwrap().done = true;
+				cvar.notify_all();
+				res
+			})?;
+
+		let res = self.validate();
+		if res.is_err() {
+			let &(ref lock, ref cvar) = &*self.queue;
+			lock.lock().unwrap().stopped = true;
+			cvar.notify_all();
+		}
+		let download_res = downloader.join().map_err(|_| {
+			Error::Other("block downloader panicked".to_string())
+		})?;
+		let tip = res?;
+		download_res?;
+		Ok(tip)
+	}
+
+	// Pulls the downloaded blocks from the front of the queue and adds them
+	// to the chain, until the downloader is done.
+	fn validate(&self) -> Result<Option<Tip>, Error> {
+		let &(ref lock, ref cvar) = &*self.queue;
+		let mut tip = None;
+		loop {
+			let b = {
+				let mut queue = lock.lock().unwrap();
+				while queue.blocks.is_empty() && !queue.done {
+					queue = cvar.wait(queue).unwrap();
+				}
+				match queue.blocks.pop_front() {
+					Some(b) => b,
+					None => break,
+				}
+			};
+			// there's room for the downloader again
+			cvar.notify_all();
+
+			let bhash = b.hash();
+			match self.chain.process_block(b, self.opts) {
+				Ok(Some(t)) => tip = Some(t),
+				Ok(None) => {}
+				// already added or left behind by a fork of the header chain
+				Err(Error::Unfit(_)) | Err(Error::Orphan) => {}
+				Err(e) => {
+					error!("Block {} refused during sync: {:?}", bhash, e);
+					return Err(e);
+				}
+			}
+		}
+		Ok(tip)
+	}
+}
+
+// Fetches the blocks of the header chain past the chain head in order,
+// waiting for room in the queue before each. Follows the header chain head
+// as it moves, flushing the queue when it forks off below the blocks we
+// already downloaded.
+fn download(
+	chain: &Chain,
+	fetcher: &BlockFetcher,
+	queue: &(Mutex<Queue>, Condvar),
+	lookahead: usize,
+) -> Result<(), Error> {
+	let &(ref lock, ref cvar) = queue;
+	let mut plan = Plan::new();
+	loop {
+		if !plan.update(chain)? {
+			debug!("Header chain forked off the downloaded blocks, restarting.");
+			lock.lock().unwrap().blocks.clear();
+			cvar.notify_all();
+			plan = Plan::new();
+			continue;
+		}
+		let h = match plan.next() {
+			Some(h) => h,
+			None => return Ok(()),
+		};
+
+		{
+			let mut queue = lock.lock().unwrap();
+			while queue.blocks.len() >= lookahead && !queue.stopped {
+				queue = cvar.wait(queue).unwrap();
+			}
+			if queue.stopped {
+				return Ok(());
+			}
+		}
+
+		let b = fetcher.fetch_block(&h).ok_or_else(|| {
+			Error::Other(format!("could not fetch block {}", h))
+		})?;
+		lock.lock().unwrap().blocks.push_back(b);
+		cvar.notify_all();
+	}
+}
+
+// Hashes of the blocks to download along the header chain, oldest first,
+// with how many were handed to the downloader already.
+struct Plan {
+	hashes: Vec<Hash>,
+	positions: HashMap<Hash, usize>,
+	next: usize,
+	header_head: Hash,
+}
+
+impl Plan {
+	fn new() -> Plan {
+		Plan {
+			hashes: vec![],
+			positions: HashMap::new(),
+			next: 0,
+			header_head: ZERO_HASH,
+		}
+	}
+
+	// Next hash to download, if any left.
+	fn next(&mut self) -> Option<Hash> {
+		let h = self.hashes.get(self.next).cloned();
+		if h.is_some() {
+			self.next += 1;
+		}
+		h
+	}
+
+	// Follows the header chain head if it moved, walking back from it to
+	// either a planned block or the chain. Returns false if the header chain
+	// doesn't include the blocks already downloaded anymore.
+	fn update(&mut self, chain: &Chain) -> Result<bool, Error> {
+		let header_head = chain.get_header_head()?.last_block_h;
+		if header_head == self.header_head {
+			return Ok(true);
+		}
+		let head = chain.head()?;
+
+		let mut missing = vec![];
+		let mut h = header_head;
+		let mut fork_pos = None;
+		loop {
+			if let Some(&pos) = self.positions.get(&h) {
+				fork_pos = Some(pos);
+				break;
+			}
+			let header = chain.get_block_header(&h)?;
+			if is_on_chain(chain, &header, &head) {
+				break;
+			}
+			missing.push(h);
+			h = header.previous;
+		}
+
+		let kept = fork_pos.map(|pos| pos + 1).unwrap_or(0);
+		if kept < self.next {
+			return Ok(false);
+		}
+		for h in self.hashes.drain(kept..) {
+			self.positions.remove(&h);
+		}
+		for h in missing.into_iter().rev() {
+			self.positions.insert(h, self.hashes.len());
+			self.hashes.push(h);
+		}
+		self.header_head = header_head;
+		Ok(true)
+	}
+}
+
+// Whether the header is part of the chain up to its head, using the height
+// index (that doesn't include genesis).
+fn is_on_chain(chain: &Chain, header: &BlockHeader, head: &Tip) -> bool {
+	if header.height > head.height {
+		return false;
+	}
+	if header.height == 0 {
+		return true;
+	}
+	chain
+		.get_header_by_height(header.height)
+		.map(|h| h.hash() == header.hash())
+		.unwrap_or(false)
+}
@@ -14,13 +14,19 @@
 
 //! Base types that the block chain pipeline requires.
 
+use std::collections::HashMap;
+use std::io;
+use std::u64;
+
 use secp;
 use secp::pedersen::Commitment;
 
 use grin_store as store;
-use core::core::{Block, BlockHeader, Output};
+use core::consensus;
+use core::core::{Block, BlockHeader, Output, TxKernel, VersionError};
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
+use core::global::{MiningParameterMode, MINING_PARAMETER_MODE};
 use core::ser;
 use grin_store;
 
@@ -29,12 +35,16 @@ bitflags! {
 	pub flags Options: u32 {
 		/// None flag
 		const NONE = 0b00000001,
-		/// Runs without checking the Proof of Work, mostly to make testing easier.
+		/// Runs without checking the Proof of Work or the difficulty, only to
+		/// make testing easier.
 		const SKIP_POW = 0b00000010,
 		/// Runs PoW verification with a lower cycle size.
 		const EASY_POW = 0b00000100,
 		/// Adds block while in syncing mode.
 		const SYNC = 0b00001000,
+		/// Skips verifying the cuckoo cycle of a header already known to be
+		/// valid, difficulties are still checked.
+		const KNOWN_POW = 0b00010000,
 	}
 }
 
@@ -49,14 +59,29 @@ pub enum Error {
 	DifficultyTooLow,
 	/// Addition of difficulties on all previous block is wrong
 	WrongTotalDifficulty,
+	/// The total difficulty overflows or is above the maximum
+	DifficultyOverflow,
 	/// The proof of work is invalid
 	InvalidPow,
 	/// The block doesn't sum correctly or a tx signature is invalid
 	InvalidBlockProof(secp::Error),
+	/// The block weighs more than the consensus maximum
+	BlockWeightExceeded(u64),
 	/// Block time is too old
 	InvalidBlockTime,
 	/// Block height is invalid (not previous + 1)
 	InvalidBlockHeight,
+	/// Block header version isn't the one expected at its height
+	InvalidBlockVersion(VersionError),
+	/// Block header at a checkpoint height doesn't match the checkpoint
+	CheckpointMismatch(u64),
+	/// A MMR root committed to by the block header doesn't match the chain
+	MmrRootMismatch(&'static str),
+	/// The block would reorganize the chain deeper than allowed
+	ReorgTooDeep {
+		/// number of blocks the head would be rolled back by
+		depth: u64,
+	},
 	/// coinbase can only be spent after it has matured (n blocks)
 	ImmatureCoinbase,
 	/// output not found
@@ -67,6 +92,10 @@ pub enum Error {
 	StoreErr(grin_store::Error),
 	/// Error serializing or deserializing a type
 	SerErr(ser::Error),
+	/// Error reading or writing the header chain or MMR files
+	IOErr(io::Error),
+	/// Error updating the chain MMRs
+	SumTreeErr(String),
 	/// No chain exists and genesis block is required
 	GenesisBlockRequired,
 	/// Anything else
@@ -83,6 +112,217 @@ impl From<ser::Error> for Error {
 		Error::SerErr(e)
 	}
 }
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Error {
+		Error::IOErr(e)
+	}
+}
+
+/// Maximum depth of a reorganization of the production chain, about a day of
+/// blocks.
+pub const MAINNET_MAX_REORG_DEPTH: u64 = 1440;
+
+/// Chain configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainConfig {
+	/// Maximum number of blocks a reorganization can roll the head back by,
+	/// blocks switching to a fork deeper than that are refused
+	pub max_reorg_depth: u64,
+	/// When spent outputs get pruned from the output MMR
+	#[serde(default)]
+	pub pruning: PruningPolicy,
+	/// Whether the outputs and range proofs pruned from the MMRs get
+	/// archived, for archival nodes to still serve them
+	#[serde(default)]
+	pub archive_pruned: bool,
+}
+
+impl ChainConfig {
+	/// Configuration of the production chain.
+	pub fn mainnet() -> ChainConfig {
+		ChainConfig {
+			max_reorg_depth: MAINNET_MAX_REORG_DEPTH,
+			pruning: PruningPolicy::default(),
+			archive_pruned: false,
+		}
+	}
+
+	/// Configuration of test chains, reorganized at any depth.
+	pub fn testnet() -> ChainConfig {
+		ChainConfig {
+			max_reorg_depth: u64::MAX,
+			pruning: PruningPolicy::default(),
+			archive_pruned: false,
+		}
+	}
+}
+
+/// When spent outputs, along with their range proofs, get pruned from the
+/// chain MMRs. Nodes retaining spent outputs can still prove they were
+/// included in the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PruningPolicy {
+	/// Outputs are pruned as soon as they're spent
+	Aggressive,
+	/// Outputs are only pruned once spent more than retain_blocks blocks ago
+	Conservative {
+		/// Number of blocks spent outputs are retained for
+		retain_blocks: u64,
+	},
+	/// Outputs are never pruned
+	Archive,
+}
+
+impl PruningPolicy {
+	/// Height of the block whose spent outputs get pruned when the block at
+	/// the provided height is applied, if any.
+	pub fn prune_height(&self, height: u64) -> Option<u64> {
+		match *self {
+			PruningPolicy::Aggressive => Some(height),
+			PruningPolicy::Conservative { retain_blocks } => height.checked_sub(retain_blocks.saturating_add(1)),
+			PruningPolicy::Archive => None,
+		}
+	}
+}
+
+impl Default for PruningPolicy {
+	fn default() -> PruningPolicy {
+		PruningPolicy::Aggressive
+	}
+}
+
+impl Default for ChainConfig {
+	fn default() -> ChainConfig {
+		let param_ref = MINING_PARAMETER_MODE.read().unwrap();
+		match *param_ref {
+			MiningParameterMode::Production => ChainConfig::mainnet(),
+			_ => ChainConfig::testnet(),
+		}
+	}
+}
+
+/// How much of the chain a node downloads and validates.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SyncMode {
+	/// Full blocks are downloaded and validated
+	Full,
+	/// Only block headers are downloaded, validating their proof of work,
+	/// difficulty and timestamps. Meant for light clients and mobile wallets.
+	HeadersOnly,
+}
+
+/// Sync mode of the chain and how far along it is.
+#[derive(Debug, Clone)]
+pub struct SyncStatus {
+	/// Whether full blocks or only headers are synced
+	pub mode: SyncMode,
+	/// Height of the head of the header chain
+	pub header_height: u64,
+	/// Height of the head of the full block chain, doesn't move past genesis
+	/// when only syncing headers
+	pub body_height: u64,
+}
+
+/// Kernels gathered over a range of blocks.
+#[derive(Debug, Clone)]
+pub struct KernelScan {
+	/// All the kernels, ordered by block height first and position in their
+	/// block second
+	pub kernels: Vec<TxKernel>,
+	/// Number of kernels in each of the scanned blocks, by increasing height
+	pub kernels_per_block: Vec<usize>,
+}
+
+/// Statistics of the chain up to a given block, maintained as blocks are
+/// saved so they don't require scanning the whole chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChainStats {
+	/// Number of unspent outputs
+	pub utxo_count: u64,
+	/// Number of kernels
+	pub kernel_count: u64,
+	/// Sum of all block rewards, in nanogrins
+	pub total_supply_nanogrin: u64,
+	/// Sum of all transaction fees paid, in nanogrins
+	pub total_fees_nanogrin: u64,
+}
+
+impl ChainStats {
+	/// Statistics after the provided block, built on the ones of its
+	/// previous block. The genesis block carries no reward.
+	pub fn apply(&self, b: &Block) -> ChainStats {
+		let reward = if b.header.height > 0 { consensus::REWARD } else { 0 };
+		ChainStats {
+			utxo_count: self.utxo_count + b.outputs.len() as u64 - b.inputs.len() as u64,
+			kernel_count: self.kernel_count + b.kernels.len() as u64,
+			total_supply_nanogrin: self.total_supply_nanogrin + reward,
+			total_fees_nanogrin: self.total_fees_nanogrin + b.total_fees(),
+		}
+	}
+}
+
+impl ser::Writeable for ChainStats {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.utxo_count));
+		try!(writer.write_u64(self.kernel_count));
+		try!(writer.write_u64(self.total_supply_nanogrin));
+		writer.write_u64(self.total_fees_nanogrin)
+	}
+}
+
+impl ser::Readable for ChainStats {
+	fn read(reader: &mut ser::Reader) -> Result<ChainStats, ser::Error> {
+		Ok(ChainStats {
+			utxo_count: try!(reader.read_u64()),
+			kernel_count: try!(reader.read_u64()),
+			total_supply_nanogrin: try!(reader.read_u64()),
+			total_fees_nanogrin: try!(reader.read_u64()),
+		})
+	}
+}
+
+/// Sizes of the chain output, range proof and kernel MMRs after a block,
+/// kept to rewind them to that block.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MmrSizes {
+	/// Size of the output MMR
+	pub output_size: u64,
+	/// Size of the range proof MMR
+	pub rangeproof_size: u64,
+	/// Size of the kernel MMR
+	pub kernel_size: u64,
+}
+
+impl ser::Writeable for MmrSizes {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.output_size));
+		try!(writer.write_u64(self.rangeproof_size));
+		writer.write_u64(self.kernel_size)
+	}
+}
+
+impl ser::Readable for MmrSizes {
+	fn read(reader: &mut ser::Reader) -> Result<MmrSizes, ser::Error> {
+		Ok(MmrSizes {
+			output_size: try!(reader.read_u64()),
+			rangeproof_size: try!(reader.read_u64()),
+			kernel_size: try!(reader.read_u64()),
+		})
+	}
+}
+
+/// Positions of the outputs and kernels added to the chain MMRs, along with
+/// the outputs each block spends, kept in memory as blocks get applied and
+/// saved at once when the MMRs are synced.
+#[derive(Debug, Clone, Default)]
+pub struct MmrIndexes {
+	/// Positions of the outputs in the output MMR, by commitment
+	pub outputs: HashMap<Commitment, u64>,
+	/// Positions of the kernels in the kernel MMR, by excess
+	pub kernels: HashMap<Commitment, u64>,
+	/// Positions of the outputs spent by each block, by block hash
+	pub spent: HashMap<Hash, Vec<u64>>,
+}
 
 /// The tip of a fork. A handle to the fork ancestry from its leaf in the
 /// blockchain tree. References the max height and the latest and previous
@@ -172,9 +412,21 @@ pub trait ChainStore: Send + Sync {
 	/// Checks whether a block has been been processed and saved
 	fn check_block_exists(&self, h: &Hash) -> Result<bool, store::Error>;
 
-	/// Save the provided block in store
+	/// Save the provided block in store, along with the chain statistics up
+	/// to it
 	fn save_block(&self, b: &Block) -> Result<(), store::Error>;
 
+	/// Gets the chain statistics up to the block with the provided hash
+	fn get_block_stats(&self, h: &Hash) -> Result<ChainStats, store::Error>;
+
+	/// Save the sizes of the chain MMRs after the block with the provided
+	/// hash
+	fn save_block_mmr_sizes(&self, h: &Hash, sizes: &MmrSizes) -> Result<(), store::Error>;
+
+	/// Gets the sizes of the chain MMRs after the block with the provided
+	/// hash
+	fn get_block_mmr_sizes(&self, h: &Hash) -> Result<MmrSizes, store::Error>;
+
 	/// Save the provided block header in store
 	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), store::Error>;
 
@@ -196,6 +448,48 @@ pub trait ChainStore: Send + Sync {
     /// Gets a block_header for the given input commit
     fn get_block_header_by_output_commit(&self, commit: &Commitment) -> Result<BlockHeader, store::Error>;
 
+	/// Gets the header of the block including the kernel with the provided
+	/// excess commitment
+	fn get_block_header_by_kernel_excess(&self, excess: &Commitment) -> Result<BlockHeader, store::Error>;
+
+	/// Indexes the kernels of all the blocks in store by excess, for the ones
+	/// saved before the index existed. Only runs once, saving blocks keeping
+	/// the index up to date after that. Returns the number of blocks indexed.
+	fn index_kernels(&self) -> Result<u64, store::Error>;
+
+	/// Saves the positions of outputs and kernels in the chain MMRs and the
+	/// outputs spent by blocks, indexed by block as well as by position, the
+	/// MMRs then being at the block with the provided hash.
+	fn save_mmr_indexes(&self, indexes: &MmrIndexes, tip: &Hash) -> Result<(), store::Error>;
+
+	/// Hash of the last block whose MMR indexes were saved.
+	fn get_mmr_indexes_tip(&self) -> Result<Hash, store::Error>;
+
+	/// Gets the position in the output MMR of the output with the provided
+	/// commitment. The position can be stale after a reorganization, to be
+	/// checked against get_output_at_pos.
+	fn get_output_pos(&self, commit: &Commitment) -> Result<u64, store::Error>;
+
+	/// Gets the commitment of the output last added at the provided position
+	/// of the output MMR.
+	fn get_output_at_pos(&self, pos: u64) -> Result<Commitment, store::Error>;
+
+	/// Gets the position in the kernel MMR of the kernel with the provided
+	/// excess. Can be stale like output positions.
+	fn get_kernel_pos(&self, excess: &Commitment) -> Result<u64, store::Error>;
+
+	/// Gets the excess of the kernel last added at the provided position of
+	/// the kernel MMR.
+	fn get_kernel_at_pos(&self, pos: u64) -> Result<Commitment, store::Error>;
+
+	/// Gets the output MMR positions spent by the block with the provided
+	/// hash.
+	fn get_block_spent(&self, h: &Hash) -> Result<Vec<u64>, store::Error>;
+
+	/// Gets the hash of the block that last spent the output at the provided
+	/// position of the output MMR. The block can have been rewound since.
+	fn get_spent_by(&self, pos: u64) -> Result<Hash, store::Error>;
+
 	/// Saves the provided block header at the corresponding height. Also check
 	/// the consistency of the height chain in store by assuring previous
 	/// headers
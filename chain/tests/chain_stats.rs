@@ -0,0 +1,90 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate env_logger;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate grin_pow as pow;
+
+use std::fs;
+use std::sync::Arc;
+use rand::os::OsRng;
+
+use chain::types::*;
+use core::consensus;
+use core::core::Block;
+use core::core::build::{input_rand, output_rand, transaction, with_fee};
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+
+fn clean_output_dir(dir_name:&str){
+    let _ = fs::remove_dir_all(dir_name);
+}
+
+fn init_chain(dir_name: &str, genesis: Option<Block>) -> chain::Chain {
+	chain::Chain::init(dir_name.to_string(), Arc::new(NoopAdapter {}),
+	                   genesis, pow::verify_size, ChainConfig::default(), SyncMode::Full,
+	                   chain::Checkpoints::none()).unwrap()
+}
+
+#[test]
+fn stats_after_1000_blocks() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_stats");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let chain = init_chain(".grin_stats", pow::mine_genesis_block(None));
+	assert_eq!(chain.get_stats().unwrap(), ChainStats::default());
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let mut expected = ChainStats::default();
+	for n in 1..1001 {
+		// between 0 and 2 transactions, each spending 1 input for 2 outputs
+		let tx_count = (n % 7) / 3;
+		let txs = (0..tx_count)
+			.map(|_| {
+				transaction(vec![input_rand(10), output_rand(6), output_rand(2), with_fee(2)])
+					.unwrap()
+					.0
+			})
+			.collect::<Vec<_>>();
+
+		let prev = chain.head_header().unwrap();
+		let mut b = Block::new(&prev, txs.iter().collect(), reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_mmr_roots(&mut b).unwrap();
+		b.header.total_difficulty = Difficulty::from_num(n + 1);
+		chain.process_block(b, SKIP_POW).unwrap();
+
+		// coinbase output and kernel along with the transactions
+		expected.utxo_count += 1 + tx_count;
+		expected.kernel_count += 1 + tx_count;
+		expected.total_supply_nanogrin += consensus::REWARD;
+		expected.total_fees_nanogrin += 2 * tx_count;
+	}
+	assert_eq!(chain.head().unwrap().height, 1000);
+	assert_eq!(chain.get_stats().unwrap(), expected);
+
+	// reloaded as is on restart
+	drop(chain);
+	let chain = init_chain(".grin_stats", None);
+	assert_eq!(chain.get_stats().unwrap(), expected);
+}
@@ -0,0 +1,136 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate env_logger;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate grin_pow as pow;
+extern crate time;
+
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use rand::os::OsRng;
+
+use chain::Checkpoints;
+use chain::checkpoints::CHECKPOINT_INTERVAL;
+use chain::types::*;
+use core::consensus;
+use core::core::{Block, BlockHeader};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::global;
+use core::global::MiningParameterMode;
+use pow::cuckoo;
+
+static VERIFIED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// counts the cuckoo cycles verified
+fn counting_verifier(bh: &BlockHeader, cuckoo_sz: u32) -> bool {
+	VERIFIED.fetch_add(1, Ordering::SeqCst);
+	pow::verify_size(bh, cuckoo_sz)
+}
+
+fn clean_output_dir(dir_name:&str){
+    let _ = fs::remove_dir_all(dir_name);
+}
+
+fn init_chain(dir_name: &str, genesis: Block, checkpoints: Checkpoints) -> Result<chain::Chain, Error> {
+	clean_output_dir(dir_name);
+	chain::Chain::init(dir_name.to_string(), Arc::new(NoopAdapter {}),
+	                   Some(genesis), counting_verifier, ChainConfig::default(), SyncMode::Full,
+	                   checkpoints)
+}
+
+fn mine_block(chain: &chain::Chain, reward_key: secp::key::SecretKey) -> Block {
+	let prev = chain.head_header().unwrap();
+	let mut b = Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_mmr_roots(&mut b).unwrap();
+
+	let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
+	b.header.difficulty = difficulty.clone();
+	let mut miner = cuckoo::Miner::new(consensus::EASINESS, global::sizeshift() as u32, global::proofsize());
+	pow::pow_size(&mut miner, &mut b.header, difficulty, global::sizeshift() as u32).unwrap();
+
+	chain.process_block(b.clone(), EASY_POW).unwrap();
+	b
+}
+
+// all in a single test as the mining mode is global
+#[test]
+fn checkpoints() {
+	let _ = env_logger::init();
+
+	// the checkpoints shipped are well formed
+	global::set_mining_mode(MiningParameterMode::Production);
+	let hardcoded = Checkpoints::hardcoded();
+	let heights = hardcoded.all().iter().map(|&(h, _)| h).collect::<Vec<_>>();
+	assert!(heights.iter().all(|h| h % CHECKPOINT_INTERVAL == 0));
+	assert!(heights.windows(2).all(|w| w[0] < w[1]));
+
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+	assert!(Checkpoints::hardcoded().all().is_empty());
+	let genesis = pow::mine_genesis_block(None).unwrap();
+	let genesis_h = genesis.hash();
+
+	// the genesis block has to match its checkpoint
+	let wrong = Checkpoints::new(vec![(0, ZERO_HASH)]);
+	match init_chain(".grin_cp", genesis.clone(), wrong) {
+		Err(Error::CheckpointMismatch(0)) => {}
+		Err(e) => panic!("Unexpected error for a wrong genesis: {:?}", e),
+		Ok(_) => panic!("Chain initialized with a wrong genesis"),
+	}
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// a few blocks to take checkpoints from
+	let source = init_chain(".grin_cp_src", genesis.clone(), Checkpoints::none()).unwrap();
+	let blocks = (0..3).map(|_| mine_block(&source, reward_key)).collect::<Vec<_>>();
+	let checkpoints = Checkpoints::new(vec![(0, genesis_h), (2, blocks[1].hash())]);
+
+	// blocks at checkpoint heights have to match
+	let wrong = Checkpoints::new(vec![(0, genesis_h), (2, Hash([1; 32]))]);
+	let chain = init_chain(".grin_cp2", genesis.clone(), wrong).unwrap();
+	chain.process_block(blocks[0].clone(), EASY_POW).unwrap();
+	match chain.process_block(blocks[1].clone(), EASY_POW) {
+		Err(Error::CheckpointMismatch(2)) => {}
+		res => panic!("Unexpected result at a checkpoint: {:?}", res),
+	}
+	assert_eq!(chain.head().unwrap().height, 1);
+
+	// below the highest checkpoint, the proof of work is still verified
+	// until the synced headers matched it
+	let chain = init_chain(".grin_cp3", genesis.clone(), checkpoints.clone()).unwrap();
+	let verified = VERIFIED.load(Ordering::SeqCst);
+	chain.process_block(blocks[0].clone(), EASY_POW).unwrap();
+	assert_eq!(VERIFIED.load(Ordering::SeqCst), verified + 1);
+
+	// once they did, the cuckoo cycle of the blocks below it isn't verified
+	// again
+	let chain = init_chain(".grin_cp4", genesis, checkpoints).unwrap();
+	for b in &blocks {
+		chain.process_block_header(&b.header, EASY_POW).unwrap();
+	}
+	let verified = VERIFIED.load(Ordering::SeqCst);
+	chain.process_block(blocks[0].clone(), EASY_POW).unwrap();
+	assert_eq!(VERIFIED.load(Ordering::SeqCst), verified);
+	chain.process_block(blocks[1].clone(), EASY_POW).unwrap();
+	chain.process_block(blocks[2].clone(), EASY_POW).unwrap();
+	assert_eq!(VERIFIED.load(Ordering::SeqCst), verified + 2);
+	assert_eq!(chain.head().unwrap().last_block_h, blocks[2].hash());
+}
@@ -0,0 +1,189 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate env_logger;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate grin_pow as pow;
+
+use std::fs;
+use std::sync::Arc;
+use rand::os::OsRng;
+
+use chain::ForkGraph;
+use chain::types::*;
+use core::core::{Block, BlockHeader};
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+
+fn clean_output_dir(dir_name:&str){
+    let _ = fs::remove_dir_all(dir_name);
+}
+
+// header of a block on top of the provided one, with the given total
+// difficulty
+fn next_header(prev: &BlockHeader, diff: u64) -> BlockHeader {
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let mut b = Block::new(prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	b.header.total_difficulty = Difficulty::from_num(diff);
+	b.header
+}
+
+#[test]
+fn three_way_fork_graph() {
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+	let root = pow::mine_genesis_block(None).unwrap().header;
+	let mut graph = ForkGraph::new(root.clone(), 3);
+
+	// three branches forking off the same header at height 1
+	let h1 = next_header(&root, 10);
+	graph.add_header(h1.clone()).unwrap();
+	let a = next_header(&h1, 20);
+	let b = next_header(&h1, 25);
+	let c = next_header(&h1, 15);
+	for h in vec![&a, &b, &c] {
+		graph.add_header(h.clone()).unwrap();
+	}
+	let tips = graph.tips().into_iter().map(|h| h.hash()).collect::<Vec<_>>();
+	assert_eq!(tips, vec![b.hash(), a.hash(), c.hash()]);
+	assert_eq!(graph.best_tip().hash(), b.hash());
+
+	// a header without known parent can't be linked
+	match graph.add_header(next_header(&next_header(&c, 16), 17)) {
+		Err(Error::Orphan) => {}
+		res => panic!("Unexpected result for an orphan: {:?}", res),
+	}
+	assert!(!graph.is_better(&next_header(&b, 25)));
+	assert!(graph.is_better(&next_header(&b, 26)));
+
+	// extending a lighter branch past the best tip moves it
+	let a3 = next_header(&a, 30);
+	graph.add_header(a3.clone()).unwrap();
+	assert_eq!(graph.best_tip().hash(), a3.hash());
+	assert_eq!(graph.tips().len(), 3);
+
+	// once the fork point is too far behind, the other branches are dropped
+	let mut tip = a3.clone();
+	for n in 4..6 {
+		tip = next_header(&tip, 10 * n);
+		graph.add_header(tip.clone()).unwrap();
+	}
+	let tips = graph.tips().into_iter().map(|h| h.hash()).collect::<Vec<_>>();
+	assert_eq!(tips, vec![tip.hash()]);
+	assert!(!graph.contains(&b.hash()));
+	assert!(!graph.contains(&h1.hash()));
+	assert!(graph.contains(&a.hash()));
+}
+
+#[test]
+fn three_way_fork_chain() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_fork_graph");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let chain = chain::Chain::init(".grin_fork_graph".to_string(), Arc::new(NoopAdapter {}),
+	                               pow::mine_genesis_block(None), pow::verify_size,
+	                               ChainConfig::default(), SyncMode::Full,
+	                               chain::Checkpoints::none()).unwrap();
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// adds a block on top of the provided header, returning its header
+	let add_block = |prev: &BlockHeader, diff: u64| {
+		let mut b = Block::new(prev, vec![], reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = Difficulty::from_num(diff);
+		chain.set_mmr_roots(&mut b).unwrap();
+		let header = b.header.clone();
+		chain.process_block(b, SKIP_POW).unwrap();
+		header
+	};
+
+	let genesis = chain.head_header().unwrap();
+	let h1 = add_block(&genesis, 10);
+	let a = add_block(&h1, 20);
+	let b = add_block(&h1, 15);
+	let c = add_block(&h1, 25);
+	assert_eq!(chain.head().unwrap().last_block_h, c.hash());
+	let tips = chain.fork_tips().iter().map(|h| h.hash()).collect::<Vec<_>>();
+	assert_eq!(tips, vec![c.hash(), a.hash(), b.hash()]);
+
+	// the lightest branch takes over by getting extended twice
+	let b2 = add_block(&b, 22);
+	let b3 = add_block(&b2, 30);
+	assert_eq!(chain.head().unwrap().last_block_h, b3.hash());
+	let tips = chain.fork_tips().iter().map(|h| h.hash()).collect::<Vec<_>>();
+	assert_eq!(tips, vec![b3.hash(), c.hash(), a.hash()]);
+}
+
+#[test]
+fn heavier_sibling_after_restart() {
+	let _ = env_logger::init();
+	let dir = ".grin_fork_restart";
+	clean_output_dir(dir);
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let init = || {
+		chain::Chain::init(dir.to_string(), Arc::new(NoopAdapter {}),
+		                   pow::mine_genesis_block(None), pow::verify_size,
+		                   ChainConfig::default(), SyncMode::Full,
+		                   chain::Checkpoints::none()).unwrap()
+	};
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// adds a block on top of the provided header, returning its header
+	let add_block = |chain: &chain::Chain, prev: &BlockHeader, diff: u64| {
+		let mut b = Block::new(prev, vec![], reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = Difficulty::from_num(diff);
+		chain.set_mmr_roots(&mut b).unwrap();
+		let header = b.header.clone();
+		chain.process_block(b, SKIP_POW).unwrap();
+		header
+	};
+
+	let h1 = {
+		let chain = init();
+		let genesis = chain.head_header().unwrap();
+		let h1 = add_block(&chain, &genesis, 10);
+		let a = add_block(&chain, &h1, 20);
+		assert_eq!(chain.head().unwrap().last_block_h, a.hash());
+		h1
+	};
+
+	// after a restart, a lighter sibling of the head is only kept and a
+	// heavier one takes over
+	let chain = init();
+	add_block(&chain, &h1, 15);
+	let b = add_block(&chain, &h1, 25);
+	assert_eq!(chain.head().unwrap().last_block_h, b.hash());
+
+	// with the MMRs following, the new head gets extended
+	let b2 = add_block(&chain, &b, 30);
+	assert_eq!(chain.head().unwrap().last_block_h, b2.hash());
+	let tips = chain.fork_tips().iter().map(|h| h.hash()).collect::<Vec<_>>();
+	assert_eq!(tips[0], b2.hash());
+}
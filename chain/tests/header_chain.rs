@@ -0,0 +1,192 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate env_logger;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate grin_pow as pow;
+
+use std::fs;
+use rand::os::OsRng;
+
+use chain::HeaderChain;
+use chain::types::*;
+use core::consensus::{self, TargetError};
+use core::core::{Block, BlockHeader};
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+
+use pow::cuckoo;
+
+fn clean_output_dir(dir_name:&str){
+    let _ = fs::remove_dir_all(dir_name);
+}
+
+fn next_difficulty(headers: &[BlockHeader]) -> Difficulty {
+	let diff_iter = headers[1..].iter().rev().map(
+		|h| -> Result<(u64, Difficulty), TargetError> {
+			Ok((h.timestamp.to_timespec().sec as u64, h.difficulty.clone()))
+		},
+	);
+	consensus::next_difficulty(diff_iter).unwrap()
+}
+
+// Mines the header of a block following the provided headers, timestamped
+// the provided number of seconds after the last one.
+fn mine_header(headers: &[BlockHeader], reward_key: secp::key::SecretKey, secs: i64) -> BlockHeader {
+	let prev = headers[headers.len() - 1].clone();
+	let mut b = Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(secs);
+
+	let difficulty = next_difficulty(headers);
+	b.header.difficulty = difficulty.clone();
+	let mut cuckoo_miner = cuckoo::Miner::new(consensus::EASINESS, global::sizeshift() as u32, global::proofsize());
+	pow::pow_size(
+		&mut cuckoo_miner,
+		&mut b.header,
+		difficulty,
+		global::sizeshift() as u32,
+	).unwrap();
+	b.header
+}
+
+#[test]
+fn mine_header_chain() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_headers");
+	fs::create_dir_all(".grin_headers").unwrap();
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let path = ".grin_headers/headers.bin".to_string();
+	let mut header_chain = HeaderChain::open(path.clone(), EASY_POW, MAINNET_MAX_REORG_DEPTH, pow::verify_size).unwrap();
+	assert!(header_chain.head().is_none());
+
+	let genesis = pow::mine_genesis_block(None).unwrap();
+	header_chain.process_header(&genesis.header).unwrap();
+	let mut headers = vec![genesis.header];
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	for n in 1..4 {
+		let header = mine_header(&headers, reward_key, 60);
+		header_chain.process_header(&header).unwrap();
+		assert_eq!(header_chain.head().unwrap().height, n);
+		headers.push(header);
+	}
+	header_chain.sync().unwrap();
+
+	// already known headers don't extend the head
+	match header_chain.process_header(&headers[2]) {
+		Err(Error::Unfit(_)) => {}
+		res => panic!("Unexpected result for a known header: {:?}", res),
+	}
+
+	// nor do headers without a valid proof of work
+	let prev = headers[3].clone();
+	let mut b = Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	b.header.difficulty = next_difficulty(&headers);
+	match header_chain.process_header(&b.header) {
+		Err(Error::InvalidPow) => {}
+		res => panic!("Unexpected result for an unmined header: {:?}", res),
+	}
+
+	// headers can be looked up by hash
+	for h in &headers {
+		assert_eq!(header_chain.get_header(&h.hash()).unwrap().height, h.height);
+	}
+	assert!(header_chain.get_header(&b.header.hash()).is_none());
+
+	// the header chain is the same once reopened, its hash index getting
+	// rebuilt as it wasn't saved yet
+	drop(header_chain);
+	let header_chain = HeaderChain::open(path.clone(), EASY_POW, MAINNET_MAX_REORG_DEPTH, pow::verify_size).unwrap();
+	let head = header_chain.head().unwrap();
+	assert_eq!(head.height, 3);
+	assert_eq!(head.last_block_h, headers[3].hash());
+	assert_eq!(header_chain.get_header_by_height(1).unwrap().hash(), headers[1].hash());
+	assert_eq!(header_chain.get_header(&headers[2].hash()).unwrap().height, 2);
+
+	// the rebuilt index got saved but isn't required either
+	drop(header_chain);
+	fs::remove_file(format!("{}.idx", path)).unwrap();
+	let header_chain = HeaderChain::open(path, EASY_POW, MAINNET_MAX_REORG_DEPTH, pow::verify_size).unwrap();
+	assert_eq!(header_chain.get_header(&headers[3].hash()).unwrap().height, 3);
+}
+
+#[test]
+fn header_chain_reorg() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_headers_reorg");
+	fs::create_dir_all(".grin_headers_reorg").unwrap();
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let path = ".grin_headers_reorg/headers.bin".to_string();
+	let mut header_chain = HeaderChain::open(path.clone(), EASY_POW, MAINNET_MAX_REORG_DEPTH, pow::verify_size).unwrap();
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// a chain of 3 headers after genesis
+	let genesis = pow::mine_genesis_block(None).unwrap();
+	header_chain.process_header(&genesis.header).unwrap();
+	let mut chain = vec![genesis.header];
+	for _ in 0..3 {
+		let header = mine_header(&chain, reward_key, 60);
+		header_chain.process_header(&header).unwrap();
+		chain.push(header);
+	}
+	header_chain.sync().unwrap();
+	let head = header_chain.head().unwrap();
+
+	// a branch forking off after the first header is kept aside until it
+	// has more work than the chain
+	let mut fork = chain[..2].to_vec();
+	loop {
+		let header = mine_header(&fork, reward_key, 61);
+		header_chain.process_header(&header).unwrap();
+		fork.push(header);
+		if fork[fork.len() - 1].total_difficulty > head.total_difficulty {
+			break;
+		}
+		assert_eq!(header_chain.head().unwrap().last_block_h, head.last_block_h);
+		assert!(header_chain.get_header(&fork[fork.len() - 1].hash()).is_some());
+	}
+	header_chain.sync().unwrap();
+
+	// the chain got rewound to the fork point to follow the branch
+	let tip = fork[fork.len() - 1].clone();
+	assert_eq!(header_chain.head().unwrap().last_block_h, tip.hash());
+	for h in &fork {
+		assert_eq!(header_chain.get_header_by_height(h.height).unwrap().hash(), h.hash());
+	}
+	assert!(header_chain.get_header_by_height(tip.height + 1).is_none());
+
+	// the rewound headers are kept aside as a branch now
+	assert_eq!(header_chain.get_header(&chain[3].hash()).unwrap().height, 3);
+
+	// the file and index follow the branch once reopened
+	drop(header_chain);
+	let header_chain = HeaderChain::open(path, EASY_POW, MAINNET_MAX_REORG_DEPTH, pow::verify_size).unwrap();
+	assert_eq!(header_chain.head().unwrap().last_block_h, tip.hash());
+	assert_eq!(header_chain.get_header(&fork[2].hash()).unwrap().height, 2);
+	assert!(header_chain.get_header(&chain[2].hash()).is_none());
+}
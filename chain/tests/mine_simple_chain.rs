@@ -50,7 +50,8 @@ fn mine_empty_chain() {
 		genesis_block=pow::mine_genesis_block(None);
 	}
 	let chain = chain::Chain::init(".grin".to_string(), Arc::new(NoopAdapter {}),
-									genesis_block, pow::verify_size).unwrap();
+									genesis_block, pow::verify_size, ChainConfig::default(), SyncMode::Full,
+									chain::Checkpoints::none()).unwrap();
 
 	// mine and add a few blocks
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
@@ -68,6 +69,7 @@ fn mine_empty_chain() {
 		let prev = chain.head_header().unwrap();
 		let mut b = core::core::Block::new(&prev, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_mmr_roots(&mut b).unwrap();
 
 		let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
 		b.header.difficulty = difficulty.clone();
@@ -107,6 +109,11 @@ fn mine_empty_chain() {
         let header_by_output_commit = chain.get_block_header_by_output_commit(&output.commitment()).unwrap();
         assert_eq!(header_by_output_commit.hash(), bhash);
 	}
+
+	// one coinbase kernel per mined block, none in genesis
+	let scan = chain.scan_kernels(0, 10).unwrap();
+	assert_eq!(scan.kernels_per_block, vec![0, 1, 1, 1]);
+	assert_eq!(scan.kernels.len(), 3);
 }
 
 #[test]
@@ -121,7 +128,8 @@ fn mine_forks() {
 		genesis_block=pow::mine_genesis_block(None);
 	}
 	let chain = chain::Chain::init(".grin2".to_string(), Arc::new(NoopAdapter {}),
-									genesis_block, pow::verify_size).unwrap();
+									genesis_block, pow::verify_size, ChainConfig::default(), SyncMode::Full,
+									chain::Checkpoints::none()).unwrap();
 
 	// mine and add a few blocks
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
@@ -131,6 +139,7 @@ fn mine_forks() {
 		let prev = chain.head_header().unwrap();
 		let mut b = core::core::Block::new(&prev, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_mmr_roots(&mut b).unwrap();
 		b.header.total_difficulty = Difficulty::from_num(2 * n);
 		let bhash = b.hash();
 		chain.process_block(b, chain::SKIP_POW).unwrap();
@@ -145,6 +154,7 @@ fn mine_forks() {
 		// build another block with higher difficulty
 		let mut b = core::core::Block::new(&prev, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_mmr_roots(&mut b).unwrap();
 		b.header.total_difficulty = Difficulty::from_num(2 * n + 1);
 		let bhash = b.hash();
 		chain.process_block(b, chain::SKIP_POW).unwrap();
@@ -157,3 +167,144 @@ fn mine_forks() {
 		assert_eq!(head.prev_block_h, prev.hash());
 	}
 }
+
+#[test]
+fn refuse_deep_reorg() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin3");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	// mainnet rules, only with a shallower maximum to keep the test quick
+	assert_eq!(ChainConfig::mainnet().max_reorg_depth, 1440);
+	let config = ChainConfig { max_reorg_depth: 3, ..ChainConfig::mainnet() };
+	let chain = chain::Chain::init(".grin3".to_string(), Arc::new(NoopAdapter {}),
+									pow::mine_genesis_block(None), pow::verify_size, config,
+									SyncMode::Full, chain::Checkpoints::none()).unwrap();
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// adds a block on top of the provided header, returning its header
+	let add_block = |prev: &core::core::BlockHeader, diff: u64| {
+		let mut b = core::core::Block::new(prev, vec![], reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_mmr_roots(&mut b).unwrap();
+		b.header.total_difficulty = Difficulty::from_num(diff);
+		let header = b.header.clone();
+		(header, chain.process_block(b, chain::SKIP_POW))
+	};
+
+	// main chain up to height 5
+	let mut main = vec![chain.head_header().unwrap()];
+	for n in 1..6 {
+		let (header, res) = add_block(&main[n - 1], 10 * n as u64);
+		res.unwrap();
+		main.push(header);
+	}
+
+	// a heavier fork from height 1 would roll back 4 blocks
+	let mut prev = main[1].clone();
+	for n in 2..6 {
+		let (header, res) = add_block(&prev, 10 * n as u64 + 1);
+		if n < 5 {
+			assert!(res.unwrap().is_none());
+		} else {
+			match res {
+				Err(Error::ReorgTooDeep { depth: 4 }) => {}
+				res => panic!("Unexpected result for a deep reorg: {:?}", res),
+			}
+		}
+		prev = header;
+	}
+	assert_eq!(chain.head().unwrap().last_block_h, main[5].hash());
+
+	// while one from height 3 only rolls back 2 blocks
+	let (header, res) = add_block(&main[3], 41);
+	assert!(res.unwrap().is_none());
+	let (header, res) = add_block(&header, 52);
+	res.unwrap();
+	assert_eq!(chain.head().unwrap().last_block_h, header.hash());
+}
+
+#[test]
+fn refuse_wrong_mmr_roots() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin4");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let chain = chain::Chain::init(".grin4".to_string(), Arc::new(NoopAdapter {}),
+									pow::mine_genesis_block(None), pow::verify_size, ChainConfig::default(),
+									SyncMode::Full, chain::Checkpoints::none()).unwrap();
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let prev = chain.head_header().unwrap();
+	let new_block = || {
+		let mut b = core::core::Block::new(&prev, vec![], reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = Difficulty::from_num(10);
+		chain.set_mmr_roots(&mut b).unwrap();
+		b
+	};
+
+	// a block committing to a wrong output root
+	let mut wrong = new_block();
+	let roots = core::core::MmrRoots {
+		output_root: prev.hash(),
+		rangeproof_root: wrong.header.rangeproof_mmr_root,
+		kernel_root: wrong.header.kernel_mmr_root,
+	};
+	wrong.header.set_mmr_roots(&roots);
+	match chain.process_block(wrong, chain::SKIP_POW) {
+		Err(Error::MmrRootMismatch("output_mmr_root")) => {}
+		res => panic!("Unexpected result for wrong roots: {:?}", res),
+	}
+	assert_eq!(chain.head().unwrap().last_block_h, prev.hash());
+
+	// the MMRs were restored, a block with the right roots gets in
+	let b = new_block();
+	let bhash = b.hash();
+	chain.process_block(b, chain::SKIP_POW).unwrap();
+	assert_eq!(chain.head().unwrap().last_block_h, bhash);
+}
+
+#[test]
+fn refuse_difficulty_overflow() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin5");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let chain = chain::Chain::init(".grin5".to_string(), Arc::new(NoopAdapter {}),
+									pow::mine_genesis_block(None), pow::verify_size, ChainConfig::default(),
+									SyncMode::Full, chain::Checkpoints::none()).unwrap();
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let prev = chain.head_header().unwrap();
+	let new_block = |diff: Difficulty| {
+		let mut b = core::core::Block::new(&prev, vec![], reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = diff;
+		chain.set_mmr_roots(&mut b).unwrap();
+		b
+	};
+
+	// even without checking the proof of work, a total difficulty past the
+	// maximum is refused
+	let over = Difficulty::MAX + Difficulty::one();
+	match chain.process_block(new_block(over), chain::SKIP_POW) {
+		Err(Error::DifficultyOverflow) => {}
+		res => panic!("Unexpected result for a difficulty overflow: {:?}", res),
+	}
+	assert_eq!(chain.head().unwrap().last_block_h, prev.hash());
+
+	let b = new_block(Difficulty::MAX);
+	let bhash = b.hash();
+	chain.process_block(b, chain::SKIP_POW).unwrap();
+	assert_eq!(chain.head().unwrap().last_block_h, bhash);
+}
@@ -15,6 +15,7 @@
 extern crate env_logger;
 extern crate grin_chain as chain;
 extern crate grin_core as core;
+extern crate grin_store;
 extern crate rand;
 extern crate secp256k1zkp as secp;
 
@@ -53,4 +54,38 @@ fn test_various_store_indices() {
 		.get_block_header_by_output_commit(&commit)
 		.unwrap();
 	assert_eq!(block_header.hash(), block_hash);
+
+	let block_header = chain_store
+		.get_block_header_by_kernel_excess(&block.kernels[0].excess)
+		.unwrap();
+	assert_eq!(block_header.hash(), block_hash);
+}
+
+#[test]
+fn index_kernels_of_older_blocks() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_kernels");
+
+	let block = Block::new(&BlockHeader::default(), vec![], key::ONE_KEY).unwrap();
+	let excess = block.kernels[0].excess;
+	{
+		let chain_store = chain::store::ChainKVStore::new(".grin_kernels".to_string()).unwrap();
+		chain_store.save_block(&block).unwrap();
+		chain_store.setup_height(&block.header).unwrap();
+	}
+
+	// a block saved before the kernel index existed
+	{
+		let db = grin_store::Store::open(".grin_kernels/chain").unwrap();
+		db.delete(&grin_store::to_key('k' as u8, &mut excess.as_ref().to_vec())).unwrap();
+	}
+
+	let chain_store = chain::store::ChainKVStore::new(".grin_kernels".to_string()).unwrap();
+	assert!(chain_store.get_block_header_by_kernel_excess(&excess).is_err());
+	assert_eq!(chain_store.index_kernels().unwrap(), 1);
+	let block_header = chain_store.get_block_header_by_kernel_excess(&excess).unwrap();
+	assert_eq!(block_header.hash(), block.hash());
+
+	// only indexed once
+	assert_eq!(chain_store.index_kernels().unwrap(), 0);
 }
@@ -0,0 +1,254 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate env_logger;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate grin_pow as pow;
+
+use std::fs;
+use std::sync::Arc;
+use rand::os::OsRng;
+
+use chain::types::*;
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::build::{input, input_rand, output, output_rand, transaction, with_fee};
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+
+fn clean_output_dir(dir_name:&str){
+    let _ = fs::remove_dir_all(dir_name);
+}
+
+fn init_chain(dir_name: &str, genesis: Option<Block>, config: ChainConfig) -> chain::Chain {
+	chain::Chain::init(dir_name.to_string(), Arc::new(NoopAdapter {}),
+	                   genesis, pow::verify_size, config, SyncMode::Full,
+	                   chain::Checkpoints::none()).unwrap()
+}
+
+#[test]
+fn spend_across_reorg() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_sumtree");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let out_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let (tx1, _) = transaction(vec![input_rand(10), output(6, out_key), output_rand(2), with_fee(2)]).unwrap();
+	let (tx2, _) = transaction(vec![input(6, out_key), output_rand(4), with_fee(2)]).unwrap();
+	let out = secp.commit(6, out_key).unwrap();
+
+	let (excess, head_h) = {
+		let chain = init_chain(".grin_sumtree", pow::mine_genesis_block(None), ChainConfig::default());
+
+		// adds a block on top of the provided header, returning its header
+		let add_block = |prev: &BlockHeader, txs: Vec<&Transaction>, diff: u64| {
+			let mut b = Block::new(prev, txs, reward_key).unwrap();
+			b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+			b.header.total_difficulty = Difficulty::from_num(diff);
+			chain.set_mmr_roots(&mut b).unwrap();
+			let header = b.header.clone();
+			chain.process_block(b, SKIP_POW).unwrap();
+			header
+		};
+
+		// the output of tx1 gets spent by tx2 in the next block
+		let genesis = chain.head_header().unwrap();
+		let h1 = add_block(&genesis, vec![&tx1], 10);
+		assert!(chain.is_output_retained(&out));
+		let h2 = add_block(&h1, vec![&tx2], 20);
+		assert!(!chain.is_output_retained(&out));
+		let excess = kernel_excess(&chain, &h2);
+		assert_eq!(chain.get_kernel_by_excess(&excess).unwrap().excess, excess);
+
+		// a heavier fork without tx2 restores the output
+		let h2_fork = add_block(&h1, vec![], 15);
+		let h3_fork = add_block(&h2_fork, vec![], 25);
+		assert_eq!(chain.head().unwrap().last_block_h, h3_fork.hash());
+		assert!(chain.get_kernel_by_excess(&excess).is_none());
+		assert!(chain.is_output_retained(&out));
+
+		// which can be spent again on the fork
+		let h4_fork = add_block(&h3_fork, vec![&tx2], 35);
+		assert!(!chain.is_output_retained(&out));
+		assert_eq!(chain.get_kernel_by_excess(&excess).unwrap().excess, excess);
+		(excess, h4_fork.hash())
+	};
+
+	// the MMR indexes are read back from store on restart, the entries left
+	// by the rewound blocks ignored
+	let chain = init_chain(".grin_sumtree", None, ChainConfig::default());
+	assert_eq!(chain.head().unwrap().last_block_h, head_h);
+	assert_eq!(chain.get_kernel_by_excess(&excess).unwrap().excess, excess);
+	assert!(!chain.is_output_retained(&out));
+	assert_eq!(chain.get_output_at(&out, 3).unwrap().unwrap().commitment(), out);
+	assert!(chain.get_output_at(&out, 4).unwrap().is_none());
+	assert!(chain.get_kernel_by_excess(&tx1.outputs[0].commitment()).is_none());
+}
+
+#[test]
+fn conservative_pruning() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_pruning");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let config = ChainConfig {
+		pruning: PruningPolicy::Conservative { retain_blocks: 100 },
+		..ChainConfig::default()
+	};
+	let chain = init_chain(".grin_pruning", pow::mine_genesis_block(None), config);
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let out_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let (tx1, _) = transaction(vec![input_rand(10), output(6, out_key), output_rand(2), with_fee(2)]).unwrap();
+	let (tx2, _) = transaction(vec![input(6, out_key), output_rand(4), with_fee(2)]).unwrap();
+	let out = secp.commit(6, out_key).unwrap();
+
+	// the output is created at height 1 and spent at height 2
+	for n in 1..104 {
+		let txs = match n {
+			1 => vec![&tx1],
+			2 => vec![&tx2],
+			_ => vec![],
+		};
+		let prev = chain.head_header().unwrap();
+		let mut b = Block::new(&prev, txs, reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = Difficulty::from_num(n);
+		chain.set_mmr_roots(&mut b).unwrap();
+		chain.process_block(b, SKIP_POW).unwrap();
+
+		// retained until spent more than 100 blocks ago
+		assert_eq!(chain.is_output_retained(&out), n <= 102, "at height {}", n);
+	}
+}
+
+#[test]
+fn outputs_at_height() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_outputs_at");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let chain = init_chain(".grin_outputs_at", pow::mine_genesis_block(None), ChainConfig::default());
+
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let out_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let (tx1, _) = transaction(vec![input_rand(10), output(6, out_key), output_rand(2), with_fee(2)]).unwrap();
+	let (tx2, _) = transaction(vec![input(6, out_key), output_rand(4), with_fee(2)]).unwrap();
+	let out = secp.commit(6, out_key).unwrap();
+
+	let mut headers = vec![chain.head_header().unwrap()];
+	for (n, txs) in vec![vec![&tx1], vec![&tx2], vec![]].into_iter().enumerate() {
+		// distinct coinbase commitments
+		let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+		let prev = chain.head_header().unwrap();
+		let mut b = Block::new(&prev, txs, reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		b.header.total_difficulty = Difficulty::from_num(n as u64 + 1);
+		chain.set_mmr_roots(&mut b).unwrap();
+		headers.push(b.header.clone());
+		chain.process_block(b, SKIP_POW).unwrap();
+	}
+
+	// a coinbase per block, tx1 adds 2 outputs and tx2 replaces one
+	let unspent_counts = vec![0, 3, 4, 5];
+	for (height, header) in headers.iter().enumerate() {
+		assert_eq!(chain.output_pmmr_root_at(height as u64).unwrap(), header.output_mmr_root);
+		let (outputs, next) = chain.get_outputs_at(height as u64, 1, 100).unwrap();
+		assert_eq!((outputs.len(), next), (unspent_counts[height], None));
+	}
+
+	// paging through them, the spent output is skipped
+	let (all, _) = chain.get_outputs_at(3, 1, 100).unwrap();
+	let mut paged = vec![];
+	let mut pages = 0;
+	let mut next = Some(1);
+	while let Some(start) = next {
+		let (page, page_next) = chain.get_outputs_at(3, start, 2).unwrap();
+		assert!(page.len() == 2 || page_next.is_none());
+		paged.extend(page);
+		pages += 1;
+		next = page_next;
+	}
+	assert_eq!(pages, 3);
+	assert_eq!(paged, all);
+
+	// the output only existed unspent at height 1, it's pruned since
+	assert!(chain.get_output_at(&out, 0).unwrap().is_none());
+	assert_eq!(chain.get_output_at(&out, 1).unwrap().unwrap().commitment(), out);
+	assert!(chain.get_output_at(&out, 2).unwrap().is_none());
+	assert!(!chain.is_output_retained(&out));
+	assert!(chain.get_archived_output(&out).unwrap().is_none());
+}
+
+#[test]
+fn archive_pruned_outputs() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_archive");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let config = ChainConfig {
+		archive_pruned: true,
+		..ChainConfig::default()
+	};
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let out_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let (tx1, _) = transaction(vec![input_rand(10), output(6, out_key), output_rand(2), with_fee(2)]).unwrap();
+	let (tx2, _) = transaction(vec![input(6, out_key), output_rand(4), with_fee(2)]).unwrap();
+	let out = secp.commit(6, out_key).unwrap();
+	let tx1_out = *tx1.outputs.iter().find(|o| o.commitment() == out).unwrap();
+
+	{
+		let chain = init_chain(".grin_archive", pow::mine_genesis_block(None), config.clone());
+		for txs in vec![vec![&tx1], vec![&tx2]] {
+			let prev = chain.head_header().unwrap();
+			let mut b = Block::new(&prev, txs, reward_key).unwrap();
+			b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+			b.header.total_difficulty = Difficulty::from_num(prev.height + 1);
+			chain.set_mmr_roots(&mut b).unwrap();
+			chain.process_block(b, SKIP_POW).unwrap();
+		}
+
+		// pruned from the MMRs but still served from the archive
+		assert!(!chain.is_output_retained(&out));
+		assert_eq!(chain.get_archived_output(&out).unwrap(), Some(tx1_out));
+		chain.compact().unwrap();
+		assert_eq!(chain.get_archived_output(&out).unwrap(), Some(tx1_out));
+	}
+
+	// and after a restart
+	let chain = init_chain(".grin_archive", None, config);
+	assert_eq!(chain.get_archived_output(&out).unwrap(), Some(tx1_out));
+}
+
+// excess of the kernel of the only transaction in the block
+fn kernel_excess(chain: &chain::Chain, header: &BlockHeader) -> secp::pedersen::Commitment {
+	let b = chain.get_block(&header.hash()).unwrap();
+	b.kernels.iter().find(|k| k.fee > 0).unwrap().excess
+}
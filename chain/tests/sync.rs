@@ -0,0 +1,148 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate env_logger;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate grin_pow as pow;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use rand::os::OsRng;
+
+use chain::{BlockFetcher, BodySync};
+use chain::sync::DEFAULT_LOOKAHEAD;
+use chain::types::*;
+use core::core::{Block, BlockHeader};
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+
+fn clean_output_dir(dir_name:&str){
+    let _ = fs::remove_dir_all(dir_name);
+}
+
+fn init_chain(dir_name: &str, genesis: Option<Block>) -> Arc<chain::Chain> {
+	Arc::new(chain::Chain::init(dir_name.to_string(), Arc::new(NoopAdapter {}),
+	                            genesis, pow::verify_size, ChainConfig::default(),
+	                            SyncMode::Full, chain::Checkpoints::none()).unwrap())
+}
+
+// Serves the blocks of a source chain. Once the block to fork at is fetched,
+// switches the header chain of the syncing chain to the fork headers.
+struct ChainFetcher {
+	source: Arc<chain::Chain>,
+	target: Arc<chain::Chain>,
+	fork: Mutex<Option<(Hash, Vec<BlockHeader>)>>,
+}
+
+impl BlockFetcher for ChainFetcher {
+	fn fetch_block(&self, h: &Hash) -> Option<Block> {
+		let mut fork = self.fork.lock().unwrap();
+		if fork.as_ref().map(|f| f.0 == *h).unwrap_or(false) {
+			for bh in fork.take().unwrap().1 {
+				self.target.process_block_header(&bh, SKIP_POW).unwrap();
+			}
+		}
+		self.source.get_block(h).ok()
+	}
+}
+
+// Adds blocks with the provided total difficulties to the source chain on top
+// of the provided header, returning their headers.
+fn add_blocks(source: &chain::Chain, prev: &BlockHeader, diffs: Vec<u64>) -> Vec<BlockHeader> {
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let mut headers: Vec<BlockHeader> = vec![];
+	for diff in diffs {
+		let mut b = {
+			let prev = headers.last().unwrap_or(prev);
+			let mut b = Block::new(prev, vec![], reward_key).unwrap();
+			b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+			b
+		};
+		b.header.total_difficulty = Difficulty::from_num(diff);
+		source.set_mmr_roots(&mut b).unwrap();
+		headers.push(b.header.clone());
+		source.process_block(b, SKIP_POW).unwrap();
+	}
+	headers
+}
+
+#[test]
+fn sync_bodies() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_sync_src");
+	clean_output_dir(".grin_sync_dst");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let source = init_chain(".grin_sync_src", pow::mine_genesis_block(None));
+	let genesis = source.head_header().unwrap();
+	let headers = add_blocks(&source, &genesis, (1..21).map(|n| 10 * n).collect());
+
+	// the syncing chain only knows the headers
+	let target = init_chain(".grin_sync_dst", source.get_block(&genesis.hash()).ok());
+	for bh in &headers {
+		target.process_block_header(bh, SKIP_POW).unwrap();
+	}
+
+	let fetcher = Arc::new(ChainFetcher {
+		source: source.clone(),
+		target: target.clone(),
+		fork: Mutex::new(None),
+	});
+	let sync = BodySync::new(target.clone(), fetcher, DEFAULT_LOOKAHEAD, SYNC);
+	let tip = sync.run().unwrap().unwrap();
+	assert_eq!(tip.last_block_h, headers[19].hash());
+	assert_eq!(target.head().unwrap().last_block_h, headers[19].hash());
+
+	// nothing left to do the second time
+	assert!(sync.run().unwrap().is_none());
+}
+
+#[test]
+fn sync_bodies_fork() {
+	let _ = env_logger::init();
+	clean_output_dir(".grin_sync_fork_src");
+	clean_output_dir(".grin_sync_fork_dst");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	// the source has a main branch and a heavier fork from height 4
+	let source = init_chain(".grin_sync_fork_src", pow::mine_genesis_block(None));
+	let genesis = source.head_header().unwrap();
+	let main = add_blocks(&source, &genesis, (1..21).map(|n| 10 * n).collect());
+	let fork = add_blocks(&source, &main[3], (5..25).map(|n| 10 * n + 5).collect());
+
+	let target = init_chain(".grin_sync_fork_dst", source.get_block(&genesis.hash()).ok());
+	for bh in &main {
+		target.process_block_header(bh, SKIP_POW).unwrap();
+	}
+
+	// the header chain switches to the fork while downloading the main branch
+	let fetcher = Arc::new(ChainFetcher {
+		source: source.clone(),
+		target: target.clone(),
+		fork: Mutex::new(Some((main[11].hash(), fork.clone()))),
+	});
+	let sync = BodySync::new(target.clone(), fetcher, 4, SYNC);
+	sync.run().unwrap();
+	assert_eq!(target.head().unwrap().last_block_h, fork[19].hash());
+	assert_eq!(target.get_header_head().unwrap().last_block_h, fork[19].hash());
+}
@@ -49,7 +49,8 @@ fn test_coinbase_maturity() {
 		genesis_block=pow::mine_genesis_block(None);
 	}
 	let chain = chain::Chain::init(".grin".to_string(), Arc::new(NoopAdapter {}),
-									genesis_block, pow::verify_size).unwrap();
+									genesis_block, pow::verify_size, ChainConfig::default(), SyncMode::Full,
+									chain::Checkpoints::none()).unwrap();
 
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
 
@@ -66,6 +67,7 @@ fn test_coinbase_maturity() {
     let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
 	let mut block = core::core::Block::new(&prev, vec![], reward_key).unwrap();
 	block.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_mmr_roots(&mut block).unwrap();
 
 	let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
 	block.header.difficulty = difficulty.clone();
@@ -95,6 +97,7 @@ fn test_coinbase_maturity() {
 	let mut block = core::core::Block::new(&prev, vec![&coinbase_txn], reward_key).unwrap();
 
 	block.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_mmr_roots(&mut block).unwrap();
 
 	let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
 	block.header.difficulty = difficulty.clone();
@@ -120,6 +123,7 @@ fn test_coinbase_maturity() {
         let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
         let mut block = core::core::Block::new(&prev, vec![], reward_key).unwrap();
         block.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+        chain.set_mmr_roots(&mut block).unwrap();
 
         let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
         block.header.difficulty = difficulty.clone();
@@ -140,6 +144,7 @@ fn test_coinbase_maturity() {
     let mut block = core::core::Block::new(&prev, vec![&coinbase_txn], reward_key).unwrap();
 
     block.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+    chain.set_mmr_roots(&mut block).unwrap();
 
     let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
     block.header.difficulty = difficulty.clone();
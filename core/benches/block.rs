@@ -0,0 +1,64 @@
+// Copyright 2017 The Grin Developers
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Full validation of a 500 outputs block, on a single thread and on as many
+//! threads as there are CPUs. The ratio between the two is the speedup of
+//! verifying range proofs and signatures in parallel.
+
+#![feature(test)]
+
+extern crate test;
+extern crate rand;
+extern crate rayon;
+extern crate grin_core as core;
+extern crate secp256k1zkp as secp;
+
+use test::Bencher;
+
+use core::core::{Block, BlockHeader};
+use core::core::build::{input_rand, output_rand, transaction, with_fee};
+use secp::Secp256k1;
+use secp::key::SecretKey;
+
+// the coinbase output makes up the 500th
+const TX_OUTPUTS: u64 = 499;
+
+fn block_500_outputs() -> Block {
+	let secp = Secp256k1::new();
+	let txs = (0..TX_OUTPUTS)
+		.map(|n| transaction(vec![input_rand(10 + n), output_rand(9 + n), with_fee(1)]).unwrap().0)
+		.collect::<Vec<_>>();
+	let key = SecretKey::new(&secp, &mut rand::thread_rng());
+	Block::new(&BlockHeader::default(), txs.iter().collect(), key).unwrap()
+}
+
+fn bench_validate(b: &mut Bencher, pool: rayon::ThreadPool) {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let block = block_500_outputs();
+	assert_eq!(block.outputs.len(), 500);
+
+	b.iter(|| pool.install(|| block.validate_full(&secp)).unwrap());
+}
+
+#[bench]
+fn bench_validate_single_thread(b: &mut Bencher) {
+	let pool = rayon::ThreadPool::new(rayon::Configuration::new().num_threads(1)).unwrap();
+	bench_validate(b, pool);
+}
+
+#[bench]
+fn bench_validate_multi_thread(b: &mut Bencher) {
+	// defaults to the number of CPUs
+	let pool = rayon::ThreadPool::new(rayon::Configuration::new()).unwrap();
+	bench_validate(b, pool);
+}
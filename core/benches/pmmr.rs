@@ -0,0 +1,133 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![feature(test)]
+
+extern crate test;
+extern crate rand;
+extern crate grin_core as core;
+
+use rand::Rng;
+use test::Bencher;
+
+use core::core::pmmr::{bintree_postorder_height, PruneList, PMMR, VecBackend};
+
+const PRUNED_COUNT: usize = 1_000_000;
+const LEAF_COUNT: u64 = 1_000_000;
+const PROOF_COUNT: usize = 1000;
+
+// Prune list with every other leaf pruned, so nothing gets compacted, along
+// with the last position it covers.
+fn prune_list() -> (PruneList, u64) {
+	let mut nodes = Vec::with_capacity(PRUNED_COUNT);
+	let mut pos = 1;
+	while nodes.len() < PRUNED_COUNT {
+		if bintree_postorder_height(pos) == 0 && bintree_postorder_height(pos + 1) == 0 {
+			nodes.push(pos);
+			pos += 1;
+		}
+		pos += 1;
+	}
+	(PruneList::with_nodes(nodes), pos)
+}
+
+fn bench_shift(b: &mut Bencher, pl: &PruneList, max_pos: u64) {
+	let mut rng = rand::thread_rng();
+	b.iter(|| {
+		for _ in 0..100 {
+			test::black_box(pl.get_shift(rng.gen_range(1, max_pos)));
+		}
+	});
+}
+
+#[bench]
+fn bench_get_shift_linear(b: &mut Bencher) {
+	let (pl, max_pos) = prune_list();
+	bench_shift(b, &pl, max_pos);
+}
+
+#[bench]
+fn bench_get_shift_indexed(b: &mut Bencher) {
+	let (mut pl, max_pos) = prune_list();
+	pl.build_index();
+	bench_shift(b, &pl, max_pos);
+}
+
+// MMR of LEAF_COUNT leaves, along with a random batch of PROOF_COUNT leaf
+// positions to prove.
+fn mmr_batch() -> (VecBackend<u64>, u64, Vec<u64>) {
+	let mut rng = rand::thread_rng();
+	let mut ba = VecBackend::new();
+	let (size, leaves) = {
+		let mut pmmr = PMMR::new(&mut ba);
+		let leaves = (0..LEAF_COUNT).map(|n| pmmr.push(n)).collect::<Vec<_>>();
+		(pmmr.unpruned_size(), leaves)
+	};
+	let batch = (0..PROOF_COUNT).map(|_| *rng.choose(&leaves).unwrap()).collect();
+	(ba, size, batch)
+}
+
+#[bench]
+fn bench_inclusion_proofs(b: &mut Bencher) {
+	let (mut ba, size, batch) = mmr_batch();
+	let pmmr = PMMR::at(&mut ba, size);
+	b.iter(|| {
+		for pos in &batch {
+			test::black_box(pmmr.inclusion_proof(*pos));
+		}
+	});
+}
+
+#[bench]
+fn bench_batch_inclusion_proofs(b: &mut Bencher) {
+	let (mut ba, size, batch) = mmr_batch();
+	let pmmr = PMMR::at(&mut ba, size);
+	b.iter(|| {
+		test::black_box(pmmr.batch_inclusion_proofs(&batch));
+	});
+}
+
+// Same as above, for a run of consecutive leaves like the outputs of a
+// block, whose paths mostly overlap.
+fn mmr_run() -> (VecBackend<u64>, u64, Vec<u64>) {
+	let mut ba = VecBackend::new();
+	let (size, leaves) = {
+		let mut pmmr = PMMR::new(&mut ba);
+		let leaves = (0..LEAF_COUNT).map(|n| pmmr.push(n)).collect::<Vec<_>>();
+		(pmmr.unpruned_size(), leaves)
+	};
+	let start = leaves.len() / 2;
+	let run = leaves[start..(start + PROOF_COUNT)].to_vec();
+	(ba, size, run)
+}
+
+#[bench]
+fn bench_inclusion_proofs_run(b: &mut Bencher) {
+	let (mut ba, size, run) = mmr_run();
+	let pmmr = PMMR::at(&mut ba, size);
+	b.iter(|| {
+		for pos in &run {
+			test::black_box(pmmr.inclusion_proof(*pos));
+		}
+	});
+}
+
+#[bench]
+fn bench_batch_inclusion_proofs_run(b: &mut Bencher) {
+	let (mut ba, size, run) = mmr_run();
+	let pmmr = PMMR::at(&mut ba, size);
+	b.iter(|| {
+		test::black_box(pmmr.batch_inclusion_proofs(&run));
+	});
+}
@@ -19,6 +19,7 @@
 //! enough, consensus-relevant constants and short functions should be kept
 //! here.
 
+use std::collections::BTreeMap;
 use std::fmt;
 
 use core::target::Difficulty;
@@ -59,6 +60,26 @@ pub const CUT_THROUGH_HORIZON: u32 = 48 * 3600 / (BLOCK_TIME_SEC as u32);
 /// peer-to-peer networking layer only for DoS protection.
 pub const MAX_MSG_LEN: u64 = 20_000_000;
 
+/// The maximum serialized size of a block. Checked against the number of
+/// inputs, outputs and kernels a block announces before reading them, so a
+/// peer can't make us allocate arbitrary amounts of memory.
+pub const MAX_BLOCK_SERIALIZED_BYTES: usize = 20_000_000;
+
+/// Weight of an input when comparing the cost of transactions and blocks
+pub const INPUT_WEIGHT: u64 = 1;
+
+/// Weight of an output, the heaviest element as it carries a range proof and
+/// grows the UTXO set
+pub const OUTPUT_WEIGHT: u64 = 4;
+
+/// Weight of a kernel
+pub const KERNEL_WEIGHT: u64 = 1;
+
+/// The maximum total weight of a block's inputs, outputs and kernels. Leaves
+/// room for about 20,000 outputs, enough for the maximum serialized size to
+/// be the limit on output-heavy blocks.
+pub const MAX_BLOCK_WEIGHT: u64 = 80_000;
+
 /// The minimum mining difficulty we'll allow
 pub const MINIMUM_DIFFICULTY: u64 = 10;
 
@@ -77,6 +98,38 @@ pub const UPPER_TIME_BOUND: u64 = BLOCK_TIME_WINDOW * 4 / 3;
 /// Minimum size time window used for difficutly adjustments
 pub const LOWER_TIME_BOUND: u64 = BLOCK_TIME_WINDOW * 5 / 6;
 
+/// Block header version in use since genesis.
+pub const HEADER_VERSION: u16 = 1;
+
+/// Consensus parameters that change over the life of the chain, like the
+/// header version that applies from a given height on.
+#[derive(Debug, Clone)]
+pub struct ConsensusParams {
+	/// Header version blocks must have, from the height it's keyed by up to
+	/// the height of the next entry
+	pub header_version_schedule: BTreeMap<u64, u16>,
+}
+
+impl Default for ConsensusParams {
+	fn default() -> ConsensusParams {
+		let mut schedule = BTreeMap::new();
+		schedule.insert(0, HEADER_VERSION);
+		ConsensusParams { header_version_schedule: schedule }
+	}
+}
+
+impl ConsensusParams {
+	/// Header version expected for a block at the provided height.
+	pub fn header_version(&self, height: u64) -> u16 {
+		self.header_version_schedule
+			.iter()
+			.take_while(|&(h, _)| *h <= height)
+			.last()
+			.map(|(_, v)| *v)
+			.unwrap_or(HEADER_VERSION)
+	}
+}
+
 /// Error when computing the next difficulty adjustment.
 #[derive(Debug, Clone)]
 pub struct TargetError(pub String);
@@ -119,7 +172,9 @@ pub fn next_difficulty<T>(cursor: T) -> Result<Difficulty, TargetError>
 		// timestamps within median windows (at ]start;start-11] and ]end;end-11]
 		// to later calculate medians.
 		if m < DIFFICULTY_ADJUST_WINDOW {
-			diff_sum = diff_sum + diff;
+			diff_sum = diff_sum.checked_add(&diff).ok_or_else(|| {
+				TargetError("difficulty sum overflow".to_string())
+			})?;
 
 			if m < MEDIAN_TIME_WINDOW {
 				window_begin.push(ts);
@@ -164,6 +219,9 @@ use std;
 
 #[cfg(test)]
 mod test {
+	use rand::{SeedableRng, XorShiftRng};
+	use rand::distributions::{Exp, IndependentSample};
+
 	use core::target::Difficulty;
 
 	use super::*;
@@ -242,4 +300,48 @@ mod test {
 		           Difficulty::from_num(750));
 	}
 
+	/// Mines 2000 blocks against a simulated network, block times following
+	/// an exponential distribution, and checks the difficulty converges to
+	/// the one giving a 60 seconds block time. Includes a burst of 100 blocks
+	/// where hash power surges and blocks come every 500ms or so.
+	#[test]
+	fn simulate_dag() {
+		let hash_rate = 1_000;
+		let expected_diff = hash_rate * BLOCK_TIME_SEC;
+
+		let mut rng = XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]);
+		let exp = Exp::new(1.0);
+
+		// start with a history at the right difficulty, timestamps in ms
+		let mut blocks = vec![];
+		let mut now = 0;
+		for _ in 0..(DIFFICULTY_ADJUST_WINDOW + MEDIAN_TIME_WINDOW) {
+			blocks.push((now, expected_diff));
+			now += BLOCK_TIME_SEC * 1000;
+		}
+
+		for height in 0..2000 {
+			let diff = next_difficulty(blocks.iter()
+					.rev()
+					.map(|&(ts, diff)| Ok((ts / 1000, Difficulty::from_num(diff)))))
+				.unwrap()
+				.into_num();
+			let rate = if height >= 500 && height < 600 {
+				hash_rate * 120
+			} else {
+				hash_rate
+			};
+			let mean_ms = (diff * 1000 / rate) as f64;
+			now += (exp.ind_sample(&mut rng) * mean_ms) as u64;
+			blocks.push((now, diff));
+		}
+
+		// the difficulty of single blocks is too noisy, averaging over the
+		// last 500 blocks, well past the burst
+		let last = &blocks[blocks.len() - 500..];
+		let avg_diff = last.iter().map(|&(_, diff)| diff).sum::<u64>() / last.len() as u64;
+		assert!(avg_diff > expected_diff * 8 / 10, "difficulty too low: {}", avg_diff);
+		assert!(avg_diff < expected_diff * 12 / 10, "difficulty too high: {}", avg_diff);
+	}
+
 }
@@ -15,15 +15,18 @@
 //! Blocks and blockheaders
 
 use time;
+use rayon;
+use rayon::prelude::*;
 use secp::{self, Secp256k1};
 use secp::key::SecretKey;
+use secp::pedersen::Commitment;
 use std::collections::HashSet;
 
 use core::Committed;
 use core::{Input, Output, Proof, TxKernel, Transaction, COINBASE_KERNEL, COINBASE_OUTPUT};
 use core::transaction::merkle_inputs_outputs;
-use consensus::REWARD;
-use consensus::MINIMUM_DIFFICULTY;
+use consensus::{REWARD, MAX_BLOCK_SERIALIZED_BYTES, HEADER_VERSION, ConsensusParams};
+use consensus::{MINIMUM_DIFFICULTY, MAX_BLOCK_WEIGHT, INPUT_WEIGHT, OUTPUT_WEIGHT, KERNEL_WEIGHT};
 use core::hash::{Hash, Hashed, ZERO_HASH};
 use core::target::Difficulty;
 use ser::{self, Readable, Reader, Writeable, Writer};
@@ -37,17 +40,91 @@ bitflags! {
     }
 }
 
+/// Errors thrown when verifying the range proofs of a block's outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeProofError {
+	/// The range proof of the output with the provided commitment is invalid
+	Invalid(Commitment),
+	/// More than one output failed range proof verification
+	MultipleFailures(Vec<Commitment>),
+}
+
+/// Errors thrown by the full validation of a block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+	/// The block doesn't sum correctly, a signature is invalid or the Merkle
+	/// root doesn't match
+	Secp(secp::Error),
+	/// The block weighs more than allowed
+	WeightExceeded {
+		/// total weight of the block
+		weight: u64,
+		/// maximum weight allowed
+		max: u64,
+	},
+	/// A MMR root committed to by the header doesn't match the one of the
+	/// chain after applying the block
+	MmrRootMismatch {
+		/// name of the mismatching header field
+		field: &'static str,
+	},
+}
+
+impl From<secp::Error> for BlockError {
+	fn from(e: secp::Error) -> BlockError {
+		BlockError::Secp(e)
+	}
+}
+
+// Minimum serialized sizes of the block content: a commitment for inputs,
+// features, commitment and proof length for outputs and features,
+// excess, signature length and fee for kernels.
+const MIN_INPUT_SIZE: u64 = 33;
+const MIN_OUTPUT_SIZE: u64 = 1 + 33 + 8;
+const MIN_KERNEL_SIZE: u64 = 1 + 33 + 8 + 8;
+
+/// Error when a block header doesn't have the version expected at its
+/// height.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionError {
+	/// The header version isn't the one scheduled for the height
+	Unexpected {
+		/// version of the header
+		got: u16,
+		/// version scheduled for the height
+		expected: u16,
+	},
+}
+
+/// Roots of the output, range proof and kernel MMRs of the chain, as they
+/// are after a given block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MmrRoots {
+	/// Root of the MMR of all outputs
+	pub output_root: Hash,
+	/// Root of the MMR of all output range proofs
+	pub rangeproof_root: Hash,
+	/// Root of the MMR of all kernels
+	pub kernel_root: Hash,
+}
+
 /// Block header, fairly standard compared to other blockchains.
 #[derive(Clone, Debug, PartialEq)]
 pub struct BlockHeader {
+	/// Version of the block header, following the consensus schedule
+	pub version: u16,
 	/// Height of this block since the genesis block (height 0)
 	pub height: u64,
 	/// Hash of the block previous to this in the chain.
 	pub previous: Hash,
 	/// Timestamp at which the block was built.
 	pub timestamp: time::Tm,
-	/// Merkle root of the UTXO set
-	pub utxo_merkle: Hash,
+	/// Root of the output MMR of the chain after this block
+	pub output_mmr_root: Hash,
+	/// Root of the range proof MMR of the chain after this block
+	pub rangeproof_mmr_root: Hash,
+	/// Root of the kernel MMR of the chain after this block
+	pub kernel_mmr_root: Hash,
 	/// Merkle tree of hashes for all inputs, outputs and kernels in the block
 	pub tx_merkle: Hash,
 	/// Features specific to this block, allowing possible future extensions
@@ -62,16 +139,63 @@ pub struct BlockHeader {
 	pub total_difficulty: Difficulty,
 }
 
+impl BlockHeader {
+	/// Hash of the header.
+	pub fn hash(&self) -> Hash {
+		Hashed::hash(self)
+	}
+
+	/// Sets the MMR roots.
+	pub fn set_mmr_roots(&mut self, roots: &MmrRoots) {
+		self.output_mmr_root = roots.output_root;
+		self.rangeproof_mmr_root = roots.rangeproof_root;
+		self.kernel_mmr_root = roots.kernel_root;
+	}
+
+	/// Checks the MMR roots the header commits to against the provided ones,
+	/// the roots of the chain MMRs once the block has been applied to them.
+	/// The MMRs depend on the whole chain, so the check can't be part of the
+	/// block validation and is left to the chain.
+	pub fn verify_mmr_roots(&self, roots: &MmrRoots) -> Result<(), BlockError> {
+		if self.output_mmr_root != roots.output_root {
+			return Err(BlockError::MmrRootMismatch { field: "output_mmr_root" });
+		}
+		if self.rangeproof_mmr_root != roots.rangeproof_root {
+			return Err(BlockError::MmrRootMismatch { field: "rangeproof_mmr_root" });
+		}
+		if self.kernel_mmr_root != roots.kernel_root {
+			return Err(BlockError::MmrRootMismatch { field: "kernel_mmr_root" });
+		}
+		Ok(())
+	}
+
+	/// Checks the header version is the one the consensus parameters
+	/// schedule for the provided height.
+	pub fn check_version(&self, height: u64, params: &ConsensusParams) -> Result<(), VersionError> {
+		let expected = params.header_version(height);
+		if self.version != expected {
+			return Err(VersionError::Unexpected {
+				got: self.version,
+				expected: expected,
+			});
+		}
+		Ok(())
+	}
+}
+
 impl Default for BlockHeader {
 	fn default() -> BlockHeader {
 		let proof_size = global::proofsize();
 		BlockHeader {
+			version: HEADER_VERSION,
 			height: 0,
 			previous: ZERO_HASH,
 			timestamp: time::at_utc(time::Timespec { sec: 0, nsec: 0 }),
 			difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
 			total_difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
-			utxo_merkle: ZERO_HASH,
+			output_mmr_root: ZERO_HASH,
+			rangeproof_mmr_root: ZERO_HASH,
+			kernel_mmr_root: ZERO_HASH,
 			tx_merkle: ZERO_HASH,
 			features: DEFAULT_BLOCK,
 			nonce: 0,
@@ -84,10 +208,13 @@ impl Default for BlockHeader {
 impl Writeable for BlockHeader {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		ser_multiwrite!(writer,
+		                [write_u16, self.version],
 		                [write_u64, self.height],
 		                [write_fixed_bytes, &self.previous],
 		                [write_i64, self.timestamp.to_timespec().sec],
-		                [write_fixed_bytes, &self.utxo_merkle],
+		                [write_fixed_bytes, &self.output_mmr_root],
+		                [write_fixed_bytes, &self.rangeproof_mmr_root],
+		                [write_fixed_bytes, &self.kernel_mmr_root],
 		                [write_fixed_bytes, &self.tx_merkle],
 		                [write_u8, self.features.bits()]);
 
@@ -105,10 +232,13 @@ impl Writeable for BlockHeader {
 /// Deserialization of a block header
 impl Readable for BlockHeader {
 	fn read(reader: &mut Reader) -> Result<BlockHeader, ser::Error> {
+		let version = reader.read_u16()?;
 		let height = try!(reader.read_u64());
 		let previous = try!(Hash::read(reader));
 		let timestamp = reader.read_i64()?;
-		let utxo_merkle = try!(Hash::read(reader));
+		let output_mmr_root = Hash::read(reader)?;
+		let rangeproof_mmr_root = Hash::read(reader)?;
+		let kernel_mmr_root = Hash::read(reader)?;
 		let tx_merkle = try!(Hash::read(reader));
 		let (features, nonce) = ser_multiread!(reader, read_u8, read_u64);
 		let difficulty = try!(Difficulty::read(reader));
@@ -116,13 +246,16 @@ impl Readable for BlockHeader {
 		let pow = try!(Proof::read(reader));
 
 		Ok(BlockHeader {
+			version: version,
 			height: height,
 			previous: previous,
 			timestamp: time::at_utc(time::Timespec {
 				sec: timestamp,
 				nsec: 0,
 			}),
-			utxo_merkle: utxo_merkle,
+			output_mmr_root: output_mmr_root,
+			rangeproof_mmr_root: rangeproof_mmr_root,
+			kernel_mmr_root: kernel_mmr_root,
 			tx_merkle: tx_merkle,
 			features: BlockFeatures::from_bits(features).ok_or(ser::Error::CorruptedData)?,
 			pow: pow,
@@ -185,6 +318,15 @@ impl Readable for Block {
 		let (input_len, output_len, proof_len) =
 			ser_multiread!(reader, read_u64, read_u64, read_u64);
 
+		// check the smallest size the announced content could serialize to
+		// before allocating anything for it
+		let min_size = input_len.saturating_mul(MIN_INPUT_SIZE)
+			.saturating_add(output_len.saturating_mul(MIN_OUTPUT_SIZE))
+			.saturating_add(proof_len.saturating_mul(MIN_KERNEL_SIZE));
+		if min_size > MAX_BLOCK_SERIALIZED_BYTES as u64 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+
 		let inputs = try!((0..input_len).map(|_| Input::read(reader)).collect());
 		let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
 		let kernels = try!((0..proof_len).map(|_| TxKernel::read(reader)).collect());
@@ -396,6 +538,36 @@ impl Block {
         Ok(())
 	}
 
+	/// Full validation of a block, checking its weight is under the
+	/// consensus maximum before validating its content and the range proofs
+	/// of its outputs. Signatures and range proofs are verified in parallel,
+	/// on the rayon pool the call is made from (the global one by default).
+	pub fn validate_full(&self, secp: &Secp256k1) -> Result<(), BlockError> {
+		self.verify_weight(MAX_BLOCK_WEIGHT)?;
+		self.validate(secp)?;
+		self.verify_rangeproofs(secp)?;
+		Ok(())
+	}
+
+	/// Total weight of the block content, the sum of the weights of the
+	/// transactions it aggregates.
+	pub fn total_weight(&self) -> u64 {
+		self.inputs.len() as u64 * INPUT_WEIGHT + self.outputs.len() as u64 * OUTPUT_WEIGHT +
+			self.kernels.len() as u64 * KERNEL_WEIGHT
+	}
+
+	/// Checks the block doesn't weigh more than the provided maximum.
+	pub fn verify_weight(&self, max_weight: u64) -> Result<(), BlockError> {
+		let weight = self.total_weight();
+		if weight > max_weight {
+			return Err(BlockError::WeightExceeded {
+				weight: weight,
+				max: max_weight,
+			});
+		}
+		Ok(())
+	}
+
     /// Verify the transaction Merkle root
     pub fn verify_merkle_inputs_outputs(&self) -> Result<(), secp::Error> {
         let tx_merkle = merkle_inputs_outputs(&self.inputs, &self.outputs);
@@ -424,10 +596,48 @@ impl Block {
 		}
 
 		// verify all signatures with the commitment as pk
-		for proof in &self.kernels {
-			proof.verify(secp)?;
+		let failed = self.kernels
+			.par_iter()
+			.map(|proof| proof.verify(secp))
+			.find_any(|res| res.is_err());
+		match failed {
+			Some(Err(e)) => Err(e),
+			_ => Ok(()),
+		}
+	}
+
+	/// Verifies the range proofs of all the block outputs in parallel,
+	/// stopping at the first invalid one found.
+	pub fn verify_rangeproofs(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
+		let failed = self.outputs
+			.par_iter()
+			.map(|out| out.verify_proof(secp))
+			.find_any(|res| res.is_err());
+		match failed {
+			Some(Err(e)) => Err(e),
+			_ => Ok(()),
+		}
+	}
+
+	/// Verifies the range proofs of all the block outputs, spreading the work
+	/// over the threads of the provided pool. All outputs are checked, so
+	/// every invalid proof gets reported instead of only the first one.
+	pub fn verify_rangeproofs_parallel(&self,
+	                                   pool: &rayon::ThreadPool)
+	                                   -> Result<(), RangeProofError> {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let mut failed = pool.install(|| {
+			self.outputs
+				.par_iter()
+				.filter(|out| out.verify_proof(&secp).is_err())
+				.map(|out| out.commitment())
+				.collect::<Vec<_>>()
+		});
+		match failed.len() {
+			0 => Ok(()),
+			1 => Err(RangeProofError::Invalid(failed.pop().unwrap())),
+			_ => Err(RangeProofError::MultipleFailures(failed)),
 		}
-		Ok(())
 	}
 
 	// Validate the coinbase outputs generated by miners. Entails 2 main checks:
@@ -467,8 +677,9 @@ impl Block {
 		let sig = try!(secp.sign(&msg, &skey));
 		let commit = secp.commit(REWARD, skey).unwrap();
 		//let switch_commit = secp.switch_commit(skey).unwrap();
-		let nonce = secp.nonce();
-		let rproof = secp.range_proof(0, REWARD, skey, commit, nonce);
+		// same as regular outputs, the key is used as the proof nonce to allow
+		// rewinding
+		let rproof = secp.range_proof(0, REWARD, skey, commit, skey);
 
 		let output = Output {
 			features: COINBASE_OUTPUT,
@@ -569,6 +780,94 @@ mod test {
 		assert_eq!(b3.outputs.len(), 4);
 	}
 
+	#[test]
+	fn verify_rangeproofs_parallel() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o();
+		let skey = SecretKey::new(secp, &mut rng);
+		let (mut btx2, _) = build::transaction(vec![input_rand(5), output(4, skey), with_fee(1)])
+			.unwrap();
+		let mut btx3 = txspend1i1o(4, skey);
+		let pool = rayon::ThreadPool::new(rayon::Configuration::new().num_threads(2)).unwrap();
+
+		let mut b = new_block(vec![&mut btx1, &mut btx2, &mut btx3], secp);
+		assert_eq!(b.verify_rangeproofs_parallel(&pool), Ok(()));
+
+		// swapping proofs between two outputs breaks both
+		let proof0 = b.outputs[0].proof;
+		b.outputs[0].proof = b.outputs[1].proof;
+		b.outputs[1].proof = proof0;
+		match b.verify_rangeproofs_parallel(&pool) {
+			Err(RangeProofError::MultipleFailures(commits)) => {
+				assert_eq!(commits.len(), 2);
+				assert!(commits.contains(&b.outputs[0].commit));
+				assert!(commits.contains(&b.outputs[1].commit));
+			}
+			r => panic!("expected multiple failures, got {:?}", r),
+		}
+	}
+
+	#[test]
+	fn validate_full_parallel() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o();
+		let skey = SecretKey::new(secp, &mut rng);
+		let (mut btx2, _) = build::transaction(vec![input_rand(5), output(4, skey), with_fee(1)])
+			.unwrap();
+		let mut btx3 = tx2i1o();
+		let mut b = new_block(vec![&mut btx1, &mut btx2, &mut btx3], secp);
+
+		// a single thread validates sequentially
+		let single = rayon::ThreadPool::new(rayon::Configuration::new().num_threads(1)).unwrap();
+		let multi = rayon::ThreadPool::new(rayon::Configuration::new().num_threads(4)).unwrap();
+		let mut results = vec![];
+		for pool in &[single, multi] {
+			let valid = pool.install(|| b.validate_full(secp));
+
+			// swapping proofs between two outputs breaks both
+			let proof0 = b.outputs[0].proof;
+			b.outputs[0].proof = b.outputs[1].proof;
+			b.outputs[1].proof = proof0;
+			let bad_proof = pool.install(|| b.validate_full(secp));
+			b.outputs[1].proof = b.outputs[0].proof;
+			b.outputs[0].proof = proof0;
+
+			// same for signatures between two kernels
+			let sig0 = b.kernels[0].excess_sig.clone();
+			b.kernels[0].excess_sig = b.kernels[1].excess_sig.clone();
+			b.kernels[1].excess_sig = sig0.clone();
+			let bad_sig = pool.install(|| b.validate_full(secp));
+			b.kernels[1].excess_sig = b.kernels[0].excess_sig.clone();
+			b.kernels[0].excess_sig = sig0;
+
+			assert_eq!(valid, Ok(()));
+			match (&bad_proof, &bad_sig) {
+				(&Err(BlockError::Secp(_)), &Err(BlockError::Secp(_))) => {}
+				r => panic!("expected invalid proof and signature, got {:?}", r),
+			}
+			results.push((bad_proof, bad_sig));
+		}
+		assert_eq!(results[0], results[1]);
+	}
+
+	#[test]
+	fn block_weight() {
+		let ref secp = new_secp();
+		let mut btx1 = tx2i1o();
+		let b = new_block(vec![&mut btx1], secp);
+
+		// 2 inputs, tx and coinbase outputs, tx and coinbase kernels
+		assert_eq!(b.total_weight(), 2 + 2 * 4 + 2);
+		assert_eq!(b.validate_full(&secp), Ok(()));
+		assert_eq!(b.verify_weight(12), Ok(()));
+		assert_eq!(b.verify_weight(11),
+		           Err(BlockError::WeightExceeded { weight: 12, max: 11 }));
+	}
+
     #[test]
     fn empty_block_with_coinbase_is_valid() {
         let ref secp = new_secp();
@@ -645,4 +944,59 @@ mod test {
         assert_eq!(b.kernels, b2.kernels);
         assert_eq!(b.header, b2.header);
     }
+
+	#[test]
+	fn deserialize_too_large_block() {
+		let mut vec = ser::ser_vec(&BlockHeader::default()).unwrap();
+		// announcing a million inputs, outputs and kernels
+		for _ in 0..3 {
+			vec.append(&mut ser::ser_vec(&1_000_000u64).unwrap());
+		}
+		match ser::deserialize::<Block>(&mut &vec[..]) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			r => panic!("expected a too large read error, got {:?}", r.map(|b| b.header)),
+		}
+	}
+
+	#[test]
+	fn header_version_schedule() {
+		let mut params = ConsensusParams::default();
+		params.header_version_schedule.insert(100, 2);
+
+		let mut header = BlockHeader::default();
+		assert_eq!(header.check_version(99, &params), Ok(()));
+		assert_eq!(header.check_version(100, &params),
+		           Err(VersionError::Unexpected { got: 1, expected: 2 }));
+
+		header.version = 2;
+		assert_eq!(header.check_version(150, &params), Ok(()));
+		assert_eq!(header.check_version(0, &params),
+		           Err(VersionError::Unexpected { got: 2, expected: 1 }));
+	}
+
+	#[test]
+	fn header_mmr_roots() {
+		let roots = MmrRoots {
+			output_root: Hash([1; 32]),
+			rangeproof_root: Hash([2; 32]),
+			kernel_root: Hash([3; 32]),
+		};
+		let mut header = BlockHeader::default();
+		let h1 = header.hash();
+		assert_eq!(header.verify_mmr_roots(&roots),
+		           Err(BlockError::MmrRootMismatch { field: "output_mmr_root" }));
+
+		header.set_mmr_roots(&roots);
+		assert!(header.hash() != h1);
+		assert_eq!(header.verify_mmr_roots(&roots), Ok(()));
+
+		// the roots survive serialization
+		let vec = ser::ser_vec(&header).unwrap();
+		let header2: BlockHeader = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(header2.verify_mmr_roots(&roots), Ok(()));
+
+		let other = MmrRoots { kernel_root: Hash([4; 32]), ..roots };
+		assert_eq!(header.verify_mmr_roots(&other),
+		           Err(BlockError::MmrRootMismatch { field: "kernel_mmr_root" }));
+	}
 }
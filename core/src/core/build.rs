@@ -28,9 +28,10 @@
 use byteorder::{ByteOrder, BigEndian};
 use secp::{self, Secp256k1};
 use secp::key::SecretKey;
+use secp::pedersen::{Commitment, RangeProof};
 use rand::os::OsRng;
 
-use core::{Transaction, Input, Output, DEFAULT_OUTPUT};
+use core::{Transaction, Input, Output, OutputFeatures, DEFAULT_OUTPUT};
 
 /// Context information available to transaction combinators.
 pub struct Context {
@@ -110,8 +111,9 @@ pub fn input_rand(value: u64) -> Box<Append> {
 pub fn output(value: u64, blinding: SecretKey) -> Box<Append> {
 	Box::new(move |build, (tx, sum)| -> (Transaction, BlindSum) {
 		let commit = build.secp.commit(value, blinding).unwrap();
-		let nonce = build.secp.nonce();
-		let rproof = build.secp.range_proof(0, value, blinding, commit, nonce);
+		// the blinding key doubles as the proof nonce so the owner of the
+		// output can later rewind the proof to recover the value
+		let rproof = build.secp.range_proof(0, value, blinding, commit, blinding);
 		(tx.with_output(Output {
 			features: DEFAULT_OUTPUT,
 			commit: commit,
@@ -139,6 +141,26 @@ pub fn output_rand(value: u64) -> Box<Append> {
 	})
 }
 
+/// Adds an output which commitment and range proof have been built elsewhere
+/// (typically by a hardware signing device) to the transaction being built.
+/// No proof gets generated. As the blinding factor isn't known here, its
+/// contribution to the sum must be provided separately (with with_excess for
+/// example). Wallets add such outputs to a slate with
+/// Slate::add_output_with_prebuilt_proof.
+pub fn output_with_prebuilt_proof(commit: Commitment,
+                                  proof: RangeProof,
+                                  features: OutputFeatures)
+                                  -> Box<Append> {
+	Box::new(move |_build, (tx, sum)| -> (Transaction, BlindSum) {
+		(tx.with_output(Output {
+			features: features,
+			commit: commit,
+			proof: proof,
+		}),
+		 sum)
+	})
+}
+
 /// Sets the fee on the transaction being built.
 pub fn with_fee(fee: u64) -> Box<Append> {
 	Box::new(move |_build, (tx, sum)| -> (Transaction, BlindSum) { (tx.with_fee(fee), sum) })
@@ -159,6 +181,8 @@ pub fn initial_tx(tx: Transaction) -> Box<Append> {
 /// Builds a new transaction by combining all the combinators provided in a
 /// Vector. Transactions can either be built "from scratch" with a list of
 /// inputs or outputs or from a pre-existing transaction that gets added to.
+/// The excess signature nonce is derived deterministically from the excess
+/// and the message by secp (RFC6979), so no nonce ever gets reused.
 ///
 /// Example:
 /// let (tx1, sum) = build::transaction(vec![input_rand(4), output_rand(1),
@@ -211,4 +235,16 @@ mod test {
 			.unwrap();
 		tx.verify_sig(&secp).unwrap();
 	}
+	#[test]
+	fn blind_prebuilt_proof_tx() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let commit = secp.commit(2, key::ONE_KEY).unwrap();
+		let proof = secp.range_proof(0, 2, key::ONE_KEY, commit, key::ONE_KEY);
+		let (tx, _) = transaction(vec![input_rand(6),
+		                               output_with_prebuilt_proof(commit, proof, DEFAULT_OUTPUT),
+		                               with_excess(key::ONE_KEY),
+		                               with_fee(4)])
+			.unwrap();
+		tx.validate(&secp).unwrap();
+	}
 }
@@ -101,14 +101,16 @@ impl fmt::Debug for Proof {
 		write!(f, ")")
 	}
 }
+/// Proofs are compared on their canonical form, the order of the nonces
+/// doesn't matter.
 impl PartialOrd for Proof {
 	fn partial_cmp(&self, other: &Proof) -> Option<Ordering> {
-		self.nonces.partial_cmp(&other.nonces)
+		self.canonical().nonces.partial_cmp(&other.canonical().nonces)
 	}
 }
 impl PartialEq for Proof {
 	fn eq(&self, other: &Proof) -> bool {
-		self.nonces[..] == other.nonces[..]
+		self.canonical().nonces[..] == other.canonical().nonces[..]
 	}
 }
 impl Eq for Proof {}
@@ -157,10 +159,19 @@ impl Proof {
 		self.clone().nonces
 	}
 
+	/// Copy of the proof with its nonces sorted in ascending order. A cycle
+	/// is the same whatever order its edges are listed in, so that's the form
+	/// proofs get compared and hashed in.
+	pub fn canonical(&self) -> Proof {
+		let mut nonces = self.nonces.clone();
+		nonces.sort();
+		Proof::new(nonces)
+	}
+
 	/// Converts the proof to a proof-of-work Target so they can be compared.
-	/// Hashes the Cuckoo Proof data.
+	/// Hashes the canonical Cuckoo Proof data.
 	pub fn to_difficulty(self) -> target::Difficulty {
-		target::Difficulty::from_hash(&self.hash())
+		target::Difficulty::from_hash(&self.canonical().hash())
 	}
 }
 
@@ -307,6 +318,19 @@ mod test {
 		assert!(h != h2);
 	}
 
+	#[test]
+	fn cut_through_violation() {
+		let ref secp = new_secp();
+		let mut tx = tx2i1o();
+		assert_eq!(tx.verify_cut_through(), Ok(()));
+
+		// spending the transaction's own output
+		let spent = tx.outputs[0].commitment();
+		tx.inputs.push(Input(spent));
+		assert_eq!(tx.verify_cut_through(), Err(TxError::CutThroughViolation));
+		assert_eq!(tx.validate(&secp).unwrap_err(), TxError::CutThroughViolation);
+	}
+
 	#[test]
 	fn blind_tx() {
 		let ref secp = new_secp();
@@ -402,6 +426,39 @@ mod test {
 		b.validate(&secp).unwrap();
 	}
 
+	#[test]
+	fn canonical_id_ignores_ordering() {
+		let tx = tx2i1o();
+		let mut reordered = tx.clone();
+		reordered.inputs.reverse();
+
+		assert_eq!(tx.canonical_id(), reordered.canonical_id());
+		assert!(tx.canonical_id() != tx1i1o().canonical_id());
+	}
+
+	#[test]
+	fn proof_canonical() {
+		let proof = Proof::new(vec![3, 1, 2]);
+		let sorted = Proof::new(vec![1, 2, 3]);
+		assert_eq!(proof.canonical().nonces, vec![1, 2, 3]);
+		assert_eq!(proof, sorted);
+		assert_eq!(proof.clone().to_difficulty(), sorted.clone().to_difficulty());
+		assert!(proof != Proof::new(vec![1, 2, 4]));
+	}
+
+	#[test]
+	fn weight_and_fee_rate() {
+		let tx = tx2i1o();
+		assert_eq!(tx.weight(), 7);
+		assert_eq!(tx.fee_rate(), 0);
+
+		let tx = build::transaction(vec![input_rand(50), output_rand(20), with_fee(30)])
+			.map(|(tx, _)| tx)
+			.unwrap();
+		assert_eq!(tx.weight(), 6);
+		assert_eq!(tx.fee_rate(), 5);
+	}
+
 	// utility producing a transaction with 2 inputs and a single outputs
 	pub fn tx2i1o() -> Transaction {
 		build::transaction(vec![input_rand(10), input_rand(11), output_rand(20), with_fee(1)])
@@ -36,12 +36,18 @@
 //! a simple Vec or a database.
 
 use std::clone::Clone;
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
 use std::ops::{self, Deref};
 
 use core::hash::{Hash, Hashed};
-use ser::{self, Readable, Reader, Writeable, Writer};
+use ser::{self, Readable, Reader, Writeable, Writer, VersionedReadable, VersionedWriteable};
+
+/// Magic number starting the compact serialization of a prune list.
+pub const PRUNE_LIST_MAGIC: [u8; 4] = *b"PRNL";
 
 /// Trait for an element of the tree that has a well-defined sum and hash that
 /// the tree can sum over
@@ -53,10 +59,32 @@ pub trait Summable {
 	fn sum(&self) -> Self::Sum;
 
 	/// Length of the Sum type when serialized. Can be used as a hint by
-	/// underlying storages.
+	/// underlying storages. An associated function rather than a constant as
+	/// it's computed by serializing a zero sum for simple summable types (see
+	/// the blanket implementation below), no instance is needed to call it:
+	/// `T::sum_len()`.
 	fn sum_len() -> usize;
 }
 
+/// Any simple type that can be added, serialized and has a zero value (its
+/// default) can be summed over directly, being its own sum. Mostly useful for
+/// integer types and simple wrappers around them.
+impl<T> Summable for T
+	where T: Default + Copy + ops::Add<Output = T> + Readable + Writeable
+{
+	type Sum = T;
+
+	fn sum(&self) -> T {
+		*self
+	}
+
+	fn sum_len() -> usize {
+		ser::ser_vec(&T::default())
+			.expect("serializing a default value should never fail")
+			.len()
+	}
+}
+
 /// An empty sum that takes no space, to store elements that do not need summing
 /// but can still leverage the hierarchical hashing.
 #[derive(Copy, Clone, Debug)]
@@ -81,7 +109,8 @@ impl Writeable for NullSum {
 }
 
 /// Wrapper for a type that allows it to be inserted in a tree without summing
-pub struct NoSum<T>(T);
+#[derive(Clone, Debug)]
+pub struct NoSum<T>(pub T);
 impl<T> Summable for NoSum<T> {
 	type Sum = NullSum;
 	fn sum(&self) -> NullSum {
@@ -92,9 +121,16 @@ impl<T> Summable for NoSum<T> {
 	}
 }
 
+/// Hashed as the wrapped element.
+impl<T> Writeable for NoSum<T> where T: Writeable {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.0.write(writer)
+	}
+}
+
 /// A utility type to handle (Hash, Sum) pairs more conveniently. The addition
 /// of two HashSums is the (Hash(h1|h2), h1 + h2) HashSum.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Readable, Writeable)]
 pub struct HashSum<T> where T: Summable {
 	/// The hash
 	pub hash: Hash,
@@ -115,19 +151,22 @@ impl<T> HashSum<T> where T: Summable + Writeable {
 	}
 }
 
-impl<T> Readable for HashSum<T> where T: Summable {
-	fn read(r: &mut Reader) -> Result<HashSum<T>, ser::Error> {
-		Ok(HashSum {
-			hash: Hash::read(r)?,
-			sum: T::Sum::read(r)?,
-		})
+/// Current version of the versioned HashSum serialization.
+pub const HASHSUM_VERSION: u16 = 1;
+
+impl<T> VersionedWriteable for HashSum<T> where T: Summable {
+	fn write_fields<W: Writer>(&self, _version: u16, w: &mut W) -> Result<(), ser::Error> {
+		// version 1 is the only one so far
+		self.write(w)
 	}
 }
 
-impl<T> Writeable for HashSum<T> where T: Summable {
-	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ser::Error> {
-		self.hash.write(w)?;
-		self.sum.write(w)
+impl<T> VersionedReadable for HashSum<T> where T: Summable {
+	fn read_fields(r: &mut Reader, version: u16) -> Result<HashSum<T>, ser::Error> {
+		if version == 0 {
+			return Err(ser::Error::CorruptedData);
+		}
+		HashSum::read(r)
 	}
 }
 
@@ -154,6 +193,13 @@ pub trait Backend<T> where T: Summable {
 
 	/// Remove HashSums by insertion position
 	fn remove(&mut self, positions: Vec<u64>) -> Result<(), String>;
+
+	/// Rewind the backend state to the provided position, discarding
+	/// everything appended after it.
+	fn rewind(&mut self, position: u64) -> Result<(), String>;
+
+	/// Size of the MMR stored in the backend, as its last position.
+	fn current_size(&self) -> u64;
 }
 
 /// Prunable Merkle Mountain Range implementation. All positions within the tree
@@ -192,49 +238,38 @@ impl<'a, T, B> PMMR<'a, T, B> where T: Summable + Writeable + Debug + Clone, B:
 	}
 
 	/// Computes the root of the MMR. Find all the peaks in the current
-	/// tree and "bags" them to get a single peak.
+	/// tree and "bags" them to get a single peak. Peaks are bagged from left
+	/// to right, each one being added to the right of the bag so far: with
+	/// peaks p1, p2 and p3 the root is (p1 + p2) + p3. Panics on an empty
+	/// MMR.
 	pub fn root(&self) -> HashSum<T> {
-		let peaks_pos = peaks(self.last_pos);
-		let peaks: Vec<Option<HashSum<T>>> = map_vec!(peaks_pos, |&pi| self.backend.get(pi));
-
-		let mut ret = None;
-		for peak in peaks {
-			ret = match (ret, peak) {
-				(None, x) => x,
-				(Some(hsum), None) => Some(hsum),
-				(Some(lhsum), Some(rhsum)) => Some(lhsum + rhsum)
-			}
-		}
-		ret.expect("no root, invalid tree")
+		bag_peaks(&*self.backend, self.last_pos)
+	}
+
+	/// Gets the HashSum at the provided position, if it hasn't been pruned.
+	pub fn get(&self, position: u64) -> Option<HashSum<T>> {
+		self.backend.get(position)
+	}
+
+	/// HashSums of all the peaks of the MMR, from left to right.
+	pub fn peaks(&self) -> Vec<HashSum<T>> {
+		peak_hashsums(&*self.backend, self.last_pos)
+	}
+
+	/// Rewinds the MMR to the provided position, as it was when that
+	/// position was the last one. Everything appended after it is discarded
+	/// by the backend.
+	pub fn rewind(&mut self, position: u64) -> Result<(), String> {
+		self.backend.rewind(position)?;
+		self.last_pos = position;
+		Ok(())
 	}
 
 	/// Push a new Summable element in the MMR. Computes new related peaks at
 	/// the same time if applicable.
 	pub fn push(&mut self, elmt: T) -> u64 {
-		let elmt_pos = self.last_pos + 1;
-		let mut current_hashsum = HashSum::from_summable(elmt_pos, &elmt);
-		let mut to_append = vec![current_hashsum.clone()];
-		let mut height = 0;
-		let mut pos = elmt_pos;
-
-		// we look ahead one position in the MMR, if the expected node has a higher
-		// height it means we have to build a higher peak by summing with a previous
-		// sibling. we do it iteratively in case the new peak itself allows the
-		// creation of another parent.
-		while bintree_postorder_height(pos+1) > height {
-			let left_sibling = bintree_jump_left_sibling(pos);
-			let left_hashsum = self.backend.get(left_sibling)
-				.expect("missing left sibling in tree, should not have been pruned");
-			current_hashsum = left_hashsum + current_hashsum;
-
-			to_append.push(current_hashsum.clone());
-			height += 1;
-			pos += 1;
-		}
-
-		// append all the new nodes and update the MMR index
-		self.backend.append(elmt_pos, to_append);
-		self.last_pos = pos;
+		let (elmt_pos, last_pos) = append_elmt(self.backend, self.last_pos, elmt);
+		self.last_pos = last_pos;
 		elmt_pos
 	}
 
@@ -254,7 +289,7 @@ impl<'a, T, B> PMMR<'a, T, B> where T: Summable + Writeable + Debug + Clone, B:
 		let mut to_prune = vec![];
 		let mut current = position;
 		while current+1 < self.last_pos {
-			let (parent, sibling) = family(current);
+			let (sibling, parent) = family(current);
 			if parent > self.last_pos {
 				// can't prune when our parent isn't here yet
 				break;
@@ -273,6 +308,25 @@ impl<'a, T, B> PMMR<'a, T, B> where T: Summable + Writeable + Debug + Clone, B:
 		self.backend.remove(to_prune);
 	}
 
+	/// Builds a proof that the leaf at the provided position is included in
+	/// the MMR, for light clients to check against its root. None if the
+	/// position isn't a leaf of the MMR or if any node on its path has been
+	/// pruned.
+	pub fn inclusion_proof(&self, position: u64) -> Option<MerkleProof<T>> {
+		let backend = &*self.backend;
+		inclusion_proof(self.last_pos, position, &peaks(self.last_pos), |pos| backend.get(pos))
+	}
+
+	/// Builds the inclusion proofs of all the leaves at the provided
+	/// positions. All the paths are climbed together, level by level, so
+	/// paths joining share the rest of their climb and each node is only
+	/// read once from the backend. Positions without a proof (see
+	/// inclusion_proof) are skipped, each proof carries its leaf position.
+	pub fn batch_inclusion_proofs(&self, positions: &[u64]) -> Vec<MerkleProof<T>> {
+		let backend = &*self.backend;
+		batch_inclusion_proofs(self.last_pos, positions, &peaks(self.last_pos), |pos| backend.get(pos))
+	}
+
 	/// Total size of the tree, including intermediary nodes an ignoring any
 	/// pruning.
 	pub fn unpruned_size(&self) -> u64 {
@@ -280,6 +334,375 @@ impl<'a, T, B> PMMR<'a, T, B> where T: Summable + Writeable + Debug + Clone, B:
 	}
 }
 
+/// Write side of a prunable Merkle Mountain Range, only allowing elements to
+/// be appended. Once done, turn it into a PMMRReader to query the MMR.
+pub struct PMMRBuilder<'a, T, B> where T: Summable, B: 'a + Backend<T> {
+	last_pos: u64,
+	backend: &'a mut B,
+	// only needed for parameterizing Backend
+	summable: PhantomData<T>,
+}
+
+impl<'a, T, B> PMMRBuilder<'a, T, B> where T: Summable + Writeable + Debug + Clone, B: 'a + Backend<T> {
+
+	/// Build a new MMR builder using the provided backend.
+	pub fn new(backend: &'a mut B) -> PMMRBuilder<T, B> {
+		PMMRBuilder::at(backend, 0)
+	}
+
+	/// Build a new MMR builder appending after last_pos in the provided
+	/// backend.
+	pub fn at(backend: &'a mut B, last_pos: u64) -> PMMRBuilder<T, B> {
+		PMMRBuilder {
+			last_pos: last_pos,
+			backend: backend,
+			summable: PhantomData,
+		}
+	}
+
+	/// Append a new Summable element to the MMR, returning its position.
+	pub fn append(&mut self, elmt: T) -> u64 {
+		let (elmt_pos, last_pos) = append_elmt(self.backend, self.last_pos, elmt);
+		self.last_pos = last_pos;
+		elmt_pos
+	}
+
+	/// Done appending, the MMR can now be queried.
+	pub fn into_reader(self) -> PMMRReader<'a, T, B> {
+		PMMRReader::at(self.backend, self.last_pos)
+	}
+}
+
+/// Read side of a prunable Merkle Mountain Range, only allowing queries.
+pub struct PMMRReader<'a, T, B> where T: Summable, B: 'a + Backend<T> {
+	last_pos: u64,
+	backend: &'a B,
+	// only needed for parameterizing Backend
+	summable: PhantomData<T>,
+}
+
+impl<'a, T, B> PMMRReader<'a, T, B> where T: Summable + Writeable + Debug + Clone, B: 'a + Backend<T> {
+
+	/// Build a reader over the MMR of size last_pos held by the provided
+	/// backend.
+	pub fn at(backend: &'a B, last_pos: u64) -> PMMRReader<T, B> {
+		PMMRReader {
+			last_pos: last_pos,
+			backend: backend,
+			summable: PhantomData,
+		}
+	}
+
+	/// Gets the HashSum at the provided position, if it hasn't been pruned.
+	pub fn get(&self, position: u64) -> Option<HashSum<T>> {
+		self.backend.get(position)
+	}
+
+	/// Computes the root of the MMR, bagging all its peaks.
+	pub fn root(&self) -> HashSum<T> {
+		bag_peaks(self.backend, self.last_pos)
+	}
+
+	/// HashSums of all the peaks of the MMR, from left to right.
+	pub fn peaks(&self) -> Vec<HashSum<T>> {
+		peak_hashsums(self.backend, self.last_pos)
+	}
+}
+
+// Appends the element after last_pos, along with the new parents it allows
+// to build. Returns the position of the element and the new last position.
+fn append_elmt<T, B>(backend: &mut B, last_pos: u64, elmt: T) -> (u64, u64)
+	where T: Summable + Writeable, B: Backend<T> {
+
+	let elmt_pos = last_pos + 1;
+	let mut current_hashsum = HashSum::from_summable(elmt_pos, &elmt);
+	let mut to_append = vec![current_hashsum.clone()];
+	let mut height = 0;
+	let mut pos = elmt_pos;
+
+	// we look ahead one position in the MMR, if the expected node has a higher
+	// height it means we have to build a higher peak by summing with a previous
+	// sibling. we do it iteratively in case the new peak itself allows the
+	// creation of another parent.
+	while bintree_postorder_height(pos+1) > height {
+		let left_sibling = bintree_jump_left_sibling(pos);
+		let left_hashsum = backend.get(left_sibling)
+			.expect("missing left sibling in tree, should not have been pruned");
+		current_hashsum = left_hashsum + current_hashsum;
+
+		to_append.push(current_hashsum.clone());
+		height += 1;
+		pos += 1;
+	}
+
+	// append all the new nodes
+	backend.append(elmt_pos, to_append);
+	(elmt_pos, pos)
+}
+
+// HashSums of the peaks of a MMR of size last_pos, skipping pruned ones.
+fn peak_hashsums<T, B>(backend: &B, last_pos: u64) -> Vec<HashSum<T>>
+	where T: Summable, B: Backend<T> {
+
+	peaks(last_pos).iter().filter_map(|&pi| backend.get(pi)).collect()
+}
+
+// Find all the peaks of a MMR of size last_pos and "bags" them to get a
+// single peak, from left to right.
+fn bag_peaks<T, B>(backend: &B, last_pos: u64) -> HashSum<T>
+	where T: Summable, B: Backend<T> {
+
+	let mut ret = None;
+	for peak in peak_hashsums(backend, last_pos) {
+		ret = match ret {
+			None => Some(peak),
+			Some(lhsum) => Some(lhsum + peak),
+		}
+	}
+	ret.expect("no root, invalid tree")
+}
+
+/// Proof that a leaf is included in a MMR of a given size. Holds the
+/// siblings of all the nodes on the path from the leaf to its peak, ordered
+/// from the leaf up, followed by the other peaks of the MMR, from left to
+/// right.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof<T> where T: Summable {
+	/// Siblings on the path to the peak, then the other peaks
+	pub path: Vec<HashSum<T>>,
+	/// Position of the leaf
+	pub leaf_pos: u64,
+	/// Size of the MMR the leaf is included in
+	pub mmr_size: u64,
+}
+
+impl<T> MerkleProof<T> where T: Summable {
+	/// Whether the proof shows that the provided leaf HashSum is included in
+	/// the MMR of the provided root.
+	pub fn verify(&self, root: HashSum<T>, element: HashSum<T>) -> bool {
+		let peak_pos = peaks(self.mmr_size);
+		if peak_pos.is_empty() || self.leaf_pos == 0 || self.leaf_pos > self.mmr_size ||
+			bintree_postorder_height(self.leaf_pos) > 0 {
+			return false;
+		}
+
+		// climb up to the peak, hashing with each sibling in turn
+		let mut path = self.path.iter();
+		let mut current = element;
+		let mut pos = self.leaf_pos;
+		while !peak_pos.contains(&pos) {
+			let (sibling, parent) = family(pos);
+			current = match path.next() {
+				Some(hs) if sibling < pos => hs.clone() + current,
+				Some(hs) => current + hs.clone(),
+				None => return false,
+			};
+			pos = parent;
+		}
+
+		// bag our peak with the others, in order
+		let others = path.cloned().collect::<Vec<_>>();
+		if others.len() + 1 != peak_pos.len() {
+			return false;
+		}
+		let idx = peak_pos.iter().position(|&p| p == pos).unwrap();
+		let mut bagged = None;
+		for (n, peak) in others.into_iter().enumerate() {
+			if n == idx {
+				bagged = bag(bagged, current.clone());
+			}
+			bagged = bag(bagged, peak);
+		}
+		if idx == peak_pos.len() - 1 {
+			bagged = bag(bagged, current);
+		}
+		match bagged {
+			Some(b) => b.hash == root.hash && ser::ser_vec(&b.sum).ok() == ser::ser_vec(&root.sum).ok(),
+			None => false,
+		}
+	}
+}
+
+impl<T> Writeable for MerkleProof<T> where T: Summable {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.leaf_pos)?;
+		writer.write_u64(self.mmr_size)?;
+		writer.write_u64(self.path.len() as u64)?;
+		for hs in &self.path {
+			hs.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl<T> Readable for MerkleProof<T> where T: Summable {
+	fn read(reader: &mut Reader) -> Result<MerkleProof<T>, ser::Error> {
+		let leaf_pos = reader.read_u64()?;
+		let mmr_size = reader.read_u64()?;
+		let len = reader.read_u64()?;
+		// a path can't be longer than the height of the MMR plus its peaks
+		if len > 128 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut path = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			path.push(HashSum::read(reader)?);
+		}
+		Ok(MerkleProof {
+			path: path,
+			leaf_pos: leaf_pos,
+			mmr_size: mmr_size,
+		})
+	}
+}
+
+// Adds the provided HashSum to the right of the accumulated one, if any.
+fn bag<T>(acc: Option<HashSum<T>>, hs: HashSum<T>) -> Option<HashSum<T>>
+	where T: Summable {
+
+	match acc {
+		None => Some(hs),
+		Some(acc) => Some(acc + hs),
+	}
+}
+
+// Builds the inclusion proof of the leaf at position in a MMR of size
+// last_pos, with the provided peak positions. Nodes are obtained through
+// get, typically reading from the backend.
+fn inclusion_proof<T, F>(last_pos: u64, position: u64, peak_pos: &[u64], mut get: F) -> Option<MerkleProof<T>>
+	where T: Summable, F: FnMut(u64) -> Option<HashSum<T>> {
+
+	if peak_pos.is_empty() || position == 0 || position > last_pos ||
+		bintree_postorder_height(position) > 0 {
+		return None;
+	}
+	if let None = get(position) {
+		return None;
+	}
+	let mut path = vec![];
+	let mut pos = position;
+	while !peak_pos.contains(&pos) {
+		let (sibling, parent) = family(pos);
+		match get(sibling) {
+			Some(hs) => path.push(hs),
+			None => return None,
+		}
+		pos = parent;
+	}
+	for &peak in peak_pos.iter().filter(|&&p| p != pos) {
+		match get(peak) {
+			Some(hs) => path.push(hs),
+			None => return None,
+		}
+	}
+	Some(MerkleProof {
+		path: path,
+		leaf_pos: position,
+		mmr_size: last_pos,
+	})
+}
+
+// Builds the inclusion proofs of the leaves at positions in a MMR of size
+// last_pos, with the provided peak positions, climbing all the paths at once.
+// All leaves being at height 0, the nodes reached after each step are all at
+// the same height, so two paths meet as soon as they reach the same parent,
+// from where the proofs they carry go up as one.
+fn batch_inclusion_proofs<T, F>(
+	last_pos: u64,
+	positions: &[u64],
+	peak_pos: &[u64],
+	get: F,
+) -> Vec<MerkleProof<T>>
+	where T: Summable, F: Fn(u64) -> Option<HashSum<T>> {
+
+	if peak_pos.is_empty() {
+		return vec![];
+	}
+
+	// the proofs carried by each node of the current level, by their index
+	// in positions
+	let mut paths: Vec<Option<Vec<HashSum<T>>>> = positions.iter().map(|_| None).collect();
+	let mut level: HashMap<u64, Vec<usize>> = HashMap::new();
+	for (n, &position) in positions.iter().enumerate() {
+		if position == 0 || position > last_pos || bintree_postorder_height(position) > 0 {
+			continue;
+		}
+		paths[n] = Some(vec![]);
+		level.entry(position).or_insert(vec![]).push(n);
+	}
+	let mut missing = level.keys().filter(|&&pos| get(pos).is_none()).cloned().collect::<Vec<_>>();
+	for pos in missing.drain(..) {
+		for n in level.remove(&pos).unwrap() {
+			paths[n] = None;
+		}
+	}
+
+	// climb up to the peaks, reading each sibling once for all the proofs
+	// going through its node
+	let mut at_peaks: HashMap<u64, Vec<usize>> = HashMap::new();
+	while !level.is_empty() {
+		let mut next: HashMap<u64, Vec<usize>> = HashMap::new();
+		for (pos, proofs) in level {
+			if peak_pos.contains(&pos) {
+				at_peaks.entry(pos).or_insert(vec![]).extend(proofs);
+				continue;
+			}
+			let (sibling, parent) = family(pos);
+			match get(sibling) {
+				Some(hs) => {
+					for &n in &proofs {
+						if let Some(ref mut path) = paths[n] {
+							path.push(hs.clone());
+						}
+					}
+					next.entry(parent).or_insert(vec![]).extend(proofs);
+				}
+				None => {
+					for n in proofs {
+						paths[n] = None;
+					}
+				}
+			}
+		}
+		level = next;
+	}
+
+	// then add the other peaks, each read once
+	let peak_hs = peak_pos.iter().map(|&peak| get(peak)).collect::<Vec<_>>();
+	for (pos, proofs) in at_peaks {
+		let others = peak_pos
+			.iter()
+			.zip(peak_hs.iter())
+			.filter(|&(&p, _)| p != pos)
+			.map(|(_, hs)| hs.clone())
+			.collect::<Option<Vec<_>>>();
+		for n in proofs {
+			match others {
+				Some(ref others) => {
+					if let Some(ref mut path) = paths[n] {
+						path.extend(others.iter().cloned());
+					}
+				}
+				None => paths[n] = None,
+			}
+		}
+	}
+
+	paths
+		.into_iter()
+		.zip(positions.iter())
+		.filter_map(|(path, &position)| {
+			path.map(|path| {
+				MerkleProof {
+					path: path,
+					leaf_pos: position,
+					mmr_size: last_pos,
+				}
+			})
+		})
+		.collect()
+}
+
 /// Simple MMR backend implementation based on a Vector. Pruning does not
 /// compact the Vector itself but still frees the reference to the
 /// underlying HashSum.
@@ -302,6 +725,13 @@ impl<T> Backend<T> for VecBackend<T> where T: Summable + Clone {
 		}
 		Ok(())
 	}
+	fn rewind(&mut self, position: u64) -> Result<(), String> {
+		self.elems.truncate(position as usize);
+		Ok(())
+	}
+	fn current_size(&self) -> u64 {
+		self.elems.len() as u64
+	}
 }
 
 impl<T> VecBackend<T> where T: Summable + Clone {
@@ -310,6 +740,12 @@ impl<T> VecBackend<T> where T: Summable + Clone {
 		VecBackend{elems: vec![]}
 	}
 
+	/// Instantiates a new VecBackend<T> with room for capacity elements
+	/// before reallocating.
+	pub fn with_capacity(capacity: usize) -> VecBackend<T> {
+		VecBackend{elems: Vec::with_capacity(capacity)}
+	}
+
 	/// Current number of HashSum elements in the underlying Vec.
 	pub fn used_size(&self) -> usize {
 		let mut usz = self.elems.len();
@@ -321,15 +757,23 @@ impl<T> VecBackend<T> where T: Summable + Clone {
 		usz
 	}
 
-	/// Resets the backend, emptying the underlying Vec.
+	/// Resets the backend, emptying the underlying Vec but keeping its
+	/// capacity.
 	pub fn clear(&mut self) {
-		self.elems = Vec::new();
+		self.elems.clear();
 	}
 
 	/// Total length of the underlying vector.
 	pub fn len(&self) -> usize {
 		self.elems.len()
 	}
+
+	/// Drops the count oldest elements, the following ones moving down to
+	/// the first positions.
+	pub fn drop_oldest(&mut self, count: usize) {
+		let count = cmp::min(count, self.elems.len());
+		self.elems.drain(..count);
+	}
 }
 
 /// Maintains a list of previously pruned nodes in PMMR, compacting the list as
@@ -345,11 +789,97 @@ impl<T> VecBackend<T> where T: Summable + Clone {
 /// the position translation.
 pub struct PruneList {
 	pub pruned_nodes: Vec<u64>,
+	// sizes of the pruned subtrees indexed by the position of their root,
+	// only maintained once built
+	index: Option<FenwickTree>,
+	// sum of the sizes of all pruned subtrees
+	total_shift: u64,
 }
 
 impl PruneList {
 	pub fn new() -> PruneList {
-		PruneList{pruned_nodes: vec![]}
+		PruneList::with_nodes(vec![])
+	}
+
+	/// Prune list with the provided pruned nodes, which need to be ordered.
+	/// The index isn't built.
+	pub fn with_nodes(pruned_nodes: Vec<u64>) -> PruneList {
+		let total_shift = pruned_nodes.iter().map(|n| subtree_size(*n)).sum();
+		PruneList {
+			pruned_nodes: pruned_nodes,
+			index: None,
+			total_shift: total_shift,
+		}
+	}
+
+	/// Builds the index of the pruned subtree sizes, after which shifts are
+	/// computed in logarithmic rather than linear time. The index is kept up
+	/// to date as nodes get added.
+	pub fn build_index(&mut self) {
+		let mut index = FenwickTree::new();
+		for n in &self.pruned_nodes {
+			index.add(*n, subtree_size(*n) as i64);
+		}
+		self.index = Some(index);
+	}
+
+	/// Writes the prune list in its compact form. After a magic number and
+	/// the number of positions, each position is written as its distance from
+	/// the previous one, all as LEB128 varints. Pruned positions are spread
+	/// apart by at most a few subtrees, so most take a byte or two.
+	pub fn write_compact<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+		let mut data = PRUNE_LIST_MAGIC.to_vec();
+		write_varint(&mut data, self.pruned_nodes.len() as u64);
+		let mut prev = 0;
+		for n in &self.pruned_nodes {
+			write_varint(&mut data, n - prev);
+			prev = *n;
+		}
+		writer.write_all(&data)
+	}
+
+	/// Reads a prune list written by write_compact. The index isn't built.
+	pub fn read_compact<R: Read>(reader: &mut R) -> io::Result<PruneList> {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic)?;
+		if magic != PRUNE_LIST_MAGIC {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Not a compact prune list",
+			));
+		}
+		let mut data = vec![];
+		reader.read_to_end(&mut data)?;
+
+		let mut cursor = &data[..];
+		let count = read_varint(&mut cursor)?;
+		// every position takes at least a byte
+		if count > cursor.len() as u64 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Truncated compact prune list",
+			));
+		}
+		let mut nodes = Vec::with_capacity(count as usize);
+		let mut prev = 0;
+		for _ in 0..count {
+			let delta = read_varint(&mut cursor)?;
+			if delta == 0 {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"Unordered compact prune list",
+				));
+			}
+			prev += delta;
+			nodes.push(prev);
+		}
+		if cursor.len() > 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Trailing data in compact prune list",
+			));
+		}
+		Ok(PruneList::with_nodes(nodes))
 	}
 
 	/// Computes by how many positions a node at pos should be shifted given the
@@ -362,31 +892,76 @@ impl PruneList {
 			Some(idx) => {
 				// skip by the number of elements pruned in the preceding subtrees,
 				// which is the sum of the size of each subtree
+				if let Some(ref index) = self.index {
+					return Some(index.prefix_sum(pos - 1));
+				}
 				Some(
 					self.pruned_nodes[0..(idx as usize)]
 						.iter()
-						.map(|n| (1 << (bintree_postorder_height(*n) + 1)) - 1)
+						.map(|n| subtree_size(*n))
 						.sum(),
 				)
 			}
 		}
 	}
 
+	/// Total number of positions pruned, counting every node of the pruned
+	/// subtrees.
+	pub fn total_shift(&self) -> u64 {
+		self.total_shift
+	}
+
+	/// Computes how many positions in the complete subtree rooted at root_pos
+	/// have been pruned. If the subtree is itself entirely pruned, that's its
+	/// full size, which lets callers skip it in one go. Otherwise the pruned
+	/// subtrees it contains are contiguous in the prune list, so their sizes
+	/// are summed without going through the subtree leaves.
+	pub fn subtree_shift(&self, root_pos: u64) -> u64 {
+		let height = bintree_postorder_height(root_pos);
+		let size = (1 << (height + 1)) - 1;
+		if let None = self.pruned_pos(root_pos) {
+			return size;
+		}
+
+		// all positions in the subtree fall within [root_pos - size + 1, root_pos]
+		let first_pos = root_pos + 1 - size;
+		let start = match self.pruned_nodes.binary_search(&first_pos) {
+			Ok(idx) => idx,
+			Err(idx) => idx,
+		};
+		let end = match self.pruned_nodes.binary_search(&root_pos) {
+			Ok(idx) => idx + 1,
+			Err(idx) => idx,
+		};
+		self.pruned_nodes[start..end]
+			.iter()
+			.map(|n| subtree_size(*n))
+			.sum()
+	}
+
 	/// Push the node at the provided position in the prune list. Compacts the
 	/// list if pruning the additional node means a parent can get pruned as
 	/// well.
 	pub fn add(&mut self, pos: u64) {
 		let mut current = pos;
 		loop {
-			let (parent, sibling) = family(current);
+			let (sibling, parent) = family(current);
 			match self.pruned_nodes.binary_search(&sibling) {
 				Ok(idx) => {
 					self.pruned_nodes.remove(idx);
+					self.total_shift -= subtree_size(sibling);
+					if let Some(ref mut index) = self.index {
+						index.add(sibling, -(subtree_size(sibling) as i64));
+					}
 					current = parent;
 				}
 				Err(_) => {
 					if let Err(idx) = self.pruned_nodes.binary_search(&current) {
 						self.pruned_nodes.insert(idx, current);
+						self.total_shift += subtree_size(current);
+						if let Some(ref mut index) = self.index {
+							index.add(current, subtree_size(current) as i64);
+						}
 					}
 					break;
 				}
@@ -408,7 +983,7 @@ impl PruneList {
 					let next_peak_pos = self.pruned_nodes[idx];
 					let mut cursor = pos;
 					loop {
-						let (parent, _) = family(cursor);
+						let (_, parent) = family(cursor);
 						if next_peak_pos == parent {
 							return None;
 						}
@@ -424,6 +999,99 @@ impl PruneList {
 	}
 }
 
+/// Binary indexed tree (or Fenwick tree) over MMR positions, giving the sum
+/// of the values at all positions up to a given one in logarithmic time.
+/// Sparse, only the nodes of the tree holding a non-zero sum are kept, so its
+/// size depends on the number of values added rather than on their positions.
+pub struct FenwickTree {
+	// 1-indexed, positions up to FENWICK_MAX_POS
+	tree: HashMap<u64, u64>,
+}
+
+// Highest position a Fenwick tree covers, well past any MMR size.
+const FENWICK_MAX_POS: u64 = 1 << 48;
+
+impl FenwickTree {
+	/// New empty tree.
+	pub fn new() -> FenwickTree {
+		FenwickTree { tree: HashMap::new() }
+	}
+
+	/// Adds delta, which can be negative, to the value at the provided
+	/// position.
+	pub fn add(&mut self, pos: u64, delta: i64) {
+		let mut idx = pos;
+		while idx > 0 && idx <= FENWICK_MAX_POS {
+			let sum = {
+				let sum = self.tree.entry(idx).or_insert(0);
+				*sum = (*sum as i64 + delta) as u64;
+				*sum
+			};
+			if sum == 0 {
+				self.tree.remove(&idx);
+			}
+			idx += idx & idx.wrapping_neg();
+		}
+	}
+
+	/// Sum of the values at all positions lower or equal to pos.
+	pub fn prefix_sum(&self, pos: u64) -> u64 {
+		let mut idx = cmp::min(pos, FENWICK_MAX_POS);
+		let mut sum = 0;
+		while idx > 0 {
+			sum += self.tree.get(&idx).cloned().unwrap_or(0);
+			idx -= idx & idx.wrapping_neg();
+		}
+		sum
+	}
+}
+
+// Number of nodes in the subtree rooted at the provided position.
+fn subtree_size(pos: u64) -> u64 {
+	(1 << (bintree_postorder_height(pos) + 1)) - 1
+}
+
+// Writes n as a LEB128 varint, 7 bits per byte starting with the lowest, the
+// high bit set on all bytes but the last.
+fn write_varint(data: &mut Vec<u8>, mut n: u64) {
+	while n >= 0x80 {
+		data.push((n as u8 & 0x7f) | 0x80);
+		n >>= 7;
+	}
+	data.push(n as u8);
+}
+
+// Reads a LEB128 varint written by write_varint, advancing the cursor.
+fn read_varint(cursor: &mut &[u8]) -> io::Result<u64> {
+	let mut n = 0;
+	let mut shift = 0;
+	loop {
+		let byte = match cursor.split_first() {
+			Some((byte, rest)) => {
+				*cursor = rest;
+				*byte
+			}
+			None => {
+				return Err(io::Error::new(
+					io::ErrorKind::UnexpectedEof,
+					"Truncated compact prune list",
+				))
+			}
+		};
+		if shift > 63 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Varint overflow in compact prune list",
+			));
+		}
+		n |= ((byte & 0x7f) as u64) << shift;
+		if byte & 0x80 == 0 {
+			return Ok(n);
+		}
+		shift += 7;
+	}
+}
+
 /// Gets the postorder traversal index of all peaks in a MMR given the last
 /// node's position. Starts with the top peak, which is always on the left
 /// side of the range, and navigates toward lower siblings toward the right
@@ -540,8 +1208,8 @@ pub fn bintree_postorder_height(num: u64) -> u64 {
 	most_significant_pos(h) - 1
 }
 
-/// Calculates the positions of the parent and sibling of the node at the
-/// provided position.
+/// Calculates the positions of the sibling and parent of the node at the
+/// provided position, returned in that order.
 pub fn family(pos: u64) -> (u64, u64) {
 	let sibling: u64;
 	let parent: u64;
@@ -555,7 +1223,14 @@ pub fn family(pos: u64) -> (u64, u64) {
 		sibling = bintree_jump_right_sibling(pos);
 		parent = sibling + 1;
 	}
-	(parent, sibling)
+	(sibling, parent)
+}
+
+/// Position in the MMR of the leaf inserted at the provided index, starting
+/// at 1. Allows finding the position of an element from the number of
+/// elements appended before it, without the MMR at hand.
+pub fn insertion_to_pmmr_index(idx: u64) -> u64 {
+	2 * (idx - 1) + 1 - (idx - 1).count_ones() as u64
 }
 
 /// Calculates the position of the top-left child of a parent node in the
@@ -653,6 +1328,34 @@ mod test {
 		}
 	}
 
+	#[test]
+	fn leaves_family() {
+		// all leaf positions in a MMR of 1000 leaves
+		let leaves = (1..2000).filter(|pos| bintree_postorder_height(*pos) == 0)
+			.take(1000)
+			.collect::<Vec<_>>();
+		assert_eq!(leaves.len(), 1000);
+
+		// leaves come in pairs of left and right children
+		for pair in leaves.chunks(2) {
+			let (left, right) = (pair[0], pair[1]);
+			assert_eq!(right, left + 1);
+			assert_eq!(family(left).0, right);
+			assert_eq!(family(right).0, left);
+			assert_eq!(family(left).1, family(right).1);
+			assert_eq!(family(left).1, right + 1);
+		}
+	}
+
+	#[test]
+	fn leaf_insertion_positions() {
+		let leaves = (1..10).map(insertion_to_pmmr_index).collect::<Vec<_>>();
+		assert_eq!(leaves, vec![1, 2, 4, 5, 8, 9, 11, 12, 16]);
+		for pos in leaves {
+			assert_eq!(bintree_postorder_height(pos), 0);
+		}
+	}
+
 	#[test]
 	fn some_peaks() {
 		let empty: Vec<u64> = vec![];
@@ -667,42 +1370,65 @@ mod test {
 		assert_eq!(peaks(42), vec![31, 38, 41, 42]);
 	}
 
-	#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-	struct TestElem([u32; 4]);
-	impl Summable for TestElem {
-		type Sum = u64;
-		fn sum(&self) -> u64 {
-			// sums are not allowed to overflow, so we use this simple
-			// non-injective "sum" function that will still be homomorphic
-			self.0[0] as u64 * 0x1000 + self.0[1] as u64 * 0x100 + self.0[2] as u64 * 0x10 +
-				self.0[3] as u64
-		}
-		fn sum_len() -> usize {
-			8
+	/// Simple summable test payload, relying on the blanket Summable
+	/// implementation.
+	#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+	struct ZeroSum(u64);
+
+	impl ops::Add for ZeroSum {
+		type Output = ZeroSum;
+		fn add(self, other: ZeroSum) -> ZeroSum {
+			ZeroSum(self.0 + other.0)
 		}
 	}
 
-	impl Writeable for TestElem {
+	impl Writeable for ZeroSum {
 		fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
-			try!(writer.write_u32(self.0[0]));
-			try!(writer.write_u32(self.0[1]));
-			try!(writer.write_u32(self.0[2]));
-			writer.write_u32(self.0[3])
+			writer.write_u64(self.0)
 		}
 	}
 
+	impl Readable for ZeroSum {
+		fn read(reader: &mut Reader) -> Result<ZeroSum, ser::Error> {
+			Ok(ZeroSum(reader.read_u64()?))
+		}
+	}
+
+	#[test]
+	fn zero_sum_blanket_summable() {
+		assert_eq!(ZeroSum::sum_len(), 8);
+		assert_eq!(ZeroSum(3).sum(), ZeroSum(3));
+		assert_eq!(u32::sum_len(), 4);
+	}
+
+	#[test]
+	fn hashsum_versioned() {
+		let hs = HashSum::from_summable(3, &ZeroSum(7));
+		let mut data = vec![];
+		hs.write_versioned(HASHSUM_VERSION, &mut data).unwrap();
+		assert_eq!(&data[0..2], &[0, 1]);
+		let (read, version) = ser::versioned_deserialize::<HashSum<ZeroSum>>(&data).unwrap();
+		assert_eq!((read, version), (hs.clone(), HASHSUM_VERSION));
+
+		// a future version with an extra field still reads
+		data[1] = 2;
+		data.extend_from_slice(&[9; 8]);
+		let (read, version) = ser::versioned_deserialize::<HashSum<ZeroSum>>(&data).unwrap();
+		assert_eq!((read, version), (hs, 2));
+	}
+
 	#[test]
 	fn pmmr_push_root() {
 		let elems = [
-			TestElem([0, 0, 0, 1]),
-			TestElem([0, 0, 0, 2]),
-			TestElem([0, 0, 0, 3]),
-			TestElem([0, 0, 0, 4]),
-			TestElem([0, 0, 0, 5]),
-			TestElem([0, 0, 0, 6]),
-			TestElem([0, 0, 0, 7]),
-			TestElem([0, 0, 0, 8]),
-			TestElem([1, 0, 0, 0]),
+			ZeroSum(1),
+			ZeroSum(2),
+			ZeroSum(3),
+			ZeroSum(4),
+			ZeroSum(5),
+			ZeroSum(6),
+			ZeroSum(7),
+			ZeroSum(8),
+			ZeroSum(0x1000),
 		];
 
 		let mut ba = VecBackend::new();
@@ -765,21 +1491,182 @@ mod test {
 		assert_eq!(pmmr.unpruned_size(), 16);
 	}
 
+	#[test]
+	fn pmmr_peaks_bagging() {
+		let elems = (1..8).map(|n| ZeroSum(n)).collect::<Vec<_>>();
+		let mut ba = VecBackend::new();
+		let mut pmmr = PMMR::new(&mut ba);
+		for elem in &elems {
+			pmmr.push(*elem);
+		}
+
+		// 7 leaves make peaks of 4, 2 and 1 leaves, at 7, 10 and 11
+		let leaf = |pos: u64, n: usize| HashSum::from_summable(pos, &elems[n]);
+		let p7 = (leaf(1, 0) + leaf(2, 1)) + (leaf(4, 2) + leaf(5, 3));
+		let p10 = leaf(8, 4) + leaf(9, 5);
+		let p11 = leaf(11, 6);
+		assert_eq!(pmmr.peaks(), vec![p7.clone(), p10.clone(), p11.clone()]);
+		assert_eq!(pmmr.root(), (p7 + p10) + p11);
+		assert_eq!(pmmr.root().sum, ZeroSum(28));
+	}
+
+	#[test]
+	fn pmmr_builder_reader() {
+		let elems = (1..10).map(|n| ZeroSum(n)).collect::<Vec<_>>();
+
+		let mut ba1 = VecBackend::new();
+		let mut pmmr = PMMR::new(&mut ba1);
+		let mut ba2 = VecBackend::new();
+		let mut builder = PMMRBuilder::new(&mut ba2);
+		for elem in &elems {
+			assert_eq!(pmmr.push(*elem), builder.append(*elem));
+		}
+
+		let reader = builder.into_reader();
+		assert_eq!(reader.root(), pmmr.root());
+		assert_eq!(reader.peaks(), pmmr.peaks());
+		assert_eq!(reader.peaks().len(), 2);
+		assert_eq!(reader.get(1), Some(HashSum::from_summable(1, &elems[0])));
+		assert_eq!(reader.get(17), None);
+	}
+
+	#[test]
+	fn pmmr_rewind() {
+		let elems = (1..10).map(|n| ZeroSum(n)).collect::<Vec<_>>();
+
+		let mut ba = VecBackend::new();
+		{
+			let mut pmmr = PMMR::new(&mut ba);
+			for elem in &elems[0..4] {
+				pmmr.push(*elem);
+			}
+			let (size4, root4) = (pmmr.unpruned_size(), pmmr.root());
+			for elem in &elems[4..] {
+				pmmr.push(*elem);
+			}
+			let root9 = pmmr.root();
+
+			pmmr.rewind(size4).unwrap();
+			assert_eq!(pmmr.root(), root4);
+			for elem in &elems[4..] {
+				pmmr.push(*elem);
+			}
+			assert_eq!(pmmr.root(), root9);
+		}
+		assert_eq!(ba.current_size(), 16);
+	}
+
+	#[test]
+	fn pmmr_inclusion_proof() {
+		let elems = (1..8).map(|n| ZeroSum(n)).collect::<Vec<_>>();
+		let leaf_pos = [1, 2, 4, 5, 8, 9, 11];
+		let leaf = |n: usize| HashSum::from_summable(leaf_pos[n], &elems[n]);
+
+		let mut ba = VecBackend::new();
+		let mut pmmr = PMMR::new(&mut ba);
+		for elem in &elems {
+			pmmr.push(*elem);
+		}
+		assert_eq!(pmmr.unpruned_size(), 11);
+		let root = pmmr.root();
+
+		// leaf 4 (the third one) climbs to peak 7 with 5 and 3, then bags with
+		// peaks 10 and 11
+		let proof = pmmr.inclusion_proof(4).unwrap();
+		assert_eq!(proof.leaf_pos, 4);
+		assert_eq!(proof.mmr_size, 11);
+		assert_eq!(
+			proof.path,
+			vec![
+				leaf(3),
+				leaf(0) + leaf(1),
+				leaf(4) + leaf(5),
+				leaf(6),
+			]
+		);
+
+		// the last leaf is a peak on its own
+		let proof = pmmr.inclusion_proof(11).unwrap();
+		assert_eq!(proof.path, vec![pmmr.get(7).unwrap(), pmmr.get(10).unwrap()]);
+
+		for n in 0..7 {
+			let proof = pmmr.inclusion_proof(leaf_pos[n]).unwrap();
+			assert!(proof.verify(root.clone(), leaf(n)));
+			assert!(!proof.verify(root.clone(), leaf((n + 1) % 7)));
+
+			let read: MerkleProof<ZeroSum> = ser::deserialize(&mut &ser::ser_vec(&proof).unwrap()[..]).unwrap();
+			assert_eq!(read, proof);
+		}
+
+		// tampered proofs don't verify
+		let mut proof = pmmr.inclusion_proof(8).unwrap();
+		proof.path.pop();
+		assert!(!proof.verify(root.clone(), leaf(4)));
+		let mut proof = pmmr.inclusion_proof(8).unwrap();
+		proof.leaf_pos = 9;
+		assert!(!proof.verify(root.clone(), leaf(4)));
+
+		// only leaves have proofs
+		assert_eq!(pmmr.inclusion_proof(3), None);
+		assert_eq!(pmmr.inclusion_proof(12), None);
+	}
+
+	#[test]
+	fn pmmr_batch_inclusion_proofs() {
+		let elems = (1..20).map(|n| ZeroSum(n)).collect::<Vec<_>>();
+		let mut ba = VecBackend::new();
+		let mut pmmr = PMMR::new(&mut ba);
+		let leaf_pos = elems.iter().map(|elem| pmmr.push(*elem)).collect::<Vec<_>>();
+		let root = pmmr.root();
+
+		let mut positions = leaf_pos.clone();
+		positions.push(3);
+		let proofs = pmmr.batch_inclusion_proofs(&positions);
+		assert_eq!(proofs.len(), elems.len());
+		for (n, proof) in proofs.iter().enumerate() {
+			assert_eq!(Some(proof.clone()), pmmr.inclusion_proof(leaf_pos[n]));
+			assert!(proof.verify(root.clone(), HashSum::from_summable(leaf_pos[n], &elems[n])));
+		}
+
+		// duplicates get a proof each, leaves whose path got pruned none
+		pmmr.prune(1);
+		let proofs = pmmr.batch_inclusion_proofs(&[1, 2, 4, 4]);
+		let expected = [4, 4].iter().map(|&pos| pmmr.inclusion_proof(pos).unwrap()).collect::<Vec<_>>();
+		assert_eq!(proofs, expected);
+	}
+
+	#[test]
+	fn vec_backend_clear() {
+		let mut ba = VecBackend::with_capacity(16);
+		{
+			let mut pmmr = PMMR::new(&mut ba);
+			for n in 1..9 {
+				pmmr.push(ZeroSum(n));
+			}
+		}
+		assert_eq!(ba.used_size(), 15);
+
+		ba.clear();
+		assert_eq!(ba.used_size(), 0);
+		assert_eq!(ba.len(), 0);
+		assert!(ba.elems.capacity() >= 16);
+	}
+
 	#[test]
 	fn pmmr_prune() {
 		let elems = [
-			TestElem([0, 0, 0, 1]),
-			TestElem([0, 0, 0, 2]),
-			TestElem([0, 0, 0, 3]),
-			TestElem([0, 0, 0, 4]),
-			TestElem([0, 0, 0, 5]),
-			TestElem([0, 0, 0, 6]),
-			TestElem([0, 0, 0, 7]),
-			TestElem([0, 0, 0, 8]),
-			TestElem([1, 0, 0, 0]),
+			ZeroSum(1),
+			ZeroSum(2),
+			ZeroSum(3),
+			ZeroSum(4),
+			ZeroSum(5),
+			ZeroSum(6),
+			ZeroSum(7),
+			ZeroSum(8),
+			ZeroSum(0x1000),
 		];
 
-		let orig_root: HashSum<TestElem>;
+		let orig_root: HashSum<ZeroSum>;
 		let sz: u64;
 		let mut ba = VecBackend::new();
 		{
@@ -887,4 +1774,93 @@ mod test {
 		assert_eq!(pl.get_shift(9), Some(8));
 		assert_eq!(pl.get_shift(17), Some(11));
 	}
+
+	#[test]
+	fn pmmr_prune_list_index() {
+		let mut indexed = PruneList::new();
+		indexed.build_index();
+		let mut pl = PruneList::new();
+		for n in vec![4, 5, 2, 8, 11, 1, 12, 16, 26, 19, 20, 32] {
+			indexed.add(n);
+			pl.add(n);
+			for pos in 1..40 {
+				assert_eq!(indexed.get_shift(pos), pl.get_shift(pos));
+			}
+		}
+
+		// building from existing nodes gets the same shifts
+		let mut rebuilt = PruneList::with_nodes(pl.pruned_nodes.clone());
+		rebuilt.build_index();
+		for pos in 1..40 {
+			assert_eq!(rebuilt.get_shift(pos), pl.get_shift(pos));
+		}
+		assert_eq!(rebuilt.total_shift(), pl.total_shift());
+		assert_eq!(pl.total_shift(), pl.get_shift(40).unwrap());
+
+		// far away positions don't make the index any bigger
+		let mut far = PruneList::new();
+		far.build_index();
+		far.add(1 << 40);
+		assert_eq!(far.get_shift((1 << 40) + 1), Some(1));
+		assert_eq!(far.get_shift(1), Some(0));
+	}
+
+	#[test]
+	fn pmmr_prune_list_compact() {
+		// pruning 2 leaves out of 3, some of which get pruned along with their
+		// sibling as their parent
+		let mut pl = PruneList::new();
+		for n in 1..100_001 {
+			if n % 3 != 0 {
+				pl.add(insertion_to_pmmr_index(n));
+			}
+		}
+		let nodes = pl.pruned_nodes.clone();
+
+		let mut compact = vec![];
+		pl.write_compact(&mut compact).unwrap();
+		let read = PruneList::read_compact(&mut &compact[..]).unwrap();
+		assert_eq!(read.pruned_nodes, nodes);
+
+		let raw = ser::ser_vec(&nodes).unwrap();
+		assert!(compact.len() < raw.len() / 4);
+
+		// the raw form isn't mistaken for the compact one, nor is a truncated
+		// compact form
+		assert!(PruneList::read_compact(&mut &raw[..]).is_err());
+		assert!(PruneList::read_compact(&mut &compact[..compact.len() - 1]).is_err());
+
+		let mut empty = vec![];
+		PruneList::new().write_compact(&mut empty).unwrap();
+		assert!(PruneList::read_compact(&mut &empty[..]).unwrap().pruned_nodes.is_empty());
+	}
+
+	#[test]
+	fn pmmr_prune_list_subtree_shift() {
+		let mut pl = PruneList::new();
+		assert_eq!(pl.subtree_shift(7), 0);
+
+		// a single pruned leaf under 7
+		pl.add(4);
+		assert_eq!(pl.subtree_shift(7), 1);
+		assert_eq!(pl.subtree_shift(3), 0);
+		assert_eq!(pl.subtree_shift(6), 1);
+
+		// pruning the sibling prunes the parent, 6 is now fully pruned
+		pl.add(5);
+		assert_eq!(pl.subtree_shift(6), 3);
+		assert_eq!(pl.subtree_shift(4), 1);
+		assert_eq!(pl.subtree_shift(7), 3);
+
+		// pruned subtrees on both sides of 7
+		pl.add(1);
+		pl.add(8);
+		assert_eq!(pl.subtree_shift(7), 4);
+		assert_eq!(pl.subtree_shift(15), 5);
+
+		// the whole subtree gets pruned
+		pl.add(2);
+		assert_eq!(pl.subtree_shift(7), 7);
+		assert_eq!(pl.subtree_shift(15), 8);
+	}
 }
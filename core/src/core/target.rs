@@ -20,6 +20,7 @@
 //! wrapper in case the internal representation needs to change again
 
 use std::fmt;
+use std::u64;
 use std::ops::{Add, Mul, Div, Sub};
 
 use serde::{Serialize, Serializer, Deserialize, Deserializer, de};
@@ -38,6 +39,11 @@ pub struct Difficulty {
 }
 
 impl Difficulty {
+	/// Maximum total difficulty a chain can claim. Half the range of the
+	/// underlying u64 so adding the difficulty of any block, itself bounded
+	/// by the total, can never overflow.
+	pub const MAX: Difficulty = Difficulty { num: u64::MAX / 2 };
+
 	/// Difficulty of zero, which is practically invalid (not target can be
 	/// calculated from it) but very useful as a start for additions.
 	pub fn zero() -> Difficulty {
@@ -70,6 +76,11 @@ impl Difficulty {
 	pub fn into_num(&self) -> u64 {
 		self.num
 	}
+
+	/// Adds the two difficulties, None if the sum overflows.
+	pub fn checked_add(&self, other: &Difficulty) -> Option<Difficulty> {
+		self.num.checked_add(other.num).map(|num| Difficulty { num: num })
+	}
 }
 
 impl fmt::Display for Difficulty {
@@ -154,3 +165,35 @@ impl<'de> de::Visitor<'de> for DiffVisitor {
 		Ok(Difficulty { num: num_in.unwrap() })
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	proptest! {
+		#[test]
+		/// Accumulating the difficulty of a valid chain, with each block
+		/// total (and so each block difficulty) within the maximum, never
+		/// overflows.
+		fn prop_total_difficulty_no_overflow(
+			diffs in ::proptest::collection::vec(1u64..u64::MAX / 2 + 1, 0..100)) {
+			let mut total = Difficulty::zero();
+			for diff in diffs {
+				let next = total.checked_add(&Difficulty::from_num(diff)).unwrap();
+				if next > Difficulty::MAX {
+					// a block claiming this total would be refused
+					break;
+				}
+				total = next;
+			}
+			assert!(total <= Difficulty::MAX);
+		}
+	}
+
+	#[test]
+	fn checked_add_overflow() {
+		assert_eq!(Difficulty::MAX.checked_add(&Difficulty::MAX),
+		           Some(Difficulty::from_num(u64::MAX - 1)));
+		assert_eq!(Difficulty::from_num(u64::MAX).checked_add(&Difficulty::one()), None);
+	}
+}
@@ -15,12 +15,14 @@
 //! Transactions
 
 use byteorder::{ByteOrder, BigEndian};
+use std::collections::HashSet;
 use secp::{self, Secp256k1, Message, Signature};
 use secp::pedersen::{RangeProof, Commitment};
 
+use consensus::{INPUT_WEIGHT, KERNEL_WEIGHT, OUTPUT_WEIGHT};
 use core::Committed;
 use core::MerkleRow;
-use core::hash::{Hash, Hashed};
+use core::hash::{Hash, Hashed, HashWriter};
 use ser::{self, Reader, Writer, Readable, Writeable};
 
 bitflags! {
@@ -33,11 +35,28 @@ bitflags! {
     }
 }
 
+/// Errors thrown when validating a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxError {
+	/// A commitment is both spent and created by the transaction, which
+	/// should have been cut-through
+	CutThroughViolation,
+	/// The transaction doesn't sum correctly or a proof or signature is
+	/// invalid
+	Secp(secp::Error),
+}
+
+impl From<secp::Error> for TxError {
+	fn from(e: secp::Error) -> TxError {
+		TxError::Secp(e)
+	}
+}
+
 /// A proof that a transaction sums to zero. Includes both the transaction's
 /// Pedersen commitment and the signature, that guarantees that the commitments
 /// amount to zero. The signature signs the fee, which is retained for
 /// signature validation.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Readable, Writeable)]
 pub struct TxKernel {
 	/// Options for a kernel's structure or use
 	pub features: KernelFeatures,
@@ -47,31 +66,21 @@ pub struct TxKernel {
 	pub excess: Commitment,
 	/// The signature proving the excess is a valid public key, which signs
 	/// the transaction fee.
+	#[grin_ser(length_prefix = "u64")]
 	pub excess_sig: Vec<u8>,
 	/// Fee originally included in the transaction this proof is for.
 	pub fee: u64,
 }
 
-impl Writeable for TxKernel {
+impl Writeable for KernelFeatures {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
-		ser_multiwrite!(writer,
-		                [write_u8, self.features.bits()],
-		                [write_fixed_bytes, &self.excess],
-		                [write_bytes, &self.excess_sig],
-		                [write_u64, self.fee]);
-		Ok(())
+		writer.write_u8(self.bits())
 	}
 }
 
-impl Readable for TxKernel {
-	fn read(reader: &mut Reader) -> Result<TxKernel, ser::Error> {
-		Ok(TxKernel {
-			features:
-				KernelFeatures::from_bits(reader.read_u8()?).ok_or(ser::Error::CorruptedData)?,
-			excess: Commitment::read(reader)?,
-			excess_sig: reader.read_vec()?,
-			fee: reader.read_u64()?,
-		})
+impl Readable for KernelFeatures {
+	fn read(reader: &mut Reader) -> Result<KernelFeatures, ser::Error> {
+		KernelFeatures::from_bits(reader.read_u8()?).ok_or(ser::Error::CorruptedData)
 	}
 }
 
@@ -230,14 +239,73 @@ impl Transaction {
 		})
 	}
 
-	/// Validates all relevant parts of a fully built transaction. Checks the
-	/// excess value against the signature as well as range proofs for each
-	/// output.
-	pub fn validate(&self, secp: &Secp256k1) -> Result<TxKernel, secp::Error> {
+	/// Identifier for the transaction that doesn't depend on the ordering of
+	/// its kernels, inputs or outputs. Hashes the sorted kernel hashes
+	/// followed by the sorted input and output commitments.
+	pub fn canonical_id(&self) -> Hash {
+		// the transaction kernel is only represented by its fee and excess
+		// signature at this stage, the excess itself being the commitments sum
+		let mut kernel_hasher = HashWriter::default();
+		kernel_hasher.write_u64(self.fee).unwrap();
+		kernel_hasher.write_bytes(&self.excess_sig).unwrap();
+		let mut kernel_hashes = vec![kernel_hasher.into_hash()];
+		kernel_hashes.sort();
+
+		let mut input_commits = map_vec!(self.inputs, |inp| inp.commitment());
+		input_commits.sort_by(|a, b| a.0[..].cmp(&b.0[..]));
+		let mut output_commits = map_vec!(self.outputs, |out| out.commitment());
+		output_commits.sort_by(|a, b| a.0[..].cmp(&b.0[..]));
+
+		let mut hasher = HashWriter::default();
+		for h in &kernel_hashes {
+			h.write(&mut hasher).unwrap();
+		}
+		for commit in input_commits.iter().chain(output_commits.iter()) {
+			hasher.write_fixed_bytes(commit).unwrap();
+		}
+		hasher.into_hash()
+	}
+
+	/// Weight of the transaction, approximating its cost to the network.
+	/// Outputs weigh the most as they carry a range proof and grow the UTXO
+	/// set. A transaction always has a single kernel at this point.
+	pub fn weight(&self) -> u64 {
+		self.inputs.len() as u64 * INPUT_WEIGHT + self.outputs.len() as u64 * OUTPUT_WEIGHT +
+			KERNEL_WEIGHT
+	}
+
+	/// Fee paid per unit of weight, what miners should prioritize
+	/// transactions by.
+	pub fn fee_rate(&self) -> u64 {
+		self.fee / self.weight()
+	}
+
+	/// Minimum fee the transaction needs to pay to be accepted by a pool
+	/// requiring the provided fee rate.
+	pub fn minimum_fee(&self, min_rate: u64) -> u64 {
+		self.weight() * min_rate
+	}
+
+	/// Checks no commitment is both an input and an output of the
+	/// transaction, as MimbleWimble requires once cut-through is applied.
+	pub fn verify_cut_through(&self) -> Result<(), TxError> {
+		let inputs = self.inputs.iter().map(|inp| inp.commitment()).collect::<HashSet<_>>();
+		let outputs = self.outputs.iter().map(|out| out.commitment()).collect::<HashSet<_>>();
+		if inputs.intersection(&outputs).next().is_some() {
+			return Err(TxError::CutThroughViolation);
+		}
+		Ok(())
+	}
+
+	/// Validates all relevant parts of a fully built transaction. Checks
+	/// cut-through has been applied, the excess value against the signature
+	/// as well as range proofs for each output.
+	pub fn validate(&self, secp: &Secp256k1) -> Result<TxKernel, TxError> {
+		self.verify_cut_through()?;
 		for out in &self.outputs {
 			out.verify_proof(secp)?;
 		}
-		self.verify_sig(secp)
+		Ok(self.verify_sig(secp)?)
 	}
 }
 
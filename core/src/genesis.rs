@@ -17,8 +17,9 @@
 use time;
 
 use core;
-use consensus::MINIMUM_DIFFICULTY;
-use core::hash::Hashed;
+use consensus::{self, MINIMUM_DIFFICULTY};
+use core::hash::{Hash, Hashed, ZERO_HASH};
+use core::pmmr::{NoSum, PMMR, VecBackend};
 use core::target::Difficulty;
 use global;
 
@@ -28,6 +29,7 @@ pub fn genesis() -> core::Block {
 	let proof_size = global::proofsize();
 	core::Block {
 		header: core::BlockHeader {
+			version: consensus::HEADER_VERSION,
 			height: 0,
 			previous: core::hash::Hash([0xff; 32]),
 			timestamp: time::Tm {
@@ -38,7 +40,9 @@ pub fn genesis() -> core::Block {
 			},
 			difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
 			total_difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
-			utxo_merkle: [].hash(),
+			output_mmr_root: ZERO_HASH,
+			rangeproof_mmr_root: ZERO_HASH,
+			kernel_mmr_root: ZERO_HASH,
 			tx_merkle: [].hash(),
 			features: core::DEFAULT_BLOCK,
 			nonce: global::get_genesis_nonce(),
@@ -49,3 +53,35 @@ pub fn genesis() -> core::Block {
 		kernels: vec![],
 	}
 }
+
+/// Builds the output MMR of the genesis block from scratch, appending the
+/// genesis outputs to a fresh in-memory backend, and returns it along with
+/// its root. An empty MMR has a zero root. The persistent backend can't be
+/// used here as the store depends on this crate, the chain checks the MMRs
+/// it builds with it against the genesis header instead.
+pub fn build_genesis_output_pmmr() -> (VecBackend<NoSum<core::Output>>, Hash) {
+	let mut backend = VecBackend::new();
+	let root = {
+		let mut pmmr = PMMR::new(&mut backend);
+		for out in &genesis().outputs {
+			pmmr.push(NoSum(*out));
+		}
+		if pmmr.unpruned_size() == 0 {
+			ZERO_HASH
+		} else {
+			pmmr.root().hash
+		}
+	};
+	(backend, root)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn genesis_output_root() {
+		let (_, root) = build_genesis_output_pmmr();
+		assert_eq!(root, genesis().header.output_mmr_root);
+	}
+}
@@ -26,8 +26,11 @@ extern crate bitflags;
 extern crate blake2_rfc as blake2;
 extern crate byteorder;
 extern crate crypto;
+#[macro_use]
+extern crate grin_derive;
 extern crate num_bigint as bigint;
 extern crate rand;
+extern crate rayon;
 extern crate secp256k1zkp as secp;
 extern crate serde;
 #[macro_use]
@@ -35,6 +38,9 @@ extern crate serde_derive;
 extern crate time;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 #[macro_use]
 pub mod macros;
@@ -20,6 +20,7 @@
 //! `serialize` or `deserialize` functions on them as appropriate.
 
 use std::{error, fmt, cmp};
+use std::marker::PhantomData;
 use std::io::{self, Write, Read};
 use byteorder::{ByteOrder, ReadBytesExt, BigEndian};
 use secp::pedersen::Commitment;
@@ -186,6 +187,43 @@ pub trait Readable
 	fn read(reader: &mut Reader) -> Result<Self, Error>;
 }
 
+/// Trait for types serialized with a version, to be able to add fields
+/// without breaking older nodes. The payload is prefixed with the version
+/// as a u16. Fields are only ever appended from one version to the next,
+/// so older nodes can still read the fields they know about.
+pub trait VersionedWriteable {
+	/// Write the fields of the provided version of the format.
+	fn write_fields<W: Writer>(&self, version: u16, writer: &mut W) -> Result<(), Error>;
+
+	/// Write the version followed by the fields for that version.
+	fn write_versioned<W: Write>(&self, version: u16, writer: &mut W) -> Result<(), Error> {
+		let mut bin_writer = BinWriter { sink: writer };
+		bin_writer.write_u16(version)?;
+		self.write_fields(version, &mut bin_writer)
+	}
+}
+
+/// Trait for types deserialized from a versioned payload, see
+/// VersionedWriteable.
+pub trait VersionedReadable
+	where Self: Sized
+{
+	/// Read the fields of the provided version of the format. Versions more
+	/// recent than the ones known should be read like the latest known one,
+	/// the fields added since are ignored.
+	fn read_fields(reader: &mut Reader, version: u16) -> Result<Self, Error>;
+}
+
+/// Deserializes a VersionedReadable, returning it with the version it was
+/// serialized with.
+pub fn versioned_deserialize<T: VersionedReadable>(buf: &[u8]) -> Result<(T, u16), Error> {
+	let mut source = buf;
+	let mut reader = BinReader { source: &mut source };
+	let version = reader.read_u16()?;
+	let thing = T::read_fields(&mut reader, version)?;
+	Ok((thing, version))
+}
+
 /// Deserializes a Readeable from any std::io::Read implementation.
 pub fn deserialize<T: Readable>(source: &mut Read) -> Result<T, Error> {
 	let mut reader = BinReader { source: source };
@@ -206,6 +244,77 @@ pub fn ser_vec<W: Writeable>(thing: &W) -> Result<Vec<u8>, Error> {
 	Ok(vec)
 }
 
+/// Serializes values one at a time into the wrapped Write, for collections
+/// too large to be serialized in memory first.
+pub struct StreamingSerializer<W: Write> {
+	sink: W,
+}
+
+impl<W: Write> StreamingSerializer<W> {
+	/// New serializer writing to the provided sink.
+	pub fn new(sink: W) -> StreamingSerializer<W> {
+		StreamingSerializer { sink: sink }
+	}
+
+	/// Serializes the next item.
+	pub fn write_item<T: Writeable>(&mut self, item: &T) -> Result<(), Error> {
+		serialize(&mut self.sink, item)
+	}
+
+	/// The underlying sink.
+	pub fn into_inner(self) -> W {
+		self.sink
+	}
+}
+
+/// Deserializes values one at a time from the wrapped Read, until it's
+/// exhausted. Reading stops after the first error, which is returned as the
+/// last item. Running out of data in the middle of a value is an error.
+pub struct StreamingDeserializer<R: Read, T: Readable> {
+	source: R,
+	failed: bool,
+	readable: PhantomData<T>,
+}
+
+impl<R: Read, T: Readable> StreamingDeserializer<R, T> {
+	/// New deserializer reading from the provided source.
+	pub fn new(source: R) -> StreamingDeserializer<R, T> {
+		StreamingDeserializer {
+			source: source,
+			failed: false,
+			readable: PhantomData,
+		}
+	}
+}
+
+impl<R: Read, T: Readable> Iterator for StreamingDeserializer<R, T> {
+	type Item = Result<T, Error>;
+
+	fn next(&mut self) -> Option<Result<T, Error>> {
+		if self.failed {
+			return None;
+		}
+		// peek at the first byte, to tell the end of the data from a
+		// truncated value
+		let mut first = [0; 1];
+		let mut read;
+		loop {
+			read = self.source.read(&mut first);
+			match read {
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+				_ => break,
+			}
+		}
+		let res = match read {
+			Ok(0) => return None,
+			Ok(_) => deserialize(&mut (&first[..]).chain(&mut self.source)),
+			Err(e) => Err(Error::IOErr(e)),
+		};
+		self.failed = res.is_err();
+		Some(res)
+	}
+}
+
 struct BinReader<'a> {
 	source: &'a mut Read,
 }
@@ -273,6 +382,18 @@ impl Readable for Commitment {
 	}
 }
 
+impl Writeable for Commitment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_fixed_bytes(self)
+	}
+}
+
+impl Writeable for RangeProof {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_bytes(self)
+	}
+}
+
 impl Readable for RangeProof {
 	fn read(reader: &mut Reader) -> Result<RangeProof, Error> {
 		let p = try!(reader.read_limited_vec(MAX_PROOF_SIZE));
@@ -481,3 +602,57 @@ impl AsFixedBytes for ::secp::pedersen::Commitment {
 		return PEDERSEN_COMMITMENT_SIZE;
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[derive(Debug, Default, PartialEq, Readable, Writeable)]
+	struct Derived {
+		height: u64,
+		#[grin_ser(skip)]
+		cached: u32,
+		#[grin_ser(length_prefix = "u16")]
+		values: Vec<u32>,
+		flag: u8,
+	}
+
+	#[test]
+	fn derived_ser() {
+		let derived = Derived {
+			height: 12,
+			cached: 5,
+			values: vec![1, 2, 3],
+			flag: 1,
+		};
+		let data = ser_vec(&derived).unwrap();
+		// no cached field, 2 bytes of length and 3 u32 values
+		assert_eq!(data.len(), 8 + 2 + 3 * 4 + 1);
+		assert_eq!(&data[8..10], &[0, 3]);
+
+		let read: Derived = deserialize(&mut &data[..]).unwrap();
+		assert_eq!(read, Derived { cached: 0, ..derived });
+	}
+
+	#[test]
+	fn streaming_ser() {
+		let mut serializer = StreamingSerializer::new(vec![]);
+		for n in 1..4u64 {
+			serializer.write_item(&n).unwrap();
+		}
+		let data = serializer.into_inner();
+		assert_eq!(data, ser_vec(&vec![1u64, 2, 3]).unwrap());
+
+		let read = StreamingDeserializer::<_, u64>::new(&data[..])
+			.collect::<Result<Vec<_>, Error>>()
+			.unwrap();
+		assert_eq!(read, vec![1, 2, 3]);
+
+		// a truncated last value is an error, and the last item
+		let mut items = StreamingDeserializer::<_, u64>::new(&data[..20]);
+		assert_eq!(items.next().unwrap().unwrap(), 1);
+		assert_eq!(items.next().unwrap().unwrap(), 2);
+		assert!(items.next().unwrap().is_err());
+		assert!(items.next().is_none());
+	}
+}
@@ -0,0 +1,186 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Custom derives of the `Readable` and `Writeable` serialization traits of
+//! grin_core, for structs with named fields. Fields are written and read
+//! sequentially, in declaration order. Field attributes:
+//!
+//! * `#[grin_ser(skip)]` leaves the field out, it's set to its default
+//! value when reading.
+//! * `#[grin_ser(length_prefix = "u32")]` writes a `Vec` field as its
+//! length (here a u32, can be any of u8, u16, u32 or u64) followed by its
+//! elements.
+//!
+//! The generated code refers to the `ser` module at the root of the crate,
+//! so these derives are only meant to be used within grin_core.
+
+#![deny(non_upper_case_globals)]
+#![deny(non_camel_case_types)]
+#![deny(non_snake_case)]
+#![deny(unused_mut)]
+#![warn(missing_docs)]
+
+extern crate proc_macro;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+
+// Maximum number of elements read for a length prefixed field, same limit
+// as the ser module byte vectors
+const MAX_PREFIXED_LEN: u64 = 100000;
+
+/// Derives `ser::Writeable`.
+#[proc_macro_derive(Writeable, attributes(grin_ser))]
+pub fn derive_writeable(input: TokenStream) -> TokenStream {
+	let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+	let name = &ast.ident;
+	let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+	let writes = fields(&ast).iter().filter_map(|field| {
+		let ident = &field.ident;
+		match field.ser {
+			FieldSer::Skip => None,
+			FieldSer::Plain => Some(quote! {
+				::ser::Writeable::write(&self.#ident, writer)?;
+			}),
+			FieldSer::LengthPrefix(ref prefix) => {
+				let write_len = syn::Ident::new(format!("write_{}", prefix));
+				let len_ty = syn::Ident::new(prefix.clone());
+				Some(quote! {
+					writer.#write_len(self.#ident.len() as #len_ty)?;
+					for item in &self.#ident {
+						::ser::Writeable::write(item, writer)?;
+					}
+				})
+			}
+		}
+	}).collect::<Vec<_>>();
+
+	let gen = quote! {
+		impl #impl_generics ::ser::Writeable for #name #ty_generics #where_clause {
+			fn write<W: ::ser::Writer>(&self, writer: &mut W) -> Result<(), ::ser::Error> {
+				#(#writes)*
+				Ok(())
+			}
+		}
+	};
+	gen.parse().unwrap()
+}
+
+/// Derives `ser::Readable`.
+#[proc_macro_derive(Readable, attributes(grin_ser))]
+pub fn derive_readable(input: TokenStream) -> TokenStream {
+	let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+	let name = &ast.ident;
+	let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+	let reads = fields(&ast).iter().map(|field| {
+		let ident = &field.ident;
+		match field.ser {
+			FieldSer::Skip => quote! {
+				#ident: Default::default()
+			},
+			FieldSer::Plain => quote! {
+				#ident: ::ser::Readable::read(reader)?
+			},
+			FieldSer::LengthPrefix(ref prefix) => {
+				let read_len = syn::Ident::new(format!("read_{}", prefix));
+				let max_len = MAX_PREFIXED_LEN;
+				quote! {
+					#ident: {
+						let len = reader.#read_len()? as u64;
+						if len > #max_len {
+							return Err(::ser::Error::TooLargeReadErr);
+						}
+						let mut items = Vec::with_capacity(len as usize);
+						for _ in 0..len {
+							items.push(::ser::Readable::read(reader)?);
+						}
+						items
+					}
+				}
+			}
+		}
+	}).collect::<Vec<_>>();
+
+	let gen = quote! {
+		impl #impl_generics ::ser::Readable for #name #ty_generics #where_clause {
+			fn read(reader: &mut ::ser::Reader) -> Result<#name #ty_generics, ::ser::Error> {
+				Ok(#name {
+					#(#reads),*
+				})
+			}
+		}
+	};
+	gen.parse().unwrap()
+}
+
+// How a field gets serialized
+enum FieldSer {
+	Plain,
+	Skip,
+	LengthPrefix(String),
+}
+
+struct Field {
+	ident: syn::Ident,
+	ser: FieldSer,
+}
+
+// The fields of the struct, with how they should be serialized given their
+// grin_ser attributes
+fn fields(ast: &syn::DeriveInput) -> Vec<Field> {
+	let fields = match ast.body {
+		syn::Body::Struct(syn::VariantData::Struct(ref fields)) => fields,
+		_ => panic!("Readable and Writeable can only be derived for structs with named fields"),
+	};
+	fields
+		.iter()
+		.map(|field| {
+			Field {
+				ident: field.ident.clone().unwrap(),
+				ser: field_ser(&field.attrs),
+			}
+		})
+		.collect()
+}
+
+fn field_ser(attrs: &[syn::Attribute]) -> FieldSer {
+	let mut ser = FieldSer::Plain;
+	for attr in attrs {
+		let items = match attr.value {
+			syn::MetaItem::List(ref name, ref items) if name == "grin_ser" => items,
+			_ => continue,
+		};
+		for item in items {
+			ser = match *item {
+				syn::NestedMetaItem::MetaItem(syn::MetaItem::Word(ref word)) if word == "skip" => {
+					FieldSer::Skip
+				}
+				syn::NestedMetaItem::MetaItem(
+					syn::MetaItem::NameValue(ref name, syn::Lit::Str(ref prefix, _)),
+				) if name == "length_prefix" => {
+					match prefix.as_str() {
+						"u8" | "u16" | "u32" | "u64" => FieldSer::LengthPrefix(prefix.clone()),
+						_ => panic!("Unsupported length prefix {}, expected u8, u16, u32 or u64", prefix),
+					}
+				}
+				_ => panic!("Unknown grin_ser attribute, expected skip or length_prefix"),
+			};
+		}
+	}
+	ser
+}
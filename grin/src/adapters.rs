@@ -12,23 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::thread;
+use time;
 
 use chain::{self, ChainAdapter};
-use core::core::{self, Output};
+use core::core::{self, Committed, Output};
 use core::core::block::BlockHeader;
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
-use p2p::{self, NetAdapter, Server, PeerStore, PeerData, State};
+use p2p::{self, NetAdapter, Server, PeerStore, PeerData, ShortId, State};
 use pool;
+use secp;
 use secp::pedersen::Commitment;
 use util::OneTime;
 use store;
 use sync;
 use core::global::{MiningParameterMode,MINING_PARAMETER_MODE};
 
+/// Number of recent blocks we keep the transactions of, to serve the peers
+/// rebuilding our compact blocks.
+const RECENT_BLOCKS: usize = 10;
+
+/// Transactions that went out of the pool with each of the most recent
+/// blocks, latest last.
+pub type RecentBlockTxs = RwLock<VecDeque<(Hash, Vec<core::Transaction>)>>;
+
 /// Implementation of the NetAdapter for the blockchain. Gets notified when new
 /// blocks and transactions are received and forwards to the chain and pool
 /// implementations.
@@ -38,6 +50,9 @@ pub struct NetToChainAdapter {
 	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
 
 	syncer: OneTime<Arc<sync::Syncer>>,
+	checkpoints: Arc<p2p::CheckpointManager>,
+	recent_txs: Arc<RecentBlockTxs>,
+	scorer: Arc<p2p::PeerScorer>,
 }
 
 impl NetAdapter for NetToChainAdapter {
@@ -45,29 +60,42 @@ impl NetAdapter for NetToChainAdapter {
 		self.chain.total_difficulty()
 	}
 
-	fn transaction_received(&self, tx: core::Transaction) {
+	fn transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {
+		let tx_hash = tx.hash();
 		let source = pool::TxSource {
 			debug_name: "p2p".to_string(),
-			identifier: "?.?.?.?".to_string(),
+			identifier: addr.to_string(),
 		};
 		if let Err(e) = self.tx_pool.write().unwrap().add_to_memory_pool(source, tx) {
 			error!("Transaction rejected: {:?}", e);
+			if let pool::PoolError::Invalid = e {
+				self.scorer.misbehaved(addr, p2p::Misbehavior::InvalidTransaction(tx_hash));
+			}
 		}
 	}
 
-	fn block_received(&self, b: core::Block) {
+	fn block_received(&self, b: core::Block, addr: SocketAddr) {
     let bhash = b.hash();
 		debug!("Received block {} from network, going to process.", bhash);
 
+		// blocks the sync asked for are validated by its block download
+		let b = if self.syncer.borrow().syncing() {
+			match self.syncer.borrow().block_received(b) {
+				Some(b) => b,
+				None => return,
+			}
+		} else {
+			b
+		};
+
 		// pushing the new block through the chain pipeline
 		let res = self.chain.process_block(b, self.chain_opts());
 
 		if let Err(e) = res {
 			debug!("Block {} refused by chain: {:?}", bhash, e);
-		}
-
-		if self.syncer.borrow().syncing() {
-			self.syncer.borrow().block_received(bhash);
+			if is_invalid_block(&e) {
+				self.scorer.misbehaved(addr, p2p::Misbehavior::InvalidBlock(bhash));
+			}
 		}
 	}
 
@@ -96,6 +124,10 @@ impl NetAdapter for NetToChainAdapter {
 				}
 			}
 		}
+		if let Err(e) = self.chain.sync_header_chain() {
+			error!("Could not sync the header chain: {:?}", e);
+			return;
+		}
 		info!("Added {} headers to the header chain.", added_hs.len());
 
 		if self.syncer.borrow().syncing() {
@@ -147,6 +179,24 @@ impl NetAdapter for NetToChainAdapter {
 		}
 	}
 
+	/// Pool transactions matching the provided kernel short ids.
+	fn get_transactions(&self, kern_ids: &[ShortId]) -> Vec<Option<core::Transaction>> {
+		let tx_pool = self.tx_pool.read().unwrap();
+		let txs = tx_pool.transactions.values().map(|tx| &**tx);
+		match_short_ids(txs, kern_ids).into_iter().map(|tx| tx.cloned()).collect()
+	}
+
+	/// Transactions of a recent block matching the provided kernel short ids.
+	fn get_block_transactions(&self, h: Hash, kern_ids: &[ShortId]) -> Vec<core::Transaction> {
+		let recent_txs = self.recent_txs.read().unwrap();
+		match recent_txs.iter().find(|&&(ref bh, _)| *bh == h) {
+			Some(&(_, ref txs)) => {
+				match_short_ids(txs.iter(), kern_ids).into_iter().filter_map(|tx| tx.cloned()).collect()
+			}
+			None => vec![],
+		}
+	}
+
 	/// Find good peers we know with the provided capability and return their
 	/// addresses.
 	fn find_peer_addrs(&self, capab: p2p::Capabilities) -> Vec<SocketAddr> {
@@ -155,8 +205,9 @@ impl NetAdapter for NetToChainAdapter {
 		map_vec!(peers, |p| p.addr)
 	}
 
-	/// A list of peers has been received from one of our peers.
-	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>) {
+	/// A list of peers has been received from one of our peers. Untrusted
+	/// ones are only tried once we run out of healthy peers.
+	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>, trusted: bool) {
 		debug!("Received {} peer addrs, saving.", peer_addrs.len());
 		for pa in peer_addrs {
 			if let Ok(e) = self.peer_store.exists_peer(pa) {
@@ -168,7 +219,8 @@ impl NetAdapter for NetToChainAdapter {
 				addr: pa,
 				capabilities: p2p::UNKNOWN,
 				user_agent: "".to_string(),
-				flags: State::Healthy,
+				flags: if trusted { State::Healthy } else { State::Untrusted },
+				last_seen: 0,
 			};
 			if let Err(e) = self.peer_store.save_peer(&peer) {
 				error!("Could not save received peer address: {:?}", e);
@@ -176,6 +228,44 @@ impl NetAdapter for NetToChainAdapter {
 		}
 	}
 
+	/// Kernel hashes of the transactions in our pool.
+	fn pool_kernels(&self) -> Vec<Hash> {
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let tx_pool = self.tx_pool.read().unwrap();
+		tx_pool.transactions.values().filter_map(|tx| p2p::tx_kernel_hash(&secp, tx)).collect()
+	}
+
+	/// Find good peers we know with the provided capability and return their
+	/// addresses along with when we last saw them.
+	fn find_peers(&self, capab: p2p::Capabilities) -> Vec<(SocketAddr, u64)> {
+		let peers = self.peer_store.find_peers(State::Healthy, capab, p2p::MAX_PEERS as usize);
+		debug!("Got {} peers to send.", peers.len());
+		map_vec!(peers, |p| (p.addr, p.last_seen))
+	}
+
+	/// A list of peers with their last seen time has been received from one of
+	/// our peers. New ones get saved, with times in the future capped to now,
+	/// untrusted ones as such.
+	fn peers_received(&self, peers: Vec<(SocketAddr, u64)>, trusted: bool) {
+		debug!("Received {} peers, saving.", peers.len());
+		let now = time::now_utc().to_timespec().sec as u64;
+		for (pa, last_seen) in peers {
+			if let Ok(true) = self.peer_store.exists_peer(pa) {
+				continue;
+			}
+			let peer = PeerData {
+				addr: pa,
+				capabilities: p2p::UNKNOWN,
+				user_agent: "".to_string(),
+				flags: if trusted { State::Healthy } else { State::Untrusted },
+				last_seen: min(last_seen, now),
+			};
+			if let Err(e) = self.peer_store.save_peer(&peer) {
+				error!("Could not save received peer: {:?}", e);
+			}
+		}
+	}
+
 	/// Network successfully connected to a peer.
 	fn peer_connected(&self, pi: &p2p::PeerInfo) {
 		debug!("Saving newly connected peer {}.", pi.addr);
@@ -184,26 +274,68 @@ impl NetAdapter for NetToChainAdapter {
 			capabilities: pi.capabilities,
 			user_agent: pi.user_agent.clone(),
 			flags: State::Healthy,
+			last_seen: time::now_utc().to_timespec().sec as u64,
 		};
 		if let Err(e) = self.peer_store.save_peer(&peer) {
 			error!("Could not save connected peer: {:?}", e);
 		}
 	}
+
+	/// Scores the peer for misbehaving.
+	fn peer_misbehaved(&self, addr: SocketAddr, m: p2p::Misbehavior) {
+		self.scorer.misbehaved(addr, m);
+	}
+
+	/// Whether the peer has been banned for misbehaving.
+	fn is_banned(&self, addr: SocketAddr) -> bool {
+		self.scorer.is_banned(&addr)
+	}
+
+	/// Our checkpoint at the provided height, built from the header at that
+	/// height on our current chain.
+	fn get_checkpoint(&self, height: u64) -> Option<p2p::Checkpoint> {
+		let header = match self.chain.get_header_by_height(height) {
+			Ok(header) => header,
+			Err(_) => return None,
+		};
+		Some(p2p::Checkpoint {
+			height: height,
+			block_hash: header.hash(),
+			utxo_root: header.output_mmr_root,
+			kernel_root: header.kernel_mmr_root,
+		})
+	}
+
+	/// A checkpoint has been received from one of our peers.
+	fn checkpoint_received(&self, cp: p2p::Checkpoint, addr: SocketAddr) {
+		debug!("Received checkpoint at {} from {}.", cp.height, addr);
+		self.checkpoints.checkpoint_received(cp, addr);
+	}
 }
 
 impl NetToChainAdapter {
 	pub fn new(chain_ref: Arc<chain::Chain>,
 	           tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
-	           peer_store: Arc<PeerStore>)
+	           peer_store: Arc<PeerStore>,
+	           recent_txs: Arc<RecentBlockTxs>,
+	           scorer: Arc<p2p::PeerScorer>)
 	           -> NetToChainAdapter {
 		NetToChainAdapter {
 			chain: chain_ref,
 			peer_store: peer_store,
 			tx_pool: tx_pool,
 			syncer: OneTime::new(),
+			checkpoints: Arc::new(p2p::CheckpointManager::new()),
+			recent_txs: recent_txs,
+			scorer: scorer,
 		}
 	}
 
+	/// Checkpoints collected from our peers, to coordinate fast-sync.
+	pub fn checkpoints(&self) -> Arc<p2p::CheckpointManager> {
+		self.checkpoints.clone()
+	}
+
 	/// Start syncing the chain by instantiating and running the Syncer in the
 	/// background (a new thread is created).
 	pub fn start_sync(&self, sync: sync::Syncer) {
@@ -245,16 +377,29 @@ impl NetToChainAdapter {
 pub struct ChainToPoolAndNetAdapter {
 	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
 	p2p: OneTime<Arc<Server>>,
+	recent_txs: Arc<RecentBlockTxs>,
 }
 
 impl ChainAdapter for ChainToPoolAndNetAdapter {
 	fn block_accepted(&self, b: &core::Block) {
 		{
-			if let Err(e) = self.tx_pool.write().unwrap().reconcile_block(b) {
-				error!("Pool could not update itself at block {}: {:?}",
-				       b.hash(),
-				       e);
+			let mut tx_pool = self.tx_pool.write().unwrap();
+			match tx_pool.reconcile_block(b) {
+				Ok(freed) => {
+					let mut recent_txs = self.recent_txs.write().unwrap();
+					if recent_txs.len() >= RECENT_BLOCKS {
+						recent_txs.pop_front();
+					}
+					recent_txs.push_back((b.hash(), freed.into_iter().map(|tx| *tx).collect()));
+				}
+				Err(e) => {
+					error!("Pool could not update itself at block {}: {:?}",
+					       b.hash(),
+					       e);
+				}
 			}
+			let ttl_secs = tx_pool.config.tx_ttl_secs;
+			tx_pool.evict_expired(time::now_utc().to_timespec().sec as u64, ttl_secs);
 		}
 		self.p2p.borrow().broadcast_block(b);
 	}
@@ -266,11 +411,49 @@ impl ChainToPoolAndNetAdapter {
 		ChainToPoolAndNetAdapter {
 			tx_pool: tx_pool,
 			p2p: OneTime::new(),
+			recent_txs: Arc::new(RwLock::new(VecDeque::new())),
 		}
 	}
 	pub fn init(&self, p2p: Arc<Server>) {
 		self.p2p.init(p2p);
 	}
+
+	/// Transactions of the most recent blocks, to serve compact blocks from.
+	pub fn recent_txs(&self) -> Arc<RecentBlockTxs> {
+		self.recent_txs.clone()
+	}
+}
+
+/// Whether the chain refused a block because it's invalid, rather than
+/// because it doesn't fit our chain or we couldn't process it.
+fn is_invalid_block(e: &chain::Error) -> bool {
+	match *e {
+		chain::Error::DifficultyTooLow |
+		chain::Error::WrongTotalDifficulty |
+		chain::Error::DifficultyOverflow |
+		chain::Error::InvalidPow |
+		chain::Error::InvalidBlockProof(_) |
+		chain::Error::BlockWeightExceeded(_) |
+		chain::Error::InvalidBlockTime |
+		chain::Error::InvalidBlockHeight |
+		chain::Error::InvalidBlockVersion(_) |
+		chain::Error::CheckpointMismatch(_) |
+		chain::Error::MmrRootMismatch(_) => true,
+		_ => false,
+	}
+}
+
+/// Finds the transactions matching each of the provided kernel short ids,
+/// in the same order.
+fn match_short_ids<'a, I>(txs: I, kern_ids: &[ShortId]) -> Vec<Option<&'a core::Transaction>>
+	where I: Iterator<Item = &'a core::Transaction>
+{
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let by_id = txs.filter_map(|tx| {
+			tx.sum_commitments(&secp).ok().map(|excess| (ShortId::from_excess(&excess), tx))
+		})
+		.collect::<HashMap<_, _>>();
+	kern_ids.iter().map(|id| by_id.get(id).cloned()).collect()
 }
 
 /// Implements the view of the blockchain required by the TransactionPool to
@@ -49,8 +49,9 @@ use pow::plugin::PluginMiner;
 use itertools::Itertools;
 
 
-// Max number of transactions this miner will assemble in a block
-const MAX_TX: u32 = 5000;
+// Weight of the block coinbase, a single output and its kernel, which the
+// pool transactions can't take up
+const COINBASE_WEIGHT: u64 = consensus::OUTPUT_WEIGHT + consensus::KERNEL_WEIGHT;
 
 const PRE_NONCE_SIZE: usize = 113;
 
@@ -191,7 +192,7 @@ impl Miner {
 		while head.hash() == *latest_hash && time::get_time().sec < deadline {
 			if let Some(s) = job_handle.get_solution()  {
 				sol = Some(Proof::new(s.solution_nonces.to_vec()));
-				b.header.nonce=s.get_nonce_as_u64();
+				b.header.nonce = s.get_nonce_as_u64();
 				println!("Nonce: {}", b.header.nonce);
 				break;
 			}
@@ -267,7 +268,8 @@ impl Miner {
 					break;
 				}
 			}
-			b.header.nonce += 1;
+			let nonce = b.header.nonce + 1;
+			b.header.nonce = nonce;
 			*latest_hash = self.chain.head().unwrap().last_block_h;
 			iter_count += 1;
 
@@ -381,7 +383,8 @@ impl Miner {
 		let diff_iter = self.chain.difficulty_iter();
 		let difficulty = consensus::next_difficulty(diff_iter).unwrap();
 
-		let txs_box = self.tx_pool.read().unwrap().prepare_mineable_transactions(MAX_TX);
+		let max_weight = consensus::MAX_BLOCK_WEIGHT - COINBASE_WEIGHT;
+		let txs_box = self.tx_pool.read().unwrap().select_for_block(max_weight);
 		let txs = txs_box.iter().map(|tx| tx.as_ref()).collect();
 		let (output, kernel) = coinbase;
 		let mut b = core::Block::with_reward(head, txs, output, kernel).unwrap();
@@ -393,7 +396,8 @@ impl Miner {
 
 		// making sure we're not spending time mining a useless block
 		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-		b.validate(&secp).expect("Built an invalid block!");
+		b.validate_full(&secp).expect("Built an invalid block!");
+		self.chain.set_mmr_roots(&mut b).expect("Could not set the block MMR roots!");
 
 		let mut rng = rand::OsRng::new().unwrap();
 		b.header.nonce = rng.gen();
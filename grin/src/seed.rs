@@ -36,15 +36,23 @@ const PEER_MAX_COUNT: u32 = 25;
 const PEER_PREFERRED_COUNT: u32 = 8;
 const SEEDS_URL: &'static str = "http://www.mimwim.org/seeds.txt";
 
+/// Minimum time between two requests for more peers to our peers, so we
+/// don't flood them.
+const GET_PEERS_INTERVAL_SECS: u64 = 30;
+
 pub struct Seeder {
 	peer_store: Arc<p2p::PeerStore>,
 	p2p: Arc<p2p::Server>,
 
 	capabilities: p2p::Capabilities,
+	min_peers: u32,
+	ban_duration_secs: i64,
 }
 
 impl Seeder {
 	pub fn new(capabilities: p2p::Capabilities,
+	           min_peers: u32,
+	           ban_duration_secs: i64,
 	           peer_store: Arc<p2p::PeerStore>,
 	           p2p: Arc<p2p::Server>)
 	           -> Seeder {
@@ -52,6 +60,8 @@ impl Seeder {
 			peer_store: peer_store,
 			p2p: p2p,
 			capabilities: capabilities,
+			min_peers: min_peers,
+			ban_duration_secs: ban_duration_secs,
 		}
 	}
 
@@ -78,6 +88,12 @@ impl Seeder {
 	                 -> Box<Future<Item = (), Error = String>> {
 		let peer_store = self.peer_store.clone();
 		let p2p_server = self.p2p.clone();
+		let capab = self.capabilities;
+		let min_peers = self.min_peers;
+		let ban_duration_secs = self.ban_duration_secs;
+		let get_peers_interval = time::Duration::from_secs(GET_PEERS_INTERVAL_SECS);
+		// never asked for peers yet
+		let mut last_get_peers: Option<time::Instant> = None;
 
 		// now spawn a new future to regularly check if we need to acquire more peers
 		// and if so, gets them from db
@@ -91,14 +107,26 @@ impl Seeder {
 				for p in disconnected {
 					if p.is_banned() {
 						debug!("Marking peer {} as banned.", p.info.addr);
-						let update_result = peer_store.update_state(
-							p.info.addr, p2p::State::Banned);
+						let reason = p.ban_reason().unwrap_or(p2p::BanReason::TooManyErrors);
+						let update_result =
+							peer_store.ban_peer_for(p.info.addr, reason, ban_duration_secs);
 						match update_result {
 							Ok(()) => {}
 							Err(_) => {}
 						}
+						// the ban covers the whole IP, other connections from it go too
+						p.stop();
+						for other in p2p_server.connected_peers() {
+							if other.info.addr.ip() == p.info.addr.ip() {
+								other.stop();
+							}
+						}
 					}
 				}
+				// bans that expired get lifted, their peers can be tried again
+				if let Err(e) = peer_store.purge_expired_bans() {
+					error!("Could not lift expired bans: {:?}", e);
+				}
 
 				// we don't have enough peers, getting more from db
 				if p2p_server.peer_count() < PEER_PREFERRED_COUNT {
@@ -106,6 +134,14 @@ impl Seeder {
 					                                      p2p::UNKNOWN,
 					                                      (2 * PEER_MAX_COUNT) as usize);
 					peers.retain(|p| !p2p_server.is_known(p.addr));
+					// out of healthy peers, try the ones we only heard of through
+					// unsigned addresses
+					if peers.len() == 0 {
+						peers = peer_store.find_peers(p2p::State::Untrusted,
+						                              p2p::UNKNOWN,
+						                              (2 * PEER_MAX_COUNT) as usize);
+						peers.retain(|p| !p2p_server.is_known(p.addr));
+					}
 					if peers.len() > 0 {
 						debug!("Got {} more peers from db, trying to connect.", peers.len());
 						thread_rng().shuffle(&mut peers[..]);
@@ -115,6 +151,17 @@ impl Seeder {
 						}
 					}
 				}
+
+				// running low on peers, ask one of ours for more, but not too often
+				if p2p_server.peer_count() < min_peers &&
+				   last_get_peers.map(|t| t.elapsed() >= get_peers_interval).unwrap_or(true) {
+					if let Some(p) = p2p_server.random_peer() {
+						last_get_peers = Some(time::Instant::now());
+						if let Err(e) = p.send_get_peers(capab) {
+							debug!("Could not ask {} for peers: {:?}", p.info.addr, e);
+						}
+					}
+				}
 				Ok(())
 			})
 			.map_err(|e| e.to_string());
@@ -224,12 +271,43 @@ pub fn web_seeds(h: reactor::Handle) -> Box<Future<Item = Vec<SocketAddr>, Error
 	Box::new(seeds)
 }
 
+/// Resolves our DNS seeds, saving the addresses found in the peer store so we
+/// can come back to them later. Lookups run in their own thread so they don't
+/// block the event loop.
+pub fn dns_seeds(resolver: Arc<p2p::DnsSeedResolver>,
+                 peer_store: Arc<p2p::PeerStore>)
+                 -> Box<Future<Item = Vec<SocketAddr>, Error = String>> {
+	let thread_pool = cpupool::CpuPool::new(1);
+	let seeds = thread_pool.spawn_fn(move || {
+		let addrs = resolver.resolve();
+		for addr in &addrs {
+			if let Ok(true) = peer_store.exists_peer(*addr) {
+				continue;
+			}
+			let peer = p2p::PeerData {
+				addr: *addr,
+				capabilities: p2p::UNKNOWN,
+				user_agent: "".to_string(),
+				flags: p2p::State::Healthy,
+				last_seen: ::time::now_utc().to_timespec().sec as u64,
+			};
+			if let Err(e) = peer_store.save_peer(&peer) {
+				error!("Could not save DNS seed peer address: {:?}", e);
+			}
+		}
+		Ok(addrs)
+	});
+	Box::new(seeds)
+}
+
 /// Convenience function when the seed list is immediately known. Mostly used
 /// for tests.
 pub fn predefined_seeds(addrs_str: Vec<String>)
                         -> Box<Future<Item = Vec<SocketAddr>, Error = String>> {
 	let seeds = future::ok(())
-		.and_then(move |_| Ok(addrs_str.iter().map(|s| s.parse().unwrap()).collect::<Vec<_>>()));
+		.and_then(move |_| {
+			Ok(addrs_str.iter().map(|s| p2p::parse_peer_addr(s).unwrap()).collect::<Vec<_>>())
+		});
 	Box::new(seeds)
 }
 
@@ -16,6 +16,7 @@
 //! the peer-to-peer server, the blockchain and the transaction pool) and acts
 //! as a facade.
 
+use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::thread;
@@ -38,6 +39,13 @@ use pow;
 
 use core::global;
 
+/// How long we give our peers, all combined, to receive our disconnect
+/// message when shutting down.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+
+/// File, under the db root, the transaction pool is dumped to on shutdown.
+const POOL_DUMP_FILE: &'static str = "pool.bin";
+
 /// Grin server holding internal structures.
 pub struct Server {
 	/// server config
@@ -79,7 +87,8 @@ impl Server {
 	pub fn future(mut config: ServerConfig, evt_handle: &reactor::Handle) -> Result<Server, Error> {
 
 		let pool_adapter = Arc::new(PoolToChainAdapter::new());
-		let tx_pool = Arc::new(RwLock::new(pool::TransactionPool::new(pool_adapter.clone())));
+		let tx_pool = Arc::new(RwLock::new(pool::TransactionPool::new(config.pool_config.clone().unwrap_or_default(),
+		                                                                   pool_adapter.clone())));
 
 		let chain_adapter = Arc::new(ChainToPoolAndNetAdapter::new(tx_pool.clone()));
 
@@ -88,22 +97,53 @@ impl Server {
 			genesis_block=pow::mine_genesis_block(config.mining_config.clone());
 		}
 
+		let checkpoints = if config.disable_checkpoints.unwrap_or(false) {
+			chain::Checkpoints::none()
+		} else {
+			chain::Checkpoints::hardcoded()
+		};
 		let shared_chain = Arc::new(chain::Chain::init(config.db_root.clone(),
 		                                               chain_adapter.clone(),
 		                                               genesis_block,
-		                                               pow::verify_size)?);
+		                                               pow::verify_size,
+		                                               config.chain_config.clone().unwrap_or_default(),
+		                                               config.sync_mode.unwrap_or(chain::SyncMode::Full),
+		                                               checkpoints)?);
 			
 		pool_adapter.set_chain(shared_chain.clone());
 
+		// bring back the transactions that were in the pool when we stopped
+		let pool_dump = format!("{}/{}", config.db_root, POOL_DUMP_FILE);
+		match tx_pool.write().unwrap().restore(&pool_dump) {
+			Ok(n) => info!("Restored {} transactions in the pool.", n),
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+			Err(e) => warn!("Could not restore the pool from {}: {}", pool_dump, e),
+		}
+
+		let p2p_config = config.p2p_config.unwrap();
 		let peer_store = Arc::new(p2p::PeerStore::new(config.db_root.clone())?);
+		match peer_store.purge_expired_bans() {
+			Ok(bans) => info!("Loaded {} active peer bans.", bans.len()),
+			Err(e) => warn!("Could not purge expired peer bans: {:?}", e),
+		}
+		let scorer = Arc::new(p2p::PeerScorer::new(peer_store.clone(), &p2p_config));
 		let net_adapter = Arc::new(NetToChainAdapter::new(shared_chain.clone(),
 		                                                  tx_pool.clone(),
-		                                                  peer_store.clone()));
-		let p2p_server =
-			Arc::new(p2p::Server::new(config.capabilities, config.p2p_config.unwrap(), net_adapter.clone()));
+		                                                  peer_store.clone(),
+		                                                  chain_adapter.recent_txs(),
+		                                                  scorer));
+		let addr_key = peer_store.addr_key()?;
+		let p2p_server = Arc::new(p2p::Server::with_addr_key(config.capabilities,
+		                                                     p2p_config,
+		                                                     net_adapter.clone(),
+		                                                     addr_key));
 		chain_adapter.init(p2p_server.clone());
 
-		let seed = seed::Seeder::new(config.capabilities, peer_store.clone(), p2p_server.clone());
+		let seed = seed::Seeder::new(config.capabilities,
+		                             p2p_config.min_peers,
+		                             p2p_config.ban_duration_secs,
+		                             peer_store.clone(),
+		                             p2p_server.clone());
 		match config.seeding_type.clone() {
 			Seeding::None => {}
 			Seeding::List => {
@@ -112,9 +152,18 @@ impl Server {
 			Seeding::WebStatic => {
 				seed.connect_and_monitor(evt_handle.clone(), seed::web_seeds(evt_handle.clone()));
 			}
+			Seeding::DNS => {
+				let ttl_secs = config.dns_seed_ttl_secs.unwrap_or(p2p::DEFAULT_DNS_SEED_TTL_SECS);
+				let resolver = p2p::DnsSeedResolver::new(config.dns_seeds.clone().unwrap_or(vec![]),
+				                                         time::Duration::from_secs(ttl_secs));
+				seed.connect_and_monitor(evt_handle.clone(),
+				                         seed::dns_seeds(Arc::new(resolver), peer_store.clone()));
+			}
 		}
 
-		let sync = sync::Syncer::new(shared_chain.clone(), p2p_server.clone());
+		let sync = sync::Syncer::new(shared_chain.clone(),
+		                             p2p_server.clone(),
+		                             net_adapter.checkpoints());
 		net_adapter.start_sync(sync);
 
 		evt_handle.spawn(p2p_server.start(evt_handle.clone()).map_err(|_| ()));
@@ -123,7 +172,8 @@ impl Server {
 
 		api::start_rest_apis(config.api_http_addr.clone(),
 		                     shared_chain.clone(),
-		                     tx_pool.clone());
+		                     tx_pool.clone(),
+		                     p2p_server.clone());
 
 		warn!("Grin server started.");
 		Ok(Server {
@@ -142,6 +192,17 @@ impl Server {
 		Ok(())
 	}
 
+	/// Stops the server, letting our peers know we're going away before
+	/// closing all connections.
+	pub fn stop(&self) {
+		self.p2p.graceful_shutdown(time::Duration::from_secs(SHUTDOWN_TIMEOUT_SECS));
+
+		let pool_dump = format!("{}/{}", self.config.db_root, POOL_DUMP_FILE);
+		if let Err(e) = self.tx_pool.read().unwrap().dump(&pool_dump) {
+			error!("Could not dump the pool to {}: {}", pool_dump, e);
+		}
+	}
+
 	/// Number of peers
 	pub fn peer_count(&self) -> u32 {
 		self.p2p.peer_count()
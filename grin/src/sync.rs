@@ -15,39 +15,129 @@
 //! Synchronization of the local blockchain with the rest of the network. Used
 //! either on a brand new node or when a node is late based on others' heads.
 //! Always starts by downloading the header chain before asking either for full
-//! blocks or a full UTXO set with related information.
+//! blocks or a full UTXO set with related information, unless only syncing
+//! headers.
 
-/// How many block bodies to download in parallel
-const MAX_BODY_DOWNLOADS: usize = 8;
+/// How long to wait for a requested block before asking another peer
+const BLOCK_REQUEST_TIMEOUT_SECS: u64 = 20;
+/// How many peers to ask for a block before giving up on it
+const MAX_BLOCK_REQUESTS: usize = 3;
+/// How long to wait for our peers to agree on a checkpoint
+const CHECKPOINT_WAIT_SECS: u64 = 10;
 
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::{Instant, Duration};
 
+use core::core;
 use core::core::hash::{Hash, Hashed};
 use chain;
+use chain::checkpoints::CHECKPOINT_INTERVAL;
+use chain::sync::DEFAULT_LOOKAHEAD;
 use p2p;
 use types::Error;
 
+/// Fetches the full blocks the body sync asks for from our peers, waiting
+/// for each to be received and asking another peer when one doesn't answer
+/// in time.
+struct PeerFetcher {
+	p2p: Arc<p2p::Server>,
+	// blocks requested, along with the block once received
+	requested: Mutex<HashMap<Hash, Option<core::Block>>>,
+	received: Condvar,
+}
+
+impl PeerFetcher {
+	// Hands over a block received from the network if it was requested,
+	// giving it back otherwise.
+	fn block_received(&self, b: core::Block) -> Option<core::Block> {
+		let mut requested = self.requested.lock().unwrap();
+		match requested.get_mut(&b.hash()) {
+			Some(slot) => {
+				*slot = Some(b);
+				self.received.notify_all();
+				None
+			}
+			None => Some(b),
+		}
+	}
+}
+
+impl chain::BlockFetcher for PeerFetcher {
+	fn fetch_block(&self, h: &Hash) -> Option<core::Block> {
+		let timeout = Duration::from_secs(BLOCK_REQUEST_TIMEOUT_SECS);
+		let mut requested = self.requested.lock().unwrap();
+		requested.insert(*h, None);
+
+		for _ in 0..MAX_BLOCK_REQUESTS {
+			match self.p2p.low_latency_peer() {
+				Some(peer) => {
+					if let Err(e) = peer.send_block_request(*h) {
+						debug!("Could not request block {}: {:?}", h, e);
+					}
+				}
+				None => warn!("No peer to request block {} from.", h),
+			}
+
+			let start = Instant::now();
+			loop {
+				if requested.get(h).map(|b| b.is_some()).unwrap_or(false) {
+					return requested.remove(h).and_then(|b| b);
+				}
+				let elapsed = start.elapsed();
+				if elapsed >= timeout {
+					break;
+				}
+				requested = self.received.wait_timeout(requested, timeout - elapsed).unwrap().0;
+			}
+			debug!("Block {} not received in time, asking again.", h);
+		}
+		requested.remove(h);
+		None
+	}
+}
+
 pub struct Syncer {
 	chain: Arc<chain::Chain>,
 	p2p: Arc<p2p::Server>,
+	headers_only: bool,
 
 	sync: Mutex<bool>,
 	last_header_req: Mutex<Instant>,
-	blocks_to_download: Mutex<Vec<Hash>>,
-	blocks_downloading: Mutex<Vec<(Hash, Instant)>>,
+	fetcher: Arc<PeerFetcher>,
+	body_sync: chain::BodySync,
+	checkpoints: Arc<p2p::CheckpointManager>,
+	checked_height: Mutex<u64>,
 }
 
 impl Syncer {
-	pub fn new(chain_ref: Arc<chain::Chain>, p2p: Arc<p2p::Server>) -> Syncer {
+	pub fn new(chain_ref: Arc<chain::Chain>,
+	           p2p: Arc<p2p::Server>,
+	           checkpoints: Arc<p2p::CheckpointManager>)
+	           -> Syncer {
+		let headers_only = chain_ref.sync_status()
+			.map(|s| s.mode == chain::SyncMode::HeadersOnly)
+			.unwrap_or(false);
+		let fetcher = Arc::new(PeerFetcher {
+			p2p: p2p.clone(),
+			requested: Mutex::new(HashMap::new()),
+			received: Condvar::new(),
+		});
+		let body_sync = chain::BodySync::new(chain_ref.clone(),
+		                                     fetcher.clone(),
+		                                     DEFAULT_LOOKAHEAD,
+		                                     chain::SYNC);
 		Syncer {
 			chain: chain_ref,
 			p2p: p2p,
+			headers_only: headers_only,
 			sync: Mutex::new(true),
 			last_header_req: Mutex::new(Instant::now() - Duration::from_secs(2)),
-			blocks_to_download: Mutex::new(vec![]),
-			blocks_downloading: Mutex::new(vec![]),
+			fetcher: fetcher,
+			body_sync: body_sync,
+			checkpoints: checkpoints,
+			checked_height: Mutex::new(0),
 		}
 	}
 
@@ -71,11 +161,9 @@ impl Syncer {
 			thread::sleep(Duration::from_millis(200));
 		}
 
-		// check if we have missing full blocks for which we already have a header
-		self.init_download()?;
-
-		// main syncing loop, requests more headers and bodies periodically as long
-		// as a peer with higher difficulty exists and we're not fully caught up
+		// main syncing loop, requests more headers periodically as long as a
+		// peer with higher difficulty exists and downloads the full blocks
+		// missing below the header chain head, until we're fully caught up
 		info!("Starting sync loop.");
 		loop {
 			let tip = self.chain.get_header_head()?;
@@ -83,11 +171,8 @@ impl Syncer {
 			let peer = self.p2p.most_work_peer().unwrap();
 
 			let more_headers = peer.info.total_difficulty > tip.total_difficulty;
-			let more_bodies = {
-				let blocks_to_download = self.blocks_to_download.lock().unwrap();
-				let blocks_downloading = self.blocks_downloading.lock().unwrap();
-				blocks_to_download.len() > 0 || blocks_downloading.len() > 0
-			};
+			let more_bodies = !self.headers_only &&
+				self.chain.head()?.last_block_h != tip.last_block_h;
 
 			{
 				let last_header_req = self.last_header_req.lock().unwrap().clone();
@@ -95,8 +180,11 @@ impl Syncer {
 					self.request_headers()?;
 				}
 			}
-			if more_bodies {
-				self.request_bodies();
+			if more_bodies && self.check_headers()? {
+				// follows the header chain head as more headers come in
+				if let Err(e) = self.body_sync.run() {
+					warn!("Block download interrupted, retrying: {:?}", e);
+				}
 			}
 			if !more_headers && !more_bodies {
 				// TODO check we haven't been lied to on the total work
@@ -111,63 +199,58 @@ impl Syncer {
 		Ok(())
 	}
 
-	/// Checks the gap between the header chain and the full block chain and
-	/// initializes the blocks_to_download structure with the missing full
-	/// blocks
-	fn init_download(&self) -> Result<(), Error> {
-		// compare the header's head to the full one to see what we're missing
-		let header_head = self.chain.get_header_head()?;
-		let full_head = self.chain.head()?;
-		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
-
-		// go back the chain and insert for download all blocks we only have the
-		// head for
-		let mut prev_h = header_head.last_block_h;
-		while prev_h != full_head.last_block_h {
-			let header = self.chain.get_block_header(&prev_h)?;
-			blocks_to_download.push(header.hash());
-			prev_h = header.previous;
+	/// Asks our peers for their checkpoint at the highest checkpoint height
+	/// of our header chain, checking our header there against the one a
+	/// majority of them agree on before downloading the full blocks. If it
+	/// doesn't match, asks the fastest of the agreeing peers for its headers
+	/// and returns false. Each height only gets checked once.
+	fn check_headers(&self) -> Result<bool, Error> {
+		let tip = self.chain.get_header_head()?;
+		let height = tip.height - tip.height % CHECKPOINT_INTERVAL;
+		if height == 0 || height <= *self.checked_height.lock().unwrap() {
+			return Ok(true);
 		}
 
-		debug!("Added {} full block hashes to download.",
-		       blocks_to_download.len());
-		Ok(())
-	}
-
-	/// Asks for the blocks we haven't downloaded yet and place them in the
-	/// downloading structure.
-	fn request_bodies(&self) {
-		let mut blocks_downloading = self.blocks_downloading.lock().unwrap();
-		if blocks_downloading.len() > MAX_BODY_DOWNLOADS {
-			// clean up potentially dead downloads
-			let twenty_sec_ago = Instant::now() - Duration::from_secs(20);
-			blocks_downloading.iter()
-				.position(|&h| h.1 < twenty_sec_ago)
-				.map(|n| blocks_downloading.remove(n));
-		} else {
-			// consume hashes from blocks to download, place them in downloading and
-			// request them from the network
-			let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
-			while blocks_to_download.len() > 0 && blocks_downloading.len() < MAX_BODY_DOWNLOADS {
-				let h = blocks_to_download.pop().unwrap();
-				let peer = self.p2p.random_peer().unwrap();
-				let send_result = peer.send_block_request(h);
-				match send_result {
-					Ok(_) => {}
-					Err(_) => {}
-				}
-				blocks_downloading.push((h, Instant::now()));
+		self.checkpoints.request(height, self.p2p.connected_peers());
+		let start = Instant::now();
+		let mut accepted = None;
+		while accepted.is_none() && start.elapsed() < Duration::from_secs(CHECKPOINT_WAIT_SECS) {
+			thread::sleep(Duration::from_millis(200));
+			accepted = self.checkpoints.accepted();
+		}
+		let (cp, addr) = match accepted {
+			Some(accepted) => accepted,
+			None => {
+				debug!("Our peers didn't agree on a checkpoint at {}.", height);
+				*self.checked_height.lock().unwrap() = height;
+				return Ok(true);
 			}
-			debug!("Requesting more full block hashes to download, total: {}.",
-			       blocks_to_download.len());
+		};
+
+		let mut header = self.chain.get_block_header(&tip.last_block_h)?;
+		while header.height > height {
+			header = self.chain.get_block_header(&header.previous)?;
 		}
+		if header.hash() == cp.block_hash {
+			*self.checked_height.lock().unwrap() = height;
+			return Ok(true);
+		}
+
+		warn!("Header {} at {} doesn't match the checkpoint our peers agree on, asking {} for headers.",
+		      header.hash(),
+		      height,
+		      addr);
+		if let Some(peer) = self.p2p.get_peer(addr) {
+			let locator = self.get_locator(&tip)?;
+			peer.send_header_request(locator)?;
+		}
+		Ok(false)
 	}
 
-	/// We added a block, clean up the downloading structure
-	pub fn block_received(&self, bh: Hash) {
-		// just clean up the downloading list
-		let mut bds = self.blocks_downloading.lock().unwrap();
-		bds.iter().position(|&h| h.0 == bh).map(|n| bds.remove(n));
+	/// We received a block, handing it over to the block download if it
+	/// requested it. Given back otherwise, to go through the chain as usual.
+	pub fn block_received(&self, b: core::Block) -> Option<core::Block> {
+		self.fetcher.block_received(b)
 	}
 
 	/// Request some block headers from a peer to advance us
@@ -192,16 +275,10 @@ impl Syncer {
 		Ok(())
 	}
 
-	/// We added a header, add it to the full block download list
+	/// We added some headers, asking for more if we got as many as required.
+	/// The block download follows the header chain head by itself.
 	pub fn headers_received(&self, bhs: Vec<Hash>) {
-		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
-		let hs_len = bhs.len();
-		for h in bhs {
-			// enlist for full block download
-			blocks_to_download.insert(0, h);
-		}
-		// ask for more headers if we got as many as required
-		if hs_len == (p2p::MAX_BLOCK_HEADERS as usize) {
+		if bhs.len() == (p2p::MAX_BLOCK_HEADERS as usize) {
 			self.request_headers().unwrap();
 		}
 	}
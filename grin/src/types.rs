@@ -17,6 +17,7 @@ use std::convert::From;
 use api;
 use chain;
 use p2p;
+use pool;
 use store;
 use pow;
 use core::global::MiningParameterMode;
@@ -67,6 +68,8 @@ pub enum Seeding {
 	List,
 	/// Automatically download a text file with a list of server addresses
 	WebStatic,
+	/// Resolve a list of DNS seeds into server addresses
+	DNS,
 }
 
 /// Full server configuration, aggregating configurations required for the
@@ -82,12 +85,27 @@ pub struct ServerConfig {
 	/// Setup the server for tests and testnet
 	pub mining_parameter_mode: Option<MiningParameterMode>,
 
+	/// Whether full blocks or only headers are synced, the latter for light
+	/// clients
+	pub sync_mode: Option<chain::SyncMode>,
+
+	/// Verify the proof of work of all headers instead of trusting the
+	/// hardcoded checkpoints
+	pub disable_checkpoints: Option<bool>,
+
 	/// Method used to get the list of seed nodes for initial bootstrap.
 	pub seeding_type: Seeding,
 
 	/// The list of seed nodes, if using Seeding as a seed type
 	pub seeds: Option<Vec<String>>,
 
+	/// The list of DNS seed hostnames, if using DNS as a seed type
+	pub dns_seeds: Option<Vec<String>>,
+
+	/// How long, in seconds, addresses resolved from DNS seeds are reused
+	/// for before resolving again
+	pub dns_seed_ttl_secs: Option<u64>,
+
 	/// Capabilities expose by this node, also conditions which other peers this
 	/// node will have an affinity toward when connection.
 	pub capabilities: p2p::Capabilities,
@@ -97,6 +115,13 @@ pub struct ServerConfig {
 
 	/// Configuration for the mining daemon
 	pub mining_config: Option<pow::types::MinerConfig>,
+
+	/// Configuration for the transaction pool
+	pub pool_config: Option<pool::PoolConfig>,
+
+	/// Configuration for the chain, defaults depend on the mining parameter
+	/// mode
+	pub chain_config: Option<chain::ChainConfig>,
 }
 
 impl Default for ServerConfig {
@@ -107,9 +132,15 @@ impl Default for ServerConfig {
 			capabilities: p2p::FULL_NODE,
 			seeding_type: Seeding::None,
 			seeds: None,
+			dns_seeds: None,
+			dns_seed_ttl_secs: None,
 			p2p_config: Some(p2p::P2PConfig::default()),
 			mining_config: Some(pow::types::MinerConfig::default()),
+			pool_config: Some(pool::PoolConfig::default()),
+			chain_config: None,
 			mining_parameter_mode: Some(MiningParameterMode::Production),
+			sync_mode: Some(chain::SyncMode::Full),
+			disable_checkpoints: Some(false),
 		}
 	}
 }
@@ -0,0 +1,91 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![feature(test)]
+
+extern crate test;
+extern crate rand;
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate secp256k1zkp as secp;
+
+use test::Bencher;
+
+use core::core::{Block, BlockHeader};
+use core::core::build::{input_rand, output_rand, transaction, with_fee};
+use core::ser;
+use p2p::{MsgHeader, Type, HEADER_LEN, DEFAULT_COMPRESSION_THRESHOLD};
+use secp::Secp256k1;
+use secp::key::SecretKey;
+
+// Number of block messages a simulated sync goes through.
+const SYNC_BLOCKS: usize = 10_000;
+
+// Distinct blocks the simulated sync cycles through, building 10,000 of them
+// would take longer than the benchmark itself.
+const DISTINCT_BLOCKS: usize = 20;
+
+const TXS_PER_BLOCK: u64 = 5;
+
+// Full Block messages, header included, as sent during a sync.
+fn block_msgs() -> Vec<Vec<u8>> {
+	let secp = Secp256k1::new();
+	(0..DISTINCT_BLOCKS)
+		.map(|_| {
+			let txs = (0..TXS_PER_BLOCK)
+				.map(|n| {
+					transaction(vec![input_rand(100 + n), output_rand(90), with_fee(10 + n)]).unwrap().0
+				})
+				.collect::<Vec<_>>();
+			let key = SecretKey::new(&secp, &mut rand::thread_rng());
+			let b = Block::new(&BlockHeader::default(), txs.iter().collect(), key).unwrap();
+			let body = ser::ser_vec(&b).unwrap();
+			let mut msg = ser::ser_vec(&MsgHeader::new(Type::Block, body.len() as u64)).unwrap();
+			msg.extend_from_slice(&body);
+			msg
+		})
+		.collect()
+}
+
+#[bench]
+fn bench_compress_sync(b: &mut Bencher) {
+	let msgs = block_msgs();
+	let (raw, compressed) = (0..SYNC_BLOCKS).fold((0, 0), |(raw, compressed), n| {
+		let msg = &msgs[n % DISTINCT_BLOCKS];
+		let c = p2p::compress_msg(msg.clone(), DEFAULT_COMPRESSION_THRESHOLD);
+		(raw + msg.len(), compressed + c.len())
+	});
+	println!("\n{} blocks, {} bytes raw, {} compressed ({:.1}% saved)",
+	         SYNC_BLOCKS,
+	         raw,
+	         compressed,
+	         100.0 * (raw - compressed) as f64 / raw as f64);
+
+	b.iter(|| for n in 0..SYNC_BLOCKS {
+		test::black_box(p2p::compress_msg(msgs[n % DISTINCT_BLOCKS].clone(),
+		                                  DEFAULT_COMPRESSION_THRESHOLD));
+	});
+}
+
+#[bench]
+fn bench_decompress_sync(b: &mut Bencher) {
+	let bodies = block_msgs()
+		.into_iter()
+		.map(|msg| p2p::compress_msg(msg, DEFAULT_COMPRESSION_THRESHOLD)[HEADER_LEN as usize..].to_vec())
+		.collect::<Vec<_>>();
+
+	b.iter(|| for n in 0..SYNC_BLOCKS {
+		test::black_box(p2p::decompress_body(&bodies[n % DISTINCT_BLOCKS]).unwrap());
+	});
+}
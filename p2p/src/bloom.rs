@@ -0,0 +1,150 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bloom filter of the transaction kernels a peer already knows, sent to us
+//! so we only advertise the transactions it's missing. The filter bits are
+//! exchanged over the wire, so both ends need to agree on the hash functions
+//! used.
+
+use std::cmp::{max, min};
+use std::f64::consts::LN_2;
+
+use blake2::blake2b::blake2b;
+use byteorder::{ByteOrder, BigEndian};
+use rand::{thread_rng, Rng};
+
+use core::core::Transaction;
+use core::core::hash::{Hash, Hashed};
+use core::ser::{self, Readable, Writeable, Reader, Writer};
+use secp::Secp256k1;
+
+/// Maximum size of a filter, in bytes.
+pub const MAX_FILTER_SIZE: usize = 36000;
+
+/// Maximum number of hash functions a filter can use.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Bloom filter over kernel hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvBloomFilter {
+	bits: Vec<u8>,
+	hash_funcs: u32,
+	tweak: u32,
+}
+
+impl InvBloomFilter {
+	/// New empty filter sized to hold the provided number of elements with
+	/// the provided false positive rate, within the maximum filter size.
+	pub fn new(elements: usize, fp_rate: f64) -> InvBloomFilter {
+		let elements = max(elements, 1) as f64;
+		let nbits = -elements * fp_rate.ln() / (LN_2 * LN_2);
+		let size = min(max((nbits / 8.0).ceil() as usize, 1), MAX_FILTER_SIZE);
+		let hash_funcs = (size as f64 * 8.0 / elements * LN_2).round() as u32;
+		InvBloomFilter {
+			bits: vec![0; size],
+			hash_funcs: min(max(hash_funcs, 1), MAX_HASH_FUNCS),
+			tweak: thread_rng().gen(),
+		}
+	}
+
+	/// Adds a kernel hash to the filter.
+	pub fn insert(&mut self, h: &Hash) {
+		for n in 0..self.hash_funcs {
+			let bit = self.bit_index(n, h);
+			self.bits[bit / 8] |= 1 << (bit % 8);
+		}
+	}
+
+	/// Whether the filter may contain the provided kernel hash. False
+	/// positives are possible, false negatives aren't.
+	pub fn contains(&self, h: &Hash) -> bool {
+		(0..self.hash_funcs).all(|n| {
+			let bit = self.bit_index(n, h);
+			self.bits[bit / 8] & (1 << (bit % 8)) != 0
+		})
+	}
+
+	fn bit_index(&self, n: u32, h: &Hash) -> usize {
+		let mut data = [0; 40];
+		BigEndian::write_u32(&mut data[0..4], self.tweak);
+		BigEndian::write_u32(&mut data[4..8], n);
+		data[8..].copy_from_slice(&h.0);
+		let digest = blake2b(8, &[], &data);
+		(BigEndian::read_u64(digest.as_bytes()) % (self.bits.len() as u64 * 8)) as usize
+	}
+}
+
+/// Hash of the kernel excess of a transaction, identifying it in filters and
+/// advertisements. Requires a secp context with commitment capabilities.
+pub fn tx_kernel_hash(secp: &Secp256k1, tx: &Transaction) -> Option<Hash> {
+	tx.sum_commitments(secp).ok().map(|excess| excess.hash())
+}
+
+impl Writeable for InvBloomFilter {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		ser_multiwrite!(writer,
+		                [write_bytes, &self.bits],
+		                [write_u32, self.hash_funcs],
+		                [write_u32, self.tweak]);
+		Ok(())
+	}
+}
+
+impl Readable for InvBloomFilter {
+	fn read(reader: &mut Reader) -> Result<InvBloomFilter, ser::Error> {
+		let (bits, hash_funcs, tweak) = ser_multiread!(reader, read_vec, read_u32, read_u32);
+		if bits.len() > MAX_FILTER_SIZE || hash_funcs > MAX_HASH_FUNCS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		if bits.is_empty() || hash_funcs == 0 {
+			return Err(ser::Error::CorruptedData);
+		}
+		Ok(InvBloomFilter {
+			bits: bits,
+			hash_funcs: hash_funcs,
+			tweak: tweak,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use msg::Inv;
+
+	#[test]
+	fn inv_bloom_filter() {
+		let kernels = (0..100u64).map(|n| n.hash()).collect::<Vec<_>>();
+		let mut filter = InvBloomFilter::new(kernels.len(), 0.000001);
+		for k in &kernels {
+			filter.insert(k);
+		}
+		assert!(kernels.iter().all(|k| filter.contains(k)));
+
+		// a peer knowing all our transactions gets nothing advertised
+		let inv = Inv::filtered(kernels.clone(), Some(&filter));
+		assert!(inv.kernels.is_empty());
+
+		// and only what it doesn't know otherwise
+		let others = (100..110u64).map(|n| n.hash()).collect::<Vec<_>>();
+		let mut all = kernels.clone();
+		all.extend(others.iter().cloned());
+		assert_eq!(Inv::filtered(all, Some(&filter)).kernels, others);
+
+		// survives serialization
+		let data = ser::ser_vec(&filter).unwrap();
+		let read = ser::deserialize::<InvBloomFilter>(&mut &data[..]).unwrap();
+		assert_eq!(read, filter);
+	}
+}
@@ -0,0 +1,157 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coordination of the checkpoints a syncing node checks its header chain
+//! against. All connected peers are asked for their checkpoint at a given
+//! height and the checkpoint is only accepted once a majority of them agree
+//! on it.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use msg::Checkpoint;
+use peer::Peer;
+
+/// A round of checkpoint requests, for a given height.
+struct Round {
+	height: u64,
+	// when each peer was asked
+	requested: HashMap<SocketAddr, Instant>,
+	// what each peer answered and how long it took
+	responses: HashMap<SocketAddr, (Checkpoint, Duration)>,
+}
+
+/// Collects the checkpoints sent by our peers and decides on the one to
+/// accept.
+pub struct CheckpointManager {
+	round: RwLock<Option<Round>>,
+}
+
+impl CheckpointManager {
+	/// New manager, with no checkpoint requested yet.
+	pub fn new() -> CheckpointManager {
+		CheckpointManager { round: RwLock::new(None) }
+	}
+
+	/// Asks all the provided peers for their checkpoint at the given height.
+	/// Starts a new round, forgetting the responses of any previous one.
+	pub fn request(&self, height: u64, peers: Vec<Arc<Peer>>) {
+		let mut asked = vec![];
+		for peer in peers {
+			if !peer.is_connected() {
+				continue;
+			}
+			match peer.send_checkpoint_request(height) {
+				Ok(_) => asked.push(peer.info.addr),
+				Err(e) => debug!("Could not ask {} for a checkpoint: {:?}", peer.info.addr, e),
+			}
+		}
+		self.start_round(height, asked);
+	}
+
+	fn start_round(&self, height: u64, asked: Vec<SocketAddr>) {
+		let now = Instant::now();
+		let mut round = self.round.write().unwrap();
+		*round = Some(Round {
+			height: height,
+			requested: asked.into_iter().map(|addr| (addr, now)).collect(),
+			responses: HashMap::new(),
+		});
+	}
+
+	/// Records the checkpoint received from a peer. Ignored if the peer
+	/// wasn't asked, already answered or the checkpoint is for another
+	/// height.
+	pub fn checkpoint_received(&self, cp: Checkpoint, addr: SocketAddr) {
+		let mut round = self.round.write().unwrap();
+		if let Some(ref mut round) = *round {
+			if cp.height != round.height || round.responses.contains_key(&addr) {
+				return;
+			}
+			if let Some(asked_at) = round.requested.get(&addr) {
+				round.responses.insert(addr, (cp, asked_at.elapsed()));
+			}
+		}
+	}
+
+	/// The checkpoint a strict majority of the peers asked agree on, if any,
+	/// along with the most responsive of the peers that sent it, which the
+	/// headers should be downloaded from if ours don't match.
+	pub fn accepted(&self) -> Option<(Checkpoint, SocketAddr)> {
+		let round = self.round.read().unwrap();
+		let round = match *round {
+			Some(ref round) => round,
+			None => return None,
+		};
+
+		for &(ref cp, _) in round.responses.values() {
+			let agreeing = round.responses
+				.iter()
+				.filter(|&(_, &(ref other, _))| cp.agrees(other))
+				.collect::<Vec<_>>();
+			if agreeing.len() * 2 > round.requested.len() {
+				let fastest = agreeing.iter().min_by_key(|&&(_, &(_, latency))| latency).unwrap();
+				return Some((cp.clone(), *fastest.0));
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::core::hash::{Hash, ZERO_HASH};
+
+	fn checkpoint(utxo_root: Hash) -> Checkpoint {
+		Checkpoint {
+			height: 10,
+			block_hash: ZERO_HASH,
+			utxo_root: utxo_root,
+			kernel_root: ZERO_HASH,
+		}
+	}
+
+	#[test]
+	fn majority_checkpoint() {
+		let addrs = (0..4)
+			.map(|n| format!("127.0.0.1:{}", 4000 + n).parse().unwrap())
+			.collect::<Vec<SocketAddr>>();
+		let good = checkpoint(ZERO_HASH);
+		let bad = checkpoint(Hash([1; 32]));
+
+		let cm = CheckpointManager::new();
+		cm.start_round(10, addrs.clone());
+		cm.checkpoint_received(good.clone(), addrs[0]);
+		cm.checkpoint_received(bad.clone(), addrs[1]);
+		assert_eq!(cm.accepted(), None);
+
+		// half isn't a majority
+		cm.checkpoint_received(good.clone(), addrs[2]);
+		assert_eq!(cm.accepted(), None);
+
+		// other heights are ignored
+		let mut other_height = good.clone();
+		other_height.height = 11;
+		cm.checkpoint_received(other_height, addrs[3]);
+		assert_eq!(cm.accepted(), None);
+
+		cm.checkpoint_received(good.clone(), addrs[3]);
+		let (cp, addr) = cm.accepted().unwrap();
+		assert_eq!(cp, good);
+		assert!(addr != addrs[1]);
+	}
+}
@@ -18,15 +18,16 @@
 use std::iter;
 use std::ops::Deref;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Instant, Duration};
 
 use futures;
-use futures::{Stream, Future};
+use futures::{Async, Poll, Stream, Future, Sink};
 use futures::stream;
 use futures::sync::mpsc::{Sender, UnboundedSender, UnboundedReceiver};
 use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::io::{read_exact, write_all};
+use tokio_io::io::{flush, read_exact, write_all};
 use tokio_timer::{Timer, TimerError};
 
 use core::core::hash::Hash;
@@ -43,18 +44,18 @@ pub trait Handler: Sync + Send {
 	/// reply immediately as well as the message header and its unparsed body
 	/// are provided.
 	fn handle(&self,
-	          sender: UnboundedSender<Vec<u8>>,
+	          sender: ReplySender,
 	          header: MsgHeader,
 	          body: Vec<u8>)
 	          -> Result<Option<Hash>, ser::Error>;
 }
 
 impl<F> Handler for F
-	where F: Fn(UnboundedSender<Vec<u8>>, MsgHeader, Vec<u8>) -> Result<Option<Hash>, ser::Error>,
+	where F: Fn(ReplySender, MsgHeader, Vec<u8>) -> Result<Option<Hash>, ser::Error>,
 	      F: Sync + Send
 {
 	fn handle(&self,
-	          sender: UnboundedSender<Vec<u8>>,
+	          sender: ReplySender,
 	          header: MsgHeader,
 	          body: Vec<u8>)
 	          -> Result<Option<Hash>, ser::Error> {
@@ -62,13 +63,115 @@ impl<F> Handler for F
 	}
 }
 
+/// Priority of an outbound message. Messages of a higher priority are always
+/// written out first, so new blocks don't wait behind a flood of transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+	/// Blocks and everything else consensus relies on
+	High = 0,
+	/// Transaction relay
+	Normal = 1,
+	/// Peer discovery
+	Low = 2,
+}
+
+/// Number of priority tiers.
+const PRIORITY_TIERS: usize = 3;
+
+impl Priority {
+	/// Priority messages of the provided type are sent with.
+	pub fn of(t: Type) -> Priority {
+		match t {
+			Type::Transaction | Type::Inv => Priority::Normal,
+			Type::GetPeerAddrs | Type::PeerAddrs | Type::SignedPeerAddrs | Type::GetPeers |
+			Type::Peers => Priority::Low,
+			_ => Priority::High,
+		}
+	}
+}
+
+/// Sender handed to the message handler to reply immediately, replies going
+/// out ahead of everything queued. They count in the queue depth until
+/// written out like any other message.
+#[derive(Clone)]
+pub struct ReplySender {
+	sender: UnboundedSender<Vec<u8>>,
+	queue_depth: Arc<AtomicUsize>,
+}
+
+impl ReplySender {
+	/// Sends the reply data to the remote peer.
+	pub fn send(&self, data: Vec<u8>) -> Result<(), Error> {
+		self.queue_depth.fetch_add(1, Ordering::SeqCst);
+		self.sender.send(data).map_err(|_| {
+			self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+			Error::ConnectionClose
+		})
+	}
+}
+
+/// Outbound messages along with the time they were queued at.
+type Queued = (Vec<u8>, Instant);
+
+/// Merges the replies and the queues of each priority tier into the stream of
+/// data to write out, always taking replies first and then the highest
+/// priority queue that has anything. Keeps track of the queue depth and how
+/// long the last message of each tier waited. Messages taken out of the queue
+/// are in flight until written out.
+struct PriorityQueue {
+	replies: UnboundedReceiver<Vec<u8>>,
+	tiers: Vec<UnboundedReceiver<Queued>>,
+	queue_depth: Arc<AtomicUsize>,
+	in_flight: Arc<AtomicUsize>,
+	queue_delays: Arc<Mutex<[u64; PRIORITY_TIERS]>>,
+}
+
+impl Stream for PriorityQueue {
+	type Item = Vec<u8>;
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<Option<Vec<u8>>, ()> {
+		let mut ended = 0;
+		match self.replies.poll()? {
+			Async::Ready(Some(data)) => {
+				self.in_flight.fetch_add(1, Ordering::SeqCst);
+				self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+				return Ok(Async::Ready(Some(data)));
+			}
+			Async::Ready(None) => ended += 1,
+			Async::NotReady => {}
+		}
+		for (n, tier) in self.tiers.iter_mut().enumerate() {
+			match tier.poll()? {
+				Async::Ready(Some((data, queued_at))) => {
+					self.in_flight.fetch_add(1, Ordering::SeqCst);
+					self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+					self.queue_delays.lock().unwrap()[n] = to_millis(queued_at.elapsed());
+					return Ok(Async::Ready(Some(data)));
+				}
+				Async::Ready(None) => ended += 1,
+				Async::NotReady => {}
+			}
+		}
+		if ended > PRIORITY_TIERS {
+			Ok(Async::Ready(None))
+		} else {
+			Ok(Async::NotReady)
+		}
+	}
+}
+
+fn to_millis(d: Duration) -> u64 {
+	d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
 /// A higher level connection wrapping the TcpStream. Maintains the amount of
 /// data transmitted and deals with the low-level task of sending and
 /// receiving data, parsing message headers and timeouts.
 #[allow(dead_code)]
 pub struct Connection {
-	// Channel to push bytes to the remote peer
-	outbound_chan: UnboundedSender<Vec<u8>>,
+	// Channels to push bytes to the remote peer, one per priority tier
+	outbound_chans: Vec<UnboundedSender<Queued>>,
 
 	// Close the connection with the remote peer
 	close_chan: Sender<()>,
@@ -81,13 +184,30 @@ pub struct Connection {
 
 	// Counter for read errors.
 	error_count: Mutex<u64>,
+
+	// Messages queued on the outbound channel not written out yet.
+	queue_depth: Arc<AtomicUsize>,
+
+	// Queue depth past which low priority messages get dropped, the peer
+	// being disconnected at twice that.
+	max_queue_depth: usize,
+
+	// Messages taken out of the queue but not written out and flushed yet.
+	in_flight: Arc<AtomicUsize>,
+
+	// How long the last message written out of each priority tier was
+	// queued for, in milliseconds.
+	queue_delays: Arc<Mutex<[u64; PRIORITY_TIERS]>>,
 }
 
 impl Connection {
 	/// Start listening on the provided connection and wraps it. Does not hang
 	/// the current thread, instead just returns a future and the Connection
-	/// itself.
+	/// itself. Messages larger than the compression threshold, if provided,
+	/// get compressed before being written out.
 	pub fn listen<F>(conn: TcpStream,
+	                 max_queue_depth: usize,
+	                 compress_threshold: Option<usize>,
 	                 handler: F)
 	                 -> (Connection, Box<Future<Item = (), Error = Error>>)
 		where F: Handler + 'static
@@ -100,27 +220,47 @@ impl Connection {
 		// Set Max Write to 12 Mb/s
 		let writer = ThrottledWriter::new(writer, 12_000_000);
 
-		// prepare the channel that will transmit data to the connection writer
-		let (tx, rx) = futures::sync::mpsc::unbounded();
+		// prepare the channels that will transmit data to the connection writer,
+		// one per priority tier, replies sent directly by the handler get their
+		// own so they go out first
+		let (txs, rxs): (Vec<_>, Vec<_>) =
+			(0..PRIORITY_TIERS).map(|_| futures::sync::mpsc::unbounded()).unzip();
+		let (reply_tx, reply_rx) = futures::sync::mpsc::unbounded();
 
 		// same for closing the connection
 		let (close_tx, close_rx) = futures::sync::mpsc::channel(1);
-		let close_conn = close_rx.for_each(|_| Ok(())).map_err(|_| Error::ConnectionClose);
+		let close_conn = close_rx.into_future().map(|_| ()).map_err(|_| Error::ConnectionClose);
 
 		let me = Connection {
-			outbound_chan: tx.clone(),
+			outbound_chans: txs,
 			close_chan: close_tx,
 			sent_bytes: Arc::new(Mutex::new(0)),
 			received_bytes: Arc::new(Mutex::new(0)),
 			error_count: Mutex::new(0),
+			queue_depth: Arc::new(AtomicUsize::new(0)),
+			max_queue_depth: max_queue_depth,
+			in_flight: Arc::new(AtomicUsize::new(0)),
+			queue_delays: Arc::new(Mutex::new([0; PRIORITY_TIERS])),
 		};
 
 		// setup the reading future, getting messages from the peer and processing them
-		let read_msg = me.read_msg(tx, reader, handler).map(|_| ());
+		let reply_tx = ReplySender {
+			sender: reply_tx,
+			queue_depth: me.queue_depth.clone(),
+		};
+		let read_msg = me.read_msg(reply_tx, compress_threshold.is_some(), reader, handler)
+			.map(|_| ());
 
 		// setting the writing future, getting messages from our system and sending
 		// them out
-		let write_msg = me.write_msg(rx, writer).map(|_| ());
+		let queue = PriorityQueue {
+			replies: reply_rx,
+			tiers: rxs,
+			queue_depth: me.queue_depth.clone(),
+			in_flight: me.in_flight.clone(),
+			queue_delays: me.queue_delays.clone(),
+		};
+		let write_msg = me.write_msg(queue, compress_threshold, writer).map(|_| ());
 
 		// select between our different futures and return them
 		let fut =
@@ -134,32 +274,45 @@ impl Connection {
 	/// Prepares the future that gets message data produced by our system and
 	/// sends it to the peer connection
 	fn write_msg<W>(&self,
-	                rx: UnboundedReceiver<Vec<u8>>,
+	                queue: PriorityQueue,
+	                compress_threshold: Option<usize>,
 	                writer: W)
 	                -> Box<Future<Item = W, Error = Error>>
 		where W: AsyncWrite + 'static
 	{
 
 		let sent_bytes = self.sent_bytes.clone();
-		let send_data = rx
+		let in_flight = self.in_flight.clone();
+		let send_data = queue
 			.map_err(|_| Error::ConnectionClose)
-      .map(move |data| {
-        // add the count of bytes sent
-				let mut sent_bytes = sent_bytes.lock().unwrap();
-				*sent_bytes += data.len() as u64;
-				data
+			.map(move |data| match compress_threshold {
+				Some(threshold) => compress_msg(data, threshold),
+				None => data,
 			})
       // write the data and make sure the future returns the right types
-			.fold(writer, |writer, data| {
-        write_all(writer, data).map_err(|e| Error::Connection(e)).map(|(writer, _)| writer)
+			.fold(writer, move |writer, data| {
+				let sent_bytes = sent_bytes.clone();
+				let in_flight = in_flight.clone();
+				let len = data.len() as u64;
+				write_all(writer, data)
+					.and_then(|(writer, _)| flush(writer))
+					.map(move |writer| {
+						// add the count of bytes sent, once they're all out
+						*sent_bytes.lock().unwrap() += len;
+						in_flight.fetch_sub(1, Ordering::SeqCst);
+						writer
+					})
+					.map_err(|e| Error::Connection(e))
       });
 		Box::new(send_data)
 	}
 
 	/// Prepares the future reading from the peer connection, parsing each
-	/// message and forwarding them appropriately based on their type
+	/// message and forwarding them appropriately based on their type.
+	/// Compressed messages are only accepted once compression is negotiated.
 	fn read_msg<F, R>(&self,
-	                  sender: UnboundedSender<Vec<u8>>,
+	                  sender: ReplySender,
+	                  compression: bool,
 	                  reader: R,
 	                  handler: F)
 	                  -> Box<Future<Item = R, Error = Error>>
@@ -185,6 +338,9 @@ impl Connection {
 				.from_err()
 				.and_then(move |(reader, buf)| {
 					let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
+					if header.compressed && !compression {
+						return Err(Error::Serialization(ser::Error::CorruptedData));
+					}
 					Ok((reader, header))
 				})
 				.and_then(move |(reader, header)| {
@@ -198,6 +354,14 @@ impl Connection {
 					let mut recv_bytes = recv_bytes.lock().unwrap();
 					*recv_bytes += header.serialized_len() + header.msg_len;
 
+					// inflate compressed bodies before handling them
+					let (header, buf) = if header.compressed {
+						let buf = try!(decompress_body(&buf));
+						(MsgHeader::new(header.msg_type, buf.len() as u64), buf)
+					} else {
+						(header, buf)
+					};
+
 					// and handle the different message types
 					let msg_type = header.msg_type;
 					if let Err(e) = handler.handle(sender_inner.clone(), header, buf) {
@@ -212,16 +376,45 @@ impl Connection {
 	}
 
 	/// Utility function to send any Writeable. Handles adding the header and
-	/// serialization.
+	/// serialization. Messages are queued according to their priority, so
+	/// blocks go out ahead of transactions. Lower priority messages are
+	/// dropped when the peer doesn't keep up with what we send and the peer
+	/// gets disconnected if it falls too far behind.
 	pub fn send_msg<W: ser::Writeable>(&self, t: Type, body: &W) -> Result<(), Error> {
 
+		let depth = self.queue_depth.load(Ordering::SeqCst);
+		if depth >= 2 * self.max_queue_depth {
+			warn!("Send queue of {} messages full, disconnecting.", depth);
+			self.close();
+			return Err(Error::ConnectionClose);
+		}
+		let priority = Priority::of(t);
+		if depth >= self.max_queue_depth && priority != Priority::High {
+			debug!("Send queue of {} messages, dropping {:?}.", depth, t);
+			return Ok(());
+		}
+
 		let mut body_data = vec![];
 		try!(ser::serialize(&mut body_data, body));
 		let mut data = vec![];
 		try!(ser::serialize(&mut data, &MsgHeader::new(t, body_data.len() as u64)));
 		data.append(&mut body_data);
 
-		self.outbound_chan.send(data).map_err(|_| Error::ConnectionClose)
+		// counted before sending so the writer can't take it out of the queue
+		// first, which would underflow the depth
+		self.queue_depth.fetch_add(1, Ordering::SeqCst);
+		self.outbound_chans[priority as usize]
+			.send((data, Instant::now()))
+			.map_err(|_| {
+				self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+				Error::ConnectionClose
+			})
+	}
+
+	/// Closes the connection with the remote peer. Whatever hasn't been
+	/// written out to the socket yet is dropped.
+	pub fn close(&self) {
+		let _ = self.close_chan.clone().send(()).wait();
 	}
 
 	/// Bytes sent and received by this peer to the remote peer.
@@ -230,6 +423,25 @@ impl Connection {
 		let recv = *self.received_bytes.lock().unwrap();
 		(sent, recv)
 	}
+
+	/// Number of messages waiting to be written out to the remote peer.
+	pub fn queue_depth(&self) -> usize {
+		self.queue_depth.load(Ordering::SeqCst)
+	}
+
+	/// Whether all messages sent so far have been written out to the socket
+	/// and flushed.
+	pub fn flushed(&self) -> bool {
+		// messages are counted in flight before leaving the queue, checking the
+		// queue first can't miss one
+		self.queue_depth.load(Ordering::SeqCst) == 0 && self.in_flight.load(Ordering::SeqCst) == 0
+	}
+
+	/// How long, in milliseconds, the last message written out of each
+	/// priority tier waited in its queue, highest priority first.
+	pub fn queue_delays(&self) -> [u64; PRIORITY_TIERS] {
+		*self.queue_delays.lock().unwrap()
+	}
 }
 
 /// Connection wrapper that handles a request/response oriented interaction with
@@ -243,6 +455,8 @@ pub struct TimeoutConnection {
 impl TimeoutConnection {
 	/// Same as Connection
 	pub fn listen<F>(conn: TcpStream,
+	                 max_queue_depth: usize,
+	                 compress_threshold: Option<usize>,
 	                 handler: F)
 	                 -> (TimeoutConnection, Box<Future<Item = (), Error = Error>>)
 		where F: Handler + 'static
@@ -253,7 +467,7 @@ impl TimeoutConnection {
 		// Decorates the handler to remove the "subscription" from the expected
 		// responses. We got our replies, so no timeout should occur.
 		let exp = expects.clone();
-		let (conn, fut) = Connection::listen(conn, move |sender, header: MsgHeader, data| {
+		let (conn, fut) = Connection::listen(conn, max_queue_depth, compress_threshold, move |sender, header: MsgHeader, data| {
 			let msg_type = header.msg_type;
 			let recv_h = try!(handler.handle(sender, header, data));
 
@@ -311,8 +525,100 @@ impl TimeoutConnection {
 		self.underlying.send_msg(t, body)
 	}
 
+	/// Same as Connection
+	pub fn close(&self) {
+		self.underlying.close()
+	}
+
 	/// Same as Connection
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
 		self.underlying.transmitted_bytes()
 	}
+
+	/// Same as Connection
+	pub fn queue_depth(&self) -> usize {
+		self.underlying.queue_depth()
+	}
+
+	/// Same as Connection
+	pub fn flushed(&self) -> bool {
+		self.underlying.flushed()
+	}
+
+	/// Same as Connection
+	pub fn queue_delays(&self) -> [u64; 3] {
+		self.underlying.queue_delays()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn priority_queue() {
+		let (reply_tx, reply_rx) = futures::sync::mpsc::unbounded();
+		let (txs, rxs): (Vec<_>, Vec<_>) =
+			(0..PRIORITY_TIERS).map(|_| futures::sync::mpsc::unbounded()).unzip();
+		for n in 0..100u8 {
+			txs[Priority::Low as usize].send((vec![n], Instant::now())).unwrap();
+		}
+		txs[Priority::High as usize].send((vec![200], Instant::now())).unwrap();
+		reply_tx.send(vec![201]).unwrap();
+
+		let queue_depth = Arc::new(AtomicUsize::new(102));
+		let in_flight = Arc::new(AtomicUsize::new(0));
+		let queue = PriorityQueue {
+			replies: reply_rx,
+			tiers: rxs,
+			queue_depth: queue_depth.clone(),
+			in_flight: in_flight.clone(),
+			queue_delays: Arc::new(Mutex::new([0; PRIORITY_TIERS])),
+		};
+		drop(reply_tx);
+		drop(txs);
+
+		let sent = queue.wait().map(|data| data.unwrap()[0]).collect::<Vec<_>>();
+		assert_eq!(sent[..3], [201, 200, 0]);
+		assert_eq!(sent.len(), 102);
+		assert_eq!(queue_depth.load(Ordering::SeqCst), 0);
+
+		// out of the queue, but nothing has been written out
+		assert_eq!(in_flight.load(Ordering::SeqCst), 102);
+	}
+
+	#[test]
+	fn queue_depth_accounting() {
+		let (txs, rxs): (Vec<_>, Vec<_>) =
+			(0..PRIORITY_TIERS).map(|_| futures::sync::mpsc::unbounded()).unzip();
+		let (reply_tx, reply_rx) = futures::sync::mpsc::unbounded();
+		let (close_tx, _close_rx) = futures::sync::mpsc::channel(1);
+		let conn = Connection {
+			outbound_chans: txs,
+			close_chan: close_tx,
+			sent_bytes: Arc::new(Mutex::new(0)),
+			received_bytes: Arc::new(Mutex::new(0)),
+			error_count: Mutex::new(0),
+			queue_depth: Arc::new(AtomicUsize::new(0)),
+			max_queue_depth: 10,
+			in_flight: Arc::new(AtomicUsize::new(0)),
+			queue_delays: Arc::new(Mutex::new([0; PRIORITY_TIERS])),
+		};
+		let replies = ReplySender {
+			sender: reply_tx,
+			queue_depth: conn.queue_depth.clone(),
+		};
+
+		// queued messages and replies both count until written out
+		conn.send_msg(Type::Ping, &1u64).unwrap();
+		replies.send(vec![1]).unwrap();
+		assert_eq!(conn.queue_depth(), 2);
+
+		// nothing stays counted when the connection is gone
+		drop(rxs);
+		drop(reply_rx);
+		assert!(conn.send_msg(Type::Ping, &1u64).is_err());
+		assert!(replies.send(vec![1]).is_err());
+		assert_eq!(conn.queue_depth(), 2);
+	}
 }
@@ -0,0 +1,138 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bootstrapping from DNS seeds, hostnames resolving to the addresses of
+//! healthy peers. Lookups block, so the resolver should be used outside of
+//! the event loop.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+/// How long the addresses a seed resolved to are reused for, by default.
+pub const DEFAULT_DNS_SEED_TTL_SECS: u64 = 3600;
+
+/// Port assumed for seeds that don't specify one.
+const DEFAULT_SEED_PORT: u16 = 13414;
+
+/// How long we wait on a seed lookup before giving up on it.
+const RESOLVE_TIMEOUT_SECS: u64 = 5;
+
+/// Resolves a list of DNS seeds into peer addresses, caching the result of
+/// each lookup for a while.
+pub struct DnsSeedResolver {
+	seeds: Vec<String>,
+	ttl: Duration,
+	cache: Mutex<HashMap<String, (Vec<SocketAddr>, Instant)>>,
+}
+
+impl DnsSeedResolver {
+	/// New resolver for the provided seed hostnames, optionally followed by a
+	/// port, caching lookups for the provided duration.
+	pub fn new(seeds: Vec<String>, ttl: Duration) -> DnsSeedResolver {
+		DnsSeedResolver {
+			seeds: seeds,
+			ttl: ttl,
+			cache: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Addresses all our seeds resolve to, shuffled so we don't all hit the
+	/// same peers. Seeds that fail to resolve are skipped.
+	pub fn resolve(&self) -> Vec<SocketAddr> {
+		let mut addrs = vec![];
+		for seed in &self.seeds {
+			addrs.extend(self.resolve_seed(seed));
+		}
+		thread_rng().shuffle(&mut addrs[..]);
+		addrs
+	}
+
+	fn resolve_seed(&self, seed: &str) -> Vec<SocketAddr> {
+		if let Some(&(ref addrs, at)) = self.cache.lock().unwrap().get(seed) {
+			if at.elapsed() < self.ttl {
+				return addrs.clone();
+			}
+		}
+		match lookup(seed) {
+			Some(addrs) => {
+				debug!("DNS seed {} resolved to {} addresses.", seed, addrs.len());
+				let mut cache = self.cache.lock().unwrap();
+				cache.insert(seed.to_string(), (addrs.clone(), Instant::now()));
+				addrs
+			}
+			None => vec![],
+		}
+	}
+}
+
+// Lookup in its own thread as the standard library doesn't let us set a
+// timeout. A lookup timing out is left to finish on its own.
+fn lookup(seed: &str) -> Option<Vec<SocketAddr>> {
+	let host = if seed.contains(':') {
+		seed.to_string()
+	} else {
+		format!("{}:{}", seed, DEFAULT_SEED_PORT)
+	};
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || {
+		let _ = tx.send(host.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()));
+	});
+	match rx.recv_timeout(Duration::from_secs(RESOLVE_TIMEOUT_SECS)) {
+		Ok(Ok(addrs)) => Some(addrs),
+		Ok(Err(e)) => {
+			warn!("Could not resolve DNS seed {}: {}", seed, e);
+			None
+		}
+		Err(_) => {
+			warn!("Timed out resolving DNS seed {}.", seed);
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn dns_seed_cache() {
+		let resolver = DnsSeedResolver::new(vec!["localhost".to_string(), "seed.invalid".to_string()],
+		                                    Duration::from_secs(DEFAULT_DNS_SEED_TTL_SECS));
+		let addrs = resolver.resolve();
+		assert!(addrs.len() > 0);
+		assert!(addrs.iter().all(|a| a.ip().is_loopback() && a.port() == DEFAULT_SEED_PORT));
+
+		// cached lookups don't hit DNS
+		let cached = "10.0.0.1:13414".parse().unwrap();
+		resolver.cache
+			.lock()
+			.unwrap()
+			.insert("seed.invalid".to_string(), (vec![cached], Instant::now()));
+		assert!(resolver.resolve().contains(&cached));
+
+		// until they expire
+		let expiring = DnsSeedResolver::new(vec!["seed.invalid".to_string()], Duration::from_secs(0));
+		expiring.cache
+			.lock()
+			.unwrap()
+			.insert("seed.invalid".to_string(), (vec![cached], Instant::now()));
+		assert!(expiring.resolve().is_empty());
+	}
+}
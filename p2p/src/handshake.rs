@@ -12,17 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
-use futures::Future;
+use futures::{future, Future};
 use rand::Rng;
 use rand::os::OsRng;
 use tokio_core::net::TcpStream;
 
 use core::core::target::Difficulty;
 use core::ser;
+use secp::key::PublicKey;
 use msg::*;
 use types::*;
 use protocol::ProtocolV1;
@@ -35,15 +37,41 @@ pub struct Handshake {
 	/// Ring buffer of nonces sent to detect self connections without requiring
 	/// a node id.
 	nonces: Arc<RwLock<VecDeque<u64>>>,
+	/// Key we sign our address with, sent along our handshake messages so
+	/// peers only accept the addresses we advertise signed with it.
+	addr_pubkey: Option<PublicKey>,
 }
 
 unsafe impl Sync for Handshake {}
 unsafe impl Send for Handshake {}
 
 impl Handshake {
-	/// Creates a new handshake handler
+	/// Creates a new handshake handler, for a peer that doesn't sign its
+	/// address.
 	pub fn new() -> Handshake {
-		Handshake { nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))) }
+		Handshake {
+			nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))),
+			addr_pubkey: None,
+		}
+	}
+
+	/// Creates a new handshake handler presenting the provided key, the one
+	/// our advertised address is signed with.
+	pub fn with_addr_key(addr_pubkey: PublicKey) -> Handshake {
+		Handshake {
+			nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))),
+			addr_pubkey: Some(addr_pubkey),
+		}
+	}
+
+	// Our capabilities as advertised, SIGNED_ADDRS requiring a key to sign
+	// with.
+	fn advertised(&self, capab: Capabilities) -> Capabilities {
+		if self.addr_pubkey.is_some() {
+			capab
+		} else {
+			capab & !SIGNED_ADDRS
+		}
 	}
 
 	/// Handles connecting to a new remote peer, starting the version handshake.
@@ -51,25 +79,29 @@ impl Handshake {
 	               capab: Capabilities,
 	               total_difficulty: Difficulty,
 	               self_addr: SocketAddr,
+	               peer_addr: SocketAddr,
 	               conn: TcpStream)
 	               -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
 		// prepare the first part of the hanshake
 		let nonce = self.next_nonce();
+		let capab = self.advertised(capab);
 		let hand = Hand {
 			version: PROTOCOL_VERSION,
 			capabilities: capab,
 			nonce: nonce,
 			total_difficulty: total_difficulty,
 			sender_addr: SockAddr(self_addr),
-			receiver_addr: SockAddr(conn.peer_addr().unwrap()),
+			receiver_addr: SockAddr(peer_addr),
 			user_agent: USER_AGENT.to_string(),
+			addr_pubkey: self.addr_pubkey,
 		};
 
 		// write and read the handshake response
 		Box::new(write_msg(conn, hand, Type::Hand)
 			.and_then(|conn| read_msg::<Shake>(conn))
-			.and_then(|(conn, shake)| {
-				if shake.version != 1 {
+			.and_then(move |(conn, shake)| {
+				// the version the peer replies with is the one both speak
+				if shake.version < MIN_PROTOCOL_VERSION || shake.version > PROTOCOL_VERSION {
 					Err(Error::Serialization(ser::Error::UnexpectedData {
 						expected: vec![PROTOCOL_VERSION as u8],
 						received: vec![shake.version as u8],
@@ -78,14 +110,21 @@ impl Handshake {
 					let peer_info = PeerInfo {
 						capabilities: shake.capabilities,
 						user_agent: shake.user_agent,
-						addr: conn.peer_addr().unwrap(),
+						// not the socket's, we may be connected through a proxy
+						addr: peer_addr,
 						version: shake.version,
 						total_difficulty: shake.total_difficulty,
+						last_rtt_ms: Arc::new(RwLock::new(None)),
+						addr_pubkey: shake.addr_pubkey,
 					};
 
 					info!("Connected to peer {:?}", peer_info);
 					// when more than one protocol version is supported, choosing should go here
-					Ok((conn, ProtocolV1::new(), peer_info))
+					let proto = ProtocolV1::new(peer_info.addr,
+					                            peer_info.version,
+					                            peer_info.addr_pubkey,
+					                            peer_info.last_rtt_ms.clone());
+					Ok((conn, proto, peer_info))
 				}
 			}))
 	}
@@ -97,10 +136,18 @@ impl Handshake {
 	                 total_difficulty: Difficulty,
 	                 conn: TcpStream)
 	                 -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
+		// the peer only tells us the port it listens on, its address is the one
+		// it connected from, scores and bans being based on it
+		let conn_addr = match conn.peer_addr() {
+			Ok(addr) => addr,
+			Err(e) => return Box::new(future::err(Error::Connection(e))),
+		};
 		let nonces = self.nonces.clone();
+		let capab = self.advertised(capab);
+		let addr_pubkey = self.addr_pubkey;
 		Box::new(read_msg::<Hand>(conn)
 			.and_then(move |(conn, hand)| {
-				if hand.version != 1 {
+				if hand.version < MIN_PROTOCOL_VERSION {
 					return Err(Error::Serialization(ser::Error::UnexpectedData {
 						expected: vec![PROTOCOL_VERSION as u8],
 						received: vec![hand.version as u8],
@@ -116,20 +163,24 @@ impl Handshake {
 						}));
 					}
 				}
-				// all good, keep peer info
+				// all good, keep peer info, speaking the latest version we both know
+				let version = cmp::min(hand.version, PROTOCOL_VERSION);
 				let peer_info = PeerInfo {
 					capabilities: hand.capabilities,
 					user_agent: hand.user_agent,
-					addr: hand.sender_addr.0,
-					version: hand.version,
+					addr: SocketAddr::new(conn_addr.ip(), hand.sender_addr.0.port()),
+					version: version,
 					total_difficulty: hand.total_difficulty,
+					last_rtt_ms: Arc::new(RwLock::new(None)),
+					addr_pubkey: hand.addr_pubkey,
 				};
 				// send our reply with our info
 				let shake = Shake {
-					version: PROTOCOL_VERSION,
+					version: version,
 					capabilities: capab,
 					total_difficulty: total_difficulty,
 					user_agent: USER_AGENT.to_string(),
+					addr_pubkey: addr_pubkey,
 				};
 				Ok((conn, shake, peer_info))
 			})
@@ -137,7 +188,13 @@ impl Handshake {
 				debug!("Success handshake with {}.", peer_info.addr);
 				write_msg(conn, shake, Type::Shake)
 				  // when more than one protocol version is supported, choosing should go here
-					.map(|conn| (conn, ProtocolV1::new(), peer_info))
+					.map(|conn| {
+						let proto = ProtocolV1::new(peer_info.addr,
+						                            peer_info.version,
+						                            peer_info.addr_pubkey,
+						                            peer_info.last_rtt_ms.clone());
+						(conn, proto, peer_info)
+					})
 			}))
 	}
 
@@ -22,6 +22,8 @@
 
 #[macro_use]
 extern crate bitflags;
+extern crate blake2_rfc as blake2;
+extern crate byteorder;
 #[macro_use]
 extern crate enum_primitive;
 #[macro_use]
@@ -41,19 +43,35 @@ extern crate serde;
 extern crate serde_derive;
 extern crate time;
 extern crate num;
+extern crate secp256k1zkp as secp;
+extern crate lz4;
 
+mod bloom;
+mod checkpoint;
 mod conn;
+mod dns_seeds;
 pub mod handshake;
 mod rate_limit;
 mod msg;
 mod peer;
 mod protocol;
 mod server;
+mod socks;
 mod store;
 mod types;
 
+pub use bloom::{InvBloomFilter, tx_kernel_hash};
+pub use checkpoint::CheckpointManager;
+pub use dns_seeds::{DnsSeedResolver, DEFAULT_DNS_SEED_TTL_SECS};
+pub use msg::{Checkpoint, CompactBlock, DisconnectReason, Inv, ShortId, SignedPeerAddr,
+              MsgHeader, Type, HEADER_LEN, compress_msg, decompress_body,
+              SIGNED_ADDR_MAX_DRIFT, addr_to_onion, onion_to_addr, parse_peer_addr};
 pub use server::{Server, DummyAdapter};
 pub use peer::Peer;
+pub use socks::dial;
 pub use types::{P2PConfig, NetAdapter, MAX_LOCATORS, MAX_BLOCK_HEADERS, MAX_PEER_ADDRS,
-                Capabilities, UNKNOWN, FULL_NODE, FULL_HIST, PeerInfo, Error};
-pub use store::{PeerStore, PeerData, State};
+                MAX_PEERS,
+                Capabilities, UNKNOWN, FULL_NODE, FULL_HIST, SIGNED_ADDRS, COMPACT_BLOCKS,
+                INV_FILTER, COMPRESSION, DEFAULT_COMPRESSION_THRESHOLD, PeerInfo,
+                Error, BanReason, CORRUPTED_MESSAGE, UNEXPECTED_MESSAGE};
+pub use store::{PeerStore, PeerData, PeerScorer, Misbehavior, BanData, State, BAN_WINDOW};
@@ -17,20 +17,40 @@
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
 use num::FromPrimitive;
 
+use blake2::blake2b::blake2b;
+use byteorder::{ByteOrder, LittleEndian};
 use futures::future::{Future, ok};
+use lz4;
 use tokio_core::net::TcpStream;
 use tokio_io::io::{read_exact, write_all};
 
 use core::consensus::MAX_MSG_LEN;
-use core::core::BlockHeader;
-use core::core::hash::Hash;
+use core::core::{Block, BlockHeader, Output, Transaction, TxKernel, COINBASE_KERNEL,
+                 COINBASE_OUTPUT};
+use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
 use core::ser::{self, Writeable, Readable, Writer, Reader};
+use secp::{self, Message, Secp256k1, Signature};
+use secp::key::{PublicKey, SecretKey};
+use secp::pedersen::Commitment;
+
+use bloom::InvBloomFilter;
 
 use types::*;
 
 /// Current latest version of the protocol
-pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version of the protocol still spoken with peers
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Version of the protocol from which Ping and Pong carry a body, they only
+/// have a header before
+pub const PING_BODY_VERSION: u32 = 2;
+
+/// Version of the protocol from which message bodies can be compressed, with
+/// peers that also advertise the COMPRESSION capability
+pub const COMPRESSION_VERSION: u32 = 2;
 
 /// Grin's user agent with current version (TODO externalize)
 pub const USER_AGENT: &'static str = "MW/Grin 0.1";
@@ -41,6 +61,19 @@ const MAGIC: [u8; 2] = [0x1e, 0xc5];
 /// Size in bytes of a message header
 pub const HEADER_LEN: u64 = 11;
 
+/// High bit of the message type byte, set when the message body is LZ4
+/// compressed. Only ever set on messages sent to peers that advertised the
+/// COMPRESSION capability, everyone else gets the same header as before.
+const COMPRESSED_FLAG: u8 = 0x80;
+
+/// How far from our own clock the timestamp of a signed peer address can be
+/// before it's rejected, in seconds.
+pub const SIGNED_ADDR_MAX_DRIFT: u64 = 600;
+
+/// Size in bytes of the short ids transactions are referred to with in
+/// compact blocks.
+pub const SHORT_ID_SIZE: usize = 6;
+
 /// Codes for each error that can be produced reading a message.
 #[allow(dead_code)]
 pub enum ErrCodes {
@@ -63,6 +96,19 @@ enum_from_primitive! {
     GetBlock,
     Block,
     Transaction,
+    GetCheckpoint,
+    Checkpoint,
+    Disconnect,
+    SignedPeerAddrs,
+    CompactBlock,
+    GetBlockTransactions,
+    BlockTransactions,
+    GetPeers,
+    Peers,
+    FilterLoad,
+    FilterAdd,
+    FilterClear,
+    Inv,
   }
 }
 
@@ -76,6 +122,10 @@ pub fn read_msg<T>(conn: TcpStream) -> Box<Future<Item = (TcpStream, T), Error =
 		.from_err()
 		.and_then(|(reader, buf)| {
 			let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
+			if header.compressed {
+				// nothing negotiated yet, bodies can't be compressed
+				return Err(Error::Serialization(ser::Error::CorruptedData));
+			}
 			if header.msg_len > MAX_MSG_LEN {
 				// TODO add additional restrictions on a per-message-type basis to avoid 20MB
 				// pings
@@ -127,6 +177,9 @@ pub struct MsgHeader {
 	magic: [u8; 2],
 	/// Type of the message.
 	pub msg_type: Type,
+	/// Whether the message body is LZ4 compressed, carried by the high bit of
+	/// the type.
+	pub compressed: bool,
 	/// Tota length of the message in bytes.
 	pub msg_len: u64,
 }
@@ -137,6 +190,7 @@ impl MsgHeader {
 		MsgHeader {
 			magic: MAGIC,
 			msg_type: msg_type,
+			compressed: false,
 			msg_len: len,
 		}
 	}
@@ -145,6 +199,14 @@ impl MsgHeader {
 	pub fn serialized_len(&self) -> u64 {
 		HEADER_LEN
 	}
+
+	fn type_byte(&self) -> u8 {
+		if self.compressed {
+			self.msg_type as u8 | COMPRESSED_FLAG
+		} else {
+			self.msg_type as u8
+		}
+	}
 }
 
 impl Writeable for MsgHeader {
@@ -152,7 +214,7 @@ impl Writeable for MsgHeader {
 		ser_multiwrite!(writer,
 		                [write_u8, self.magic[0]],
 		                [write_u8, self.magic[1]],
-		                [write_u8, self.msg_type as u8],
+		                [write_u8, self.type_byte()],
 		                [write_u64, self.msg_len]);
 		Ok(())
 	}
@@ -163,11 +225,12 @@ impl Readable for MsgHeader {
 		try!(reader.expect_u8(MAGIC[0]));
 		try!(reader.expect_u8(MAGIC[1]));
 		let (t, len) = ser_multiread!(reader, read_u8, read_u64);
-		match Type::from_u8(t) {
+		match Type::from_u8(t & !COMPRESSED_FLAG) {
 			Some(ty) => {
 				Ok(MsgHeader {
 					magic: MAGIC,
 					msg_type: ty,
+					compressed: t & COMPRESSED_FLAG != 0,
 					msg_len: len,
 				})
 			}
@@ -176,6 +239,45 @@ impl Readable for MsgHeader {
 	}
 }
 
+/// Compresses the body of a full message (header included) if it's larger
+/// than the provided threshold, flagging it in the header type. Messages that
+/// don't get any smaller are left as they are. Only meant for peers that
+/// advertised the COMPRESSION capability.
+pub fn compress_msg(msg: Vec<u8>, threshold: usize) -> Vec<u8> {
+	let hlen = HEADER_LEN as usize;
+	if msg.len() <= hlen + threshold {
+		return msg;
+	}
+	let msg_type = match ser::deserialize::<MsgHeader>(&mut &msg[..hlen]) {
+		Ok(ref header) if !header.compressed => header.msg_type,
+		_ => return msg,
+	};
+	let body = match lz4::block::compress(&msg[hlen..], None, true) {
+		Ok(body) => body,
+		Err(_) => return msg,
+	};
+	if body.len() >= msg.len() - hlen {
+		return msg;
+	}
+	let mut header = MsgHeader::new(msg_type, body.len() as u64);
+	header.compressed = true;
+	let mut data = ser::ser_vec(&header).unwrap();
+	data.extend_from_slice(&body);
+	data
+}
+
+/// Decompresses a message body compressed by compress_msg, refusing to
+/// inflate it past the maximum message size.
+pub fn decompress_body(buf: &[u8]) -> Result<Vec<u8>, ser::Error> {
+	if buf.len() < 4 {
+		return Err(ser::Error::CorruptedData);
+	}
+	if LittleEndian::read_u32(&buf[..4]) as u64 > MAX_MSG_LEN {
+		return Err(ser::Error::TooLargeReadErr);
+	}
+	lz4::block::decompress(buf, None).map_err(|_| ser::Error::CorruptedData)
+}
+
 /// First part of a handshake, sender advertises its version and
 /// characteristics.
 pub struct Hand {
@@ -195,6 +297,8 @@ pub struct Hand {
 	pub receiver_addr: SockAddr,
 	/// name of version of the software
 	pub user_agent: String,
+	/// key the sender signs its address with, only with SIGNED_ADDRS
+	pub addr_pubkey: Option<PublicKey>,
 }
 
 impl Writeable for Hand {
@@ -206,7 +310,8 @@ impl Writeable for Hand {
 		self.total_difficulty.write(writer).unwrap();
 		self.sender_addr.write(writer).unwrap();
 		self.receiver_addr.write(writer).unwrap();
-		writer.write_bytes(&self.user_agent)
+		writer.write_bytes(&self.user_agent)?;
+		write_addr_pubkey(writer, &self.addr_pubkey)
 	}
 }
 
@@ -219,6 +324,7 @@ impl Readable for Hand {
 		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
 		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		let addr_pubkey = try!(read_addr_pubkey(reader, capabilities));
 		Ok(Hand {
 			version: version,
 			capabilities: capabilities,
@@ -227,6 +333,7 @@ impl Readable for Hand {
 			sender_addr: sender_addr,
 			receiver_addr: receiver_addr,
 			user_agent: user_agent,
+			addr_pubkey: addr_pubkey,
 		})
 	}
 }
@@ -244,6 +351,8 @@ pub struct Shake {
 	pub total_difficulty: Difficulty,
 	/// name of version of the software
 	pub user_agent: String,
+	/// key the sender signs its address with, only with SIGNED_ADDRS
+	pub addr_pubkey: Option<PublicKey>,
 }
 
 impl Writeable for Shake {
@@ -253,7 +362,7 @@ impl Writeable for Shake {
 		                [write_u32, self.capabilities.bits()]);
 		self.total_difficulty.write(writer).unwrap();
 		writer.write_bytes(&self.user_agent).unwrap();
-		Ok(())
+		write_addr_pubkey(writer, &self.addr_pubkey)
 	}
 }
 
@@ -264,15 +373,41 @@ impl Readable for Shake {
 		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
 		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		let addr_pubkey = try!(read_addr_pubkey(reader, capabilities));
 		Ok(Shake {
 			version: version,
 			capabilities: capabilities,
 			total_difficulty: total_diff,
 			user_agent: user_agent,
+			addr_pubkey: addr_pubkey,
 		})
 	}
 }
 
+// Writes the key a peer signs its address with, ending the handshake
+// messages of peers with the SIGNED_ADDRS capability.
+fn write_addr_pubkey<W: Writer>(writer: &mut W, key: &Option<PublicKey>) -> Result<(), ser::Error> {
+	match *key {
+		Some(ref key) => {
+			let secp = Secp256k1::with_caps(secp::ContextFlag::None);
+			writer.write_bytes(&key.serialize_vec(&secp, true).to_vec())
+		}
+		None => Ok(()),
+	}
+}
+
+// Reads the key a peer signs its address with, present only if the peer has
+// the SIGNED_ADDRS capability.
+fn read_addr_pubkey(reader: &mut Reader, capabilities: Capabilities) -> Result<Option<PublicKey>, ser::Error> {
+	if !capabilities.contains(SIGNED_ADDRS) {
+		return Ok(None);
+	}
+	let secp = Secp256k1::with_caps(secp::ContextFlag::None);
+	PublicKey::from_slice(&secp, &reader.read_vec()?)
+		.map(Some)
+		.map_err(|_| ser::Error::CorruptedData)
+}
+
 /// Ask for other peers addresses, required for network discovery.
 pub struct GetPeerAddrs {
 	/// Filters on the capabilities we'd like the peers to have
@@ -326,6 +461,176 @@ impl Readable for PeerAddrs {
 	}
 }
 
+/// Asks for peer addresses along with when they were last seen, answered by
+/// a Peers message.
+pub struct GetPeers {
+	/// Filters on the capabilities we'd like the peers to have
+	pub capabilities: Capabilities,
+}
+
+impl Writeable for GetPeers {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.capabilities.bits())
+	}
+}
+
+impl Readable for GetPeers {
+	fn read(reader: &mut Reader) -> Result<GetPeers, ser::Error> {
+		let capab = reader.read_u32()?;
+		let capabilities = Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData)?;
+		Ok(GetPeers { capabilities: capabilities })
+	}
+}
+
+/// Peer addresses we know of, each with the time (in seconds since epoch)
+/// it was last seen at, in response to GetPeers.
+pub struct Peers {
+	pub peers: Vec<(SockAddr, u64)>,
+}
+
+impl Writeable for Peers {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.peers.len() as u32)?;
+		for &(ref addr, last_seen) in &self.peers {
+			addr.write(writer)?;
+			writer.write_u64(last_seen)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Peers {
+	fn read(reader: &mut Reader) -> Result<Peers, ser::Error> {
+		let peer_count = reader.read_u32()?;
+		if peer_count > MAX_PEERS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut peers = Vec::with_capacity(peer_count as usize);
+		for _ in 0..peer_count {
+			let addr = SockAddr::read(reader)?;
+			let last_seen = reader.read_u64()?;
+			peers.push((addr, last_seen));
+		}
+		Ok(Peers { peers: peers })
+	}
+}
+
+/// A peer address signed by the peer itself, proving the peer advertising
+/// it controls the key behind it and that the advertisement is recent. Only
+/// accepted from the peer itself, signed with the key it presented in the
+/// handshake and for the IP address it's connected from. Prevents filling
+/// peer tables with made up addresses.
+pub struct SignedPeerAddr {
+	/// Advertised address
+	pub addr: SocketAddr,
+	/// When the address was signed, in seconds since the epoch
+	pub timestamp: u64,
+	/// Key the address is signed with
+	pub pubkey: PublicKey,
+	/// Signature of the address and timestamp
+	pub sig: Signature,
+}
+
+impl SignedPeerAddr {
+	/// Signs the provided address and timestamp with our key.
+	pub fn sign(secp: &Secp256k1,
+	            addr: SocketAddr,
+	            timestamp: u64,
+	            key: &SecretKey)
+	            -> Result<SignedPeerAddr, secp::Error> {
+		let msg = SignedPeerAddr::message(addr, timestamp)?;
+		Ok(SignedPeerAddr {
+			addr: addr,
+			timestamp: timestamp,
+			pubkey: PublicKey::from_secret_key(secp, key)?,
+			sig: secp.sign(&msg, key)?,
+		})
+	}
+
+	/// Whether the signature is valid and the timestamp close enough to the
+	/// provided current time.
+	pub fn verify(&self, secp: &Secp256k1, now: u64) -> bool {
+		let drift = if now > self.timestamp {
+			now - self.timestamp
+		} else {
+			self.timestamp - now
+		};
+		if drift > SIGNED_ADDR_MAX_DRIFT {
+			return false;
+		}
+		match SignedPeerAddr::message(self.addr, self.timestamp) {
+			Ok(msg) => secp.verify(&msg, &self.sig, &self.pubkey).is_ok(),
+			Err(_) => false,
+		}
+	}
+
+	// The signed message, BLAKE2b(addr_bytes || le64(timestamp))
+	fn message(addr: SocketAddr, timestamp: u64) -> Result<Message, secp::Error> {
+		let mut data = ser::ser_vec(&SockAddr(addr)).map_err(|_| secp::Error::InvalidMessage)?;
+		let mut ts = [0; 8];
+		LittleEndian::write_u64(&mut ts, timestamp);
+		data.extend_from_slice(&ts);
+		Message::from_slice(blake2b(32, &[], &data).as_bytes())
+	}
+}
+
+impl Writeable for SignedPeerAddr {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::None);
+		SockAddr(self.addr).write(writer)?;
+		writer.write_u64(self.timestamp)?;
+		writer.write_bytes(&self.pubkey.serialize_vec(&secp, true).to_vec())?;
+		writer.write_bytes(&self.sig.serialize_der(&secp))
+	}
+}
+
+impl Readable for SignedPeerAddr {
+	fn read(reader: &mut Reader) -> Result<SignedPeerAddr, ser::Error> {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::None);
+		let addr = SockAddr::read(reader)?;
+		let timestamp = reader.read_u64()?;
+		let pubkey = PublicKey::from_slice(&secp, &reader.read_vec()?)
+			.map_err(|_| ser::Error::CorruptedData)?;
+		let sig = Signature::from_der(&secp, &reader.read_vec()?)
+			.map_err(|_| ser::Error::CorruptedData)?;
+		Ok(SignedPeerAddr {
+			addr: addr.0,
+			timestamp: timestamp,
+			pubkey: pubkey,
+			sig: sig,
+		})
+	}
+}
+
+/// Peer addresses along with their signature.
+pub struct SignedPeerAddrs {
+	pub peers: Vec<SignedPeerAddr>,
+}
+
+impl Writeable for SignedPeerAddrs {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.peers.len() as u32)?;
+		for p in &self.peers {
+			p.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SignedPeerAddrs {
+	fn read(reader: &mut Reader) -> Result<SignedPeerAddrs, ser::Error> {
+		let peer_count = reader.read_u32()?;
+		if peer_count > MAX_PEER_ADDRS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut peers = Vec::with_capacity(peer_count as usize);
+		for _ in 0..peer_count {
+			peers.push(SignedPeerAddr::read(reader)?);
+		}
+		Ok(SignedPeerAddrs { peers: peers })
+	}
+}
+
 /// We found some issue in the communication, sending an error back, usually
 /// followed by closing the connection.
 pub struct PeerError {
@@ -353,6 +658,79 @@ impl Readable for PeerError {
 	}
 }
 
+/// IPv6 prefix onion addresses are mapped under (OnionCat), so onion peers can
+/// be handled, stored and advertised like any other peer.
+const ONION_PREFIX: [u8; 6] = [0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43];
+
+/// Length of the base32 encoded onion service name, excluding .onion
+const ONION_NAME_LEN: usize = 16;
+
+const BASE32_ALPHABET: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Maps the provided onion service host (i.e. expyuzz4wqqyqhjn.onion) and port
+/// to the address representing it. None if the host isn't a valid onion
+/// service name.
+pub fn onion_to_addr(host: &str, port: u16) -> Option<SocketAddr> {
+	let name = host.trim_right_matches(".onion");
+	if name.len() != ONION_NAME_LEN || !host.ends_with(".onion") {
+		return None;
+	}
+	let mut octets = [0u8; 16];
+	octets[..6].copy_from_slice(&ONION_PREFIX);
+	let (mut acc, mut bits, mut n) = (0u32, 0, 6);
+	for c in name.to_lowercase().bytes() {
+		let v = match BASE32_ALPHABET.iter().position(|&a| a == c) {
+			Some(v) => v as u32,
+			None => return None,
+		};
+		acc = (acc << 5) | v;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			octets[n] = (acc >> bits) as u8;
+			n += 1;
+		}
+	}
+	let ip = Ipv6Addr::from(octets);
+	Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+}
+
+/// The onion service host an address represents, if it does.
+pub fn addr_to_onion(addr: &SocketAddr) -> Option<String> {
+	let octets = match *addr {
+		SocketAddr::V6(sav6) => sav6.ip().octets(),
+		SocketAddr::V4(_) => return None,
+	};
+	if octets[..6] != ONION_PREFIX {
+		return None;
+	}
+	let mut name = String::new();
+	let (mut acc, mut bits) = (0u32, 0);
+	for b in &octets[6..] {
+		acc = (acc << 8) | *b as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			name.push(BASE32_ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	Some(name + ".onion")
+}
+
+/// Parses a peer address, either a regular socket address or an onion service
+/// host and port (i.e. expyuzz4wqqyqhjn.onion:13414).
+pub fn parse_peer_addr(s: &str) -> Option<SocketAddr> {
+	if let Ok(addr) = s.parse() {
+		return Some(addr);
+	}
+	let mut parts = s.rsplitn(2, ':');
+	let port = parts.next().and_then(|p| p.parse().ok());
+	match (parts.next(), port) {
+		(Some(host), Some(port)) => onion_to_addr(host, port),
+		_ => None,
+	}
+}
+
 /// Only necessary so we can implement Readable and Writeable. Rust disallows
 /// implementing traits when both types are outside of this crate (which is the
 /// case for SocketAddr and Readable/Writeable).
@@ -391,7 +769,7 @@ impl Readable for SockAddr {
 			                                                           ip[3]),
 			                                             port))))
 		} else {
-			let ip = try_map_vec!([0..8], |_| reader.read_u16());
+			let ip = try_map_vec!([0; 8], |_| reader.read_u16());
 			let port = try!(reader.read_u16());
 			Ok(SockAddr(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(ip[0],
 			                                                           ip[1],
@@ -460,8 +838,401 @@ impl Readable for Headers {
 	}
 }
 
-/// Placeholder for messages like Ping and Pong that don't send anything but
-/// the header.
+/// Request for the checkpoint a peer has at a given height.
+pub struct GetCheckpoint {
+	pub height: u64,
+}
+
+impl Writeable for GetCheckpoint {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.height)
+	}
+}
+
+impl Readable for GetCheckpoint {
+	fn read(reader: &mut Reader) -> Result<GetCheckpoint, ser::Error> {
+		Ok(GetCheckpoint { height: reader.read_u64()? })
+	}
+}
+
+/// State of the chain of a peer at a given height, used to check enough
+/// peers agree on it with the header chain we're syncing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+	/// Height of the checkpoint
+	pub height: u64,
+	/// Hash of the block at that height
+	pub block_hash: Hash,
+	/// Root of the output MMR at that height
+	pub utxo_root: Hash,
+	/// Root of the kernel MMR at that height
+	pub kernel_root: Hash,
+}
+
+impl Checkpoint {
+	/// Whether the other checkpoint is for the same block and roots.
+	pub fn agrees(&self, other: &Checkpoint) -> bool {
+		self.block_hash == other.block_hash && self.utxo_root == other.utxo_root &&
+		self.kernel_root == other.kernel_root
+	}
+}
+
+impl Writeable for Checkpoint {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.height)?;
+		self.block_hash.write(writer)?;
+		self.utxo_root.write(writer)?;
+		self.kernel_root.write(writer)
+	}
+}
+
+impl Readable for Checkpoint {
+	fn read(reader: &mut Reader) -> Result<Checkpoint, ser::Error> {
+		Ok(Checkpoint {
+			height: reader.read_u64()?,
+			block_hash: Hash::read(reader)?,
+			utxo_root: Hash::read(reader)?,
+			kernel_root: Hash::read(reader)?,
+		})
+	}
+}
+
+/// Why a peer is closing its connection with us.
+enum_from_primitive! {
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum DisconnectReason {
+    Shutdown,
+  }
+}
+
+/// Sent to a peer right before closing the connection with it, so it knows
+/// the disconnection is deliberate.
+pub struct Disconnect {
+	pub reason: DisconnectReason,
+}
+
+impl Writeable for Disconnect {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u8(self.reason as u8)
+	}
+}
+
+impl Readable for Disconnect {
+	fn read(reader: &mut Reader) -> Result<Disconnect, ser::Error> {
+		let r = reader.read_u8()?;
+		let reason = DisconnectReason::from_u8(r).ok_or(ser::Error::CorruptedData)?;
+		Ok(Disconnect { reason: reason })
+	}
+}
+
+/// Short identifier of a transaction in a compact block, the first bytes of
+/// the hash of its kernel excess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShortId(pub [u8; SHORT_ID_SIZE]);
+
+impl ShortId {
+	/// Short id of the transaction with the provided kernel excess.
+	pub fn from_excess(excess: &Commitment) -> ShortId {
+		let mut id = [0; SHORT_ID_SIZE];
+		id.copy_from_slice(&excess.hash().0[..SHORT_ID_SIZE]);
+		ShortId(id)
+	}
+}
+
+impl Writeable for ShortId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(&self.0.to_vec())
+	}
+}
+
+impl Readable for ShortId {
+	fn read(reader: &mut Reader) -> Result<ShortId, ser::Error> {
+		let bytes = reader.read_fixed_bytes(SHORT_ID_SIZE)?;
+		let mut id = [0; SHORT_ID_SIZE];
+		id.copy_from_slice(&bytes);
+		Ok(ShortId(id))
+	}
+}
+
+/// A block relayed without its transactions, which peers are expected to
+/// have in their pool already. Only the coinbase outputs and kernels, that
+/// can't be in any pool, are sent whole along with the header.
+#[derive(Debug, Clone)]
+pub struct CompactBlock {
+	/// Header of the block
+	pub header: BlockHeader,
+	/// Coinbase outputs of the block
+	pub outputs: Vec<Output>,
+	/// Coinbase kernels of the block
+	pub kernels: Vec<TxKernel>,
+	/// Short ids of the other kernels, one per transaction, in block order
+	pub kern_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+	/// Compact version of the provided block.
+	pub fn from_block(b: &Block) -> CompactBlock {
+		CompactBlock {
+			header: b.header.clone(),
+			outputs: b.outputs
+				.iter()
+				.filter(|out| out.features.contains(COINBASE_OUTPUT))
+				.cloned()
+				.collect(),
+			kernels: b.kernels
+				.iter()
+				.filter(|k| k.features.contains(COINBASE_KERNEL))
+				.cloned()
+				.collect(),
+			kern_ids: b.kernels
+				.iter()
+				.filter(|k| !k.features.contains(COINBASE_KERNEL))
+				.map(|k| ShortId::from_excess(&k.excess))
+				.collect(),
+		}
+	}
+
+	/// Hash of the block.
+	pub fn hash(&self) -> Hash {
+		self.header.hash()
+	}
+
+	/// Rebuilds the full block out of the transactions the short ids refer
+	/// to, provided in the same order. None if the transactions don't match
+	/// the ids or don't give back the inputs and outputs committed to in the
+	/// header.
+	pub fn reconstruct(&self, txs: &[Transaction]) -> Option<Block> {
+		if txs.len() != self.kern_ids.len() {
+			return None;
+		}
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let mut kernels = vec![];
+		for (tx, id) in txs.iter().zip(self.kern_ids.iter()) {
+			match tx.verify_sig(&secp) {
+				Ok(k) => {
+					if ShortId::from_excess(&k.excess) != *id {
+						return None;
+					}
+					kernels.push(k);
+				}
+				Err(_) => return None,
+			}
+		}
+		kernels.extend(self.kernels.iter().cloned());
+
+		// same ordering as blocks are built with, before cut-through
+		let mut inputs = txs.iter().flat_map(|tx| tx.inputs.iter().cloned()).collect::<Vec<_>>();
+		let mut outputs = txs.iter()
+			.flat_map(|tx| tx.outputs.iter().cloned())
+			.chain(self.outputs.iter().cloned())
+			.collect::<Vec<_>>();
+		inputs.sort_by_key(|inp| inp.hash());
+		outputs.sort_by_key(|out| out.hash());
+
+		let block = Block {
+				header: self.header.clone(),
+				inputs: inputs,
+				outputs: outputs,
+				kernels: kernels,
+			}
+			.compact();
+		if block.header.tx_merkle != self.header.tx_merkle {
+			return None;
+		}
+		Some(block)
+	}
+}
+
+impl Writeable for CompactBlock {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.header.write(writer)?;
+		ser_multiwrite!(writer,
+		                [write_u64, self.outputs.len() as u64],
+		                [write_u64, self.kernels.len() as u64],
+		                [write_u64, self.kern_ids.len() as u64]);
+		for out in &self.outputs {
+			out.write(writer)?;
+		}
+		for k in &self.kernels {
+			k.write(writer)?;
+		}
+		for id in &self.kern_ids {
+			id.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for CompactBlock {
+	fn read(reader: &mut Reader) -> Result<CompactBlock, ser::Error> {
+		let header = BlockHeader::read(reader)?;
+		let (output_len, kernel_len, id_len) =
+			ser_multiread!(reader, read_u64, read_u64, read_u64);
+		let outputs = (0..output_len).map(|_| Output::read(reader)).collect::<Result<_, _>>()?;
+		let kernels = (0..kernel_len).map(|_| TxKernel::read(reader)).collect::<Result<_, _>>()?;
+		let kern_ids = (0..id_len).map(|_| ShortId::read(reader)).collect::<Result<_, _>>()?;
+		Ok(CompactBlock {
+			header: header,
+			outputs: outputs,
+			kernels: kernels,
+			kern_ids: kern_ids,
+		})
+	}
+}
+
+/// Request for the transactions of a compact block that couldn't be found
+/// in the pool.
+pub struct BlockTransactionsRequest {
+	/// Hash of the compact block
+	pub block_hash: Hash,
+	/// Short ids of the missing transactions
+	pub kern_ids: Vec<ShortId>,
+}
+
+impl Writeable for BlockTransactionsRequest {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.block_hash.write(writer)?;
+		writer.write_u64(self.kern_ids.len() as u64)?;
+		for id in &self.kern_ids {
+			id.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BlockTransactionsRequest {
+	fn read(reader: &mut Reader) -> Result<BlockTransactionsRequest, ser::Error> {
+		let block_hash = Hash::read(reader)?;
+		let len = reader.read_u64()?;
+		let kern_ids = (0..len).map(|_| ShortId::read(reader)).collect::<Result<_, _>>()?;
+		Ok(BlockTransactionsRequest {
+			block_hash: block_hash,
+			kern_ids: kern_ids,
+		})
+	}
+}
+
+/// Transactions of a compact block, answering a BlockTransactionsRequest.
+pub struct BlockTransactions {
+	/// Hash of the compact block
+	pub block_hash: Hash,
+	/// Requested transactions that could be found
+	pub txs: Vec<Transaction>,
+}
+
+impl Writeable for BlockTransactions {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.block_hash.write(writer)?;
+		writer.write_u64(self.txs.len() as u64)?;
+		for tx in &self.txs {
+			tx.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BlockTransactions {
+	fn read(reader: &mut Reader) -> Result<BlockTransactions, ser::Error> {
+		let block_hash = Hash::read(reader)?;
+		let len = reader.read_u64()?;
+		let txs = (0..len).map(|_| Transaction::read(reader)).collect::<Result<_, _>>()?;
+		Ok(BlockTransactions {
+			block_hash: block_hash,
+			txs: txs,
+		})
+	}
+}
+
+/// Loads a filter of the transaction kernels the sender already knows, we'll
+/// only advertise it the transactions not matching.
+pub struct FilterLoad {
+	pub filter: InvBloomFilter,
+}
+
+impl Writeable for FilterLoad {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.filter.write(writer)
+	}
+}
+
+impl Readable for FilterLoad {
+	fn read(reader: &mut Reader) -> Result<FilterLoad, ser::Error> {
+		Ok(FilterLoad { filter: InvBloomFilter::read(reader)? })
+	}
+}
+
+/// Adds a transaction kernel the sender learned about to its loaded filter.
+pub struct FilterAdd {
+	pub kernel: Hash,
+}
+
+impl Writeable for FilterAdd {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.kernel.write(writer)
+	}
+}
+
+impl Readable for FilterAdd {
+	fn read(reader: &mut Reader) -> Result<FilterAdd, ser::Error> {
+		Ok(FilterAdd { kernel: Hash::read(reader)? })
+	}
+}
+
+/// Removes the filter loaded by the sender, everything gets advertised again.
+pub struct FilterClear {}
+
+impl Writeable for FilterClear {
+	fn write<W: Writer>(&self, _: &mut W) -> Result<(), ser::Error> {
+		Ok(())
+	}
+}
+
+impl Readable for FilterClear {
+	fn read(_: &mut Reader) -> Result<FilterClear, ser::Error> {
+		Ok(FilterClear {})
+	}
+}
+
+/// Advertises the transactions we have, by kernel hash.
+pub struct Inv {
+	pub kernels: Vec<Hash>,
+}
+
+impl Inv {
+	/// Advertisement of the provided kernels, leaving out the ones matching
+	/// the filter of the peer it's sent to.
+	pub fn filtered(kernels: Vec<Hash>, filter: Option<&InvBloomFilter>) -> Inv {
+		let kernels = match filter {
+			Some(filter) => kernels.into_iter().filter(|k| !filter.contains(k)).collect(),
+			None => kernels,
+		};
+		Inv { kernels: kernels }
+	}
+}
+
+impl Writeable for Inv {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.kernels.len() as u32)?;
+		for k in &self.kernels {
+			k.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Inv {
+	fn read(reader: &mut Reader) -> Result<Inv, ser::Error> {
+		let len = reader.read_u32()?;
+		if len > MAX_INV_KERNELS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let kernels = (0..len).map(|_| Hash::read(reader)).collect::<Result<_, _>>()?;
+		Ok(Inv { kernels: kernels })
+	}
+}
+
+/// Placeholder for messages that don't send anything but the header, like
+/// Ping and Pong with peers older than PING_BODY_VERSION.
 pub struct Empty {}
 
 impl Writeable for Empty {
@@ -475,3 +1246,224 @@ impl Readable for Empty {
 		Ok(Empty {})
 	}
 }
+
+/// Heartbeat sent regularly to check the remote peer is still there, expects
+/// a Pong with the same nonce back.
+pub struct Ping {
+	/// total difficulty accumulated by the sender
+	pub total_difficulty: Difficulty,
+	/// randomly generated for each ping, echoed back in the pong
+	pub nonce: u64,
+}
+
+impl Writeable for Ping {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.total_difficulty.write(writer)?;
+		writer.write_u64(self.nonce)
+	}
+}
+
+impl Readable for Ping {
+	fn read(reader: &mut Reader) -> Result<Ping, ser::Error> {
+		let total_difficulty = Difficulty::read(reader)?;
+		let nonce = reader.read_u64()?;
+		Ok(Ping {
+			total_difficulty: total_difficulty,
+			nonce: nonce,
+		})
+	}
+}
+
+/// Reply to a Ping, with the nonce of the ping it answers.
+pub struct Pong {
+	/// total difficulty accumulated by the sender
+	pub total_difficulty: Difficulty,
+	/// nonce of the ping being answered
+	pub nonce: u64,
+}
+
+impl Writeable for Pong {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.total_difficulty.write(writer)?;
+		writer.write_u64(self.nonce)
+	}
+}
+
+impl Readable for Pong {
+	fn read(reader: &mut Reader) -> Result<Pong, ser::Error> {
+		let total_difficulty = Difficulty::read(reader)?;
+		let nonce = reader.read_u64()?;
+		Ok(Pong {
+			total_difficulty: total_difficulty,
+			nonce: nonce,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::core::build::{input_rand, output_rand, transaction, with_fee};
+	use core::core::hash::ZERO_HASH;
+	use rand::thread_rng;
+
+	#[test]
+	fn signed_peer_addr() {
+		let secp = Secp256k1::new();
+		let key = SecretKey::new(&secp, &mut thread_rng());
+		let addr = "10.0.0.1:13414".parse().unwrap();
+		let now = 1_500_000_000;
+
+		let signed = SignedPeerAddr::sign(&secp, addr, now, &key).unwrap();
+		assert!(signed.verify(&secp, now));
+		assert!(signed.verify(&secp, now + SIGNED_ADDR_MAX_DRIFT));
+		assert!(!signed.verify(&secp, now + SIGNED_ADDR_MAX_DRIFT + 1));
+
+		// survives serialization
+		let data = ser::ser_vec(&signed).unwrap();
+		let read = ser::deserialize::<SignedPeerAddr>(&mut &data[..]).unwrap();
+		assert_eq!(read.addr, addr);
+		assert!(read.verify(&secp, now));
+
+		// can't be pointed to another address
+		let mut forged = read;
+		forged.addr = "10.0.0.2:13414".parse().unwrap();
+		assert!(!forged.verify(&secp, now));
+	}
+
+	#[test]
+	fn hand_addr_pubkey() {
+		let secp = Secp256k1::new();
+		let key = SecretKey::new(&secp, &mut thread_rng());
+		let pubkey = PublicKey::from_secret_key(&secp, &key).unwrap();
+		let mut hand = Hand {
+			version: PROTOCOL_VERSION,
+			capabilities: FULL_NODE | SIGNED_ADDRS,
+			nonce: 1,
+			total_difficulty: Difficulty::one(),
+			sender_addr: SockAddr("10.0.0.1:13414".parse().unwrap()),
+			receiver_addr: SockAddr("10.0.0.2:13414".parse().unwrap()),
+			user_agent: USER_AGENT.to_string(),
+			addr_pubkey: Some(pubkey),
+		};
+		let data = ser::ser_vec(&hand).unwrap();
+		let read = ser::deserialize::<Hand>(&mut &data[..]).unwrap();
+		assert_eq!(read.addr_pubkey, Some(pubkey));
+
+		// peers not signing their address send no key
+		hand.capabilities = FULL_NODE;
+		hand.addr_pubkey = None;
+		let data = ser::ser_vec(&hand).unwrap();
+		let read = ser::deserialize::<Hand>(&mut &data[..]).unwrap();
+		assert_eq!(read.addr_pubkey, None);
+	}
+
+	#[test]
+	fn compact_block() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let txs = (0..5)
+			.map(|n| {
+				transaction(vec![input_rand(100 + n), output_rand(90), with_fee(10 + n)]).unwrap().0
+			})
+			.collect::<Vec<_>>();
+		let key = SecretKey::new(&secp, &mut thread_rng());
+		let b = Block::new(&BlockHeader::default(), txs.iter().collect(), key).unwrap();
+
+		let cb = CompactBlock::from_block(&b);
+		assert_eq!(cb.kern_ids.len(), 5);
+		let cb_data = ser::ser_vec(&cb).unwrap();
+		assert!(cb_data.len() * 4 < ser::ser_vec(&b).unwrap().len());
+
+		// survives serialization and gives back the full block
+		let read = ser::deserialize::<CompactBlock>(&mut &cb_data[..]).unwrap();
+		assert_eq!(read.hash(), b.hash());
+		let rebuilt = read.reconstruct(&txs).unwrap();
+		assert_eq!(rebuilt.hash(), b.hash());
+		assert_eq!(rebuilt.inputs, b.inputs);
+		assert_eq!(rebuilt.outputs, b.outputs);
+		assert_eq!(rebuilt.kernels.len(), b.kernels.len());
+
+		// missing or swapped transactions don't
+		assert!(read.reconstruct(&txs[1..]).is_none());
+		let mut swapped = txs.clone();
+		swapped.swap(0, 1);
+		assert!(read.reconstruct(&swapped).is_none());
+	}
+
+	#[test]
+	fn compressed_msg() {
+		let (tx, _) = transaction(vec![input_rand(100), output_rand(90), with_fee(10)]).unwrap();
+		let txs = BlockTransactions {
+			block_hash: ZERO_HASH,
+			txs: vec![tx.clone(), tx.clone(), tx],
+		};
+		let body = ser::ser_vec(&txs).unwrap();
+		let mut msg = ser::ser_vec(&MsgHeader::new(Type::BlockTransactions, body.len() as u64)).unwrap();
+		msg.extend_from_slice(&body);
+
+		// the same rangeproof repeated compresses well
+		let compressed = compress_msg(msg.clone(), 1024);
+		assert!(compressed.len() < msg.len());
+		let header = ser::deserialize::<MsgHeader>(&mut &compressed[..HEADER_LEN as usize]).unwrap();
+		assert!(header.compressed);
+		assert_eq!(header.msg_len, compressed.len() as u64 - HEADER_LEN);
+		assert_eq!(decompress_body(&compressed[HEADER_LEN as usize..]).unwrap(), body);
+
+		// small messages are left alone
+		assert_eq!(compress_msg(msg.clone(), msg.len()), msg);
+	}
+
+	#[test]
+	fn uncompressed_header() {
+		// same layout as peers predating compression expect: magic, type and
+		// body length
+		let header = ser::ser_vec(&MsgHeader::new(Type::BlockTransactions, 0x0102)).unwrap();
+		assert_eq!(header.len() as u64, HEADER_LEN);
+		assert_eq!(header,
+		           vec![0x1e, 0xc5, Type::BlockTransactions as u8, 0, 0, 0, 0, 0, 0, 0x01, 0x02]);
+
+		// the compression flag only takes the high bit of the type
+		let mut compressed = MsgHeader::new(Type::BlockTransactions, 0x0102);
+		compressed.compressed = true;
+		let data = ser::ser_vec(&compressed).unwrap();
+		assert_eq!(data[2], Type::BlockTransactions as u8 | 0x80);
+		assert_eq!(&data[3..], &header[3..]);
+		let read = ser::deserialize::<MsgHeader>(&mut &data[..]).unwrap();
+		assert_eq!(read.msg_type, Type::BlockTransactions);
+		assert!(read.compressed);
+	}
+
+	#[test]
+	fn peers_msg() {
+		let addr = "10.0.0.1:13414".parse().unwrap();
+		let peers = Peers { peers: vec![(SockAddr(addr), 1500000000)] };
+		let data = ser::ser_vec(&peers).unwrap();
+		let read = ser::deserialize::<Peers>(&mut &data[..]).unwrap();
+		assert_eq!(read.peers.len(), 1);
+		assert_eq!(read.peers[0].0 .0, addr);
+		assert_eq!(read.peers[0].1, 1500000000);
+
+		// no more than we'd ever send
+		let too_many = Peers { peers: (0..MAX_PEERS + 1).map(|_| (SockAddr(addr), 0)).collect() };
+		let data = ser::ser_vec(&too_many).unwrap();
+		assert!(ser::deserialize::<Peers>(&mut &data[..]).is_err());
+	}
+
+	#[test]
+	fn onion_addr() {
+		let addr = parse_peer_addr("expyuzz4wqqyqhjn.onion:13414").unwrap();
+		assert_eq!(addr.port(), 13414);
+		assert_eq!(addr_to_onion(&addr), Some("expyuzz4wqqyqhjn.onion".to_string()));
+
+		// survives serialization like any other address
+		let data = ser::ser_vec(&SockAddr(addr)).unwrap();
+		let read = ser::deserialize::<SockAddr>(&mut &data[..]).unwrap();
+		assert_eq!(read.0, addr);
+
+		assert_eq!(addr_to_onion(&"10.0.0.1:13414".parse().unwrap()), None);
+		assert_eq!(parse_peer_addr("10.0.0.1:13414"), "10.0.0.1:13414".parse().ok());
+		assert_eq!(parse_peer_addr("expyuzz4wqqyqhj1.onion:13414"), None);
+		assert_eq!(parse_peer_addr("expyuzz4wqqyqhjn.onion"), None);
+		assert_eq!(parse_peer_addr("grin.org:13414"), None);
+	}
+}
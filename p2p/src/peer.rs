@@ -14,6 +14,7 @@
 
 use std::net::SocketAddr;
 use std::sync::{RwLock, Arc};
+use std::time::Duration;
 
 use futures::Future;
 use tokio_core::net::TcpStream;
@@ -21,7 +22,9 @@ use tokio_core::net::TcpStream;
 use core::core;
 use core::core::hash::Hash;
 use core::core::target::Difficulty;
+use bloom::InvBloomFilter;
 use handshake::Handshake;
+use msg::{DisconnectReason, SignedPeerAddr, COMPRESSION_VERSION};
 use types::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -35,6 +38,7 @@ pub struct Peer {
 	pub info: PeerInfo,
 	proto: Box<Protocol>,
 	state: Arc<RwLock<State>>,
+	ban_reason: Arc<RwLock<Option<BanReason>>>,
 }
 
 unsafe impl Sync for Peer {}
@@ -46,15 +50,17 @@ impl Peer {
 	               capab: Capabilities,
 	               total_difficulty: Difficulty,
 	               self_addr: SocketAddr,
+	               addr: SocketAddr,
 	               hs: &Handshake)
 	               -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let connect_peer = hs.connect(capab, total_difficulty, self_addr, conn)
+		let connect_peer = hs.connect(capab, total_difficulty, self_addr, addr, conn)
 			.and_then(|(conn, proto, info)| {
 				Ok((conn,
 				    Peer {
 					info: info,
 					proto: Box::new(proto),
 					state: Arc::new(RwLock::new(State::Connected)),
+					ban_reason: Arc::new(RwLock::new(None)),
 				}))
 			});
 		Box::new(connect_peer)
@@ -73,21 +79,32 @@ impl Peer {
 					info: info,
 					proto: Box::new(proto),
 					state: Arc::new(RwLock::new(State::Connected)),
+					ban_reason: Arc::new(RwLock::new(None)),
 				}))
 			});
 		Box::new(hs_peer)
 	}
 
 	/// Main peer loop listening for messages and forwarding to the rest of the
-	/// system.
+	/// system. Large messages get compressed if the negotiated version allows
+	/// it and the remote peer understands it.
 	pub fn run(&self,
 	           conn: TcpStream,
-	           na: Arc<NetAdapter>)
+	           na: Arc<NetAdapter>,
+	           config: &P2PConfig)
 	           -> Box<Future<Item = (), Error = Error>> {
 
+		let compress_threshold = if self.info.version >= COMPRESSION_VERSION &&
+		                            self.info.capabilities.contains(COMPRESSION) {
+			Some(config.compression_threshold)
+		} else {
+			None
+		};
 		let addr = self.info.addr;
 		let state = self.state.clone();
-		Box::new(self.proto.handle(conn, na).then(move |res| {
+		let ban_reason = self.ban_reason.clone();
+		let handle = self.proto.handle(conn, na, config.max_queue_depth, compress_threshold);
+		Box::new(handle.then(move |res| {
 			// handle disconnection, standard disconnections aren't considered an error
 			let mut state = state.write().unwrap();
 			match res {
@@ -97,8 +114,10 @@ impl Peer {
 					Ok(())
 				}
 				Err(Error::Serialization(e)) => {
+					let reason = BanReason::ProtocolViolation { description: CORRUPTED_MESSAGE };
+					info!("Client {} corrupted, ban: {:?}.", addr, reason);
 					*state = State::Banned;
-					info!("Client {} corrupted, ban.", addr);
+					*ban_reason.write().unwrap() = Some(reason);
 					Err(Error::Serialization(e))
 				}
 				Err(_) => {
@@ -122,13 +141,54 @@ impl Peer {
 		*state == State::Banned
 	}
 
+	/// Why this peer has been banned, if it has been.
+	pub fn ban_reason(&self) -> Option<BanReason> {
+		self.ban_reason.read().unwrap().clone()
+	}
+
 	/// Bytes sent and received by this peer to the remote peer.
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
 		self.proto.transmitted_bytes()
 	}
 
-	pub fn send_ping(&self) -> Result<(), Error> {
-		self.proto.send_ping()
+	/// Messages waiting to be sent to the remote peer.
+	pub fn queue_depth(&self) -> usize {
+		self.proto.queue_depth()
+	}
+
+	/// Whether all messages sent to the remote peer have been written out to
+	/// the socket and flushed.
+	pub fn flushed(&self) -> bool {
+		self.proto.flushed()
+	}
+
+	/// Queueing delays of the last message sent to the remote peer, for
+	/// block, transaction and peer address relay.
+	pub fn queue_delays(&self) -> [u64; 3] {
+		self.proto.queue_delays()
+	}
+
+	pub fn send_ping(&self, total_difficulty: Difficulty) -> Result<(), Error> {
+		self.proto.send_ping(total_difficulty)
+	}
+
+	/// Pings the remote peer, disconnecting it if it didn't answer the last
+	/// ping within the provided timeout.
+	pub fn heartbeat(&self, total_difficulty: Difficulty, timeout: Duration) {
+		if self.proto.ping_timed_out(timeout) {
+			info!("No pong from {} in {}s, disconnecting.",
+			      self.info.addr,
+			      timeout.as_secs());
+			self.stop();
+		} else if let Err(e) = self.send_ping(total_difficulty) {
+			debug!("Could not ping {}: {:?}", self.info.addr, e);
+		}
+	}
+
+	/// Round trip time of the last ping answered by the remote peer, in
+	/// milliseconds.
+	pub fn last_rtt_ms(&self) -> Option<u64> {
+		*self.info.last_rtt_ms.read().unwrap()
 	}
 
 	/// Sends the provided block to the remote peer. The request may be dropped
@@ -138,6 +198,11 @@ impl Peer {
 		self.proto.send_block(b)
 	}
 
+	/// Sends the provided block to the remote peer as a compact block.
+	pub fn send_compact_block(&self, b: &core::Block) -> Result<(), Error> {
+		self.proto.send_compact_block(b)
+	}
+
 	pub fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
 		self.proto.send_header_request(locator)
 	}
@@ -152,6 +217,37 @@ impl Peer {
 		self.proto.send_peer_request(capab)
 	}
 
+	pub fn send_get_peers(&self, capab: Capabilities) -> Result<(), Error> {
+		debug!("Asking {} for more peers with last seen times.", self.info.addr);
+		self.proto.send_get_peers(capab)
+	}
+
+	pub fn send_filter_load(&self, filter: InvBloomFilter) -> Result<(), Error> {
+		self.proto.send_filter_load(filter)
+	}
+
+	pub fn send_filter_add(&self, kernel: Hash) -> Result<(), Error> {
+		self.proto.send_filter_add(kernel)
+	}
+
+	pub fn send_filter_clear(&self) -> Result<(), Error> {
+		self.proto.send_filter_clear()
+	}
+
+	pub fn send_checkpoint_request(&self, height: u64) -> Result<(), Error> {
+		debug!("Asking {} for its checkpoint at {}.", self.info.addr, height);
+		self.proto.send_checkpoint_request(height)
+	}
+
+	pub fn send_signed_addrs(&self, addrs: Vec<SignedPeerAddr>) -> Result<(), Error> {
+		self.proto.send_signed_addrs(addrs)
+	}
+
+	pub fn send_disconnect(&self, reason: DisconnectReason) -> Result<(), Error> {
+		debug!("Disconnecting from {}: {:?}.", self.info.addr, reason);
+		self.proto.send_disconnect(reason)
+	}
+
 	pub fn stop(&self) {
 		self.proto.close();
 	}
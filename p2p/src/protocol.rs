@@ -12,32 +12,71 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::{Mutex, Arc};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock, Arc};
+use std::time::{Duration, Instant};
 
 use futures::Future;
-use futures::sync::mpsc::UnboundedSender;
+use rand::{self, Rng};
 use tokio_core::net::TcpStream;
 
 use core::core;
 use core::core::hash::Hash;
+use core::core::target::Difficulty;
 use core::ser;
-use conn::TimeoutConnection;
+use bloom::{InvBloomFilter, tx_kernel_hash};
+use secp;
+use secp::key::PublicKey;
+use time;
+use conn::{ReplySender, TimeoutConnection};
 use msg::*;
+use store::Misbehavior;
 use types::*;
 use util::OneTime;
 
+/// Maximum number of compact blocks waiting on missing transactions from a
+/// peer.
+const MAX_PENDING_BLOCKS: usize = 8;
+
+/// Compact blocks received that are missing some transactions, along with the
+/// transactions already found for each, in block order.
+type PendingBlocks = HashMap<Hash, (CompactBlock, Vec<Option<core::Transaction>>)>;
+
+/// Nonce of the last ping sent that's still waiting on its pong, along with
+/// when it was sent.
+type PendingPing = Mutex<Option<(u64, Instant)>>;
+
 #[allow(dead_code)]
 pub struct ProtocolV1 {
 	conn: OneTime<TimeoutConnection>,
+	addr: SocketAddr,
+	version: u32,
+	addr_pubkey: Option<PublicKey>,
 
 	expected_responses: Mutex<Vec<(Type, Hash)>>,
+	pending_blocks: Arc<Mutex<PendingBlocks>>,
+	pending_ping: Arc<PendingPing>,
+	last_rtt_ms: Arc<RwLock<Option<u64>>>,
+	inv_filter: Arc<RwLock<Option<InvBloomFilter>>>,
 }
 
 impl ProtocolV1 {
-	pub fn new() -> ProtocolV1 {
+	pub fn new(addr: SocketAddr,
+	           version: u32,
+	           addr_pubkey: Option<PublicKey>,
+	           last_rtt_ms: Arc<RwLock<Option<u64>>>)
+	           -> ProtocolV1 {
 		ProtocolV1 {
 			conn: OneTime::new(),
+			addr: addr,
+			version: version,
+			addr_pubkey: addr_pubkey,
 			expected_responses: Mutex::new(vec![]),
+			pending_blocks: Arc::new(Mutex::new(HashMap::new())),
+			pending_ping: Arc::new(Mutex::new(None)),
+			last_rtt_ms: last_rtt_ms,
+			inv_filter: Arc::new(RwLock::new(None)),
 		}
 	}
 }
@@ -46,12 +85,34 @@ impl Protocol for ProtocolV1 {
 	/// Sets up the protocol reading, writing and closing logic.
 	fn handle(&self,
 	          conn: TcpStream,
-	          adapter: Arc<NetAdapter>)
+	          adapter: Arc<NetAdapter>,
+	          max_queue_depth: usize,
+	          compress_threshold: Option<usize>)
 	          -> Box<Future<Item = (), Error = Error>> {
 
-		let (conn, listener) = TimeoutConnection::listen(conn, move |sender, header, data| {
+		let addr = self.addr;
+		let version = self.version;
+		let addr_pubkey = self.addr_pubkey;
+		let pending = self.pending_blocks.clone();
+		let ping = self.pending_ping.clone();
+		let rtt = self.last_rtt_ms.clone();
+		let filter = self.inv_filter.clone();
+		let (conn, listener) = TimeoutConnection::listen(conn,
+		                                                 max_queue_depth,
+		                                                 compress_threshold,
+		                                                 move |sender, header, data| {
 			let adapt = adapter.as_ref();
-			handle_payload(adapt, sender, header, data)
+			handle_payload(adapt,
+			               addr,
+			               version,
+			               addr_pubkey,
+			               &pending,
+			               &ping,
+			               &rtt,
+			               &filter,
+			               sender,
+			               header,
+			               data)
 		});
 
 		self.conn.init(conn);
@@ -64,10 +125,50 @@ impl Protocol for ProtocolV1 {
 		self.conn.borrow().transmitted_bytes()
 	}
 
-	/// Sends a ping message to the remote peer. Will panic if handle has never
-	/// been called on this protocol.
-	fn send_ping(&self) -> Result<(), Error> {
-		self.send_request(Type::Ping, Type::Pong, &Empty {}, None)
+	/// Messages waiting to be sent.
+	fn queue_depth(&self) -> usize {
+		self.conn.borrow().queue_depth()
+	}
+
+	/// Everything sent has been written out.
+	fn flushed(&self) -> bool {
+		self.conn.borrow().flushed()
+	}
+
+	/// Queueing delay of the last message sent, per priority.
+	fn queue_delays(&self) -> [u64; 3] {
+		self.conn.borrow().queue_delays()
+	}
+
+	/// Sends a ping message to the remote peer, unless we're still waiting on
+	/// the pong of the last one. Peers older than PING_BODY_VERSION get a
+	/// ping without body, and no nonce. Will panic if handle has never been
+	/// called on this protocol.
+	fn send_ping(&self, total_difficulty: Difficulty) -> Result<(), Error> {
+		let mut pending = self.pending_ping.lock().unwrap();
+		if pending.is_some() {
+			return Ok(());
+		}
+		let nonce = rand::thread_rng().gen::<u64>();
+		if self.version < PING_BODY_VERSION {
+			self.send_msg(Type::Ping, &Empty {})?;
+		} else {
+			self.send_msg(Type::Ping,
+			              &Ping {
+				              total_difficulty: total_difficulty,
+				              nonce: nonce,
+			              })?;
+		}
+		*pending = Some((nonce, Instant::now()));
+		Ok(())
+	}
+
+	/// Whether our last ping has been waiting on its pong for too long.
+	fn ping_timed_out(&self, timeout: Duration) -> bool {
+		match *self.pending_ping.lock().unwrap() {
+			Some((_, sent)) => sent.elapsed() > timeout,
+			None => false,
+		}
 	}
 
 	/// Serializes and sends a block to our remote peer
@@ -75,8 +176,21 @@ impl Protocol for ProtocolV1 {
 		self.send_msg(Type::Block, b)
 	}
 
-	/// Serializes and sends a transaction to our remote peer
+	/// Serializes and sends a block to our remote peer, without the
+	/// transactions it expects to already have
+	fn send_compact_block(&self, b: &core::Block) -> Result<(), Error> {
+		self.send_msg(Type::CompactBlock, &CompactBlock::from_block(b))
+	}
+
+	/// Serializes and sends a transaction to our remote peer, unless the filter
+	/// it loaded says it already knows it
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
+		if let Some(ref filter) = *self.inv_filter.read().unwrap() {
+			let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+			if tx_kernel_hash(&secp, tx).map(|k| filter.contains(&k)).unwrap_or(false) {
+				return Ok(());
+			}
+		}
 		self.send_msg(Type::Transaction, tx)
 	}
 
@@ -98,9 +212,43 @@ impl Protocol for ProtocolV1 {
 		                  None)
 	}
 
+	fn send_get_peers(&self, capab: Capabilities) -> Result<(), Error> {
+		self.send_request(Type::GetPeers,
+		                  Type::Peers,
+		                  &GetPeers { capabilities: capab },
+		                  None)
+	}
+
+	fn send_filter_load(&self, filter: InvBloomFilter) -> Result<(), Error> {
+		self.send_msg(Type::FilterLoad, &FilterLoad { filter: filter })
+	}
+
+	fn send_filter_add(&self, kernel: Hash) -> Result<(), Error> {
+		self.send_msg(Type::FilterAdd, &FilterAdd { kernel: kernel })
+	}
+
+	fn send_filter_clear(&self) -> Result<(), Error> {
+		self.send_msg(Type::FilterClear, &FilterClear {})
+	}
+
+	fn send_checkpoint_request(&self, height: u64) -> Result<(), Error> {
+		self.send_request(Type::GetCheckpoint,
+		                  Type::Checkpoint,
+		                  &GetCheckpoint { height: height },
+		                  None)
+	}
+
+	fn send_signed_addrs(&self, addrs: Vec<SignedPeerAddr>) -> Result<(), Error> {
+		self.send_msg(Type::SignedPeerAddrs, &SignedPeerAddrs { peers: addrs })
+	}
+
+	fn send_disconnect(&self, reason: DisconnectReason) -> Result<(), Error> {
+		self.send_msg(Type::Disconnect, &Disconnect { reason: reason })
+	}
+
 	/// Close the connection to the remote peer
 	fn close(&self) {
-		// TODO some kind of shutdown signal
+		self.conn.borrow().close();
 	}
 }
 
@@ -120,20 +268,60 @@ impl ProtocolV1 {
 }
 
 fn handle_payload(adapter: &NetAdapter,
-                  sender: UnboundedSender<Vec<u8>>,
+                  addr: SocketAddr,
+                  version: u32,
+                  addr_pubkey: Option<PublicKey>,
+                  pending: &Mutex<PendingBlocks>,
+                  ping: &PendingPing,
+                  rtt: &RwLock<Option<u64>>,
+                  filter: &RwLock<Option<InvBloomFilter>>,
+                  sender: ReplySender,
                   header: MsgHeader,
                   buf: Vec<u8>)
                   -> Result<Option<Hash>, ser::Error> {
 	match header.msg_type {
 		Type::Ping => {
-			let data = ser::ser_vec(&MsgHeader::new(Type::Pong, 0))?;
-			sender.send(data).unwrap();
+			// peers older than PING_BODY_VERSION send empty pings, and expect
+			// empty pongs back
+			if version < PING_BODY_VERSION {
+				send_body(&sender, Type::Pong, &Empty {})?;
+				return Ok(None);
+			}
+			let ping = ser::deserialize::<Ping>(&mut &buf[..])?;
+			send_body(&sender,
+			          Type::Pong,
+			          &Pong {
+				          total_difficulty: adapter.total_difficulty(),
+				          nonce: ping.nonce,
+			          })?;
+			Ok(None)
+		}
+		Type::Pong => {
+			// peers older than PING_BODY_VERSION answer with an empty pong,
+			// without nonce, for whatever ping we sent
+			let pong_nonce = if version < PING_BODY_VERSION {
+				None
+			} else {
+				Some(ser::deserialize::<Pong>(&mut &buf[..])?.nonce)
+			};
+			let mut ping = ping.lock().unwrap();
+			let sent = match *ping {
+				Some((nonce, sent)) if pong_nonce.map(|n| n == nonce).unwrap_or(true) => Some(sent),
+				_ => None,
+			};
+			if let Some(sent) = sent {
+				let elapsed = sent.elapsed();
+				let ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+				*rtt.write().unwrap() = Some(ms);
+				*ping = None;
+			} else {
+				debug!("Unexpected pong from {}.", addr);
+			}
 			Ok(None)
 		}
-		Type::Pong => Ok(None),
 		Type::Transaction => {
 			let tx = ser::deserialize::<core::Transaction>(&mut &buf[..])?;
-			adapter.transaction_received(tx);
+			adapter.transaction_received(tx, addr);
 			Ok(None)
 		}
 		Type::GetBlock => {
@@ -154,7 +342,7 @@ fn handle_payload(adapter: &NetAdapter,
 		Type::Block => {
 			let b = ser::deserialize::<core::Block>(&mut &buf[..])?;
 			let bh = b.hash();
-			adapter.block_received(b);
+			adapter.block_received(b, addr);
 			Ok(Some(bh))
 		}
 		Type::GetHeaders => {
@@ -197,13 +385,190 @@ fn handle_payload(adapter: &NetAdapter,
 			Ok(None)
 		}
 		Type::PeerAddrs => {
+			// peers signing their own address could sign the ones they relay
 			let peer_addrs = ser::deserialize::<PeerAddrs>(&mut &buf[..])?;
-			adapter.peer_addrs_received(peer_addrs.peers.iter().map(|pa| pa.0).collect());
+			adapter.peer_addrs_received(peer_addrs.peers.iter().map(|pa| pa.0).collect(),
+			                            addr_pubkey.is_none());
+			Ok(None)
+		}
+		Type::GetPeers => {
+			let get_peers = ser::deserialize::<GetPeers>(&mut &buf[..])?;
+			let peers = adapter.find_peers(get_peers.capabilities);
+			send_body(&sender,
+			          Type::Peers,
+			          &Peers { peers: peers.into_iter().map(|(a, t)| (SockAddr(a), t)).collect() })?;
+			Ok(None)
+		}
+		Type::Peers => {
+			let peers = ser::deserialize::<Peers>(&mut &buf[..])?;
+			adapter.peers_received(peers.peers.into_iter().map(|(a, t)| (a.0, t)).collect(),
+			                       addr_pubkey.is_none());
+			Ok(None)
+		}
+		Type::SignedPeerAddrs => {
+			let signed_addrs = ser::deserialize::<SignedPeerAddrs>(&mut &buf[..])?;
+			let secp = secp::Secp256k1::with_caps(secp::ContextFlag::VerifyOnly);
+			let now = time::now_utc().to_timespec().sec as u64;
+			// the peer's own address has to be signed with the key it presented
+			// in the handshake, relayed ones with the key they carry
+			let (valid, invalid): (Vec<_>, Vec<_>) =
+				signed_addrs.peers.into_iter().partition(|sa| {
+					(sa.addr.ip() != addr.ip() || Some(sa.pubkey) == addr_pubkey) &&
+					sa.verify(&secp, now)
+				});
+			if invalid.len() > 0 {
+				debug!("Dropping {} peer addrs with bad signatures or not matching {}",
+				       invalid.len(),
+				       addr);
+			}
+			adapter.peer_addrs_received(valid.iter().map(|sa| sa.addr).collect(), true);
+			Ok(None)
+		}
+		Type::GetCheckpoint => {
+			let get_cp = ser::deserialize::<GetCheckpoint>(&mut &buf[..])?;
+			if let Some(cp) = adapter.get_checkpoint(get_cp.height) {
+				let mut body_data = vec![];
+				try!(ser::serialize(&mut body_data, &cp));
+				let mut data = vec![];
+				try!(ser::serialize(&mut data,
+				                    &MsgHeader::new(Type::Checkpoint, body_data.len() as u64)));
+				data.append(&mut body_data);
+				sender.send(data).unwrap();
+			}
+			Ok(None)
+		}
+		Type::Checkpoint => {
+			let cp = ser::deserialize::<Checkpoint>(&mut &buf[..])?;
+			adapter.checkpoint_received(cp, addr);
+			Ok(None)
+		}
+		Type::CompactBlock => {
+			let cb = ser::deserialize::<CompactBlock>(&mut &buf[..])?;
+			let bh = cb.hash();
+			let txs = adapter.get_transactions(&cb.kern_ids);
+			let missing = cb.kern_ids
+				.iter()
+				.zip(txs.iter())
+				.filter(|&(_, tx)| tx.is_none())
+				.map(|(id, _)| *id)
+				.collect::<Vec<_>>();
+			if missing.is_empty() {
+				let txs = txs.into_iter().filter_map(|tx| tx).collect::<Vec<_>>();
+				complete_compact_block(adapter, addr, sender, cb, txs)?;
+			} else {
+				debug!("Compact block {} from {} missing {} transactions.",
+				       bh,
+				       addr,
+				       missing.len());
+				{
+					let mut pending = pending.lock().unwrap();
+					if pending.len() >= MAX_PENDING_BLOCKS {
+						pending.clear();
+					}
+					pending.insert(bh, (cb, txs));
+				}
+				send_body(&sender,
+				          Type::GetBlockTransactions,
+				          &BlockTransactionsRequest {
+					          block_hash: bh,
+					          kern_ids: missing,
+				          })?;
+			}
+			Ok(Some(bh))
+		}
+		Type::GetBlockTransactions => {
+			let req = ser::deserialize::<BlockTransactionsRequest>(&mut &buf[..])?;
+			let txs = adapter.get_block_transactions(req.block_hash, &req.kern_ids);
+			send_body(&sender,
+			          Type::BlockTransactions,
+			          &BlockTransactions {
+				          block_hash: req.block_hash,
+				          txs: txs,
+			          })?;
+			Ok(None)
+		}
+		Type::BlockTransactions => {
+			let block_txs = ser::deserialize::<BlockTransactions>(&mut &buf[..])?;
+			let entry = pending.lock().unwrap().remove(&block_txs.block_hash);
+			if let Some((cb, found)) = entry {
+				// fill the gaps in block order with what we received
+				let mut received = block_txs.txs.into_iter();
+				let txs = found.into_iter()
+					.filter_map(|tx| tx.or_else(|| received.next()))
+					.collect::<Vec<_>>();
+				complete_compact_block(adapter, addr, sender, cb, txs)?;
+			}
+			Ok(None)
+		}
+		Type::FilterLoad => {
+			let load = ser::deserialize::<FilterLoad>(&mut &buf[..])?;
+			let mut kernels = adapter.pool_kernels();
+			kernels.truncate(MAX_INV_KERNELS as usize);
+			send_body(&sender, Type::Inv, &Inv::filtered(kernels, Some(&load.filter)))?;
+			*filter.write().unwrap() = Some(load.filter);
+			Ok(None)
+		}
+		Type::FilterAdd => {
+			let add = ser::deserialize::<FilterAdd>(&mut &buf[..])?;
+			if let Some(ref mut filter) = *filter.write().unwrap() {
+				filter.insert(&add.kernel);
+			}
+			Ok(None)
+		}
+		Type::FilterClear => {
+			*filter.write().unwrap() = None;
+			Ok(None)
+		}
+		Type::Inv => {
+			let inv = ser::deserialize::<Inv>(&mut &buf[..])?;
+			debug!("Peer {} advertises {} transactions.", addr, inv.kernels.len());
+			Ok(None)
+		}
+		Type::Disconnect => {
+			let disconnect = ser::deserialize::<Disconnect>(&mut &buf[..])?;
+			info!("Peer {} is disconnecting: {:?}", addr, disconnect.reason);
 			Ok(None)
 		}
 		_ => {
 			debug!("unknown message type {:?}", header.msg_type);
+			adapter.peer_misbehaved(addr, Misbehavior::UnexpectedMessage);
 			Ok(None)
 		}
 	}
 }
+
+/// Rebuilds a compact block out of its transactions and forwards it to the
+/// adapter. Falls back to requesting the full block if the transactions
+/// don't match.
+fn complete_compact_block(adapter: &NetAdapter,
+                          addr: SocketAddr,
+                          sender: ReplySender,
+                          cb: CompactBlock,
+                          txs: Vec<core::Transaction>)
+                          -> Result<(), ser::Error> {
+	match cb.reconstruct(&txs) {
+		Some(b) => adapter.block_received(b, addr),
+		None => {
+			let bh = cb.hash();
+			debug!("Could not rebuild compact block {} from {}, requesting it.",
+			       bh,
+			       addr);
+			send_body(&sender, Type::GetBlock, &bh)?;
+		}
+	}
+	Ok(())
+}
+
+/// Serializes and sends a message with the provided body to the remote peer.
+fn send_body<W: ser::Writeable>(sender: &ReplySender,
+                                t: Type,
+                                body: &W)
+                                -> Result<(), ser::Error> {
+	let mut body_data = vec![];
+	try!(ser::serialize(&mut body_data, body));
+	let mut data = vec![];
+	try!(ser::serialize(&mut data, &MsgHeader::new(t, body_data.len() as u64)));
+	data.append(&mut body_data);
+	sender.send(data).unwrap();
+	Ok(())
+}
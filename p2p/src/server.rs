@@ -16,33 +16,46 @@
 //! other peers in the network.
 
 use std::cell::RefCell;
+use std::io;
 use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use futures;
 use futures::{Future, Stream};
 use futures::future::{self, IntoFuture};
 use rand::{self, Rng};
+use secp::{ContextFlag, Secp256k1};
+use secp::key::{PublicKey, SecretKey};
+use time;
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor;
+use tokio_timer::{Timer, TimerError};
 
 use core::core;
 use core::core::hash::Hash;
 use core::core::target::Difficulty;
+use bloom::InvBloomFilter;
 use handshake::Handshake;
+use msg::{Checkpoint, DisconnectReason, ShortId, SignedPeerAddr};
 use peer::Peer;
+use socks::dial;
+use store::Misbehavior;
 use types::*;
 
+/// False positive rate of the transaction filters we load on our peers.
+const INV_FILTER_FP_RATE: f64 = 0.001;
+
 /// A no-op network adapter used for testing.
 pub struct DummyAdapter {}
 impl NetAdapter for DummyAdapter {
 	fn total_difficulty(&self) -> Difficulty {
 		Difficulty::one()
 	}
-	fn transaction_received(&self, _: core::Transaction) {}
-	fn block_received(&self, _: core::Block) {}
+	fn transaction_received(&self, _: core::Transaction, _: SocketAddr) {}
+	fn block_received(&self, _: core::Block, _: SocketAddr) {}
 	fn headers_received(&self, _: Vec<core::BlockHeader>) {}
 	fn locate_headers(&self, _: Vec<Hash>) -> Vec<core::BlockHeader> {
 		vec![]
@@ -50,11 +63,32 @@ impl NetAdapter for DummyAdapter {
 	fn get_block(&self, _: Hash) -> Option<core::Block> {
 		None
 	}
+	fn get_transactions(&self, kern_ids: &[ShortId]) -> Vec<Option<core::Transaction>> {
+		vec![None; kern_ids.len()]
+	}
+	fn get_block_transactions(&self, _: Hash, _: &[ShortId]) -> Vec<core::Transaction> {
+		vec![]
+	}
 	fn find_peer_addrs(&self, _: Capabilities) -> Vec<SocketAddr> {
 		vec![]
 	}
-	fn peer_addrs_received(&self, _: Vec<SocketAddr>) {}
+	fn peer_addrs_received(&self, _: Vec<SocketAddr>, _: bool) {}
+	fn pool_kernels(&self) -> Vec<Hash> {
+		vec![]
+	}
+	fn find_peers(&self, _: Capabilities) -> Vec<(SocketAddr, u64)> {
+		vec![]
+	}
+	fn peers_received(&self, _: Vec<(SocketAddr, u64)>, _: bool) {}
 	fn peer_connected(&self, _: &PeerInfo) {}
+	fn peer_misbehaved(&self, _: SocketAddr, _: Misbehavior) {}
+	fn is_banned(&self, _: SocketAddr) -> bool {
+		false
+	}
+	fn get_checkpoint(&self, _: u64) -> Option<Checkpoint> {
+		None
+	}
+	fn checkpoint_received(&self, _: Checkpoint, _: SocketAddr) {}
 }
 
 /// P2P server implementation, handling bootstrapping to find and connect to
@@ -65,6 +99,8 @@ pub struct Server {
 	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
 	adapter: Arc<NetAdapter>,
 	stop: RefCell<Option<futures::sync::oneshot::Sender<()>>>,
+	// key our own address gets signed with when advertised to peers
+	addr_key: SecretKey,
 }
 
 unsafe impl Sync for Server {}
@@ -72,14 +108,40 @@ unsafe impl Send for Server {}
 
 // TODO TLS
 impl Server {
-	/// Creates a new idle p2p server with no peers
+	/// Creates a new idle p2p server with no peers, signing its address with
+	/// a new key.
 	pub fn new(capab: Capabilities, config: P2PConfig, adapter: Arc<NetAdapter>) -> Server {
+		let secp = Secp256k1::with_caps(ContextFlag::SignOnly);
+		let mut rng = rand::thread_rng();
+		Server::with_addr_key(capab, config, adapter, SecretKey::new(&secp, &mut rng))
+	}
+
+	/// Creates a new idle p2p server with no peers, signing its address with
+	/// the provided key (see PeerStore::addr_key).
+	pub fn with_addr_key(capab: Capabilities,
+	                     config: P2PConfig,
+	                     adapter: Arc<NetAdapter>,
+	                     addr_key: SecretKey)
+	                     -> Server {
 		Server {
 			config: config,
-			capabilities: capab,
+			capabilities: capab | SIGNED_ADDRS | COMPACT_BLOCKS | INV_FILTER | COMPRESSION,
 			peers: Arc::new(RwLock::new(Vec::new())),
 			adapter: adapter,
 			stop: RefCell::new(None),
+			addr_key: addr_key,
+		}
+	}
+
+	// Handshake handler presenting the key our address is signed with.
+	fn handshake(&self) -> Handshake {
+		let secp = Secp256k1::with_caps(ContextFlag::SignOnly);
+		match PublicKey::from_secret_key(&secp, &self.addr_key) {
+			Ok(pubkey) => Handshake::with_addr_key(pubkey),
+			Err(e) => {
+				error!("Invalid address key, not signing our address: {:?}", e);
+				Handshake::new()
+			}
 		}
 	}
 
@@ -90,28 +152,47 @@ impl Server {
 		let socket = TcpListener::bind(&addr, &h.clone()).unwrap();
 		warn!("P2P server started on {}", addr);
 
-		let hs = Arc::new(Handshake::new());
+		let hs = Arc::new(self.handshake());
 		let peers = self.peers.clone();
 		let adapter = self.adapter.clone();
 		let capab = self.capabilities.clone();
+		let addr_key = self.addr_key;
+		let config = self.config;
 
 		// main peer acceptance future handling handshake
 		let hp = h.clone();
-		let peers = socket.incoming().map_err(From::from).map(move |(conn, _)| {
-			let adapter = adapter.clone();
-			let total_diff = adapter.total_difficulty();
-			let peers = peers.clone();
+		let ban_adapter = self.adapter.clone();
+		let peers = socket.incoming()
+			.map_err(From::from)
+			.filter(move |&(_, peer_addr)| {
+				// connections from banned peers are dropped right away, bans being
+				// by IP the ephemeral port the peer connects from doesn't matter
+				let banned = ban_adapter.is_banned(peer_addr);
+				if banned {
+					debug!("Refusing connection from banned peer {}.", peer_addr);
+				}
+				!banned
+			})
+			.map(move |(conn, _)| {
+				let adapter = adapter.clone();
+				let total_diff = adapter.total_difficulty();
+				let peers = peers.clone();
 
-			// accept the peer and add it to the server map
-			let accept = Peer::accept(conn, capab, total_diff, &hs.clone());
-			let added = add_to_peers(peers, adapter.clone(), accept);
+				// accept the peer and add it to the server map
+				let accept = Peer::accept(conn, capab, total_diff, &hs.clone());
+				let added = add_to_peers(peers, adapter.clone(), accept);
 
-			// wire in a future to timeout the accept after 5 secs
-			let timed_peer = with_timeout(Box::new(added), &hp);
+				// wire in a future to timeout the accept after 5 secs
+				let timed_peer = with_timeout(Box::new(added), &hp);
 
-			// run the main peer protocol
-			timed_peer.and_then(move |(conn, peer)| peer.clone().run(conn, adapter))
-		});
+				// run the main peer protocol
+				timed_peer.and_then(move |(conn, peer)| {
+					let local_addr = conn.local_addr();
+					let run = peer.clone().run(conn, adapter, &config);
+					announce_addr(&peer, local_addr, config.port, &addr_key);
+					run
+				})
+			});
 
 		// spawn each peer future to its own task
 		let hs = h.clone();
@@ -126,6 +207,24 @@ impl Server {
 			Ok(())
 		});
 
+		// regularly ping all our peers, dropping the ones that stopped answering
+		let hb_peers = self.peers.clone();
+		let hb_adapter = self.adapter.clone();
+		let ping_timeout = Duration::from_secs(self.config.ping_timeout_secs);
+		let heartbeat = Timer::default()
+			.interval(Duration::from_secs(self.config.ping_interval_secs))
+			.fold((), move |_, _| -> Result<(), TimerError> {
+				let total_diff = hb_adapter.total_difficulty();
+				for p in hb_peers.read().unwrap().iter() {
+					if p.is_connected() {
+						p.heartbeat(total_diff.clone(), ping_timeout);
+					}
+				}
+				Ok(())
+			})
+			.from_err();
+		let server = server.select(heartbeat).map(|_| ()).map_err(|(e, _)| e);
+
 		// setup the stopping oneshot on the server and join it with the peer future
 		let (stop, stop_rx) = futures::sync::oneshot::channel();
 		{
@@ -151,17 +250,25 @@ impl Server {
 			// asked to connect to ourselves
 			return Box::new(future::ok(None));
 		}
+		if self.adapter.is_banned(addr) {
+			debug!("Not connecting to banned peer {}.", addr);
+			return Box::new(future::ok(None));
+		}
 
 		// cloneapalooza
 		let peers = self.peers.clone();
 		let adapter1 = self.adapter.clone();
 		let adapter2 = self.adapter.clone();
+		let adapter3 = self.adapter.clone();
 		let capab = self.capabilities.clone();
 		let self_addr = SocketAddr::new(self.config.host, self.config.port);
+		let addr_key = self.addr_key;
+		let config = self.config;
+		let hs = self.handshake();
 
 		debug!("{} connecting to {}", self_addr, addr);
 
-		let socket = TcpStream::connect(&addr, &h).map_err(|e| Error::Connection(e));
+		let socket = dial(self.config.socks5_proxy, addr, &h).map_err(|e| Error::Connection(e));
 		let h2 = h.clone();
 		let request = socket.and_then(move |socket| {
 				let peers = peers.clone();
@@ -169,16 +276,22 @@ impl Server {
 
 				// connect to the peer and add it to the server map, wiring it a timeout for
 				// the handhake
-				let connect =
-					Peer::connect(socket, capab, total_diff, self_addr, &Handshake::new());
+				let connect = Peer::connect(socket, capab, total_diff, self_addr, addr, &hs);
 				let added = add_to_peers(peers, adapter1, connect);
 				with_timeout(Box::new(added), &h)
 			})
 			.and_then(move |(socket, peer)| {
-				h2.spawn(peer.run(socket, adapter2).map_err(|e| {
+				let local_addr = socket.local_addr();
+				h2.spawn(peer.run(socket, adapter2, &config).map_err(|e| {
 					error!("Peer error: {:?}", e);
 					()
 				}));
+				// through a proxy, our address is the proxy's and shouldn't be
+				// given away anyway
+				if config.socks5_proxy.is_none() {
+					announce_addr(&peer, local_addr, config.port, &addr_key);
+				}
+				load_inv_filter(&peer, adapter3.as_ref());
 				Ok(Some(peer))
 			});
 		Box::new(request)
@@ -223,6 +336,17 @@ impl Server {
 		rm
 	}
 
+	/// All the peers we're currently connected to.
+	pub fn connected_peers(&self) -> Vec<Arc<Peer>> {
+		self.peers
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|p| p.is_connected())
+			.cloned()
+			.collect()
+	}
+
 	/// Returns the peer with the most worked branch, showing the highest total
 	/// difficulty.
 	pub fn most_work_peer(&self) -> Option<Arc<Peer>> {
@@ -250,14 +374,40 @@ impl Server {
 		}
 	}
 
+	/// Returns a random peer among the connected ones with the lowest latency,
+	/// peers we don't have a round trip time for yet coming last.
+	pub fn low_latency_peer(&self) -> Option<Arc<Peer>> {
+		let mut peers = self.peers
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|p| p.is_connected())
+			.cloned()
+			.collect::<Vec<_>>();
+		if peers.len() == 0 {
+			return None;
+		}
+		peers.sort_by_key(|p| p.last_rtt_ms().unwrap_or(u64::max_value()));
+
+		// pick among the faster half so we don't always hit the same peer
+		let idx = rand::thread_rng().gen_range(0, (peers.len() + 1) / 2);
+		Some(peers[idx].clone())
+	}
+
 	/// Broadcasts the provided block to all our peers. A peer implementation
 	/// may drop the broadcast request if it knows the remote peer already has
 	/// the block.
+	/// Peers that understand compact blocks get one, others the full block.
 	pub fn broadcast_block(&self, b: &core::Block) {
 		let peers = self.peers.write().unwrap();
 		for p in peers.deref() {
 			if p.is_connected() {
-				if let Err(e) = p.send_block(b) {
+				let res = if p.info.capabilities.contains(COMPACT_BLOCKS) {
+					p.send_compact_block(b)
+				} else {
+					p.send_block(b)
+				};
+				if let Err(e) = res {
 					debug!("Error sending block to peer: {:?}", e);
 				}
 			}
@@ -277,6 +427,86 @@ impl Server {
 		}
 		self.stop.into_inner().unwrap().send(()).unwrap();
 	}
+
+	/// Stops the server, letting our peers know first. Each connected peer is
+	/// sent a Disconnect message and given its share of the provided timeout
+	/// for it, and whatever was queued before, to get written out and flushed
+	/// before all connections are closed, in order. Also stops accepting new
+	/// connections.
+	pub fn graceful_shutdown(&self, timeout: Duration) {
+		let peers = self.peers
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|p| p.is_connected())
+			.cloned()
+			.collect::<Vec<_>>();
+
+		if peers.len() > 0 {
+			let per_peer = timeout / peers.len() as u32;
+			for p in &peers {
+				if let Err(e) = p.send_disconnect(DisconnectReason::Shutdown) {
+					debug!("Could not send disconnect to {}: {:?}", p.info.addr, e);
+					continue;
+				}
+				let start = Instant::now();
+				while !p.flushed() && start.elapsed() < per_peer {
+					thread::sleep(Duration::from_millis(10));
+				}
+			}
+			for p in &peers {
+				p.stop();
+			}
+		}
+
+		if let Some(stop) = self.stop.borrow_mut().take() {
+			let _ = stop.send(());
+		}
+	}
+}
+
+// Sends our own address, freshly signed, to a peer that understands signed
+// addresses. Our address is the one the peer sees us on, the local address of
+// the connection (not the one we listen on, which may be unspecified) with
+// the port we listen on. Must only be called once the peer is running.
+fn announce_addr(peer: &Peer, local_addr: io::Result<SocketAddr>, port: u16, key: &SecretKey) {
+	if !peer.info.capabilities.contains(SIGNED_ADDRS) {
+		return;
+	}
+	let addr = match local_addr {
+		Ok(local_addr) if !local_addr.ip().is_unspecified() => SocketAddr::new(local_addr.ip(), port),
+		Ok(_) => return,
+		Err(e) => {
+			debug!("No local address to announce to {}: {:?}", peer.info.addr, e);
+			return;
+		}
+	};
+	let secp = Secp256k1::with_caps(ContextFlag::SignOnly);
+	let now = time::now_utc().to_timespec().sec as u64;
+	match SignedPeerAddr::sign(&secp, addr, now, key) {
+		Ok(signed) => {
+			if let Err(e) = peer.send_signed_addrs(vec![signed]) {
+				debug!("Could not announce our address to {}: {:?}", peer.info.addr, e);
+			}
+		}
+		Err(e) => error!("Could not sign our address: {:?}", e),
+	}
+}
+
+// Sends a filter of the transactions in our pool to a peer we just connected
+// to, so it only advertises us the ones we're missing.
+fn load_inv_filter(peer: &Peer, adapter: &NetAdapter) {
+	if !peer.info.capabilities.contains(INV_FILTER) {
+		return;
+	}
+	let kernels = adapter.pool_kernels();
+	let mut filter = InvBloomFilter::new(kernels.len(), INV_FILTER_FP_RATE);
+	for k in &kernels {
+		filter.insert(k);
+	}
+	if let Err(e) = peer.send_filter_load(filter) {
+		debug!("Could not send our filter to {}: {:?}", peer.info.addr, e);
+	}
 }
 
 // Adds the peer built by the provided future in the peers map
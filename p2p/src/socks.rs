@@ -0,0 +1,135 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Outbound connections through a SOCKS5 proxy (RFC 1928), typically Tor.
+//! Only the CONNECT command without authentication is supported, which is
+//! all Tor requires. Onion peers are resolved by the proxy, other peers are
+//! dialed directly if the proxy can't be reached.
+
+use std::io;
+use std::net::SocketAddr;
+
+use futures::Future;
+use futures::future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+
+use msg::addr_to_onion;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+type Connecting = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+/// Opens a TCP connection to the provided peer address, through the SOCKS5
+/// proxy if one is provided. Falls back to a direct connection when the proxy
+/// is unreachable, except for onion peers that can only be reached through
+/// the proxy.
+pub fn dial(proxy: Option<SocketAddr>, addr: SocketAddr, h: &Handle) -> Connecting {
+	let onion = addr_to_onion(&addr).is_some();
+	let proxy = match proxy {
+		Some(proxy) => proxy,
+		None if onion => return Box::new(future::err(proxy_error("onion peer without proxy"))),
+		None => return Box::new(TcpStream::connect(&addr, h)),
+	};
+
+	let h = h.clone();
+	Box::new(TcpStream::connect(&proxy, &h).then(move |res| -> Connecting {
+		match res {
+			Ok(conn) => connect(conn, addr),
+			Err(e) => {
+				if onion {
+					return Box::new(future::err(e));
+				}
+				warn!("SOCKS5 proxy {} unreachable ({}), connecting to {} directly.",
+				      proxy,
+				      e,
+				      addr);
+				Box::new(TcpStream::connect(&addr, &h))
+			}
+		}
+	}))
+}
+
+/// Negotiates a connection to the provided address with the SOCKS5 proxy
+/// we're connected to.
+fn connect(conn: TcpStream, addr: SocketAddr) -> Connecting {
+	let request = connect_request(addr);
+	let fut = write_all(conn, vec![SOCKS_VERSION, 1, NO_AUTH])
+		.and_then(|(conn, _)| read_exact(conn, [0u8; 2]))
+		.and_then(|(conn, resp)| {
+			if resp[0] != SOCKS_VERSION || resp[1] != NO_AUTH {
+				return Err(proxy_error("authentication required"));
+			}
+			Ok(conn)
+		})
+		.and_then(move |conn| write_all(conn, request))
+		// reply starts with the version, status, a reserved byte and the type
+		// of the address the proxy bound to
+		.and_then(|(conn, _)| read_exact(conn, [0u8; 4]))
+		.and_then(|(conn, resp)| -> Connecting {
+			if resp[1] != 0 {
+				let msg = format!("connection refused by proxy, status {}", resp[1]);
+				return Box::new(future::err(proxy_error(&msg)));
+			}
+			match resp[3] {
+				ATYP_IPV4 => skip_bound_addr(conn, 4),
+				ATYP_IPV6 => skip_bound_addr(conn, 16),
+				ATYP_DOMAIN => {
+					Box::new(read_exact(conn, [0u8; 1])
+						.and_then(|(conn, len)| skip_bound_addr(conn, len[0] as usize)))
+				}
+				_ => Box::new(future::err(proxy_error("unknown address type"))),
+			}
+		});
+	Box::new(fut)
+}
+
+/// The bound address and port aren't of any use to us.
+fn skip_bound_addr(conn: TcpStream, addr_len: usize) -> Connecting {
+	Box::new(read_exact(conn, vec![0u8; addr_len + 2]).map(|(conn, _)| conn))
+}
+
+/// CONNECT request to the provided address, onion peers are sent by name for
+/// the proxy to resolve.
+fn connect_request(addr: SocketAddr) -> Vec<u8> {
+	let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+	match (addr_to_onion(&addr), addr) {
+		(Some(host), _) => {
+			req.push(ATYP_DOMAIN);
+			req.push(host.len() as u8);
+			req.extend_from_slice(host.as_bytes());
+		}
+		(None, SocketAddr::V4(sav4)) => {
+			req.push(ATYP_IPV4);
+			req.extend_from_slice(&sav4.ip().octets());
+		}
+		(None, SocketAddr::V6(sav6)) => {
+			req.push(ATYP_IPV6);
+			req.extend_from_slice(&sav6.ip().octets());
+		}
+	}
+	req.push((addr.port() >> 8) as u8);
+	req.push(addr.port() as u8);
+	req
+}
+
+fn proxy_error(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, format!("SOCKS5: {}", msg))
+}
@@ -14,17 +14,33 @@
 
 //! Storage implementation for peer data.
 
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use num::FromPrimitive;
+use rand;
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+use time;
 
+use core::core::hash::Hash;
 use core::ser::{self, Readable, Writeable, Reader, Writer};
 use grin_store::{self, Error, to_key, option_to_not_found};
 use msg::SockAddr;
-use types::Capabilities;
+use types::{BanReason, Capabilities, P2PConfig, UNEXPECTED_MESSAGE};
 
 const STORE_SUBPATH: &'static str = "peers";
+const BAN_LOG_FILE: &'static str = "peer_bans.bin";
 
 const PEER_PREFIX: u8 = 'p' as u8;
+const BAN_PREFIX: u8 = 'b' as u8;
+const ADDR_KEY_PREFIX: u8 = 'k' as u8;
+
+/// How long a ban lasts, in seconds
+pub const BAN_WINDOW: i64 = 3 * 3600;
 
 /// Types of messages
 enum_from_primitive! {
@@ -33,6 +49,7 @@ enum_from_primitive! {
     Healthy,
     Banned,
     Defunct,
+    Untrusted,
   }
 }
 
@@ -48,6 +65,8 @@ pub struct PeerData {
 	pub user_agent: String,
 	/// State the peer has been detected with.
 	pub flags: State,
+	/// When the peer was last seen, in seconds since epoch.
+	pub last_seen: u64,
 }
 
 impl Writeable for PeerData {
@@ -56,7 +75,8 @@ impl Writeable for PeerData {
 		ser_multiwrite!(writer,
 		                [write_u32, self.capabilities.bits()],
 		                [write_bytes, &self.user_agent],
-		                [write_u8, self.flags as u8]);
+		                [write_u8, self.flags as u8],
+		                [write_u64, self.last_seen]);
 		Ok(())
 	}
 }
@@ -64,7 +84,8 @@ impl Writeable for PeerData {
 impl Readable for PeerData {
 	fn read(reader: &mut Reader) -> Result<PeerData, ser::Error> {
 		let addr = SockAddr::read(reader)?;
-		let (capab, ua, fl) = ser_multiread!(reader, read_u32, read_vec, read_u8);
+		let (capab, ua, fl, last_seen) =
+			ser_multiread!(reader, read_u32, read_vec, read_u8, read_u64);
 		let user_agent = String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData)?;
 		let capabilities = Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData)?;
 		match State::from_u8(fl) {
@@ -74,6 +95,7 @@ impl Readable for PeerData {
 					capabilities: capabilities,
 					user_agent: user_agent,
 					flags: flags,
+					last_seen: last_seen,
 				})
 			}
 			None => Err(ser::Error::CorruptedData),
@@ -81,16 +103,54 @@ impl Readable for PeerData {
 	}
 }
 
-/// Storage facility for peer data.
+/// Record of a ban, persisted alongside the peer data. Bans apply to all the
+/// connections from the banned peer's IP address, whatever their port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanData {
+	/// Network address of the banned peer
+	pub addr: SocketAddr,
+	/// Why the peer was banned
+	pub reason: BanReason,
+	/// Time (in seconds since epoch) at which the ban expires
+	pub expiry: i64,
+}
+
+impl Writeable for BanData {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		SockAddr(self.addr).write(writer)?;
+		self.reason.write(writer)?;
+		writer.write_i64(self.expiry)
+	}
+}
+
+impl Readable for BanData {
+	fn read(reader: &mut Reader) -> Result<BanData, ser::Error> {
+		let addr = SockAddr::read(reader)?;
+		let reason = BanReason::read(reader)?;
+		let expiry = reader.read_i64()?;
+		Ok(BanData {
+			addr: addr.0,
+			reason: reason,
+			expiry: expiry,
+		})
+	}
+}
+
+/// Storage facility for peer data. Every ban is also appended to the
+/// peer_bans.bin log, kept for diagnostics once the ban is lifted.
 pub struct PeerStore {
 	db: grin_store::Store,
+	ban_log_path: String,
 }
 
 impl PeerStore {
 	/// Instantiates a new peer store under the provided root path.
 	pub fn new(root_path: String) -> Result<PeerStore, Error> {
 		let db = grin_store::Store::open(format!("{}/{}", root_path, STORE_SUBPATH).as_str())?;
-		Ok(PeerStore { db: db })
+		Ok(PeerStore {
+			db: db,
+			ban_log_path: format!("{}/{}", root_path, BAN_LOG_FILE),
+		})
 	}
 
 	pub fn save_peer(&self, p: &PeerData) -> Result<(), Error> {
@@ -132,8 +192,369 @@ impl PeerStore {
 		peer.flags = new_state;
 		self.save_peer(&peer)
 	}
+
+	/// Bans the peer for the duration of the ban window, saving the reason
+	/// along with the expiry of the ban.
+	pub fn ban_peer(&self, peer_addr: SocketAddr, reason: BanReason) -> Result<(), Error> {
+		self.ban_peer_for(peer_addr, reason, BAN_WINDOW)
+	}
+
+	/// Bans the peer for the provided number of seconds.
+	pub fn ban_peer_for(&self,
+	                    peer_addr: SocketAddr,
+	                    reason: BanReason,
+	                    duration_secs: i64)
+	                    -> Result<(), Error> {
+		info!("Banning peer {} for {}s: {:?}", peer_addr, duration_secs, reason);
+		let expiry = time::now_utc().to_timespec().sec + duration_secs;
+		self.save_ban(peer_addr, reason, expiry)?;
+		self.update_state(peer_addr, State::Banned)
+	}
+
+	/// Saves a ban of the provided peer, expiring at the provided time (in
+	/// seconds since epoch), and appends it to the ban log.
+	pub fn save_ban(&self, peer_addr: SocketAddr, reason: BanReason, expiry: i64) -> Result<(), Error> {
+		let ban = BanData {
+			addr: peer_addr,
+			reason: reason,
+			expiry: expiry,
+		};
+		let mut log = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.ban_log_path)
+			.map_err(|e| Error::SerErr(ser::Error::IOErr(e)))?;
+		ser::serialize(&mut log, &ban).map_err(&Error::SerErr)?;
+		self.db.put_ser(&ban_key(peer_addr.ip())[..], &ban)
+	}
+
+	/// All the bans ever saved, oldest first, read back from the ban log.
+	/// Stops at a record left partially written.
+	pub fn ban_history(&self) -> Result<Vec<BanData>, Error> {
+		let mut data = vec![];
+		match File::open(&self.ban_log_path) {
+			Ok(mut file) => {
+				file.read_to_end(&mut data).map_err(|e| Error::SerErr(ser::Error::IOErr(e)))?;
+			}
+			Err(_) => return Ok(vec![]),
+		}
+		let mut bans = vec![];
+		let mut remaining = &data[..];
+		while !remaining.is_empty() {
+			match ser::deserialize::<BanData>(&mut remaining) {
+				Ok(ban) => bans.push(ban),
+				Err(_) => break,
+			}
+		}
+		Ok(bans)
+	}
+
+	/// Lifts the ban of the provided peer, which gets back to healthy.
+	pub fn lift_ban(&self, peer_addr: SocketAddr) -> Result<(), Error> {
+		debug!("Ban of peer {} expired, lifting it.", peer_addr);
+		self.db.delete(&ban_key(peer_addr.ip())[..])?;
+		match self.update_state(peer_addr, State::Healthy) {
+			Ok(_) | Err(Error::NotFoundErr) => Ok(()),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Gets the ban record for the IP address of the provided peer, if it has
+	/// been banned.
+	pub fn get_ban(&self, peer_addr: SocketAddr) -> Result<BanData, Error> {
+		option_to_not_found(self.db.get_ser(&ban_key(peer_addr.ip())[..]))
+	}
+
+	/// Key our own address gets signed with when advertised to peers,
+	/// generated the first time it's asked for and kept from then on so peers
+	/// know us by the same key across restarts.
+	pub fn addr_key(&self) -> Result<SecretKey, Error> {
+		let db_key = to_key(ADDR_KEY_PREFIX, &mut "addr".to_string().into_bytes());
+		let secp = Secp256k1::with_caps(secp::ContextFlag::None);
+		if let Some(bytes) = self.db.get(&db_key[..])? {
+			return SecretKey::from_slice(&secp, &bytes[..])
+				.map_err(|_| Error::SerErr(ser::Error::CorruptedData));
+		}
+		let key = SecretKey::new(&secp, &mut rand::thread_rng());
+		self.db.put(&db_key[..], key[..].to_vec())?;
+		Ok(key)
+	}
+
+	/// All the ban records, expired or not.
+	pub fn load_bans(&self) -> Vec<BanData> {
+		self.db.iter::<BanData>(&to_key(BAN_PREFIX, &mut "".to_string().into_bytes())).collect()
+	}
+
+	/// Removes the bans that have expired, peers getting back to healthy.
+	/// Returns the bans still active.
+	pub fn purge_expired_bans(&self) -> Result<Vec<BanData>, Error> {
+		let now = time::now_utc().to_timespec().sec;
+		let (active, expired): (Vec<_>, Vec<_>) =
+			self.load_bans().into_iter().partition(|ban| ban.expiry > now);
+		for ban in expired {
+			self.lift_ban(ban.addr)?;
+		}
+		Ok(active)
+	}
+}
+
+/// Ways a peer can misbehave, each adding to its misbehavior score.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+	/// Sent us the invalid block with the provided hash
+	InvalidBlock(Hash),
+	/// Sent us the invalid transaction with the provided hash
+	InvalidTransaction(Hash),
+	/// Sent us a message we didn't expect
+	UnexpectedMessage,
+}
+
+impl Misbehavior {
+	/// How much the misbehavior adds to the peer score.
+	pub fn score(&self) -> u32 {
+		match *self {
+			Misbehavior::InvalidBlock(_) => 50,
+			Misbehavior::InvalidTransaction(_) => 10,
+			Misbehavior::UnexpectedMessage => 1,
+		}
+	}
+
+	/// Reason of the ban of a peer whose score this misbehavior took over
+	/// the threshold.
+	pub fn ban_reason(&self) -> BanReason {
+		match *self {
+			Misbehavior::InvalidBlock(hash) => BanReason::BadBlock { hash: hash },
+			Misbehavior::InvalidTransaction(hash) => BanReason::BadTransaction { hash: hash },
+			Misbehavior::UnexpectedMessage => BanReason::ProtocolViolation { description: UNEXPECTED_MESSAGE },
+		}
+	}
+}
+
+/// Keeps track of how badly each peer behaves and bans the ones going over
+/// the threshold. Peers are scored by IP address, a peer coming back from
+/// another port being the same peer. Scores only live in memory, bans are
+/// persisted in the peer store.
+pub struct PeerScorer {
+	store: Arc<PeerStore>,
+	ban_threshold: u32,
+	ban_duration_secs: i64,
+	score_reset: Duration,
+	// score of each peer and when it last misbehaved
+	scores: RwLock<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl PeerScorer {
+	/// New scorer banning peers in the provided store, following the
+	/// thresholds of the p2p configuration.
+	pub fn new(store: Arc<PeerStore>, config: &P2PConfig) -> PeerScorer {
+		PeerScorer {
+			store: store,
+			ban_threshold: config.ban_threshold,
+			ban_duration_secs: config.ban_duration_secs,
+			score_reset: Duration::from_secs(config.score_reset_secs),
+			scores: RwLock::new(HashMap::new()),
+		}
+	}
+
+	/// Records a misbehavior of the provided peer, banning it if its score
+	/// goes over the threshold. Returns whether the peer got banned. Scores
+	/// of peers that behaved for a while are reset first.
+	pub fn misbehaved(&self, addr: SocketAddr, m: Misbehavior) -> bool {
+		let score = {
+			let mut scores = self.scores.write().unwrap();
+			let entry = scores.entry(addr.ip()).or_insert((0, Instant::now()));
+			if entry.1.elapsed() > self.score_reset {
+				entry.0 = 0;
+			}
+			entry.0 += m.score();
+			entry.1 = Instant::now();
+			entry.0
+		};
+		debug!("Peer {} misbehaved ({:?}), score now {}.", addr, m, score);
+		if score <= self.ban_threshold {
+			return false;
+		}
+
+		self.scores.write().unwrap().remove(&addr.ip());
+		match self.store.ban_peer_for(addr, m.ban_reason(), self.ban_duration_secs) {
+			Ok(_) | Err(Error::NotFoundErr) => {}
+			Err(e) => error!("Could not save ban of peer {}: {:?}", addr, e),
+		}
+		true
+	}
+
+	/// Current misbehavior score of the provided peer.
+	pub fn score(&self, addr: &SocketAddr) -> u32 {
+		match self.scores.read().unwrap().get(&addr.ip()) {
+			Some(&(score, at)) if at.elapsed() <= self.score_reset => score,
+			_ => 0,
+		}
+	}
+
+	/// Whether the provided peer is banned and shouldn't be connected to. An
+	/// expired ban gets lifted.
+	pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+		match self.store.get_ban(*addr) {
+			Ok(ban) => {
+				if ban.expiry > time::now_utc().to_timespec().sec {
+					return true;
+				}
+				if let Err(e) = self.store.lift_ban(ban.addr) {
+					error!("Could not lift ban of peer {}: {:?}", ban.addr, e);
+				}
+				false
+			}
+			Err(_) => false,
+		}
+	}
 }
 
 fn peer_key(peer_addr: SocketAddr) -> Vec<u8> {
 	to_key(PEER_PREFIX, &mut format!("{}", peer_addr).into_bytes())
 }
+
+fn ban_key(ip: IpAddr) -> Vec<u8> {
+	to_key(BAN_PREFIX, &mut format!("{}", ip).into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+	use std::fs;
+	use super::*;
+	use core::core::hash::ZERO_HASH;
+	use types::{CORRUPTED_MESSAGE, UNKNOWN};
+
+	#[test]
+	fn peer_scorer() {
+		let _ = fs::remove_dir_all("target/peer_scorer");
+		let store = Arc::new(PeerStore::new("target/peer_scorer".to_string()).unwrap());
+		let scorer = PeerScorer::new(store.clone(), &P2PConfig::default());
+
+		let addr = "10.0.0.1:13414".parse().unwrap();
+		store.save_peer(&PeerData {
+				addr: addr,
+				capabilities: UNKNOWN,
+				user_agent: "".to_string(),
+				flags: State::Healthy,
+				last_seen: 0,
+			})
+			.unwrap();
+
+		assert!(!scorer.misbehaved(addr, Misbehavior::InvalidBlock(ZERO_HASH)));
+		assert!(!scorer.misbehaved(addr, Misbehavior::InvalidTransaction(ZERO_HASH)));
+		assert!(!scorer.misbehaved(addr, Misbehavior::UnexpectedMessage));
+		assert_eq!(scorer.score(&addr), 61);
+		assert_eq!(scorer.score(&"10.0.0.1:41234".parse().unwrap()), 61);
+		assert!(!scorer.is_banned(&addr));
+
+		// over the threshold
+		assert!(scorer.misbehaved(addr, Misbehavior::InvalidBlock(ZERO_HASH)));
+		assert!(scorer.is_banned(&addr));
+		assert_eq!(store.get_peer(addr).unwrap().flags, State::Banned);
+		assert_eq!(store.get_ban(addr).unwrap().reason, BanReason::BadBlock { hash: ZERO_HASH });
+
+		// the ban and the score follow the IP, whatever the port
+		let same_ip = "10.0.0.1:41234".parse().unwrap();
+		assert!(scorer.is_banned(&same_ip));
+
+		// peers we never heard of can be banned too
+		let other = "10.0.0.2:13414".parse().unwrap();
+		assert!(!scorer.is_banned(&other));
+		scorer.misbehaved(other, Misbehavior::InvalidBlock(ZERO_HASH));
+		scorer.misbehaved(other, Misbehavior::InvalidBlock(ZERO_HASH));
+		assert!(scorer.misbehaved(other, Misbehavior::InvalidBlock(ZERO_HASH)));
+		assert!(scorer.is_banned(&other));
+
+		// scores of peers that behaved for long enough are reset
+		let forgiving = PeerScorer::new(store.clone(),
+		                                &P2PConfig { score_reset_secs: 0, ..P2PConfig::default() });
+		let third = "10.0.0.3:13414".parse().unwrap();
+		for _ in 0..5 {
+			assert!(!forgiving.misbehaved(third, Misbehavior::InvalidBlock(ZERO_HASH)));
+		}
+	}
+
+	#[test]
+	fn ban_persistence() {
+		let _ = fs::remove_dir_all("target/ban_persistence");
+		let banned = "10.0.0.1:13414".parse().unwrap();
+		let expired = "10.0.0.2:13414".parse().unwrap();
+		{
+			let store = PeerStore::new("target/ban_persistence".to_string()).unwrap();
+			for addr in vec![banned, expired] {
+				store.save_peer(&PeerData {
+						addr: addr,
+						capabilities: UNKNOWN,
+						user_agent: "".to_string(),
+						flags: State::Banned,
+						last_seen: 0,
+					})
+					.unwrap();
+			}
+			let now = time::now_utc().to_timespec().sec;
+			let reason = BanReason::ProtocolViolation { description: CORRUPTED_MESSAGE };
+			store.save_ban(banned, reason, now + 3600).unwrap();
+			store.save_ban(expired, BanReason::ManualBan, now - 1).unwrap();
+		}
+
+		// bans survive a restart, the expired ones get lifted
+		let store = PeerStore::new("target/ban_persistence".to_string()).unwrap();
+		assert_eq!(store.load_bans().len(), 2);
+		let active = store.purge_expired_bans().unwrap();
+		assert_eq!(active.len(), 1);
+		assert_eq!(active[0].addr, banned);
+		assert_eq!(store.load_bans(), active);
+		assert_eq!(store.get_peer(expired).unwrap().flags, State::Healthy);
+		assert_eq!(store.get_peer(banned).unwrap().flags, State::Banned);
+
+		// the ban log keeps them all, reasons included
+		let history = store.ban_history().unwrap();
+		assert_eq!(history.iter().map(|b| (b.addr, b.reason)).collect::<Vec<_>>(),
+		           vec![(banned, BanReason::ProtocolViolation { description: CORRUPTED_MESSAGE }),
+		                (expired, BanReason::ManualBan)]);
+	}
+
+	#[test]
+	fn ban_expiry() {
+		let _ = fs::remove_dir_all("target/ban_expiry");
+		let store = Arc::new(PeerStore::new("target/ban_expiry".to_string()).unwrap());
+		let scorer = PeerScorer::new(store.clone(), &P2PConfig::default());
+		let addr = "10.0.0.1:13414".parse().unwrap();
+		store.save_peer(&PeerData {
+				addr: addr,
+				capabilities: UNKNOWN,
+				user_agent: "".to_string(),
+				flags: State::Healthy,
+				last_seen: 0,
+			})
+			.unwrap();
+
+		// an expired ban is lifted as soon as it's checked
+		store.ban_peer_for(addr, BanReason::TooManyErrors, -1).unwrap();
+		assert_eq!(store.get_peer(addr).unwrap().flags, State::Banned);
+		assert!(!scorer.is_banned(&addr));
+		assert!(store.get_ban(addr).is_err());
+		assert_eq!(store.get_peer(addr).unwrap().flags, State::Healthy);
+	}
+
+	#[test]
+	fn untrusted_peers() {
+		let _ = fs::remove_dir_all("target/untrusted_peers");
+		let store = PeerStore::new("target/untrusted_peers".to_string()).unwrap();
+		let addr = "10.0.0.1:13414".parse().unwrap();
+		store.save_peer(&PeerData {
+				addr: addr,
+				capabilities: UNKNOWN,
+				user_agent: "".to_string(),
+				flags: State::Untrusted,
+				last_seen: 0,
+			})
+			.unwrap();
+
+		// kept apart from the healthy peers, until connected to
+		assert!(store.find_peers(State::Healthy, UNKNOWN, 10).is_empty());
+		assert_eq!(store.find_peers(State::Untrusted, UNKNOWN, 10)[0].addr, addr);
+		store.update_state(addr, State::Healthy).unwrap();
+		assert_eq!(store.find_peers(State::Healthy, UNKNOWN, 10)[0].addr, addr);
+	}
+}
@@ -15,7 +15,8 @@
 use std::convert::From;
 use std::io;
 use std::net::{SocketAddr, IpAddr};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use futures::Future;
 use tokio_core::net::TcpStream;
@@ -24,7 +25,11 @@ use tokio_timer::TimerError;
 use core::core;
 use core::core::hash::Hash;
 use core::core::target::Difficulty;
-use core::ser;
+use core::ser::{self, Readable, Writeable};
+use bloom::InvBloomFilter;
+use msg::{Checkpoint, DisconnectReason, ShortId, SignedPeerAddr};
+use secp::key::PublicKey;
+use store::Misbehavior;
 
 /// Maximum number of hashes in a block header locator request
 pub const MAX_LOCATORS: u32 = 10;
@@ -39,6 +44,13 @@ pub const MAX_BLOCK_BODIES: u32 = 16;
 /// Maximum number of peer addresses a peer should ever send
 pub const MAX_PEER_ADDRS: u32 = 256;
 
+/// Maximum number of peer addresses, with when they were last seen, a peer
+/// should ever send in a Peers message
+pub const MAX_PEERS: u32 = 1000;
+
+/// Maximum number of transaction kernels a peer should ever advertise at once
+pub const MAX_INV_KERNELS: u32 = 10000;
+
 #[derive(Debug)]
 pub enum Error {
 	Serialization(ser::Error),
@@ -63,11 +75,177 @@ impl From<TimerError> for Error {
 	}
 }
 
+/// Protocol violation of a peer sending a message that can't be read.
+pub const CORRUPTED_MESSAGE: &'static str = "corrupted message";
+/// Protocol violation of a peer sending messages we didn't ask for.
+pub const UNEXPECTED_MESSAGE: &'static str = "unexpected message";
+
+// Descriptions of the protocol violations we ban for, the ones read back from
+// storage being matched against them.
+const PROTOCOL_VIOLATIONS: &'static [&'static str] = &[CORRUPTED_MESSAGE, UNEXPECTED_MESSAGE];
+
+/// Why a peer got banned. Logged when the ban happens and persisted with the
+/// ban so it can be looked at later on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BanReason {
+	/// The peer sent us an invalid block
+	BadBlock {
+		/// Hash of the offending block
+		hash: Hash,
+	},
+	/// The peer sent us an invalid transaction
+	BadTransaction {
+		/// Hash of the offending transaction
+		hash: Hash,
+	},
+	/// The peer didn't follow the protocol (i.e. sent corrupted messages)
+	ProtocolViolation {
+		/// What the peer did wrong, one of the violations defined above
+		description: &'static str,
+	},
+	/// Banned by the node operator
+	ManualBan,
+	/// The peer produced too many errors
+	TooManyErrors,
+}
+
+impl ser::Writeable for BanReason {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		match *self {
+			BanReason::BadBlock { ref hash } => {
+				writer.write_u8(0)?;
+				hash.write(writer)
+			}
+			BanReason::BadTransaction { ref hash } => {
+				writer.write_u8(1)?;
+				hash.write(writer)
+			}
+			BanReason::ProtocolViolation { description } => {
+				writer.write_u8(2)?;
+				writer.write_bytes(&description.as_bytes())
+			}
+			BanReason::ManualBan => writer.write_u8(3),
+			BanReason::TooManyErrors => writer.write_u8(4),
+		}
+	}
+}
+
+impl ser::Readable for BanReason {
+	fn read(reader: &mut ser::Reader) -> Result<BanReason, ser::Error> {
+		match reader.read_u8()? {
+			0 => Ok(BanReason::BadBlock { hash: Hash::read(reader)? }),
+			1 => Ok(BanReason::BadTransaction { hash: Hash::read(reader)? }),
+			2 => {
+				let desc = reader.read_vec()?;
+				let description = PROTOCOL_VIOLATIONS.iter()
+					.find(|d| d.as_bytes() == &desc[..])
+					.ok_or(ser::Error::CorruptedData)?;
+				Ok(BanReason::ProtocolViolation { description: *description })
+			}
+			3 => Ok(BanReason::ManualBan),
+			4 => Ok(BanReason::TooManyErrors),
+			_ => Err(ser::Error::CorruptedData),
+		}
+	}
+}
+
+/// Default number of messages queued for a peer past which low priority
+/// messages get dropped.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 512;
+
+/// Default misbehavior score past which a peer gets banned.
+pub const DEFAULT_BAN_THRESHOLD: u32 = 100;
+
+/// Default duration of a misbehavior ban, in seconds.
+pub const DEFAULT_BAN_DURATION_SECS: i64 = 24 * 3600;
+
+/// Default time, in seconds, after which the score of a peer that stopped
+/// misbehaving is reset.
+pub const DEFAULT_SCORE_RESET_SECS: u64 = 3600;
+
+/// Default size in bytes past which message bodies get compressed, smaller
+/// ones aren't worth the CPU.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Default number of connected peers under which we ask for more.
+pub const DEFAULT_MIN_PEERS: u32 = 8;
+
+/// Default interval between pings to a peer, in seconds.
+pub const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+
+/// Default time, in seconds, a peer has to answer our ping.
+pub const DEFAULT_PING_TIMEOUT_SECS: u64 = 60;
+
 /// Configuration for the peer-to-peer server.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct P2PConfig {
 	pub host: IpAddr,
 	pub port: u16,
+	/// Number of messages queued for a peer past which transactions and peer
+	/// addresses aren't sent anymore. Peers falling twice as far behind get
+	/// disconnected.
+	#[serde(default = "default_max_queue_depth")]
+	pub max_queue_depth: usize,
+	/// SOCKS5 proxy (i.e. Tor) outbound connections are routed through,
+	/// required to connect to onion peers.
+	pub socks5_proxy: Option<SocketAddr>,
+	/// Misbehavior score past which a peer gets banned
+	#[serde(default = "default_ban_threshold")]
+	pub ban_threshold: u32,
+	/// How long a peer going over the misbehavior threshold is banned for,
+	/// in seconds
+	#[serde(default = "default_ban_duration_secs")]
+	pub ban_duration_secs: i64,
+	/// Time, in seconds, without misbehaving after which a peer score is
+	/// reset
+	#[serde(default = "default_score_reset_secs")]
+	pub score_reset_secs: u64,
+	/// Size in bytes past which message bodies sent to peers understanding
+	/// it get compressed
+	#[serde(default = "default_compression_threshold")]
+	pub compression_threshold: usize,
+	/// Number of connected peers under which we ask our peers for more
+	#[serde(default = "default_min_peers")]
+	pub min_peers: u32,
+	/// How often peers are pinged, in seconds
+	#[serde(default = "default_ping_interval_secs")]
+	pub ping_interval_secs: u64,
+	/// Time, in seconds, after which a peer that didn't answer our ping gets
+	/// disconnected
+	#[serde(default = "default_ping_timeout_secs")]
+	pub ping_timeout_secs: u64,
+}
+
+fn default_max_queue_depth() -> usize {
+	DEFAULT_MAX_QUEUE_DEPTH
+}
+
+fn default_ban_threshold() -> u32 {
+	DEFAULT_BAN_THRESHOLD
+}
+
+fn default_ban_duration_secs() -> i64 {
+	DEFAULT_BAN_DURATION_SECS
+}
+
+fn default_score_reset_secs() -> u64 {
+	DEFAULT_SCORE_RESET_SECS
+}
+
+fn default_compression_threshold() -> usize {
+	DEFAULT_COMPRESSION_THRESHOLD
+}
+
+fn default_min_peers() -> u32 {
+	DEFAULT_MIN_PEERS
+}
+
+fn default_ping_interval_secs() -> u64 {
+	DEFAULT_PING_INTERVAL_SECS
+}
+
+fn default_ping_timeout_secs() -> u64 {
+	DEFAULT_PING_TIMEOUT_SECS
 }
 
 /// Default address for peer-to-peer connections.
@@ -77,6 +255,15 @@ impl Default for P2PConfig {
 		P2PConfig {
 			host: ipaddr,
 			port: 13414,
+			max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+			socks5_proxy: None,
+			ban_threshold: DEFAULT_BAN_THRESHOLD,
+			ban_duration_secs: DEFAULT_BAN_DURATION_SECS,
+			score_reset_secs: DEFAULT_SCORE_RESET_SECS,
+			compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+			min_peers: DEFAULT_MIN_PEERS,
+			ping_interval_secs: DEFAULT_PING_INTERVAL_SECS,
+			ping_timeout_secs: DEFAULT_PING_TIMEOUT_SECS,
 		}
 	}
 }
@@ -94,6 +281,15 @@ bitflags! {
     const UTXO_HIST = 0b00000010,
     /// Can provide a list of healthy peers
     const PEER_LIST = 0b00000100,
+    /// Understands peer addresses signed by the peer they belong to
+    const SIGNED_ADDRS = 0b00001000,
+    /// Relays and understands compact blocks
+    const COMPACT_BLOCKS = 0b00010000,
+    /// Accepts a filter of the transactions we know and only advertises the
+    /// ones missing from it
+    const INV_FILTER = 0b00100000,
+    /// Understands LZ4 compressed message bodies
+    const COMPRESSION = 0b01000000,
 
     const FULL_NODE = FULL_HIST.bits | UTXO_HIST.bits | PEER_LIST.bits,
   }
@@ -107,6 +303,10 @@ pub struct PeerInfo {
 	pub version: u32,
 	pub addr: SocketAddr,
 	pub total_difficulty: Difficulty,
+	/// Round trip time of the last ping answered by the peer, in milliseconds
+	pub last_rtt_ms: Arc<RwLock<Option<u64>>>,
+	/// Key the peer signs its address with, if it has SIGNED_ADDRS
+	pub addr_pubkey: Option<PublicKey>,
 }
 
 /// A given communication protocol agreed upon between 2 peers (usually
@@ -117,17 +317,30 @@ pub trait Protocol {
 	/// be  known already, usually passed during construction. Will typically
 	/// block so needs to be called withing a coroutine. Should also be called
 	/// only once.
+	/// Messages larger than the compression threshold, if provided, get
+	/// compressed.
 	fn handle(&self,
 	          conn: TcpStream,
-	          na: Arc<NetAdapter>)
+	          na: Arc<NetAdapter>,
+	          max_queue_depth: usize,
+	          compress_threshold: Option<usize>)
 	          -> Box<Future<Item = (), Error = Error>>;
 
-	/// Sends a ping message to the remote peer.
-	fn send_ping(&self) -> Result<(), Error>;
+	/// Sends a ping message to the remote peer, unless the last one hasn't
+	/// been answered yet.
+	fn send_ping(&self, total_difficulty: Difficulty) -> Result<(), Error>;
+
+	/// Whether the last ping sent has gone unanswered for longer than the
+	/// provided timeout.
+	fn ping_timed_out(&self, timeout: Duration) -> bool;
 
 	/// Relays a block to the remote peer.
 	fn send_block(&self, b: &core::Block) -> Result<(), Error>;
 
+	/// Relays a block to the remote peer as a compact block, without the
+	/// transactions it's expected to have in its pool.
+	fn send_compact_block(&self, b: &core::Block) -> Result<(), Error>;
+
 	/// Relays a transaction to the remote peer.
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
 
@@ -140,9 +353,41 @@ pub trait Protocol {
 	/// Sends a request for some peer addresses.
 	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error>;
 
+	/// Sends a request for some peer addresses along with when they were last
+	/// seen.
+	fn send_get_peers(&self, capab: Capabilities) -> Result<(), Error>;
+
+	/// Loads a filter of the transaction kernels we know on the remote peer.
+	fn send_filter_load(&self, filter: InvBloomFilter) -> Result<(), Error>;
+
+	/// Adds a transaction kernel to the filter loaded on the remote peer.
+	fn send_filter_add(&self, kernel: Hash) -> Result<(), Error>;
+
+	/// Removes the filter loaded on the remote peer.
+	fn send_filter_clear(&self) -> Result<(), Error>;
+
+	/// Sends a request for the checkpoint at the provided height.
+	fn send_checkpoint_request(&self, height: u64) -> Result<(), Error>;
+
+	/// Sends peer addresses signed by the peers they belong to.
+	fn send_signed_addrs(&self, addrs: Vec<SignedPeerAddr>) -> Result<(), Error>;
+
+	/// Tells the remote peer we're about to close the connection and why.
+	fn send_disconnect(&self, reason: DisconnectReason) -> Result<(), Error>;
+
 	/// How many bytes have been sent/received to/from the remote peer.
 	fn transmitted_bytes(&self) -> (u64, u64);
 
+	/// How many messages are waiting to be sent to the remote peer.
+	fn queue_depth(&self) -> usize;
+
+	/// Whether everything sent to the remote peer has been written out.
+	fn flushed(&self) -> bool;
+
+	/// How long, in milliseconds, the last message sent to the remote peer
+	/// waited in the queue of each priority, highest first.
+	fn queue_delays(&self) -> [u64; 3];
+
 	/// Close the connection to the remote peer.
 	fn close(&self);
 }
@@ -154,11 +399,12 @@ pub trait NetAdapter: Sync + Send {
 	/// Current height of our chain.
 	fn total_difficulty(&self) -> Difficulty;
 
-	/// A valid transaction has been received from one of our peers
-	fn transaction_received(&self, tx: core::Transaction);
+	/// A valid transaction has been received from the peer at the provided
+	/// address
+	fn transaction_received(&self, tx: core::Transaction, addr: SocketAddr);
 
-	/// A block has been received from one of our peers
-	fn block_received(&self, b: core::Block);
+	/// A block has been received from the peer at the provided address
+	fn block_received(&self, b: core::Block, addr: SocketAddr);
 
 	/// A set of block header has been received, typically in response to a
 	/// block
@@ -173,13 +419,46 @@ pub trait NetAdapter: Sync + Send {
 	/// Gets a full block by its hash.
 	fn get_block(&self, h: Hash) -> Option<core::Block>;
 
+	/// Looks up the transactions with the provided kernel short ids in our
+	/// pool, in the same order. None for the ones we don't have.
+	fn get_transactions(&self, kern_ids: &[ShortId]) -> Vec<Option<core::Transaction>>;
+
+	/// Gets the transactions of a recently seen block matching the provided
+	/// kernel short ids, skipping the ones we don't have.
+	fn get_block_transactions(&self, h: Hash, kern_ids: &[ShortId]) -> Vec<core::Transaction>;
+
 	/// Find good peers we know with the provided capability and return their
 	/// addresses.
 	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr>;
 
-	/// A list of peers has been received from one of our peers.
-	fn peer_addrs_received(&self, Vec<SocketAddr>);
+	/// A list of peers has been received from one of our peers. Untrusted
+	/// addresses are unsigned ones sent by a peer that signs its own.
+	fn peer_addrs_received(&self, Vec<SocketAddr>, trusted: bool);
+
+	/// Kernel hashes of the transactions in our pool.
+	fn pool_kernels(&self) -> Vec<Hash>;
+
+	/// Find good peers we know with the provided capability and return their
+	/// addresses along with when they were last seen.
+	fn find_peers(&self, capab: Capabilities) -> Vec<(SocketAddr, u64)>;
+
+	/// A list of peers, with when they were last seen, has been received from
+	/// one of our peers. Untrusted as above.
+	fn peers_received(&self, Vec<(SocketAddr, u64)>, trusted: bool);
 
 	/// Network successfully connected to a peer.
 	fn peer_connected(&self, &PeerInfo);
+
+	/// The peer at the provided address didn't behave.
+	fn peer_misbehaved(&self, addr: SocketAddr, m: Misbehavior);
+
+	/// Whether the peer at the provided address is banned, connections to
+	/// and from it being refused.
+	fn is_banned(&self, addr: SocketAddr) -> bool;
+
+	/// Builds our checkpoint at the provided height, if we have it.
+	fn get_checkpoint(&self, height: u64) -> Option<Checkpoint>;
+
+	/// A checkpoint has been received from the peer at the provided address.
+	fn checkpoint_received(&self, cp: Checkpoint, addr: SocketAddr);
 }
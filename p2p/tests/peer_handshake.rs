@@ -57,13 +57,15 @@ fn peer_handshake() {
 					              p2p::UNKNOWN,
 					              Difficulty::one(),
 					              my_addr,
+					              addr,
 					              &p2p::handshake::Handshake::new())
 				})
 				.and_then(move |(socket, peer)| {
-					rhandle.spawn(peer.run(socket, net_adapter.clone()).map_err(|e| {
+					let run = peer.run(socket, net_adapter.clone(), &p2p_conf);
+					rhandle.spawn(run.map_err(|e| {
 						panic!("Client run failed: {:?}", e);
 					}));
-					peer.send_ping().unwrap();
+					peer.send_ping(Difficulty::one()).unwrap();
 					timeout_send.from_err().map(|_| peer)
 				})
 				.and_then(|peer| {
@@ -0,0 +1,147 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::Future;
+use futures::stream::Stream;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{self, Core, Handle};
+
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Connects to a server, which answers pings, and to a silent peer, which
+// goes through the handshake but never reads anything after. Heartbeats
+// record the round trip time of the pongs of the server and disconnect the
+// silent peer once its pong is late.
+#[test]
+fn peer_heartbeat() {
+	let mut evtlp = Core::new().unwrap();
+	let handle = evtlp.handle();
+	let mut p2p_conf = p2p::P2PConfig::default();
+	p2p_conf.port = 5010;
+	let net_adapter = Arc::new(p2p::DummyAdapter {});
+	let server = p2p::Server::new(p2p::UNKNOWN, p2p_conf, net_adapter.clone());
+	let run_server = server.start(handle.clone());
+	let my_addr = "127.0.0.1:5012".parse().unwrap();
+	let server_addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+
+	// the silent peer keeps its connection open long after the test is done
+	let silent_addr: SocketAddr = "127.0.0.1:5011".parse().unwrap();
+	let listener = TcpListener::bind(&silent_addr, &handle).unwrap();
+	let shandle = handle.clone();
+	handle.spawn(listener.incoming()
+		.into_future()
+		.map_err(|(e, _)| p2p::Error::Connection(e))
+		.and_then(|(conn, _)| {
+			let (conn, _) = conn.unwrap();
+			p2p::handshake::Handshake::new().handshake(p2p::UNKNOWN, Difficulty::one(), conn)
+		})
+		.and_then(move |(conn, _, _)| sleep(&shandle, 10_000).map(move |_| drop(conn)))
+		.map_err(|e| {
+			panic!("Silent peer failed: {:?}", e);
+		}));
+
+	let h = handle.clone();
+	let adapter = net_adapter.clone();
+	handle.spawn(sleep(&handle, 1000)
+		.and_then({
+			let h = h.clone();
+			move |_| connect(&h, my_addr, server_addr)
+		})
+		.and_then({
+			let h = h.clone();
+			move |(socket, peer)| {
+				h.spawn(peer.run(socket, adapter, &p2p_conf).map_err(|e| {
+					panic!("Server peer run failed: {:?}", e);
+				}));
+				assert_eq!(peer.last_rtt_ms(), None);
+				peer.heartbeat(Difficulty::one(), Duration::from_secs(1));
+				sleep(&h, 500).map(|_| peer)
+			}
+		})
+		.and_then({
+			let h = h.clone();
+			move |peer| {
+				// answered, the round trip time is known
+				assert!(peer.is_connected());
+				assert!(peer.last_rtt_ms().is_some());
+				connect(&h, my_addr, silent_addr)
+			}
+		})
+		.and_then({
+			let h = h.clone();
+			move |(socket, peer)| {
+				h.spawn(peer.run(socket, net_adapter, &p2p_conf).map_err(|_| ()));
+				peer.heartbeat(Difficulty::one(), Duration::from_millis(200));
+				sleep(&h, 100).map(|_| peer)
+			}
+		})
+		.and_then({
+			let h = h.clone();
+			move |peer| {
+				// still within the timeout, nothing happens
+				peer.heartbeat(Difficulty::one(), Duration::from_millis(200));
+				assert!(peer.is_connected());
+				sleep(&h, 400).map(|_| peer)
+			}
+		})
+		.and_then({
+			let h = h.clone();
+			move |peer| {
+				peer.heartbeat(Difficulty::one(), Duration::from_millis(200));
+				sleep(&h, 200).map(|_| peer)
+			}
+		})
+		.and_then(move |peer| {
+			// late, disconnected without a round trip time
+			assert!(!peer.is_connected());
+			assert_eq!(peer.last_rtt_ms(), None);
+			server.stop();
+			Ok(())
+		})
+		.map_err(|e| {
+			panic!("Heartbeat test failed: {:?}", e);
+		}));
+
+	evtlp.run(run_server).unwrap();
+}
+
+fn connect(h: &Handle,
+           my_addr: SocketAddr,
+           addr: SocketAddr)
+           -> Box<Future<Item = (TcpStream, Peer), Error = p2p::Error>> {
+	Box::new(TcpStream::connect(&addr, h)
+		.map_err(|e| p2p::Error::Connection(e))
+		.and_then(move |socket| {
+			Peer::connect(socket,
+			              p2p::UNKNOWN,
+			              Difficulty::one(),
+			              my_addr,
+			              addr,
+			              &p2p::handshake::Handshake::new())
+		}))
+}
+
+fn sleep(h: &Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+	Box::new(reactor::Timeout::new(Duration::from_millis(ms), h).unwrap().from_err())
+}
@@ -0,0 +1,121 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc;
+use std::thread;
+
+use futures::future::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::{read_exact, write_all};
+
+// Mock SOCKS5 proxy accepting a single connection. Reports the host it was
+// asked to connect to and then plays the part of the remote peer by echoing
+// whatever it receives.
+fn mock_proxy() -> (SocketAddr, mpsc::Receiver<String>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+
+		let mut greeting = [0u8; 3];
+		conn.read_exact(&mut greeting).unwrap();
+		assert_eq!(greeting, [5, 1, 0]);
+		conn.write_all(&[5, 0]).unwrap();
+
+		let mut req = [0u8; 4];
+		conn.read_exact(&mut req).unwrap();
+		assert_eq!(req[..3], [5, 1, 0]);
+		let host = match req[3] {
+			3 => {
+				let mut len = [0u8; 1];
+				conn.read_exact(&mut len).unwrap();
+				let mut name = vec![0u8; len[0] as usize];
+				conn.read_exact(&mut name).unwrap();
+				String::from_utf8(name).unwrap()
+			}
+			1 => {
+				let mut ip = [0u8; 4];
+				conn.read_exact(&mut ip).unwrap();
+				format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
+			}
+			atyp => panic!("unexpected address type {}", atyp),
+		};
+		let mut port = [0u8; 2];
+		conn.read_exact(&mut port).unwrap();
+		let port = (port[0] as u16) << 8 | port[1] as u16;
+		tx.send(format!("{}:{}", host, port)).unwrap();
+		conn.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+		let mut buf = [0u8; 5];
+		conn.read_exact(&mut buf).unwrap();
+		conn.write_all(&buf).unwrap();
+	});
+	(addr, rx)
+}
+
+// A closed port to stand for an unreachable proxy.
+fn unreachable_addr() -> SocketAddr {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	listener.local_addr().unwrap()
+}
+
+fn echo_through(core: &mut Core, proxy: Option<SocketAddr>, addr: SocketAddr) -> Vec<u8> {
+	let fut = p2p::dial(proxy, addr, &core.handle())
+		.and_then(|conn| write_all(conn, b"hello".to_vec()))
+		.and_then(|(conn, _)| read_exact(conn, vec![0u8; 5]))
+		.map(|(_, buf)| buf);
+	core.run(fut).unwrap()
+}
+
+#[test]
+fn socks5_onion_peer() {
+	let mut core = Core::new().unwrap();
+	let (proxy, hosts) = mock_proxy();
+
+	let onion = p2p::parse_peer_addr("expyuzz4wqqyqhjn.onion:13414").unwrap();
+	assert_eq!(echo_through(&mut core, Some(proxy), onion), b"hello".to_vec());
+	assert_eq!(hosts.recv().unwrap(), "expyuzz4wqqyqhjn.onion:13414");
+
+	// onion peers can't be reached without the proxy
+	assert!(core.run(p2p::dial(None, onion, &core.handle())).is_err());
+	assert!(core.run(p2p::dial(Some(unreachable_addr()), onion, &core.handle())).is_err());
+}
+
+#[test]
+fn socks5_direct_fallback() {
+	let mut core = Core::new().unwrap();
+
+	// regular peer behind a proxy that can be reached
+	let (proxy, hosts) = mock_proxy();
+	let peer = "10.0.0.1:13414".parse().unwrap();
+	assert_eq!(echo_through(&mut core, Some(proxy), peer), b"hello".to_vec());
+	assert_eq!(hosts.recv().unwrap(), "10.0.0.1:13414");
+
+	// and when the proxy can't be reached, the peer is dialed directly
+	let (peer, _) = mock_proxy();
+	let unreachable = unreachable_addr();
+	let fut = p2p::dial(Some(unreachable), peer, &core.handle())
+		.and_then(|conn| write_all(conn, vec![5, 1, 0]))
+		.and_then(|(conn, _)| read_exact(conn, [0u8; 2]));
+	let (_, resp) = core.run(fut).unwrap();
+	assert_eq!(resp, [5, 0]);
+}
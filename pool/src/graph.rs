@@ -44,7 +44,7 @@ impl PoolEntry {
     /// Create new transaction pool entry
     pub fn new(tx: &core::transaction::Transaction) -> PoolEntry {
         PoolEntry{
-            transaction_hash: transaction_identifier(tx),
+            transaction_hash: tx.canonical_id(),
             size_estimate : estimate_transaction_size(tx),
             receive_ts: time::now_utc()}
     }
@@ -207,14 +207,15 @@ impl DirectedGraph {
     pub fn get_roots(&self) -> Vec<core::hash::Hash> {
         self.roots.iter().map(|x| x.transaction_hash).collect()
     }
-}
 
-/// Using transaction merkle_inputs_outputs to calculate a deterministic hash;
-/// this hashing mechanism has some ambiguity issues especially around range
-/// proofs and any extra data the kernel may cover, but it is used initially
-/// for testing purposes.
-pub fn transaction_identifier(tx: &core::transaction::Transaction) -> core::hash::Hash {
-    core::transaction::merkle_inputs_outputs(&tx.inputs, &tx.outputs)
+    /// Get the vertices (root + internal) received before the provided
+    /// time, in seconds since the epoch
+    pub fn get_received_before(&self, cutoff: i64) -> Vec<core::hash::Hash> {
+        self.roots.iter().chain(self.vertices.iter()).
+            filter(|x| x.receive_ts.to_timespec().sec < cutoff).
+            map(|x| x.transaction_hash).
+            collect()
+    }
 }
 
 #[cfg(test)]
@@ -28,10 +28,17 @@ mod pool;
 
 extern crate time;
 extern crate rand;
+#[macro_use]
 extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 extern crate grin_core as core;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 extern crate secp256k1zkp as secp;
 
 pub use pool::TransactionPool;
-pub use types::{BlockChain, TxSource, PoolError};
+pub use types::{BlockChain, TxSource, PoolError, PoolConfig};
@@ -14,30 +14,45 @@
 
 //! Top-level Pool type, methods, and tests
 
-use types::{Pool, BlockChain, Orphans, Parent, PoolError, TxSource, TransactionGraphContainer};
+use types::{Pool, BlockChain, Orphans, Parent, PoolConfig, PoolError, TxSource, TransactionGraphContainer};
 pub use graph;
 
 use core::core::transaction;
 use core::core::block;
-use core::core::hash;
+use core::core::hash::{self, Hashed};
+use core::core::Committed;
 use core::consensus;
+use core::ser;
 
 use secp;
 use secp::pedersen::Commitment;
 
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+
+/// Version of the format pool dumps are written in.
+const POOL_DUMP_VERSION: u16 = 1;
+
+/// Number of buckets of the fee histogram fees are estimated with.
+const FEE_ESTIMATE_BUCKETS: usize = 20;
 
 /// The pool itself.
 /// The transactions HashMap holds ownership of all transactions in the pool,
 /// keyed by their transaction hash.
 pub struct TransactionPool<T> {
+    /// Pool configuration
+    pub config: PoolConfig,
     /// All transactions in the pool
     pub transactions: HashMap<hash::Hash, Box<transaction::Transaction>>,
     /// The pool itself
     pub pool : Pool,
     /// Orphans in the pool
     pub orphans: Orphans,
+    /// Number of transactions evicted for staying too long in the pool
+    pub evicted_count: u64,
 
     // blockchain is a DummyChain, for now, which mimics what the future
     // chain will offer to the pool
@@ -46,11 +61,13 @@ pub struct TransactionPool<T> {
 
 impl<T> TransactionPool<T> where T: BlockChain {
     /// Create a new transaction pool
-    pub fn new(chain: Arc<T>) -> TransactionPool<T> {
+    pub fn new(config: PoolConfig, chain: Arc<T>) -> TransactionPool<T> {
         TransactionPool{
+            config: config,
             transactions: HashMap::new(),
             pool: Pool::empty(),
             orphans: Orphans::empty(),
+            evicted_count: 0,
             blockchain: chain,
         }
     }
@@ -120,20 +137,32 @@ impl<T> TransactionPool<T> where T: BlockChain {
         let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
         tx.validate(&secp).map_err(|_| PoolError::Invalid)?;
 
+        // Cheap transactions are rejected before looking any further.
+        let fee_rate = tx.fee_rate();
+        if fee_rate < self.config.min_fee_rate {
+            return Err(PoolError::FeeTooLow{
+                actual: fee_rate,
+                minimum: self.config.min_fee_rate,
+            })
+        }
+
         // The first check involves ensuring that an identical transaction is
         // not already in the pool's transaction set.
         // A non-authoritative similar check should be performed under the
         // pool's read lock before we get to this point, which would catch the
         // majority of duplicate cases. The race condition is caught here.
-        // TODO: When the transaction identifier is finalized, the assumptions
-        // here may change depending on the exact coverage of the identifier.
-        // The current tx.hash() method, for example, does not cover changes
-        // to fees or other elements of the signature preimage.
-        let tx_hash = graph::transaction_identifier(&tx);
+        // The canonical id covers the fee and signature along with the
+        // inputs and outputs, regardless of their ordering.
+        let tx_hash = tx.canonical_id();
         if self.transactions.contains_key(&tx_hash) {
             return Err(PoolError::AlreadyInPool)
         }
 
+        // A transaction spending outputs already spent in the pool can only
+        // get in by replacing the transactions spending them, see
+        // replaced_by. Nothing gets removed until all the checks passed.
+        let (replaced, conflict) = self.replaced_by(&tx, fee_rate);
+
         // The next issue is to identify all unspent outputs that
         // this transaction will consume and make sure they exist in the set.
         let mut pool_refs: Vec<graph::Edge> = Vec::new();
@@ -166,11 +195,30 @@ impl<T> TransactionPool<T> where T: BlockChain {
                     blockchain_refs.push(base);
                 },
                 Parent::Unknown => orphan_refs.push(base),
-                Parent::AlreadySpent{other_tx: x} => return Err(PoolError::DoubleSpend{other_tx: x, spent_output: input.commitment()}),
+                Parent::AlreadySpent{other_tx: x} => {
+                    if !replaced.contains_key(&x) {
+                        return Err(PoolError::DoubleSpend{other_tx: x, spent_output: input.commitment()});
+                    }
+                    // spent by a replaced transaction, the output gets
+                    // available again
+                    match self.pool.get_internal_spent_output(&input.commitment()) {
+                        Some(edge) => {
+                            let source = edge.source_hash();
+                            if source.map(|h| replaced.contains_key(&h)).unwrap_or(false) {
+                                return Err(PoolError::DoubleSpend{other_tx: x, spent_output: input.commitment()});
+                            }
+                            pool_refs.push(base.with_source(source));
+                        },
+                        None => blockchain_refs.push(base),
+                    }
+                },
             }
         }
 
         let is_orphan = orphan_refs.len() > 0;
+        if is_orphan && replaced.len() > 0 {
+            return Err(conflict.unwrap());
+        }
 
         // Next we examine the outputs this transaction creates and ensure
         // that they do not already exist.
@@ -179,7 +227,10 @@ impl<T> TransactionPool<T> where T: BlockChain {
         // with strict ordering. In the future, if desirable, this could
         // be node policy config or more intelligent.
         for output in &tx.outputs {
-            self.check_duplicate_outputs(output, is_orphan)?
+            match self.check_duplicate_outputs(output, is_orphan) {
+                Err(PoolError::DuplicateOutput{other_tx: Some(x), ..}) if replaced.contains_key(&x) => {},
+                res => res?,
+            }
         }
 
         // Assertion: we have exactly as many resolved spending references as
@@ -198,6 +249,9 @@ impl<T> TransactionPool<T> where T: BlockChain {
             // In the non-orphan (pool) case, we've ensured that every input
             // maps one-to-one with an unspent (available) output, and each
             // output is unique. No further checks are necessary.
+            for replaced_tx in self.sweep_transactions(replaced) {
+                info!("Replaced transaction {} in the pool by {}.", replaced_tx.canonical_id(), tx_hash);
+            }
             self.pool.add_pool_transaction(pool_entry, blockchain_refs,
                 pool_refs, new_unspents);
 
@@ -238,6 +292,29 @@ impl<T> TransactionPool<T> where T: BlockChain {
 
     }
 
+    /// Pool transactions the provided one would replace: the ones spending
+    /// the same outputs, along with all the transactions depending on them.
+    /// A transaction can only replace others paying a strictly lower fee
+    /// rate, for a sender to bump the fee of a transaction stuck in the pool.
+    /// When it can't, nothing is replaced and the double spend it would be
+    /// is returned along.
+    fn replaced_by(&self, tx: &transaction::Transaction, fee_rate: u64) -> (HashMap<hash::Hash, ()>, Option<PoolError>) {
+        let mut replaced = HashMap::new();
+        let mut conflict = None;
+        for input in &tx.inputs {
+            if let Parent::AlreadySpent{other_tx} = self.search_for_best_output(&input.commitment()) {
+                if conflict.is_none() {
+                    conflict = Some(PoolError::DoubleSpend{other_tx: other_tx, spent_output: input.commitment()});
+                }
+                self.mark_transaction(other_tx, &mut replaced);
+            }
+        }
+        if replaced.keys().any(|h| self.transactions[h].fee_rate() >= fee_rate) {
+            replaced.clear();
+        }
+        (replaced, conflict)
+    }
+
     /// Check the output for a conflict with an existing output.
     ///
     /// Checks the output (by commitment) against outputs in the blockchain
@@ -412,6 +489,64 @@ impl<T> TransactionPool<T> where T: BlockChain {
         Ok(freed_txs)
     }
 
+    /// Writes all the pool transactions to the file at the provided path,
+    /// preceded by the version of the dump format. Transactions come after
+    /// the ones they spend from, orphans aren't kept.
+    pub fn dump(&self, path: &str) -> io::Result<()> {
+        let mut serializer = ser::StreamingSerializer::new(BufWriter::new(File::create(path)?));
+        serializer.write_item(&POOL_DUMP_VERSION).map_err(to_io_error)?;
+        for h in self.parents_first() {
+            let tx = self.transactions.get(&h).unwrap();
+            serializer.write_item(tx.as_ref()).map_err(to_io_error)?;
+        }
+        serializer.into_inner().flush()
+    }
+
+    /// Reads the transactions previously dumped to the file at the provided
+    /// path and adds them back to the pool. They go through the same checks
+    /// as any new transaction, the ones that aren't valid anymore against
+    /// the current chain state are discarded. Returns the number of
+    /// transactions restored.
+    pub fn restore(&mut self, path: &str) -> io::Result<usize> {
+        let mut file = BufReader::new(File::open(path)?);
+        let version: u16 = ser::deserialize(&mut file).map_err(to_io_error)?;
+        if version != POOL_DUMP_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Unsupported pool dump version {}", version)));
+        }
+
+        let mut restored = 0;
+        for tx in ser::StreamingDeserializer::<_, transaction::Transaction>::new(file) {
+            let tx = tx.map_err(to_io_error)?;
+            let source = TxSource {
+                debug_name: "restore".to_string(),
+                identifier: path.to_string(),
+            };
+            if self.add_to_memory_pool(source, tx).is_ok() {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Evicts the transactions received more than ttl_secs before the
+    /// provided time, in seconds since the epoch, along with the
+    /// transactions spending their outputs. Returns the evicted
+    /// transactions.
+    pub fn evict_expired(&mut self, current_time: u64, ttl_secs: u64) -> Vec<Box<transaction::Transaction>> {
+        let cutoff = current_time.saturating_sub(ttl_secs) as i64;
+        let mut marked_transactions: HashMap<hash::Hash, ()> = HashMap::new();
+        for txh in self.pool.get_received_before(cutoff) {
+            self.mark_transaction(txh, &mut marked_transactions);
+        }
+        let evicted = self.sweep_transactions(marked_transactions);
+        for tx in &evicted {
+            info!("Evicted expired transaction {} from the pool.", tx.canonical_id());
+        }
+        self.evicted_count += evicted.len() as u64;
+        evicted
+    }
+
     /// The mark portion of our mark-and-sweep pool cleanup.
     ///
     /// The transaction designated by conflicting_tx is immediately marked.
@@ -470,10 +605,190 @@ impl<T> TransactionPool<T> where T: BlockChain {
 
     /// Fetch mineable transactions.
     ///
-    /// Select a set of mineable transactions for block building.
+    /// Select a set of mineable transactions for block building, the ones
+    /// paying the highest fee rate first.
     pub fn prepare_mineable_transactions(&self, num_to_fetch: u32) -> Vec<Box<transaction::Transaction>>{
-        self.pool.get_mineable_transactions(num_to_fetch).iter().
-            map(|x| self.transactions.get(x).unwrap().clone()).collect()
+        let mut txs = self.pool.get_mineable_transactions(u32::max_value()).iter().
+            map(|x| self.transactions.get(x).unwrap().clone()).
+            collect::<Vec<_>>();
+        // mineable transactions don't depend on each other, any order works
+        txs.sort_by(|a, b| b.fee_rate().cmp(&a.fee_rate()));
+        txs.truncate(num_to_fetch as usize);
+        txs
+    }
+
+    /// Select the transactions paying the highest fee rate for a block of
+    /// at most the provided weight.
+    ///
+    /// A transaction spending the outputs of other pool transactions can
+    /// only be selected along with its ancestors, so it's ranked by the fee
+    /// rate of the whole package: a child paying a high fee pulls in its
+    /// parents (child pays for parent). Packages are picked greedily until
+    /// no other fits, ties being broken by the hash of the transaction
+    /// excess so all miners build the same template out of the same pool.
+    /// Transactions are returned parents first.
+    ///
+    /// Selected transactions aren't merged with cut-through: a transaction
+    /// only carries the signature of its own excess, so two of them can't be
+    /// merged into a valid one. The cut-through is only applied once they're
+    /// aggregated in a block, which keeps all kernels.
+    pub fn select_for_block(&self, max_weight: u64) -> Vec<Box<transaction::Transaction>> {
+        let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+        let parents = self.pool_parents();
+        let excess_hashes = self.transactions.iter().
+            map(|(h, tx)| {
+                // pool transactions have been validated, their excess sums
+                let excess_hash = tx.sum_commitments(&secp).
+                    map(|excess| excess.hash()).
+                    unwrap_or(hash::ZERO_HASH);
+                (*h, excess_hash)
+            }).
+            collect::<HashMap<_, _>>();
+
+        let mut weight = 0;
+        let mut selected = vec![];
+        let mut selected_set = HashSet::new();
+        loop {
+            // the package with the best fee rate that still fits, as
+            // (fee, weight, excess hash, package)
+            let mut best: Option<(u64, u64, hash::Hash, Vec<hash::Hash>)> = None;
+            for h in self.transactions.keys() {
+                if selected_set.contains(h) {
+                    continue;
+                }
+                let mut package = vec![];
+                add_with_ancestors(*h, &parents, &selected_set, &mut package);
+                let (fee, package_weight) = package.iter().
+                    map(|p| self.transactions.get(p).unwrap()).
+                    fold((0, 0), |(f, w), tx| (f + tx.fee, w + tx.weight()));
+                if weight + package_weight > max_weight {
+                    continue;
+                }
+                let excess_hash = excess_hashes[h];
+                let better = match best {
+                    None => true,
+                    Some((best_fee, best_weight, best_hash, _)) => {
+                        let (rate, best_rate) = (fee * best_weight, best_fee * package_weight);
+                        rate > best_rate || (rate == best_rate && excess_hash < best_hash)
+                    }
+                };
+                if better {
+                    best = Some((fee, package_weight, excess_hash, package));
+                }
+            }
+            match best {
+                Some((_, package_weight, _, package)) => {
+                    weight += package_weight;
+                    for p in package {
+                        selected_set.insert(p);
+                        selected.push(self.transactions.get(&p).unwrap().clone());
+                    }
+                }
+                None => break,
+            }
+        }
+        selected
+    }
+
+    /// Histogram of the fee rates paid by the pool transactions, split in
+    /// the provided number of buckets of equal width covering the range
+    /// from the lowest to the highest fee rate. Each bucket is the lowest
+    /// fee rate it covers along with the number of transactions in it, in
+    /// ascending fee rate order. Empty if the pool is.
+    pub fn fee_histogram(&self, buckets: usize) -> Vec<(u64, u64)> {
+        let rates = self.transactions.values().map(|tx| tx.fee_rate()).collect::<Vec<_>>();
+        let (min, max) = match (rates.iter().min(), rates.iter().max()) {
+            (Some(min), Some(max)) if buckets > 0 => (*min, *max),
+            _ => return vec![],
+        };
+        let width = (max - min) / buckets as u64 + 1;
+        let mut histogram = (0..buckets as u64).map(|n| (min + n * width, 0)).collect::<Vec<_>>();
+        for rate in rates {
+            histogram[((rate - min) / width) as usize].1 += 1;
+        }
+        histogram
+    }
+
+    /// Estimates the fee rate a transaction should pay to be mined within
+    /// the provided number of blocks: the lowest fee rate of the top
+    /// 1 / target_blocks of the pool transactions. Never lower than the
+    /// minimum fee rate the pool accepts.
+    pub fn estimate_fee(&self, target_blocks: u64) -> u64 {
+        let histogram = self.fee_histogram(FEE_ESTIMATE_BUCKETS);
+        let total = histogram.iter().map(|&(_, count)| count).sum::<u64>();
+        let wanted = total / cmp::max(target_blocks, 1);
+
+        let mut count = 0;
+        let mut estimate = 0;
+        for &(rate, bucket_count) in histogram.iter().rev() {
+            if bucket_count == 0 {
+                continue;
+            }
+            count += bucket_count;
+            estimate = rate;
+            if count >= wanted {
+                break;
+            }
+        }
+        cmp::max(estimate, self.config.min_fee_rate)
+    }
+
+    // The pool transactions each pool transaction spends outputs of.
+    fn pool_parents(&self) -> HashMap<hash::Hash, Vec<hash::Hash>> {
+        let mut creators = HashMap::new();
+        for (h, tx) in &self.transactions {
+            for out in &tx.outputs {
+                creators.insert(out.commitment(), *h);
+            }
+        }
+        self.transactions.iter().map(|(h, tx)| {
+            let tx_parents = tx.inputs.iter()
+                .filter_map(|inp| creators.get(&inp.commitment()).cloned())
+                .collect::<Vec<_>>();
+            (*h, tx_parents)
+        }).collect()
+    }
+
+    // All the pool transactions, each one after the transactions it spends
+    // outputs of.
+    fn parents_first(&self) -> Vec<hash::Hash> {
+        let parents = self.pool_parents();
+        let mut ordered = vec![];
+        let mut placed = HashSet::new();
+        for h in self.transactions.keys() {
+            if placed.contains(h) {
+                continue;
+            }
+            let mut package = vec![];
+            add_with_ancestors(*h, &parents, &placed, &mut package);
+            for p in package {
+                placed.insert(p);
+                ordered.push(p);
+            }
+        }
+        ordered
+    }
+}
+
+// Adds the transaction to the package, after its ancestors that aren't
+// already selected, themselves parents first.
+fn add_with_ancestors(tx: hash::Hash,
+    parents: &HashMap<hash::Hash, Vec<hash::Hash>>,
+    selected: &HashSet<hash::Hash>,
+    package: &mut Vec<hash::Hash>) {
+
+    for p in &parents[&tx] {
+        if !selected.contains(p) && !package.contains(p) {
+            add_with_ancestors(*p, parents, selected, package);
+        }
+    }
+    package.push(tx);
+}
+
+fn to_io_error(e: ser::Error) -> io::Error {
+    match e {
+        ser::Error::IOErr(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
     }
 }
 
@@ -486,6 +801,7 @@ mod tests {
     use core::core::build;
     use blockchain::{DummyChain, DummyChainImpl, DummyUtxoSet};
     use std::sync::{Arc, RwLock};
+    use time;
 
     macro_rules! expect_output_parent {
         ($pool:expr, $expected:pat, $( $output:expr ),+ ) => {
@@ -914,12 +1230,280 @@ mod tests {
 
     }
 
+    #[test]
+    /// Mineable transactions come out highest fee rate first.
+    fn test_mineable_fee_rate_order() {
+        let mut dummy_chain = DummyChainImpl::new();
+
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(100)).
+            with_output(test_output(200)).
+            with_output(test_output(300));
+
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+
+        // all weigh 6, paying a fee rate of 1, 5 and 2 respectively
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![100], vec![94])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![200], vec![170])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![300], vec![288])).is_ok());
+
+        let txs = pool.prepare_mineable_transactions(2);
+        assert_eq!(txs.iter().map(|tx| tx.fee_rate()).collect::<Vec<_>>(), vec![5, 2]);
+    }
+
+
+    #[test]
+    /// A transaction double spending pool transactions replaces them, along
+    /// with their children, only when it pays a higher fee rate.
+    fn test_replace_by_fee() {
+        let mut dummy_chain = DummyChainImpl::new();
+
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(100));
+
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+
+        // all weigh 6, paying a fee rate of 1, 1, 0 and 5 respectively
+        let parent = test_transaction(vec![100], vec![94]);
+        let child = test_transaction(vec![94], vec![88]);
+        assert!(pool.add_to_memory_pool(test_source(), parent.clone()).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(), child).is_ok());
+
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![100], vec![95])) {
+            Err(PoolError::DoubleSpend{other_tx, spent_output}) => {
+                assert_eq!(other_tx, parent.canonical_id());
+                assert_eq!(spent_output, test_output(100).commitment());
+            },
+            res => panic!("Unexpected result when adding a cheaper double spend: {:?}", res),
+        }
+        assert_eq!(pool.pool_size(), 2);
+
+        let bumped = test_transaction(vec![100], vec![70]);
+        let bumped_hash = bumped.canonical_id();
+        assert!(pool.add_to_memory_pool(test_source(), bumped).is_ok());
+        assert_eq!(pool.pool_size(), 1);
+        assert!(pool.transactions.contains_key(&bumped_hash));
+        assert_eq!(pool.prepare_mineable_transactions(10)[0].canonical_id(), bumped_hash);
+    }
+
+    #[test]
+    /// A child paying a high fee gets selected along with its parent, ahead
+    /// of a transaction paying a better fee rate than the parent alone.
+    fn test_select_for_block_cpfp() {
+        let mut dummy_chain = DummyChainImpl::new();
+
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(100)).
+            with_output(test_output(200));
+
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+        let parent = test_transaction(vec![100], vec![99]);
+        let child = test_transaction(vec![99], vec![50]);
+        let other = test_transaction(vec![200], vec![190]);
+        let hashes = vec![parent.canonical_id(), child.canonical_id(), other.canonical_id()];
+        for tx in vec![parent, child, other] {
+            assert!(pool.add_to_memory_pool(test_source(), tx).is_ok());
+        }
+
+        // all weigh 6, the package of parent and child pays 50 / 12, the
+        // other 10 / 6 and the parent alone 1 / 6
+        let selected = |w| pool.select_for_block(w).iter().
+            map(|tx| tx.canonical_id()).
+            collect::<Vec<_>>();
+        assert_eq!(selected(12), vec![hashes[0], hashes[1]]);
+        assert_eq!(selected(18), vec![hashes[0], hashes[1], hashes[2]]);
+        assert_eq!(selected(11), vec![hashes[2]]);
+        assert_eq!(selected(5), vec![]);
+    }
+
+    #[test]
+    /// Fee rates of the pool transactions are bucketed from lowest to
+    /// highest, estimates picking from the top of the histogram.
+    fn test_fee_histogram() {
+        let mut dummy_chain = DummyChainImpl::new();
+
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(100)).
+            with_output(test_output(200)).
+            with_output(test_output(300)).
+            with_output(test_output(400));
+
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+        assert_eq!(pool.fee_histogram(4), vec![]);
+        assert_eq!(pool.estimate_fee(1), 0);
+
+        // all weigh 6, paying a fee rate of 1, 2, 3 and 10 respectively
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![100], vec![94])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![200], vec![188])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![300], vec![282])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![400], vec![340])).is_ok());
+
+        assert_eq!(pool.fee_histogram(3), vec![(1, 3), (5, 0), (9, 1)]);
+        assert_eq!(pool.fee_histogram(1), vec![(1, 4)]);
+        assert_eq!(pool.estimate_fee(4), 10);
+        assert_eq!(pool.estimate_fee(1), 1);
+
+        pool.config.min_fee_rate = 5;
+        assert_eq!(pool.estimate_fee(1), 5);
+    }
+
+    #[test]
+    /// Transactions staying too long in the pool get evicted, along with the
+    /// ones spending them.
+    fn test_evict_expired() {
+        let mut dummy_chain = DummyChainImpl::new();
+
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(5)).
+            with_output(test_output(6));
+
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![5, 6], vec![11])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![11], vec![10])).is_ok());
+
+        let now = time::now_utc().to_timespec().sec as u64;
+        assert_eq!(pool.evict_expired(now, 3600).len(), 0);
+        assert_eq!(pool.total_size(), 2);
+
+        assert_eq!(pool.evict_expired(now + 3601, 3600).len(), 2);
+        assert_eq!(pool.total_size(), 0);
+        assert_eq!(pool.evicted_count, 2);
+        expect_output_parent!(pool,
+            Parent::BlockTransaction{output: _}, 5, 6);
+    }
+
+    #[test]
+    /// Pool transactions survive a dump and restore, unless they're not
+    /// valid anymore.
+    fn test_dump_restore() {
+        let mut dummy_chain = DummyChainImpl::new();
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty().
+            with_output(test_output(5)).
+            with_output(test_output(6)));
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![5], vec![4])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![4], vec![3])).is_ok());
+        assert!(pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![6], vec![2])).is_ok());
+        let path = "target/test_pool_dump.bin";
+        pool.dump(path).unwrap();
+
+        let mut restored = test_setup(&chain_ref);
+        assert_eq!(restored.restore(path).unwrap(), 3);
+        assert_eq!(restored.pool_size(), 3);
+
+        // the output spent by the last transaction went away in the meantime
+        let mut dummy_chain = DummyChainImpl::new();
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty().with_output(test_output(5)));
+        let mut restored = test_setup(&Arc::new(dummy_chain));
+        assert_eq!(restored.restore(path).unwrap(), 2);
+        assert_eq!(restored.pool_size(), 2);
+    }
+
+    #[test]
+    /// Transactions paying less than the configured fee rate are rejected.
+    fn test_min_fee_rate() {
+        let mut dummy_chain = DummyChainImpl::new();
+
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(100)).
+            with_output(test_output(200));
+
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let chain_ref = Arc::new(dummy_chain);
+
+        let mut pool = test_setup(&chain_ref);
+        pool.config.min_fee_rate = 2;
+
+        // both weigh 6, paying a fee rate of 1 and 5 respectively
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![100], vec![94])) {
+            Err(PoolError::FeeTooLow{actual, minimum}) => {
+                assert_eq!(actual, 1);
+                assert_eq!(minimum, 2);
+            },
+            r => panic!("expected a fee too low error, got {:?}", r),
+        }
+        let tx = test_transaction(vec![200], vec![170]);
+        assert_eq!(tx.minimum_fee(2), 12);
+        assert!(pool.add_to_memory_pool(test_source(), tx).is_ok());
+        assert_eq!(pool.total_size(), 1);
+    }
+
+    proptest! {
+        #[test]
+        /// Selected transactions are distinct pool transactions that fit in
+        /// the weight, always selected the same way.
+        fn prop_select_for_block(fees in ::proptest::collection::vec(1u64..50, 0..6),
+                                 max_weight in 0u64..40) {
+            let mut dummy_chain = DummyChainImpl::new();
+            let mut new_utxo = DummyUtxoSet::empty();
+            for n in 0..fees.len() {
+                new_utxo = new_utxo.with_output(test_output(1000 * (n as u64 + 1)));
+            }
+            dummy_chain.update_utxo_set(new_utxo);
+
+            let chain_ref = Arc::new(dummy_chain);
+            let mut pool = test_setup(&chain_ref);
+            for (n, fee) in fees.iter().enumerate() {
+                let value = 1000 * (n as u64 + 1);
+                pool.add_to_memory_pool(test_source(),
+                    test_transaction(vec![value], vec![value - fee])).unwrap();
+            }
+
+            let selected = pool.select_for_block(max_weight);
+            let ids = selected.iter().map(|tx| tx.canonical_id()).collect::<Vec<_>>();
+            assert!(ids.iter().all(|id| pool.transactions.contains_key(id)));
+            assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+            assert!(selected.iter().map(|tx| tx.weight()).sum::<u64>() <= max_weight);
+
+            let again = pool.select_for_block(max_weight).iter().
+                map(|tx| tx.canonical_id()).
+                collect::<Vec<_>>();
+            assert_eq!(ids, again);
+        }
+    }
 
     fn test_setup(dummy_chain: &Arc<DummyChainImpl>) -> TransactionPool<DummyChainImpl> {
         TransactionPool{
+            config: PoolConfig::default(),
             transactions: HashMap::new(),
             pool: Pool::empty(),
             orphans: Orphans::empty(),
+            evicted_count: 0,
             blockchain: dummy_chain.clone(),
         }
     }
@@ -28,6 +28,27 @@ use core::core::block;
 use core::core::transaction;
 use core::core::hash;
 
+/// Transaction pool configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolConfig {
+    /// Minimum fee per unit of weight a transaction has to pay to be
+    /// accepted in the pool. Zero accepts all transactions, as the wallet
+    /// doesn't pay fees yet.
+    pub min_fee_rate: u64,
+    /// Time, in seconds, after which a transaction that hasn't been mined
+    /// gets evicted from the pool
+    pub tx_ttl_secs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            min_fee_rate: 0,
+            tx_ttl_secs: 3600,
+        }
+    }
+}
+
 /// Placeholder: the data representing where we heard about a tx from.
 ///
 /// Used to make decisions based on transaction acceptance priority from
@@ -73,6 +94,13 @@ pub enum PoolError {
     Invalid,
     /// An entry already in the pool
     AlreadyInPool,
+    /// The transaction pays a lower fee rate than the pool requires
+    FeeTooLow{
+        /// Fee rate paid by the transaction
+        actual: u64,
+        /// Minimum fee rate required by the pool
+        minimum: u64,
+    },
     /// A duplicate output
     DuplicateOutput{
         /// The other transaction
@@ -199,7 +227,7 @@ impl Pool {
     pub fn remove_pool_transaction(&mut self, tx: &transaction::Transaction,
         marked_txs: &HashMap<hash::Hash, ()>) {
 
-        self.graph.remove_vertex(graph::transaction_identifier(tx));
+        self.graph.remove_vertex(tx.canonical_id());
 
         for input in tx.inputs.iter().map(|x| x.commitment()) {
             match self.graph.remove_edge_by_commitment(&input) {
@@ -233,6 +261,12 @@ impl Pool {
         }
     }
 
+    /// Transactions received before the provided time, in seconds since the
+    /// epoch
+    pub fn get_received_before(&self, cutoff: i64) -> Vec<hash::Hash> {
+        self.graph.get_received_before(cutoff)
+    }
+
     /// Simplest possible implementation: just return the roots
     pub fn get_mineable_transactions(&self, num_to_fetch: u32) -> Vec<hash::Hash> {
         let mut roots = self.graph.get_roots();
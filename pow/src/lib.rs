@@ -150,7 +150,8 @@ pub fn pow_size<T: MiningWorker + ?Sized>(miner:&mut T, bh: &mut BlockHeader,
 		}
 
 		// otherwise increment the nonce
-		bh.nonce += 1;
+		let nonce = bh.nonce + 1;
+		bh.nonce = nonce;
 
 		// and if we're back where we started, update the time (changes the hash as
 		// well)
@@ -15,6 +15,7 @@
 //! Main for building the binary of a Grin peer-to-peer node.
 
 extern crate clap;
+extern crate ctrlc;
 extern crate daemonize;
 #[macro_use]
 extern crate log;
@@ -28,9 +29,11 @@ extern crate grin_grin as grin;
 extern crate grin_wallet as wallet;
 extern crate grin_config as config;
 extern crate grin_core as core;
+extern crate grin_util as util;
 extern crate secp256k1zkp as secp;
 
 use std::thread;
+use std::sync::mpsc;
 use std::io::Read;
 use std::fs::File;
 use std::time::Duration;
@@ -39,6 +42,7 @@ use clap::{Arg, App, SubCommand, ArgMatches};
 use daemonize::Daemonize;
 
 use secp::Secp256k1;
+use secp::pedersen::Commitment;
 
 use config::GlobalConfig;
 use wallet::WalletConfig;
@@ -139,6 +143,10 @@ fn main() {
                      .long("wallet_url")
                      .help("A listening wallet receiver to which mining rewards will be sent")
                 .takes_value(true))
+                .arg(Arg::with_name("disable_checkpoints")
+                     .long("disable-checkpoints")
+                     .help("Verify the proof of work of all block headers instead of trusting the \
+                            hardcoded checkpoints"))
                 .subcommand(SubCommand::with_name("start")
                             .about("Start the Grin server as a daemon"))
                 .subcommand(SubCommand::with_name("stop")
@@ -176,13 +184,26 @@ fn main() {
 			.long("api_server_address")
 			.help("Api address of running node on which to check inputs and post transactions")
 			.takes_value(true))
+		.arg(Arg::with_name("min_confirmations")
+			.short("c")
+			.long("min_confirmations")
+			.help("Number of confirmations an output needs before it can be spent \
+			(defaults to 1, coinbase outputs always need 1440)")
+			.takes_value(true))
+		.arg(Arg::with_name("key_scheme")
+			.short("k")
+			.long("key_scheme")
+			.help("How the keys are derived from the seed: legacy (default) or \
+			bip32, deriving them at m/0'/n like the watch-only wallet. A wallet \
+			has to keep the scheme its outputs were created with")
+			.takes_value(true))
 
 		.subcommand(SubCommand::with_name("receive")
 			.about("Run the wallet in receiving mode. If an input file is \
 				provided, will process it, otherwise runs in server mode waiting \
 				for send requests.")
 			.arg(Arg::with_name("input")
-				.help("Partial transaction to receive, expects as a JSON file.")
+				.help("Slate of the transaction to receive, expects as a JSON file.")
 				.short("i")
 				.long("input")
 				.takes_value(true)))
@@ -195,6 +216,33 @@ fn main() {
 			.arg(Arg::with_name("amount")
 				.help("Amount to send in the smallest denomination")
 				.index(1))
+			.arg(Arg::with_name("dest")
+				.help("Send the transaction to the provided server")
+				.short("d")
+				.long("dest")
+				.takes_value(true))
+			.arg(Arg::with_name("selection")
+				.help("How the outputs to spend get picked: automatic (default), \
+					largest, smallest or oldest first")
+				.short("s")
+				.long("selection")
+				.takes_value(true))
+			.arg(Arg::with_name("outputs")
+				.help("Spend exactly the outputs with the provided commitments (hex)")
+				.long("outputs")
+				.takes_value(true)
+				.multiple(true)))
+
+		.subcommand(SubCommand::with_name("bump_fee")
+			.about("Builds again a transaction we sent that's stuck in the pool, \
+				paying a higher fee out of our change. The receiver has to add its \
+				output again, the transaction is sent like by the send command.")
+			.arg(Arg::with_name("id")
+				.help("Id of the slate of the transaction")
+				.index(1))
+			.arg(Arg::with_name("fee")
+				.help("New fee to pay in the smallest denomination")
+				.index(2))
 			.arg(Arg::with_name("dest")
 				.help("Send the transaction to the provided server")
 				.short("d")
@@ -202,7 +250,18 @@ fn main() {
 				.takes_value(true)))
 
 		.subcommand(SubCommand::with_name("info")
-			.about("basic wallet info (outputs)")))
+			.about("basic wallet info (outputs)"))
+
+		.subcommand(SubCommand::with_name("rescan")
+			.about("Rescans the chain from the provided height to recover outputs \
+				missing from the wallet data.")
+			.arg(Arg::with_name("from")
+				.help("Height to start the rescan from")
+				.index(1)))
+
+		.subcommand(SubCommand::with_name("audit")
+			.about("Checks the outputs in the wallet data against the chain, without \
+				modifying anything.")))
 
 	.get_matches();
 
@@ -274,6 +333,10 @@ fn server_command(server_args: &ArgMatches, global_config: GlobalConfig) {
 			.wallet_receiver_url = wallet_url.to_string();
 	}
 
+	if server_args.is_present("disable_checkpoints") {
+		server_config.disable_checkpoints = Some(true);
+	}
+
 	if let Some(seeds) = server_args.values_of("seed") {
 		server_config.seeding_type = grin::Seeding::List;
 		server_config.seeds = Some(seeds.map(|s| s.to_string()).collect());
@@ -282,20 +345,18 @@ fn server_command(server_args: &ArgMatches, global_config: GlobalConfig) {
 	// start the server in the different run modes (interactive or daemon)
 	match server_args.subcommand() {
 		("run", _) => {
-			grin::Server::start(server_config).unwrap();
-			loop {
-				thread::sleep(Duration::from_secs(60));
-			}
+			let server = grin::Server::start(server_config).unwrap();
+			wait_for_termination();
+			server.stop();
 		}
 		("start", _) => {
 			let daemonize = Daemonize::new()
 				.pid_file("/tmp/grin.pid")
 				.chown_pid_file(true)
 				.privileged_action(move || {
-					grin::Server::start(server_config.clone()).unwrap();
-					loop {
-						thread::sleep(Duration::from_secs(60));
-					}
+					let server = grin::Server::start(server_config.clone()).unwrap();
+					wait_for_termination();
+					server.stop();
 				});
 			match daemonize.start() {
 				Ok(_) => info!("Grin server succesfully started."),
@@ -307,6 +368,17 @@ fn server_command(server_args: &ArgMatches, global_config: GlobalConfig) {
 	}
 }
 
+/// Blocks until the process is asked to terminate, either by SIGINT or
+/// SIGTERM.
+fn wait_for_termination() {
+	let (tx, rx) = mpsc::channel();
+	ctrlc::set_handler(move || {
+		let _ = tx.send(());
+	}).expect("Error setting termination handler.");
+	rx.recv().unwrap();
+	warn!("Received termination signal, shutting down.");
+}
+
 fn wallet_command(wallet_args: &ArgMatches) {
 	let hd_seed = wallet_args.value_of("pass").expect(
 		"Wallet passphrase required.",
@@ -315,13 +387,15 @@ fn wallet_command(wallet_args: &ArgMatches) {
 	// TODO do something closer to BIP39, eazy solution right now
 	let seed = blake2::blake2b::blake2b(32, &[], hd_seed.as_bytes());
 
-	let s = Secp256k1::new();
-	let key = wallet::ExtendedKey::from_seed(&s, seed.as_bytes()).expect(
-		"Error deriving extended key from seed.",
-	);
+	let mut wallet_config = WalletConfig::default();
+	if let Some(ks) = wallet_args.value_of("key_scheme") {
+		wallet_config.key_scheme = ks.parse().expect("Unknown key scheme.");
+	}
 
+	let s = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let key = wallet::ExtendedKey::from_seed_with_scheme(&s, seed.as_bytes(), wallet_config.key_scheme)
+		.expect("Error deriving extended key from seed.");
 
-	let mut wallet_config = WalletConfig::default();
 	if let Some(port) = wallet_args.value_of("port") {
 		let default_ip = "127.0.0.1";
 		wallet_config.api_http_addr = format!("{}:{}", default_ip, port);
@@ -335,6 +409,12 @@ fn wallet_command(wallet_args: &ArgMatches) {
 		wallet_config.check_node_api_http_addr = sa.to_string().clone();
 	}
 
+	if let Some(mc) = wallet_args.value_of("min_confirmations") {
+		wallet_config.min_confirmations = mc.parse().expect(
+			"Could not parse min_confirmations as a whole number.",
+		);
+	}
+
 	match wallet_args.subcommand() {
 
 		("receive", Some(receive_args)) => {
@@ -373,11 +453,64 @@ fn wallet_command(wallet_args: &ArgMatches) {
 			if let Some(d) = send_args.value_of("dest") {
 				dest = d;
 			}
-			wallet::issue_send_tx(&wallet_config, &key, amount, dest.to_string()).unwrap();
+			let policy = match send_args.values_of("outputs") {
+				Some(commits) => wallet::CoinSelectionPolicy::Manual(
+					commits
+						.map(|c| {
+							let bytes = util::from_hex(c.to_string())
+								.expect("Could not parse output commitment as hex.");
+							Commitment::from_vec(bytes)
+						})
+						.collect(),
+				),
+				None => send_args
+					.value_of("selection")
+					.unwrap_or("automatic")
+					.parse()
+					.expect("Unknown coin selection policy."),
+			};
+			wallet::issue_send_tx(&wallet_config, &key, amount, dest.to_string(), policy).unwrap();
+		},
+		("bump_fee", Some(bump_args)) => {
+			let id = bump_args.value_of("id").expect("Slate id required");
+			let fee = bump_args
+				.value_of("fee")
+				.expect("New fee required")
+				.parse()
+				.expect("Could not parse fee as a whole number.");
+			let dest = bump_args.value_of("dest").unwrap_or("stdout");
+			wallet::issue_fee_bump(&wallet_config, &key, id, fee, dest.to_string()).unwrap();
 		},
 		("info", Some(_)) => {
 			wallet::show_info(&wallet_config, &key);
 		},
+		("rescan", Some(rescan_args)) => {
+			let from = rescan_args
+				.value_of("from")
+				.unwrap_or("0")
+				.parse()
+				.expect("Could not parse height as a whole number.");
+			let report = wallet::rescan_from_height(&wallet_config, &key, from).unwrap();
+			println!(
+				"Scanned {} outputs between heights {} and {}, found {} new ones ({} already known).",
+				report.outputs_scanned,
+				report.start_height,
+				report.end_height,
+				report.outputs_found,
+				report.outputs_known
+			);
+		},
+		("audit", Some(_)) => {
+			let report = wallet::audit(&wallet_config, &key).unwrap();
+			println!(
+				"{} outputs match the chain, {} missing from the chain, {} unexpectedly \
+				unspent on chain, balance off by {}.",
+				report.confirmed_match,
+				report.missing_from_chain.len(),
+				report.extra_in_chain.len(),
+				report.balance_discrepancy
+			);
+		},
 		_ => panic!("Unknown wallet command, use 'grin help wallet' for details"),
 	}
 }
@@ -0,0 +1,127 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Random reads across a 1,000,000 elements MMR, from the file based backend
+//! and from the RocksDB one. Also reads of the most recent elements, like
+//! while mining, with and without the hot tier of the file based backend.
+
+#![feature(test)]
+
+extern crate test;
+extern crate rand;
+extern crate time;
+extern crate grin_core as core;
+extern crate grin_store as store;
+
+use std::fs;
+
+use rand::Rng;
+use test::Bencher;
+
+use core::core::pmmr::{Backend, PMMR, VecBackend};
+use store::rocksdb_backend::RocksDbBackend;
+use store::sumtree::{PMMRBackend, PMMROptions};
+
+const LEAF_COUNT: u64 = 1_000_000;
+
+// HashSums appended to a backend at once.
+const BATCH_LEN: u64 = 10_000;
+
+// Number of the most recent positions read by the recent data benches.
+const RECENT_LEN: u64 = 1_000;
+
+fn data_dir(name: &str) -> String {
+	let t = time::get_time();
+	let dir = format!("./target/bench_{}.{}.{}", name, t.sec, t.nsec);
+	fs::create_dir_all(dir.clone()).unwrap();
+	dir
+}
+
+// Copies an MMR of LEAF_COUNT leaves to the provided backend, in batches as
+// pushing leaves one by one would write the file backend log on each,
+// returning the MMR size.
+fn fill<B>(backend: &mut B) -> u64
+	where B: Backend<u64>
+{
+	let mut ba = VecBackend::new();
+	let size = {
+		let mut pmmr = PMMR::new(&mut ba);
+		for n in 0..LEAF_COUNT {
+			pmmr.push(n);
+		}
+		pmmr.unpruned_size()
+	};
+	let mut pos = 1;
+	while pos <= size {
+		let end = std::cmp::min(pos + BATCH_LEN, size + 1);
+		let batch = (pos..end).map(|p| ba.get(p).unwrap()).collect();
+		backend.append(pos, batch).unwrap();
+		pos = end;
+	}
+	size
+}
+
+fn bench_get<B>(b: &mut Bencher, backend: &B, size: u64)
+	where B: Backend<u64>
+{
+	bench_get_from(b, backend, 1, size);
+}
+
+// Random reads between the positions from and to, included.
+fn bench_get_from<B>(b: &mut Bencher, backend: &B, from: u64, to: u64)
+	where B: Backend<u64>
+{
+	let mut rng = rand::thread_rng();
+	b.iter(|| {
+		for _ in 0..100 {
+			test::black_box(backend.get(rng.gen_range(from, to + 1)));
+		}
+	});
+}
+
+#[bench]
+fn bench_get_file_based(b: &mut Bencher) {
+	let mut backend = PMMRBackend::new(data_dir("file_based"), false).unwrap();
+	let size = fill(&mut backend);
+	backend.sync().unwrap();
+	bench_get(b, &backend, size);
+}
+
+#[bench]
+fn bench_get_rocksdb(b: &mut Bencher) {
+	let mut backend = RocksDbBackend::new(data_dir("rocksdb")).unwrap();
+	let size = fill(&mut backend);
+	backend.sync().unwrap();
+	bench_get(b, &backend, size);
+}
+
+#[bench]
+fn bench_get_recent_file_based(b: &mut Bencher) {
+	let mut backend = PMMRBackend::new(data_dir("recent_file_based"), false).unwrap();
+	let size = fill(&mut backend);
+	backend.sync().unwrap();
+	bench_get_from(b, &backend, size - RECENT_LEN + 1, size);
+}
+
+#[bench]
+fn bench_get_recent_hot_tier(b: &mut Bencher) {
+	let opts = PMMROptions {
+		hot_threshold: RECENT_LEN,
+		..Default::default()
+	};
+	let mut backend = PMMRBackend::new_with_opts(data_dir("recent_hot_tier"), opts).unwrap();
+	let size = fill(&mut backend);
+	backend.sync().unwrap();
+	bench_get_from(b, &backend, size - RECENT_LEN + 1, size);
+}
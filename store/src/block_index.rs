@@ -0,0 +1,104 @@
+// Copyright 2017 The Grin Developers
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Index of the block headers appended to a file, by hash, for lookups
+//! without scanning the file.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use core::core::hash::Hash;
+use core::ser;
+use sumtree::write_vec;
+
+/// Maps the hash of each block header in a header file to its offset in the
+/// file. Kept in memory and saved alongside the header file as a list of
+/// (hash, offset) pairs. Entries added since the last save are recovered by
+/// rebuilding the index when reopened.
+pub struct BlockHashIndex {
+	path: String,
+	offsets: HashMap<Hash, u64>,
+}
+
+impl BlockHashIndex {
+	/// Opens the index saved at the provided path, for a header file holding
+	/// count headers. If the index file is missing, unreadable or doesn't have
+	/// count entries, the index is rebuilt from scratch from the (hash,
+	/// offset) pairs provided by rebuild, and saved.
+	pub fn open<F>(path: String, count: usize, rebuild: F) -> io::Result<BlockHashIndex>
+	where
+		F: FnOnce() -> io::Result<Vec<(Hash, u64)>>,
+	{
+		let mut index = BlockHashIndex {
+			path: path,
+			offsets: HashMap::new(),
+		};
+		match read_entries(&index.path) {
+			Ok(ref entries) if entries.len() == count => {
+				index.offsets.extend(entries.iter().cloned());
+				if index.offsets.len() == count {
+					return Ok(index);
+				}
+				index.offsets.clear();
+			}
+			Ok(_) => {}
+			Err(e) => warn!("Could not read the header index at {}: {}", index.path, e),
+		}
+
+		info!("Rebuilding the header index at {} for {} headers.", index.path, count);
+		index.offsets.extend(rebuild()?);
+		index.save()?;
+		Ok(index)
+	}
+
+	/// Adds the offset of the header with the provided hash.
+	pub fn insert(&mut self, hash: Hash, offset: u64) {
+		self.offsets.insert(hash, offset);
+	}
+
+	/// Removes the header with the provided hash from the index.
+	pub fn remove(&mut self, hash: &Hash) -> Option<u64> {
+		self.offsets.remove(hash)
+	}
+
+	/// Offset of the header with the provided hash, if indexed.
+	pub fn get(&self, hash: &Hash) -> Option<u64> {
+		self.offsets.get(hash).cloned()
+	}
+
+	/// Number of indexed headers.
+	pub fn len(&self) -> usize {
+		self.offsets.len()
+	}
+
+	/// Saves the index to its file, ordered by offset.
+	pub fn save(&self) -> io::Result<()> {
+		let mut entries = self.offsets.iter().map(|(h, o)| (*h, *o)).collect::<Vec<_>>();
+		entries.sort_by_key(|e| e.1);
+		write_vec(self.path.clone(), &entries)
+	}
+}
+
+// Reads the (hash, offset) pairs saved at the provided path, none if there's
+// no file.
+fn read_entries(path: &str) -> io::Result<Vec<(Hash, u64)>> {
+	if !Path::new(path).exists() {
+		return Ok(vec![]);
+	}
+	let reader = BufReader::new(File::open(path)?);
+	ser::StreamingDeserializer::<_, (Hash, u64)>::new(reader)
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+}
@@ -0,0 +1,113 @@
+// Copyright 2017 The Grin Developers
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Archive of the data pruned from a PMMR, kept for archival nodes that need
+//! to serve or audit it later.
+
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sumtree::AppendOnlyFile;
+
+// Length of the header preceding each archived entry: its MMR position and
+// the length of its data.
+const ENTRY_HEADER_LEN: usize = 12;
+
+/// Append-only archive of the data at pruned MMR positions. Each entry is
+/// written as its position (8 bytes), the length of its data (4 bytes) and
+/// the data itself. An in-memory index, rebuilt by scanning the file when
+/// opened, maps each position to the offset of its entry.
+pub struct ColdStore {
+	file: AppendOnlyFile,
+	// (position, file offset) pairs, ordered by position
+	index: Vec<(u64, u64)>,
+}
+
+impl ColdStore {
+	/// Opens the archive at the provided path, creating it if it doesn't exist
+	/// yet. An entry only partially written, by an interrupted archival, is
+	/// dropped.
+	pub fn open(path: String) -> io::Result<ColdStore> {
+		let mut file = AppendOnlyFile::with_segment_size(path, 0, false)?;
+		let size = file.size()?;
+		let mut index = vec![];
+		let mut offset = 0;
+		while offset < size {
+			let mut header = [0; ENTRY_HEADER_LEN];
+			if let Err(_) = file.read_into(offset as usize, &mut header) {
+				break;
+			}
+			let len = LittleEndian::read_u32(&header[8..]) as u64;
+			if offset + ENTRY_HEADER_LEN as u64 + len > size {
+				break;
+			}
+			insert(&mut index, LittleEndian::read_u64(&header[..8]), offset);
+			offset += ENTRY_HEADER_LEN as u64 + len;
+		}
+		if offset < size {
+			warn!("Dropping a partial entry at the end of the archive file.");
+			file.rewind(offset)?;
+			file.sync()?;
+		}
+		Ok(ColdStore {
+			file: file,
+			index: index,
+		})
+	}
+
+	/// Archives the data of the provided MMR position. A position archived
+	/// again (pruned again after a rewind) gets its data replaced. The data
+	/// can only be retrieved once the archive is synced.
+	pub fn archive(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+		let offset = self.file.size()?;
+		let mut entry = vec![0; ENTRY_HEADER_LEN];
+		LittleEndian::write_u64(&mut entry[..8], pos);
+		LittleEndian::write_u32(&mut entry[8..], data.len() as u32);
+		entry.extend_from_slice(data);
+		self.file.append(&entry)?;
+		insert(&mut self.index, pos, offset);
+		Ok(())
+	}
+
+	/// Data archived for the provided MMR position, if any.
+	pub fn retrieve(&self, pos: u64) -> io::Result<Option<Vec<u8>>> {
+		let offset = match self.index.binary_search_by_key(&pos, |e| e.0) {
+			Ok(idx) => self.index[idx].1 as usize,
+			Err(_) => return Ok(None),
+		};
+		let mut header = [0; ENTRY_HEADER_LEN];
+		self.file.read_into(offset, &mut header)?;
+		let mut data = vec![0; LittleEndian::read_u32(&header[8..]) as usize];
+		self.file.read_into(offset + ENTRY_HEADER_LEN, &mut data)?;
+		Ok(Some(data))
+	}
+
+	/// Number of archived positions.
+	pub fn len(&self) -> usize {
+		self.index.len()
+	}
+
+	/// Syncs the archived data to disk.
+	pub fn sync(&mut self) -> io::Result<()> {
+		self.file.sync()
+	}
+}
+
+// Indexes the entry of a position, replacing any previous one.
+fn insert(index: &mut Vec<(u64, u64)>, pos: u64, offset: u64) {
+	match index.binary_search_by_key(&pos, |e| e.0) {
+		Ok(idx) => index[idx].1 = offset,
+		Err(idx) => index.insert(idx, (pos, offset)),
+	}
+}
@@ -21,13 +21,22 @@
 #![warn(missing_docs)]
 
 extern crate byteorder;
+extern crate crc;
 extern crate grin_core as core;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate fs2;
 extern crate memmap;
 extern crate rocksdb;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
+pub mod block_index;
+pub mod cold_store;
+pub mod rocksdb_backend;
 pub mod sumtree;
 
 const SEP: u8 = ':' as u8;
@@ -152,12 +161,12 @@ impl Store {
 	}
 
 	/// Produces an iterator of `Readable` types moving forward from the
-	/// provided
-	/// key.
+	/// provided key, stopping at the first key that doesn't start with it.
 	pub fn iter<T: ser::Readable>(&self, from: &[u8]) -> SerIterator<T> {
 		let db = self.rdb.read().unwrap();
 		SerIterator {
 			iter: db.iterator(IteratorMode::From(from, Direction::Forward)),
+			prefix: from.to_vec(),
 			_marker: PhantomData,
 		}
 	}
@@ -216,6 +225,7 @@ where
 	T: ser::Readable,
 {
 	iter: DBIterator,
+	prefix: Vec<u8>,
 	_marker: PhantomData<T>,
 }
 
@@ -227,8 +237,12 @@ where
 
 	fn next(&mut self) -> Option<T> {
 		let next = self.iter.next();
+		let prefix = &self.prefix;
 		next.and_then(|r| {
-			let (_, v) = r;
+			let (k, v) = r;
+			if !k.starts_with(prefix) {
+				return None;
+			}
 			ser::deserialize(&mut &v[..]).ok()
 		})
 	}
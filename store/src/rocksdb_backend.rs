@@ -0,0 +1,156 @@
+// Copyright 2017 The Grin Developers
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend for the prunable MMR sum-tree storing each node in RocksDB, for
+//! workloads with many random reads across a large MMR.
+
+use std::io;
+use std::marker::PhantomData;
+
+use byteorder::{ByteOrder, BigEndian};
+use rocksdb::{DB, WriteBatch, DBCompactionStyle, IteratorMode, Direction};
+
+use core::core::pmmr::{Summable, Backend, HashSum};
+use core::ser;
+
+// Key of the MMR size, not 8 bytes long so it can't clash with a position
+const SIZE_KEY: &'static [u8] = b"size";
+
+/// PMMR backend keeping each HashSum in RocksDB, keyed by its MMR position as
+/// 8 big-endian bytes, so nodes are ordered by position. Pruning a node
+/// deletes it, it can't be restored by a rewind. Writes go to the database
+/// right away, in a single batch for every append or removal.
+pub struct RocksDbBackend<T>
+where
+	T: Summable + Clone,
+{
+	db: DB,
+	size: u64,
+	_marker: PhantomData<T>,
+}
+
+impl<T> Backend<T> for RocksDbBackend<T>
+where
+	T: Summable + Clone,
+{
+	/// Append the provided HashSums, along with the new MMR size.
+	fn append(&mut self, position: u64, data: Vec<HashSum<T>>) -> Result<(), String> {
+		let mut batch = WriteBatch::default();
+		let mut pos = position;
+		for hs in &data {
+			let value = ser::ser_vec(hs).map_err(|e| format!("{:?}", e))?;
+			batch.put(&pos_key(pos), &value[..]).map_err(|e| e.to_string())?;
+			pos += 1;
+		}
+		let size = position + data.len() as u64 - 1;
+		batch.put(SIZE_KEY, &pos_key(size)).map_err(|e| e.to_string())?;
+		self.db.write(batch).map_err(|e| {
+			format!("Could not write to RocksDB, disk full? {:?}", e)
+		})?;
+		self.size = size;
+		Ok(())
+	}
+
+	/// Get a HashSum by insertion position
+	fn get(&self, position: u64) -> Option<HashSum<T>> {
+		match self.db.get(&pos_key(position)) {
+			Ok(Some(value)) => match ser::deserialize(&mut &value[..]) {
+				Ok(hashsum) => Some(hashsum),
+				Err(e) => {
+					error!("Corrupted storage, could not read an entry from RocksDB: {:?}", e);
+					None
+				}
+			},
+			Ok(None) => None,
+			Err(e) => {
+				error!("Could not read from RocksDB: {:?}", e);
+				None
+			}
+		}
+	}
+
+	/// Remove HashSums by insertion position, deleting them
+	fn remove(&mut self, positions: Vec<u64>) -> Result<(), String> {
+		let mut batch = WriteBatch::default();
+		for pos in positions {
+			batch.delete(&pos_key(pos)).map_err(|e| e.to_string())?;
+		}
+		self.db.write(batch).map_err(|e| e.to_string())
+	}
+
+	/// Rewinds the backend to the provided MMR position, deleting all the
+	/// nodes after it. Nodes pruned before it stay deleted.
+	fn rewind(&mut self, position: u64) -> Result<(), String> {
+		let mut batch = WriteBatch::default();
+		{
+			let from = pos_key(position + 1);
+			let iter = self.db.iterator(IteratorMode::From(&from, Direction::Forward));
+			for (key, _) in iter {
+				if key.len() == 8 {
+					batch.delete(&key).map_err(|e| e.to_string())?;
+				}
+			}
+		}
+		batch.put(SIZE_KEY, &pos_key(position)).map_err(|e| e.to_string())?;
+		self.db.write(batch).map_err(|e| {
+			format!("Could not rewind RocksDB storage: {:?}", e)
+		})?;
+		self.size = position;
+		Ok(())
+	}
+
+	/// Size of the MMR held by the backend, pruned positions included.
+	fn current_size(&self) -> u64 {
+		self.size
+	}
+}
+
+impl<T> RocksDbBackend<T>
+where
+	T: Summable + Clone,
+{
+	/// Opens the backend stored in the provided directory, creating the
+	/// database if it doesn't exist yet.
+	pub fn new(data_dir: String) -> io::Result<RocksDbBackend<T>> {
+		let mut opts = ::rocksdb::Options::default();
+		opts.create_if_missing(true);
+		opts.set_compaction_style(DBCompactionStyle::Universal);
+		opts.set_max_open_files(256);
+		let db = DB::open(&opts, &data_dir).map_err(|e| {
+			io::Error::new(io::ErrorKind::Other, e.to_string())
+		})?;
+		let size = match db.get(SIZE_KEY) {
+			Ok(Some(value)) => BigEndian::read_u64(&value[..]),
+			Ok(None) => 0,
+			Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+		};
+		Ok(RocksDbBackend {
+			db: db,
+			size: size,
+			_marker: PhantomData,
+		})
+	}
+
+	/// Nothing to do, every write already went through the RocksDB log.
+	/// Mirrors the file based backend.
+	pub fn sync(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// Key of the node at the provided position.
+fn pos_key(pos: u64) -> [u8; 8] {
+	let mut key = [0; 8];
+	BigEndian::write_u64(&mut key, pos);
+	key
+}
@@ -13,63 +13,448 @@
 
 //! Implementation of the persistent Backend for the prunable MMR sum-tree.
 
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use fs2::FileExt;
 use memmap;
+use serde_json;
 
 use std::cmp;
+use std::fmt::{self, Debug};
+use std::iter;
+use std::slice;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Write, BufReader, BufRead, ErrorKind};
+use std::io::{self, Write, BufReader, ErrorKind, Seek, SeekFrom};
 use std::path::Path;
 use std::io::Read;
 
-use core::core::pmmr::{self, Summable, Backend, HashSum, VecBackend};
-use core::ser;
+use core::core::hash::Hash;
+use core::core::pmmr::{self, Summable, Backend, HashSum, PMMRReader, VecBackend};
+use core::ser::{self, Writeable};
+use cold_store::ColdStore;
+use rocksdb_backend::RocksDbBackend;
 
 const PMMR_DATA_FILE: &'static str = "pmmr_dat.bin";
 const PMMR_RM_LOG_FILE: &'static str = "pmmr_rm_log.bin";
 const PMMR_PRUNED_FILE: &'static str = "pmmr_pruned.bin";
+const PMMR_COLD_DATA_FILE: &'static str = "pmmr_dat_cold.bin";
+const PMMR_COLD_TIER_FILE: &'static str = "pmmr_cold_tier";
+const PMMR_FORMAT_FILE: &'static str = "pmmr_format";
+const PMMR_WAL_FILE: &'static str = "pmmr_wal.bin";
+const PMMR_ARCHIVE_FILE: &'static str = "pmmr_archive.bin";
+const PMMR_SWAP_FILE: &'static str = "pmmr_swap.json";
+const SNAPSHOT_META_FILE: &'static str = "snapshot_meta.json";
 
-/// Maximum number of nodes in the remove log before it gets flushed
+/// Version of the format of the PMMR data file. Version 1 files (which have
+/// no format file) store bare records, from version 2 every record is
+/// followed by its CRC32C checksum.
+pub const PMMR_FORMAT_VERSION: u32 = 2;
+
+// Length of the checksum following each record, when checksummed
+const CHECKSUM_LEN: usize = 4;
+
+// Length of the footer of temporary files, see write_through_tmp
+const TMP_FOOTER_LEN: usize = 8 + CHECKSUM_LEN;
+
+/// Default maximum number of nodes in the remove log before it gets flushed
 pub const RM_LOG_MAX_NODES: usize = 10000;
 
+/// Storage a PMMR backend keeps its nodes in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendType {
+	/// Append-only files, see PMMRBackend
+	FileBased,
+	/// A RocksDB database, faster for random reads across a large MMR, see
+	/// RocksDbBackend
+	RocksDb,
+}
+
+/// Options tuning a PMMR backend to the role of the node. Archive nodes may
+/// want infrequent compactions, embedded nodes aggressive ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PMMROptions {
+	/// Maximum number of nodes in the remove log before it gets flushed
+	pub rm_log_max_nodes: usize,
+	/// Number of HashSums the write buffer has room for before reallocating
+	pub buffer_capacity: usize,
+	/// Whether the checksums of all records in the data file are verified
+	/// when opening
+	pub enable_integrity_check: bool,
+	/// Whether the backend files are checked for consistency with each other
+	/// when opening (see verify_consistency)
+	pub enable_consistency_check: bool,
+	/// Storage of the nodes, when opened through AnyBackend. The other
+	/// options only apply to the file based backend.
+	pub backend_type: BackendType,
+	/// Whether the data of pruned nodes is kept in an archive file when
+	/// compacting, instead of being discarded (see get_archived)
+	pub archive_pruned: bool,
+	/// Number of the most recently appended HashSums also kept in memory, for
+	/// faster reads of recent data (see set_hot_threshold). 0 disables it.
+	pub hot_threshold: u64,
+}
+
+impl Default for PMMROptions {
+	fn default() -> PMMROptions {
+		PMMROptions {
+			rm_log_max_nodes: RM_LOG_MAX_NODES,
+			// a batch as large as the remove log fits without reallocating
+			buffer_capacity: RM_LOG_MAX_NODES,
+			enable_integrity_check: false,
+			enable_consistency_check: false,
+			backend_type: BackendType::FileBased,
+			archive_pruned: false,
+			hot_threshold: 0,
+		}
+	}
+}
+
+/// Inconsistency between the files of a PMMR backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsistencyError {
+	/// The data file doesn't hold a whole number of records
+	FileSizeMismatch {
+		/// Bytes left over past the last whole record
+		remainder: u64,
+	},
+	/// A position in the remove log has already been pruned
+	RemoveLogPrunedOverlap {
+		/// The position found in both
+		position: u64,
+	},
+	/// The number of records in the data file doesn't match the index the
+	/// write buffer starts at
+	BufferIndexMismatch {
+		/// Number of records in the data file
+		computed: usize,
+		/// Index of the write buffer
+		stored: usize,
+	},
+}
+
+impl fmt::Display for ConsistencyError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			&ConsistencyError::FileSizeMismatch { remainder } => {
+				write!(f, "Data file has {} bytes past its last record", remainder)
+			}
+			&ConsistencyError::RemoveLogPrunedOverlap { position } => {
+				write!(f, "Position {} is both in the remove log and pruned", position)
+			}
+			&ConsistencyError::BufferIndexMismatch { computed, stored } => {
+				write!(f, "Data file has {} records, buffer index is {}", computed, stored)
+			}
+		}
+	}
+}
+
 /// Wrapper for a file that can be read at any position (random read) but for
 /// which writes are append only. Reads are backed by a memory map (mmap(2)),
 /// relying on the operating system for fast access and caching. The memory
 /// map is reallocated to expand it when new writes are flushed.
-struct AppendOnlyFile {
+///
+/// The oldest part of the data can be moved to a separate "cold" file,
+/// typically on slower but cheaper storage. Reads are then transparently
+/// split between the 2 tiers: offsets below cold_threshold_bytes are read
+/// from the cold file, higher offsets from the (hot) main file.
+///
+/// The main file can also be split in segments of a maximum size, to avoid
+/// a single file growing without bounds. The first segment is stored at the
+/// base path, the following ones at {path}_001, {path}_002, etc. Only the
+/// last segment is appended to, the others are sealed and read-only.
+///
+/// When checksummed, every append is followed by the CRC32C checksum of the
+/// data, allowing partial writes or corruption to be detected.
+pub struct AppendOnlyFile {
+	path: String,
+	file: File,
+	mmap: Option<memmap::Mmap>,
+	segments: Vec<MappedFile>,
+	segment_size: u64,
+	cold: Option<MappedFile>,
+	cold_threshold_bytes: u64,
+	checksummed: bool,
+	// total size including the data appended since the last sync
+	append_pos: u64,
+	// total size as of the last sync
+	synced_pos: u64,
+}
+
+/// Exclusive lock held on an AppendOnlyFile, released when dropped.
+pub struct FileLock {
 	path: String,
 	file: File,
+}
+
+impl Drop for FileLock {
+	fn drop(&mut self) {
+		if let Err(e) = self.file.unlock() {
+			warn!("Could not release the lock on {}: {:?}", self.path, e);
+		}
+	}
+}
+
+/// Read-only file, used to hold the oldest data of an AppendOnlyFile (either
+/// in its cold tier or in sealed segments).
+struct MappedFile {
+	path: String,
 	mmap: Option<memmap::Mmap>,
+	size: u64,
+}
+
+impl MappedFile {
+	fn open(path: String) -> io::Result<MappedFile> {
+		let file = File::open(path.clone())?;
+		let size = file.metadata()?.len();
+		let mmap = if size > 0 {
+			Some(unsafe {
+				memmap::file(&file)
+					.protection(memmap::Protection::Read)
+					.map()?
+			})
+		} else {
+			None
+		};
+		Ok(MappedFile {
+			path: path,
+			mmap: mmap,
+			size: size,
+		})
+	}
 }
 
 impl AppendOnlyFile {
-	/// Open a file (existing or not) as append-only, backed by a mmap.
-	fn open(path: String) -> io::Result<AppendOnlyFile> {
-		let file = OpenOptions::new()
-			.read(true)
-			.append(true)
-			.create(true)
-			.open(path.clone())?;
+	/// Open a file (existing or not) as append-only, backed by a mmap. The
+	/// file is split in segments of at most max_bytes, unless max_bytes is 0.
+	/// Offsets are mapped to segments by dividing them by max_bytes, so it
+	/// should be a multiple of the size of the records written. Files already
+	/// split in segments are detected, in which case the segment size is the
+	/// size of the first (always full) segment.
+	pub fn with_segment_size(path: String, max_bytes: u64, checksummed: bool) -> io::Result<AppendOnlyFile> {
+		AppendOnlyFile::open_file(path, max_bytes, checksummed, false)
+	}
+
+	/// Takes an exclusive lock on the file, so no other writer (in this
+	/// process or another) appends to it at the same time. The lock is taken
+	/// on a lock file next to it, at {path}.lock, which segments or a cold
+	/// tier don't affect. Fails with WouldBlock if the lock is already held.
+	pub fn acquire_exclusive_lock(&self) -> io::Result<FileLock> {
+		lock_exclusive(&self.path)
+	}
+
+	/// Open an existing file for reads only, backed by a mmap. Segments are
+	/// detected like when opening for appends, writes will fail.
+	fn open_readonly(path: String, checksummed: bool) -> io::Result<AppendOnlyFile> {
+		AppendOnlyFile::open_file(path, 0, checksummed, true)
+	}
+
+	fn open_file(path: String, max_bytes: u64, checksummed: bool, readonly: bool) -> io::Result<AppendOnlyFile> {
+		let mut segment_size = max_bytes;
+		if segment_size == 0 && Path::new(&segment_path(&path, 1)).exists() {
+			segment_size = fs::metadata(&path)?.len();
+		}
+		let mut segments = vec![];
+		if segment_size > 0 {
+			while Path::new(&segment_path(&path, segments.len() + 1)).exists() {
+				segments.push(MappedFile::open(segment_path(&path, segments.len()))?);
+			}
+		}
+
+		let file = if readonly {
+			File::open(segment_path(&path, segments.len()))?
+		} else {
+			OpenOptions::new()
+				.read(true)
+				.append(true)
+				.create(true)
+				.open(segment_path(&path, segments.len()))?
+		};
 		let mut aof = AppendOnlyFile {
-			path: path.clone(),
+			path: path,
 			file: file,
 			mmap: None,
+			segments: segments,
+			segment_size: segment_size,
+			cold: None,
+			cold_threshold_bytes: 0,
+			checksummed: checksummed,
+			append_pos: 0,
+			synced_pos: 0,
 		};
-		let file_path = Path::new(&path);
-		if file_path.exists() {
-			aof.sync();
-		}
+		aof.sync()?;
+		Ok(aof)
+	}
+
+	/// Open a file as append-only, with its oldest data held in the provided
+	/// cold file, if it exists. The size of the cold file determines the tiers
+	/// threshold.
+	fn open_tiered(
+		path: String,
+		cold_path: String,
+		segment_size: u64,
+		checksummed: bool,
+	) -> io::Result<AppendOnlyFile> {
+		let mut aof = AppendOnlyFile::with_segment_size(path, segment_size, checksummed)?;
+		aof.attach_cold(cold_path)?;
 		Ok(aof)
 	}
 
-	/// Append data to the file.
-	fn append(&mut self, buf: &[u8]) -> io::Result<()> {
-		self.file.write_all(buf)
+	// Uses the provided cold file, if it exists, as the cold tier.
+	fn attach_cold(&mut self, cold_path: String) -> io::Result<()> {
+		if Path::new(&cold_path).exists() {
+			let cold = MappedFile::open(cold_path)?;
+			self.cold_threshold_bytes = cold.size;
+			self.append_pos += cold.size;
+			self.synced_pos += cold.size;
+			self.cold = Some(cold);
+		}
+		Ok(())
+	}
+
+	/// Append data to the file, followed by its checksum if checksummed. If
+	/// the file is segmented and the data would make the current segment
+	/// exceed its maximum size, a new segment is started.
+	pub fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+		let mut data = buf.to_vec();
+		if self.checksummed {
+			let mut checksum = [0; CHECKSUM_LEN];
+			LittleEndian::write_u32(&mut checksum, crc32::checksum_castagnoli(buf));
+			data.extend_from_slice(&checksum);
+		}
+		if self.segment_size > 0 {
+			let active_len = self.append_pos - self.cold_threshold_bytes - self.sealed_size();
+			if active_len > 0 && active_len + data.len() as u64 > self.segment_size {
+				self.rotate()?;
+			}
+		}
+		self.file.write_all(&data)?;
+		self.append_pos += data.len() as u64;
+		Ok(())
+	}
+
+	/// Number of bytes appended since the last sync.
+	fn buffered_bytes(&self) -> u64 {
+		self.append_pos - self.synced_pos
+	}
+
+	/// Total size of the file as of the last sync, cold tier and all segments
+	/// included. Unlike size, doesn't hit the file system.
+	fn synced_size(&self) -> u64 {
+		self.synced_pos
+	}
+
+	/// Reads the whole file sequentially, validating the checksum of every
+	/// record of the provided length (checksum excluded). Fails with
+	/// InvalidData on the first mismatch or on a truncated last record. Files
+	/// without checksums are always deemed valid.
+	fn verify_integrity(&self, record_len: usize) -> io::Result<()> {
+		if !self.checksummed {
+			return Ok(());
+		}
+		let mut reader = BufReader::new(self.reader()?);
+		let mut buf = vec![0; record_len + CHECKSUM_LEN];
+		let mut offset = 0;
+		loop {
+			let mut filled = 0;
+			while filled < buf.len() {
+				match reader.read(&mut buf[filled..]) {
+					Ok(0) => break,
+					Ok(len) => filled += len,
+					Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+					Err(e) => return Err(e),
+				}
+			}
+			if filled == 0 {
+				return Ok(());
+			}
+			if filled < buf.len() {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Truncated record at offset {} in {}", offset, self.path),
+				));
+			}
+			let checksum = LittleEndian::read_u32(&buf[record_len..]);
+			if checksum != crc32::checksum_castagnoli(&buf[..record_len]) {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Checksum mismatch for record at offset {} in {}", offset, self.path),
+				));
+			}
+			offset += buf.len();
+		}
+	}
+
+	/// Seals the segment currently written to and starts a new one.
+	fn rotate(&mut self) -> io::Result<()> {
+		self.file.sync_data()?;
+		let sealed = MappedFile::open(segment_path(&self.path, self.segments.len()))?;
+		self.segments.push(sealed);
+		self.file = OpenOptions::new()
+			.read(true)
+			.append(true)
+			.create(true)
+			.open(segment_path(&self.path, self.segments.len()))?;
+		self.mmap = None;
+		Ok(())
+	}
+
+	/// Truncates the file back to the provided size, dropping everything
+	/// written after it, synced or not. Segments past the new end are deleted
+	/// and the one holding it becomes active again. Data already moved to the
+	/// cold tier can't be rewound. The memory map is refreshed to the new end
+	/// of the file.
+	pub fn rewind(&mut self, target_size: u64) -> io::Result<()> {
+		// appends since the last sync are already written to the file, only
+		// not synced yet, so they can be rewound like the rest
+		let size = cmp::max(self.append_pos, self.size()?);
+		if target_size < self.cold_threshold_bytes || target_size > size {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("Can't rewind {} to {} bytes", self.path, target_size),
+			));
+		}
+		let mut hot_size = target_size - self.cold_threshold_bytes;
+		if self.segment_size > 0 {
+			let keep = cmp::min((hot_size / self.segment_size) as usize, self.segments.len());
+			if keep < self.segments.len() {
+				for idx in (keep + 1)..(self.segments.len() + 1) {
+					fs::remove_file(segment_path(&self.path, idx))?;
+				}
+				self.segments.truncate(keep);
+				self.file = OpenOptions::new()
+					.read(true)
+					.append(true)
+					.open(segment_path(&self.path, keep))?;
+			}
+			hot_size -= (keep as u64) * self.segment_size;
+		}
+		self.file.set_len(hot_size)?;
+		self.file.seek(SeekFrom::End(0))?;
+		self.append_pos = target_size;
+		// what's left of the unsynced appends, if anything, still needs a sync
+		self.synced_pos = cmp::min(self.synced_pos, target_size);
+		self.remap(hot_size)
 	}
 
 	/// Syncs all writes (fsync), reallocating the memory map to make the newly
 	/// written data accessible.
-	fn sync(&mut self) -> io::Result<()> {
+	pub fn sync(&mut self) -> io::Result<()> {
 		self.file.sync_data()?;
+		// the file is only written through append but may be shared with other
+		// read-only instances, so its size is refreshed here
+		let active_len = self.file.metadata()?.len();
+		self.append_pos = self.cold_threshold_bytes + self.sealed_size() + active_len;
+		self.synced_pos = self.append_pos;
+		self.remap(active_len)
+	}
+
+	// Maps the file currently written to, of the provided length, in memory.
+	fn remap(&mut self, active_len: u64) -> io::Result<()> {
+		if active_len == 0 {
+			// nothing to map yet
+			self.mmap = None;
+			return Ok(());
+		}
 		self.mmap = Some(unsafe {
 			memmap::file(&self.file)
 				.protection(memmap::Protection::Read)
@@ -78,20 +463,107 @@ impl AppendOnlyFile {
 		Ok(())
 	}
 
-	/// Read length bytes of data at offset from the file. Leverages the memory
-	/// map.
-	fn read(&self, offset: usize, length: usize) -> Vec<u8> {
-		if let None = self.mmap {
-			return vec![];
+	/// Reference to length bytes of data at offset in the file, without
+	/// copying anything. Leverages the memory map of the cold or hot file,
+	/// depending on the offset, and of the right segment for segmented files.
+	/// None if the data isn't all there.
+	fn slice(&self, offset: usize, length: usize) -> Option<&[u8]> {
+		if (offset as u64) < self.cold_threshold_bytes {
+			return match self.cold {
+				Some(ref cold) => slice_mmap(&cold.mmap, offset, length),
+				None => None,
+			};
+		}
+		let offset = offset - (self.cold_threshold_bytes as usize);
+		if self.segment_size > 0 {
+			let segment_size = self.segment_size as usize;
+			let idx = offset / segment_size;
+			if idx < self.segments.len() {
+				return slice_mmap(&self.segments[idx].mmap, offset % segment_size, length);
+			}
+			return slice_mmap(&self.mmap, offset - self.segments.len() * segment_size, length);
+		}
+		slice_mmap(&self.mmap, offset, length)
+	}
+
+	/// Copies the data at offset in the file into the provided buffer, filling
+	/// it entirely. Fails if the file doesn't have enough data.
+	pub fn read_into(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+		match self.slice(offset, buf.len()) {
+			Some(data) => {
+				buf.copy_from_slice(data);
+				Ok(())
+			}
+			None => Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				format!("No {} bytes at offset {} in {}", buf.len(), offset, self.path),
+			)),
+		}
+	}
+
+	/// Writes the content of the provided reader aside, its first threshold
+	/// bytes in a new version of the provided cold file and the rest in new
+	/// versions of the hot file segments. Nothing is replaced yet, the
+	/// returned swap moves the new files in place once committed, after which
+	/// the file needs to be reopened.
+	fn prepare_rewrite(&self, src: &mut Read, cold_path: Option<&str>, threshold: u64) -> io::Result<FileSwap> {
+		let mut swap = FileSwap::default();
+		if let Some(cold_path) = cold_path {
+			let tmp_path = format!("{}.tier", cold_path);
+			let mut writer = File::create(&tmp_path)?;
+			io::copy(&mut (&mut *src).take(threshold), &mut writer)?;
+			writer.sync_all()?;
+			swap.rename(tmp_path, cold_path.to_string());
+		}
+
+		let mut idx = 0;
+		loop {
+			let tmp_path = format!("{}.tier", segment_path(&self.path, idx));
+			let mut writer = File::create(&tmp_path)?;
+			let written = if self.segment_size > 0 {
+				io::copy(&mut (&mut *src).take(self.segment_size), &mut writer)?
+			} else {
+				io::copy(src, &mut writer)?
+			};
+			writer.sync_all()?;
+			if written == 0 && idx > 0 {
+				fs::remove_file(tmp_path)?;
+				break;
+			}
+			swap.rename(tmp_path, segment_path(&self.path, idx));
+			idx += 1;
+			if self.segment_size == 0 || written < self.segment_size {
+				break;
+			}
+		}
+
+		// the trailing segments the new content doesn't fill anymore go
+		while Path::new(&segment_path(&self.path, idx)).exists() {
+			swap.remove(segment_path(&self.path, idx));
+			idx += 1;
+		}
+		Ok(swap)
+	}
+
+	/// Reader over the full content of the file, cold tier and all segments
+	/// included.
+	fn reader(&self) -> io::Result<Box<Read>> {
+		let mut reader: Box<Read> = match self.cold {
+			Some(ref cold) => Box::new(File::open(cold.path.clone())?),
+			None => Box::new(io::empty()),
+		};
+		for segment in &self.segments {
+			reader = Box::new(reader.chain(File::open(segment.path.clone())?));
 		}
-		let mmap = self.mmap.as_ref().unwrap();
-		(&mmap[offset..(offset + length)]).to_vec()
+		let active = File::open(segment_path(&self.path, self.segments.len()))?;
+		Ok(Box::new(reader.chain(active)))
 	}
 
 	/// Saves a copy of the current file content, skipping data at the provided
-	/// prune indices. The prune Vec must be ordered.
+	/// prune indices. The prune Vec must be ordered. The copy includes the
+	/// data held in the cold tier, if any, and is synced once complete.
 	fn save_prune(&self, target: String, prune_offs: Vec<u64>, prune_len: u64) -> io::Result<()> {
-		let mut reader = File::open(self.path.clone())?;
+		let mut reader = self.reader()?;
 		let mut writer = File::create(target)?;
 
 		// align the buffer on prune_len to avoid misalignments
@@ -101,7 +573,7 @@ impl AppendOnlyFile {
 		loop {
 			// fill our buffer
 			let len = match reader.read(&mut buf) {
-				Ok(0) => return Ok(()),
+				Ok(0) => return writer.sync_all(),
 				Ok(len) => len,
 				Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
 				Err(e) => return Err(e),
@@ -127,9 +599,66 @@ impl AppendOnlyFile {
 		}
 	}
 
-	/// Current size of the file in bytes.
-	fn size(&self) -> io::Result<u64> {
-		fs::metadata(&self.path).map(|md| md.len())
+	/// Whether the file got replaced on disk since it was opened, as
+	/// compaction does, or a new segment was started. Either way its content
+	/// can only be read again by reopening it.
+	fn replaced(&self) -> io::Result<bool> {
+		if Path::new(&segment_path(&self.path, self.segments.len() + 1)).exists() {
+			return Ok(true);
+		}
+		let on_disk = match fs::metadata(segment_path(&self.path, self.segments.len())) {
+			Ok(meta) => meta,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+			Err(e) => return Err(e),
+		};
+		match (file_id(&self.file.metadata()?), file_id(&on_disk)) {
+			(Some(open), Some(current)) => Ok(open != current),
+			// no way to tell, assume the worst
+			_ => Ok(true),
+		}
+	}
+
+	/// Current size of the file in bytes, cold tier and all segments
+	/// included.
+	pub fn size(&self) -> io::Result<u64> {
+		let active = self.file.metadata()?.len();
+		Ok(self.cold_threshold_bytes + self.sealed_size() + active)
+	}
+
+	// Size of all the sealed segments.
+	fn sealed_size(&self) -> u64 {
+		self.segments.iter().map(|s| s.size).sum()
+	}
+}
+
+// Identifies a file on disk, changing when it gets replaced rather than
+// written to. Only known on unix, where it's the inode number.
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<u64> {
+	use std::os::unix::fs::MetadataExt;
+	Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &fs::Metadata) -> Option<u64> {
+	None
+}
+
+// Path of the segment at the provided index, the first segment being the
+// base file itself.
+fn segment_path(path: &str, idx: usize) -> String {
+	if idx == 0 {
+		path.to_string()
+	} else {
+		format!("{}_{:03}", path, idx)
+	}
+}
+
+fn slice_mmap(mmap: &Option<memmap::Mmap>, offset: usize, length: usize) -> Option<&[u8]> {
+	match *mmap {
+		Some(ref mmap) if offset + length <= mmap.len() => Some(&mmap[offset..(offset + length)]),
+		Some(_) => None,
+		None => None,
 	}
 }
 
@@ -147,10 +676,28 @@ struct RemoveLog {
 
 impl RemoveLog {
 	/// Open the remove log file. The content of the file will be read in memory
-	/// for fast checking.
+	/// for fast checking. An incomplete last entry, left by an unclean
+	/// shutdown, is dropped and the file rewritten without it.
 	fn open(path: String) -> io::Result<RemoveLog> {
-		let removed = read_ordered_vec(path.clone())?;
+		let removed: Vec<u64> = read_ordered_vec_tolerant(path.clone())?;
 		let file = OpenOptions::new().append(true).create(true).open(path.clone())?;
+		let complete = file.metadata()?.len() == (removed.len() * 8) as u64;
+		let mut rm_log = RemoveLog {
+			path: path,
+			file: file,
+			removed: removed,
+		};
+		if !complete {
+			rm_log.sync()?;
+		}
+		Ok(rm_log)
+	}
+
+	/// Open the remove log file for reads only, without recovering from an
+	/// unclean shutdown.
+	fn open_readonly(path: String) -> io::Result<RemoveLog> {
+		let removed = decode_ordered_vec(&path, true)?;
+		let file = File::open(path.clone())?;
 		Ok(RemoveLog {
 			path: path,
 			file: file,
@@ -158,6 +705,57 @@ impl RemoveLog {
 		})
 	}
 
+	/// Merges 2 remove logs (typically the ones of the main chain and of a
+	/// fork during a reorg) into a new one holding the union of their
+	/// positions. The new log uses the path of the first one but is only kept
+	/// in memory until synced.
+	#[allow(dead_code)]
+	fn merge(a: &RemoveLog, b: &RemoveLog) -> io::Result<RemoveLog> {
+		let mut removed = Vec::with_capacity(a.removed.len() + b.removed.len());
+		let (mut i, mut j) = (0, 0);
+		while i < a.removed.len() || j < b.removed.len() {
+			let next = if j == b.removed.len() || (i < a.removed.len() && a.removed[i] <= b.removed[j]) {
+				i += 1;
+				a.removed[i - 1]
+			} else {
+				j += 1;
+				b.removed[j - 1]
+			};
+			if removed.last() != Some(&next) {
+				removed.push(next);
+			}
+		}
+		let file = OpenOptions::new().append(true).create(true).open(a.path.clone())?;
+		Ok(RemoveLog {
+			path: a.path.clone(),
+			file: file,
+			removed: removed,
+		})
+	}
+
+	/// Rewrites the whole remove log file from the in-memory positions.
+	fn sync(&mut self) -> io::Result<()> {
+		write_vec(self.path.clone(), &self.removed)?;
+		self.file = OpenOptions::new().append(true).open(self.path.clone())?;
+		Ok(())
+	}
+
+	/// Forgets all positions greater than the provided threshold, rewriting
+	/// the file.
+	pub fn clear_positions_above(&mut self, threshold: u64) -> io::Result<()> {
+		self.removed.retain(|&pos| pos <= threshold);
+		self.sync()
+	}
+
+	/// Forgets all positions appended after the first len ones, truncating
+	/// the file.
+	fn rollback(&mut self, len: usize) -> io::Result<()> {
+		self.file.set_len((len * 8) as u64)?;
+		self.file.sync_data()?;
+		self.removed = decode_ordered_vec(&self.path, false)?;
+		Ok(())
+	}
+
 	/// Truncate and empties the remove log.
 	fn truncate(&mut self) -> io::Result<()> {
 		self.removed = vec![];
@@ -190,6 +788,35 @@ impl RemoveLog {
 	fn len(&self) -> usize {
 		self.removed.len()
 	}
+
+	/// Iterator over the positions stored in the remove log, in order.
+	pub fn iter(&self) -> iter::Cloned<slice::Iter<u64>> {
+		self.removed.iter().cloned()
+	}
+
+	/// The positions stored in the remove log, in order.
+	pub fn as_slice(&self) -> &[u64] {
+		&self.removed[..]
+	}
+}
+
+/// State of a PMMR backend at the start of a transaction, which can be
+/// rolled back to until the backend is synced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Savepoint {
+	mmr_size: u64,
+	data_size: u64,
+	rm_log_len: usize,
+	buffer_index: usize,
+	buffer_len: usize,
+	wal_len: usize,
+}
+
+impl Savepoint {
+	/// Size of the MMR when the savepoint was taken.
+	pub fn mmr_size(&self) -> u64 {
+		self.mmr_size
+	}
 }
 
 /// PMMR persistent backend implementation. Relies on multiple facilities to
@@ -200,32 +827,73 @@ impl RemoveLog {
 /// * An in-memory backend buffers the latest batch of writes to ensure the
 /// PMMR can always read recent values even if they haven't been flushed to
 /// disk yet.
+/// * A write-ahead log records every batch of appended HashSums before they
+/// get written to the main storage file, until they're synced. Batches that
+/// didn't make it to disk before a crash are re-applied on startup.
 /// * A remove log tracks the positions that need to be pruned from the
 /// main storage file.
+/// * Optionally, the oldest part of the main storage file can be moved to a
+/// cold tier in a separate directory (see set_cold_tier).
+/// * Optionally, the most recently appended HashSums are also kept in memory
+/// in a hot tier, reads of recent data not going through the file (see
+/// set_hot_threshold).
+///
+/// A backend opened for writes holds an exclusive lock on its main storage
+/// file for as long as it lives, a second one on the same directory can't be
+/// opened until it's dropped.
 pub struct PMMRBackend<T>
 where
 	T: Summable + Clone,
 {
 	data_dir: String,
+	cold_dir: Option<String>,
 	hashsum_file: AppendOnlyFile,
+	wal: AppendOnlyFile,
+	// write-ahead log entries of the batches appended since the last sync,
+	// logged all at once when syncing
+	wal_entries: Vec<Vec<u8>>,
 	remove_log: RemoveLog,
 	pruned_nodes: pmmr::PruneList,
 	// buffers addition of new elements until they're fully written to disk
 	buffer: VecBackend<T>,
 	buffer_index: usize,
+	// copy of the latest appended HashSums, from the position after
+	// hot_start, already in the data file or the write buffer
+	hot: VecBackend<T>,
+	hot_start: u64,
+	readonly: bool,
+	opts: PMMROptions,
+	// archive of the pruned data, when enabled
+	cold_store: Option<ColdStore>,
+	// exclusive lock on the storage files, none when read-only
+	_lock: Option<FileLock>,
 }
 
 impl<T> Backend<T> for PMMRBackend<T>
 where
 	T: Summable + Clone,
 {
-	/// Append the provided HashSums to the backend storage.
-	#[allow(unused_variables)]
+	/// Append the provided HashSums to the backend storage. The batch is
+	/// recorded in the write-ahead log on the next sync.
 	fn append(&mut self, position: u64, data: Vec<HashSum<T>>) -> Result<(), String> {
+		if self.readonly {
+			return Err("backend is read-only".into());
+		}
+		let mut entry = vec![0; 16];
+		LittleEndian::write_u64(&mut entry[0..8], position);
+		LittleEndian::write_u64(&mut entry[8..16], data.len() as u64);
+		for hs in &data {
+			entry.extend_from_slice(&ser::ser_vec(hs).unwrap()[..]);
+		}
+		self.wal_entries.push(entry);
+
 		self.buffer.append(
 			position - (self.buffer_index as u64),
 			data.clone(),
 		)?;
+		if self.opts.hot_threshold > 0 {
+			self.hot.append(position - self.hot_start, data.clone())?;
+		}
 		for hs in data {
 			if let Err(e) = self.hashsum_file.append(&ser::ser_vec(&hs).unwrap()[..]) {
 				return Err(format!(
@@ -256,13 +924,32 @@ where
 			return None
 		}
 
+		// Fourth, check if it's recent enough to be in the hot tier
+		if position > self.hot_start && position <= self.hot_start + self.hot.len() as u64 {
+			return self.hot.get(position - self.hot_start);
+		}
+
 		// The MMR starts at 1, our binary backend starts at 0
 		let pos = position - 1;
 
-		// Must be on disk, doing a read at the correct position
+		// Must be on disk, doing a read at the correct position, the checksum
+		// (if any) is left out
 		let record_len = 32 + T::sum_len();
-		let file_offset = ((pos - shift.unwrap()) as usize) * record_len;
-		let data = self.hashsum_file.read(file_offset, record_len);
+		let file_offset = ((pos - shift.unwrap()) * self.stored_len()) as usize;
+		// records are small enough to fit on the stack, sparing an allocation
+		// on every read
+		let mut stack_buf = [0; 64];
+		let mut heap_buf = vec![];
+		let data = if record_len <= stack_buf.len() {
+			&mut stack_buf[..record_len]
+		} else {
+			heap_buf.resize(record_len, 0);
+			&mut heap_buf[..]
+		};
+		if let Err(_) = self.hashsum_file.read_into(file_offset, data) {
+			// past the end, nothing there
+			return None;
+		}
 		match ser::deserialize(&mut &data[..]) {
 			Ok(hashsum) => Some(hashsum),
 			Err(e) => {
@@ -277,6 +964,9 @@ where
 
 	/// Remove HashSums by insertion position
 	fn remove(&mut self, positions: Vec<u64>) -> Result<(), String> {
+		if self.readonly {
+			return Err("backend is read-only".into());
+		}
 		if self.buffer.used_size() > 0 {
 			self.buffer.remove(positions.clone()).unwrap();
 		}
@@ -284,6 +974,55 @@ where
 			format!("Could not write to log storage, disk full? {:?}", e)
 		})
 	}
+
+	/// Rewinds the backend to the provided MMR position, dropping all the
+	/// data appended after it, whether already synced or not, and forgetting
+	/// the removal of positions after it. Used when the chain gets
+	/// reorganized. Can't rewind to a position whose successors have already
+	/// been compacted away. When rewinding within the data appended since the
+	/// last sync, what's left of it stays pending, otherwise the rewound state
+	/// gets synced.
+	fn rewind(&mut self, position: u64) -> Result<(), String> {
+		if self.readonly {
+			return Err("backend is read-only".into());
+		}
+		let shift = match self.pruned_nodes.get_shift(position + 1) {
+			Some(shift) => shift,
+			None => return Err(format!("Can't rewind to {}, following data already compacted", position)),
+		};
+		let file_pos = position - shift;
+		let stored_len = self.stored_len();
+		self.hashsum_file.rewind(file_pos * stored_len).map_err(|e| {
+			format!("Could not rewind log storage: {:?}", e)
+		})?;
+		self.remove_log.clear_positions_above(position).map_err(|e| {
+			format!("Could not rewind the remove log: {:?}", e)
+		})?;
+		self.rewind_hot(position);
+
+		// rewinding within the batches appended since the last sync, the ones
+		// before the position are kept pending
+		if file_pos >= self.buffer_index as u64 {
+			self.buffer.rewind(file_pos - self.buffer_index as u64)?;
+			self.rewind_wal_entries(position);
+			return Ok(());
+		}
+
+		// everything left is in the file, the buffer and the write-ahead log
+		// are now useless
+		self.buffer.clear();
+		self.wal_entries.clear();
+		self.buffer_index = file_pos as usize;
+		self.hashsum_file.sync()
+			.and_then(|_| self.clear_wal())
+			.map_err(|e| format!("Could not sync log storage: {:?}", e))
+	}
+
+	/// Size of the MMR held by the backend, pruned positions included.
+	fn current_size(&self) -> u64 {
+		let sz = self.hashsum_file.synced_size() + self.hashsum_file.buffered_bytes();
+		sz / self.stored_len() + self.pruned_nodes.total_shift()
+	}
 }
 
 impl<T> PMMRBackend<T>
@@ -291,133 +1030,1316 @@ where
 	T: Summable + Clone,
 {
 	/// Instantiates a new PMMR backend that will use the provided directly to
-	/// store its files.
-	pub fn new(data_dir: String) -> io::Result<PMMRBackend<T>> {
-		let hs_file = AppendOnlyFile::open(format!("{}/{}", data_dir, PMMR_DATA_FILE))?;
-		let sz = hs_file.size()?;
+	/// store its files. With integrity_check, the checksums of all records
+	/// in the data file are verified before opening.
+	pub fn new(data_dir: String, integrity_check: bool) -> io::Result<PMMRBackend<T>> {
+		let opts = PMMROptions {
+			enable_integrity_check: integrity_check,
+			..Default::default()
+		};
+		PMMRBackend::open(data_dir, 0, opts)
+	}
+
+	/// Instantiates a new PMMR backend in the provided directory, tuned with
+	/// the provided options.
+	pub fn new_with_opts(data_dir: String, opts: PMMROptions) -> io::Result<PMMRBackend<T>> {
+		PMMRBackend::open(data_dir, 0, opts)
+	}
+
+	/// Instantiates a new PMMR backend splitting its main data file in
+	/// segments of at most max_bytes (rounded down to a whole number of
+	/// records). Once created, segmented files are detected automatically
+	/// when reopened with new.
+	pub fn with_segment_size(data_dir: String, max_bytes: u64) -> io::Result<PMMRBackend<T>> {
+		if max_bytes == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty segment size"));
+		}
+		PMMRBackend::open(data_dir, max_bytes, Default::default())
+	}
+
+	fn open(data_dir: String, max_segment_size: u64, opts: PMMROptions) -> io::Result<PMMRBackend<T>> {
+		let checksummed = read_format_version(&data_dir)? >= 2;
 		let record_len = 32 + T::sum_len();
+		let stored_len = stored_record_len::<T>(checksummed);
+		if max_segment_size > 0 && max_segment_size < stored_len {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("Segment size {} too small for records of {} bytes", max_segment_size, stored_len),
+			));
+		}
+		let segment_size = max_segment_size - (max_segment_size % stored_len);
+
+		let data_path = format!("{}/{}", data_dir, PMMR_DATA_FILE);
+		// nothing gets written before we hold the lock
+		let lock = lock_exclusive(&data_path)?;
+		recover_tmp_files(&data_dir)?;
+		recover_swap(&data_dir)?;
+
+		let cold_dir = read_cold_dir(&data_dir)?;
+		let hs_file = match cold_dir {
+			Some(ref cold_dir) => AppendOnlyFile::open_tiered(
+				data_path,
+				format!("{}/{}", cold_dir, PMMR_COLD_DATA_FILE),
+				segment_size,
+				checksummed,
+			)?,
+			None => AppendOnlyFile::with_segment_size(data_path, segment_size, checksummed)?,
+		};
+		let wal = AppendOnlyFile::with_segment_size(format!("{}/{}", data_dir, PMMR_WAL_FILE), 0, true)?;
 		let rm_log = RemoveLog::open(format!("{}/{}", data_dir, PMMR_RM_LOG_FILE))?;
-		let prune_list = read_ordered_vec(format!("{}/{}", data_dir, PMMR_PRUNED_FILE))?;
+		let prune_list = read_prune_list(format!("{}/{}", data_dir, PMMR_PRUNED_FILE))?;
+		let cold_store = if opts.archive_pruned {
+			Some(ColdStore::open(format!("{}/{}", data_dir, PMMR_ARCHIVE_FILE))?)
+		} else {
+			None
+		};
 
-		Ok(PMMRBackend {
+		let mut backend = PMMRBackend {
 			data_dir: data_dir,
+			cold_dir: cold_dir,
+			hashsum_file: hs_file,
+			wal: wal,
+			wal_entries: vec![],
+			remove_log: rm_log,
+			buffer: VecBackend::with_capacity(opts.buffer_capacity),
+			buffer_index: 0,
+			hot: VecBackend::with_capacity(opts.hot_threshold as usize),
+			hot_start: 0,
+			pruned_nodes: prune_list,
+			readonly: false,
+			opts: opts,
+			cold_store: cold_store,
+			_lock: Some(lock),
+		};
+		backend.recover_compaction()?;
+		backend.replay_wal()?;
+		if backend.opts.enable_integrity_check {
+			backend.hashsum_file.verify_integrity(record_len)?;
+		}
+		backend.buffer_index = (backend.hashsum_file.synced_size() / stored_len) as usize;
+		backend.pruned_nodes.build_index();
+		// the hot tier only gets what's appended from now on
+		backend.hot_start = backend.current_size();
+		if backend.opts.enable_consistency_check {
+			backend.verify_consistency().map_err(|e| {
+				io::Error::new(io::ErrorKind::InvalidData, format!("{} in {}", e, backend.data_dir))
+			})?;
+		}
+		Ok(backend)
+	}
+
+	/// Opens the backend stored in the provided directory for reads only,
+	/// for archive nodes or explorers that shouldn't risk modifying the chain
+	/// state. No file is created or written to and data not synced yet (in
+	/// the write-ahead log) is ignored. The directory can be opened read-write
+	/// by another backend at the same time.
+	pub fn open_readonly(data_dir: &str) -> io::Result<PMMRBackend<T>> {
+		let checksummed = match read_format_version_readonly(data_dir)? {
+			Some(version) => version >= 2,
+			None => {
+				return Err(io::Error::new(
+					io::ErrorKind::NotFound,
+					format!("No PMMR data in {}", data_dir),
+				))
+			}
+		};
+		let cold_dir = read_cold_dir(data_dir)?;
+		let mut hs_file = AppendOnlyFile::open_readonly(format!("{}/{}", data_dir, PMMR_DATA_FILE), checksummed)?;
+		if let Some(ref cold_dir) = cold_dir {
+			hs_file.attach_cold(format!("{}/{}", cold_dir, PMMR_COLD_DATA_FILE))?;
+		}
+		let wal = AppendOnlyFile::open_readonly(format!("{}/{}", data_dir, PMMR_WAL_FILE), true)?;
+		let rm_log = RemoveLog::open_readonly(format!("{}/{}", data_dir, PMMR_RM_LOG_FILE))?;
+		let mut prune_list = decode_prune_list(&format!("{}/{}", data_dir, PMMR_PRUNED_FILE))?;
+		prune_list.build_index();
+		let stored_len = stored_record_len::<T>(checksummed);
+		let sz = hs_file.synced_size();
+
+		Ok(PMMRBackend {
+			data_dir: data_dir.to_string(),
+			cold_dir: cold_dir,
 			hashsum_file: hs_file,
+			wal: wal,
+			wal_entries: vec![],
 			remove_log: rm_log,
 			buffer: VecBackend::new(),
-			buffer_index: (sz as usize) / record_len,
-			pruned_nodes: pmmr::PruneList{pruned_nodes: prune_list},
+			buffer_index: (sz / stored_len) as usize,
+			hot: VecBackend::new(),
+			hot_start: 0,
+			pruned_nodes: prune_list,
+			readonly: true,
+			opts: Default::default(),
+			cold_store: None,
+			_lock: None,
 		})
 	}
 
 	/// Syncs all files to disk. A call to sync is required to ensure all the
-	/// data has been successfully written to disk.
+	/// data has been successfully written to disk. For read-only backends,
+	/// catches up with what the read-write one synced instead.
 	pub fn sync(&mut self) -> io::Result<()> {
+		if self.readonly {
+			return self.refresh();
+		}
+		self.flush_wal()?;
 		self.buffer_index = self.buffer_index + self.buffer.len();
 		self.buffer.clear();
 
-		self.hashsum_file.sync()
+		self.hashsum_file.sync()?;
+		self.migrate_hot();
+		self.clear_wal()
 	}
 
-	/// Checks the length of the remove log to see if it should get compacted.
-	/// If so, the remove log is flushed into the pruned list, which itself gets
-	/// saved, and the main hashsum data file is rewritten, cutting the removed
-	/// data.
-	///
-	/// If a max_len strictly greater than 0 is provided, the value will be used
-	/// to decide whether the remove log has reached its maximum length,
-	/// otherwise the RM_LOG_MAX_NODES default value is used.
-	pub fn check_compact(&mut self, max_len: usize) -> io::Result<()> {
-		if !(max_len > 0 && self.remove_log.len() > max_len ||
-			max_len == 0 && self.remove_log.len() > RM_LOG_MAX_NODES) {
-			return Ok(())
+	// Catches up a read-only backend with the read-write one. Once the data
+	// file has been replaced by a compaction, the prune list changed as well
+	// and everything gets reopened. Otherwise new data only needs a new
+	// memory map, new removals a reload of the remove log.
+	fn refresh(&mut self) -> io::Result<()> {
+		if self.hashsum_file.replaced()? {
+			let reopened = PMMRBackend::open_readonly(&self.data_dir)?;
+			*self = reopened;
+			return Ok(());
 		}
+		self.hashsum_file.sync()?;
+		self.buffer_index = (self.hashsum_file.synced_size() / self.stored_len()) as usize;
+		self.remove_log = RemoveLog::open_readonly(format!("{}/{}", self.data_dir, PMMR_RM_LOG_FILE))?;
+		Ok(())
+	}
 
-		// 0. validate none of the nodes in the rm log are in the prune list (to
-		// avoid accidental double compaction)
-		for pos in &self.remove_log.removed[..] {
-			if let None = self.pruned_nodes.pruned_pos(*pos) {
-				// TODO we likely can recover from this by directly jumping to 3
-				error!("The remove log contains nodes that are already in the pruned \
-							 list, a previous compaction likely failed.");
-				return Ok(());
-			}
+	/// Records the batches appended since the last sync in the write-ahead
+	/// log, syncing it once for all of them. Done first thing by sync, the
+	/// data file only getting synced once the log is safely on disk.
+	pub fn flush_wal(&mut self) -> io::Result<()> {
+		if self.wal_entries.is_empty() {
+			return Ok(());
 		}
+		for entry in &self.wal_entries {
+			self.wal.append(entry)?;
+		}
+		self.wal.sync()?;
+		self.wal_entries.clear();
+		Ok(())
+	}
 
-		// 1. save hashsum file to a compact copy, skipping data that's in the
-		// remove list
-		let tmp_prune_file = format!("{}/{}.prune", self.data_dir, PMMR_DATA_FILE);
-		let record_len = (32 + T::sum_len()) as u64;
-		let to_rm = self.remove_log.removed.iter().map(|pos| {
-			let shift = self.pruned_nodes.get_shift(*pos);
-			(*pos - 1 - shift.unwrap()) * record_len
-		}).collect();
-		self.hashsum_file.save_prune(tmp_prune_file.clone(), to_rm, record_len)?;
-
-		// 2. update the prune list and save it in place
-		for rm_pos in &self.remove_log.removed[..] {
-			self.pruned_nodes.add(*rm_pos);
+	/// Sets the number of the most recently appended HashSums kept in memory,
+	/// on top of the data file. Lowering it moves the oldest ones out of
+	/// memory, raising it only makes room for the ones appended from now on.
+	/// 0 disables the hot tier.
+	pub fn set_hot_threshold(&mut self, threshold: u64) {
+		self.opts.hot_threshold = threshold;
+		if threshold == 0 || self.hot.len() == 0 {
+			self.hot.clear();
+			self.hot_start = self.current_size();
 		}
-		write_vec(format!("{}/{}", self.data_dir, PMMR_PRUNED_FILE), &self.pruned_nodes.pruned_nodes)?;
+		self.migrate_hot();
+	}
+
+	// Drops the oldest HashSums of the hot tier past its threshold, they're
+	// already in the data file (or the write buffer until synced).
+	fn migrate_hot(&mut self) {
+		let len = self.hot.len() as u64;
+		if len > self.opts.hot_threshold {
+			let excess = len - self.opts.hot_threshold;
+			self.hot.drop_oldest(excess as usize);
+			self.hot_start += excess;
+		}
+	}
+
+	// Drops the HashSums appended after the provided position from the
+	// write-ahead log entries not flushed yet, the batch holding the
+	// position getting truncated.
+	fn rewind_wal_entries(&mut self, position: u64) {
+		self.wal_entries.retain(|entry| LittleEndian::read_u64(&entry[0..8]) <= position);
+		if let Some(entry) = self.wal_entries.last_mut() {
+			let start = LittleEndian::read_u64(&entry[0..8]);
+			let count = LittleEndian::read_u64(&entry[8..16]);
+			if start + count > position + 1 {
+				let record_len = (entry.len() as u64 - 16) / count;
+				let kept = position + 1 - start;
+				LittleEndian::write_u64(&mut entry[8..16], kept);
+				entry.truncate((16 + kept * record_len) as usize);
+			}
+		}
+	}
+
+	// Drops the HashSums of the hot tier after the provided position.
+	fn rewind_hot(&mut self, position: u64) {
+		if position > self.hot_start {
+			// can't fail, the vector backend just truncates
+			let _ = self.hot.rewind(position - self.hot_start);
+		} else {
+			self.hot.clear();
+			self.hot_start = position;
+		}
+	}
+
+	/// Starts a transaction, typically to apply a block. The returned
+	/// savepoint allows rolling back all the changes made from now on, until
+	/// they're committed.
+	pub fn begin_transaction(&self) -> Savepoint {
+		Savepoint {
+			mmr_size: self.current_size(),
+			data_size: self.hashsum_file.synced_size() + self.hashsum_file.buffered_bytes(),
+			rm_log_len: self.remove_log.len(),
+			buffer_index: self.buffer_index,
+			buffer_len: self.buffer.len(),
+			wal_len: self.wal_entries.len(),
+		}
+	}
+
+	/// Commits the changes made since the start of the transaction, making
+	/// them durable. Same as sync.
+	pub fn commit(&mut self) -> io::Result<()> {
+		self.sync()
+	}
+
+	/// Reverts all the appends and removals made since the provided
+	/// savepoint was taken. Fails if they've already been committed. The
+	/// state at the savepoint gets synced.
+	pub fn rollback(&mut self, savepoint: Savepoint) -> io::Result<()> {
+		if self.readonly {
+			return Err(io::Error::new(io::ErrorKind::Other, "backend is read-only"));
+		}
+		if savepoint.buffer_index != self.buffer_index || savepoint.buffer_len > self.buffer.len() ||
+			savepoint.rm_log_len > self.remove_log.len() || savepoint.wal_len > self.wal_entries.len() {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"Changes since the savepoint already committed",
+			));
+		}
+		self.hashsum_file.rewind(savepoint.data_size)?;
+		self.remove_log.rollback(savepoint.rm_log_len)?;
+		self.rewind_hot(savepoint.mmr_size);
+		self.buffer.rewind(savepoint.buffer_len as u64).map_err(|e| {
+			io::Error::new(io::ErrorKind::Other, e)
+		})?;
+		// the rolled back appends mustn't make it to the write-ahead log
+		self.wal_entries.truncate(savepoint.wal_len);
+		self.sync()
+	}
+
+	/// Forgets the removal of the provided positions, which become readable
+	/// again. Typically needed when the chain gets reorganized and the
+	/// blocks that spent some elements are rewound. Positions that have
+	/// already been compacted away can't be restored. Pending appends have
+	/// to be synced first.
+	pub fn unremove(&mut self, positions: Vec<u64>) -> Result<(), String> {
+		if self.readonly {
+			return Err("backend is read-only".into());
+		}
+		if self.buffer.len() > 0 {
+			return Err("Can't unremove with unsynced appends".into());
+		}
+		if let Some(pos) = positions.iter().find(|&&pos| self.pruned_nodes.get_shift(pos).is_none()) {
+			return Err(format!("Can't unremove {}, already compacted", pos));
+		}
+		let mut positions = positions;
+		positions.sort();
+		self.remove_log.removed.retain(|pos| positions.binary_search(pos).is_err());
+		self.remove_log.sync().map_err(|e| {
+			format!("Could not write to log storage, disk full? {:?}", e)
+		})
+	}
+
+	// Re-applies the batches recorded in the write-ahead log to the data
+	// file, where a crash may have left them partially written or lost.
+	// Entries are trusted up to the first one that's incomplete or fails its
+	// checksum, as the log is written before the data file. The log is
+	// emptied once done.
+	fn replay_wal(&mut self) -> io::Result<()> {
+		let mut data = vec![];
+		self.wal.reader()?.read_to_end(&mut data)?;
+		if data.is_empty() {
+			return Ok(());
+		}
+
+		let record_len = 32 + T::sum_len();
+		let stored_len = self.stored_len();
+		let mut offset = 0;
+		let mut entries = 0;
+		while let Some((position, records)) = read_wal_entry(&data[offset..], record_len) {
+			let shift = self.pruned_nodes.get_shift(position).unwrap_or(0);
+			let file_offset = (position - 1 - shift) * stored_len;
+			if file_offset > self.hashsum_file.size()? {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Write-ahead log entry at {} past the end of the data file", position),
+				));
+			}
+			self.hashsum_file.rewind(file_offset)?;
+			for record in records.chunks(record_len) {
+				self.hashsum_file.append(record)?;
+			}
+			offset += 16 + records.len() + CHECKSUM_LEN;
+			entries += 1;
+		}
+		if offset < data.len() {
+			warn!("Discarding the last {} bytes of the write-ahead log, incomplete entry", data.len() - offset);
+		}
+		warn!("Re-applied {} entries from the write-ahead log in {}", entries, self.data_dir);
 
-		// 3. move the compact copy to the hashsum file and re-open it
-		fs::rename(tmp_prune_file.clone(), format!("{}/{}", self.data_dir, PMMR_DATA_FILE))?;
-		self.hashsum_file = AppendOnlyFile::open(format!("{}/{}", self.data_dir, PMMR_DATA_FILE))?;
 		self.hashsum_file.sync()?;
+		self.clear_wal()
+	}
+
+	// Empties the write-ahead log, once all the data it holds is safely in the
+	// data file.
+	fn clear_wal(&mut self) -> io::Result<()> {
+		self.wal.rewind(0)?;
+		self.wal.sync()
+	}
+
+	/// Moves the oldest age_threshold_bytes of the main data file to a cold
+	/// tier, stored under the provided path. Reads are transparently routed
+	/// to the right tier afterward. The threshold is rounded down to a whole
+	/// number of records. All data must have been synced beforehand.
+	pub fn set_cold_tier(&mut self, cold_path: &Path, age_threshold_bytes: u64) -> io::Result<()> {
+		if self.readonly {
+			return Err(io::Error::new(io::ErrorKind::Other, "backend is read-only"));
+		}
+		if self.buffer.len() > 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				"Unsynced data, can't move data to the cold tier.",
+			));
+		}
+		let cold_dir = cold_path.to_str().ok_or(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("Invalid cold tier path {:?}", cold_path),
+		))?.to_string();
+		fs::create_dir_all(&cold_dir)?;
+
+		let stored_len = self.stored_len();
+		let threshold = age_threshold_bytes - (age_threshold_bytes % stored_len);
+		let threshold = cmp::min(threshold, self.hashsum_file.size()?);
+		let same_dir = match self.cold_dir {
+			Some(ref dir) => fs::canonicalize(dir).ok() == fs::canonicalize(&cold_dir).ok(),
+			None => false,
+		};
+		if same_dir && threshold <= self.hashsum_file.cold_threshold_bytes {
+			// the threshold only ever grows
+			return Ok(());
+		}
+
+		// both tiers and the cold tier location are all replaced at once
+		let cold_file = format!("{}/{}", cold_dir, PMMR_COLD_DATA_FILE);
+		let mut swap = {
+			let mut reader = self.hashsum_file.reader()?;
+			self.hashsum_file.prepare_rewrite(&mut reader, Some(&cold_file[..]), threshold)?
+		};
+		if !same_dir {
+			if let Some(ref old_dir) = self.cold_dir {
+				swap.remove(format!("{}/{}", old_dir, PMMR_COLD_DATA_FILE));
+			}
+		}
+		let tier_path = format!("{}/{}", self.data_dir, PMMR_COLD_TIER_FILE);
+		let mut tier_file = File::create(format!("{}.tier", tier_path))?;
+		tier_file.write_all(cold_dir.as_bytes())?;
+		tier_file.sync_all()?;
+		swap.rename(format!("{}.tier", tier_path), tier_path);
+		swap.commit(&format!("{}/{}", self.data_dir, PMMR_SWAP_FILE))?;
+
+		self.cold_dir = Some(cold_dir);
+		self.reopen_data_file()
+	}
+
+	/// Checks that the data file, remove log and prune list are consistent
+	/// with each other and with the state of the backend.
+	pub fn verify_consistency(&self) -> Result<(), ConsistencyError> {
+		let stored_len = self.stored_len();
+		let size = self.hashsum_file.synced_size() + self.hashsum_file.buffered_bytes();
+		if size % stored_len != 0 {
+			return Err(ConsistencyError::FileSizeMismatch { remainder: size % stored_len });
+		}
+		for pos in self.remove_log.iter() {
+			if let None = self.pruned_nodes.pruned_pos(pos) {
+				return Err(ConsistencyError::RemoveLogPrunedOverlap { position: pos });
+			}
+		}
+		let computed = (self.hashsum_file.synced_size() / stored_len) as usize;
+		if computed != self.buffer_index {
+			return Err(ConsistencyError::BufferIndexMismatch {
+				computed: computed,
+				stored: self.buffer_index,
+			});
+		}
+		Ok(())
+	}
+
+	/// Checks the length of the remove log to see if it should get compacted.
+	/// If so, the remove log is flushed into the pruned list, which itself gets
+	/// saved, and the main hashsum data file is rewritten, cutting the removed
+	/// data.
+	///
+	/// If a max_len strictly greater than 0 is provided, the value will be used
+	/// to decide whether the remove log has reached its maximum length,
+	/// otherwise the one the backend was opened with is used.
+	pub fn check_compact(&mut self, max_len: usize) -> io::Result<()> {
+		if self.readonly {
+			return Err(io::Error::new(io::ErrorKind::Other, "backend is read-only"));
+		}
+		if !(max_len > 0 && self.remove_log.len() > max_len ||
+			max_len == 0 && self.remove_log.len() > self.opts.rm_log_max_nodes) {
+			return Ok(())
+		}
+
+		// 0. validate none of the nodes in the rm log are in the prune list (to
+		// avoid accidental double compaction)
+		for pos in self.remove_log.iter() {
+			if let None = self.pruned_nodes.pruned_pos(pos) {
+				error!("The remove log contains nodes that are already in the pruned \
+							 list, a previous compaction likely failed and will be completed \
+							 when reopening.");
+				return Ok(());
+			}
+		}
+
+		// 1. save hashsum file to a compact copy, skipping data that's in the
+		// remove list, after archiving it if enabled
+		let tmp_prune_file = format!("{}/{}.prune", self.data_dir, PMMR_DATA_FILE);
+		let stored_len = self.stored_len();
+		let to_rm: Vec<u64> = self.remove_log.iter().map(|pos| {
+			let shift = self.pruned_nodes.get_shift(pos);
+			(pos - 1 - shift.unwrap()) * stored_len
+		}).collect();
+		if let Some(ref mut cold_store) = self.cold_store {
+			let mut data = vec![0; 32 + T::sum_len()];
+			for (pos, offset) in self.remove_log.iter().zip(to_rm.iter()) {
+				self.hashsum_file.read_into(*offset as usize, &mut data)?;
+				cold_store.archive(pos, &data)?;
+			}
+			cold_store.sync()?;
+		}
+		self.hashsum_file.save_prune(tmp_prune_file.clone(), to_rm, stored_len)?;
+
+		// 2. update the prune list and save it in place
+		for rm_pos in self.remove_log.iter() {
+			self.pruned_nodes.add(rm_pos);
+		}
+		write_prune_list(format!("{}/{}", self.data_dir, PMMR_PRUNED_FILE), &self.pruned_nodes)?;
+
+		// 3. move the compact copy to the hashsum file and re-open it
+		self.replace_data_file(tmp_prune_file)?;
 
 		// 4. truncate the rm log
 		self.remove_log.truncate()?;
 
 		Ok(())
 	}
+
+	// Moves the provided compact copy of the data file in place and re-opens
+	// it. The copy includes the cold tier data, its first bytes go back to
+	// cold storage. Both tiers are replaced and the copy removed in a single
+	// swap.
+	fn replace_data_file(&mut self, compact_file: String) -> io::Result<()> {
+		let cold_file = self.cold_dir.as_ref().map(|dir| format!("{}/{}", dir, PMMR_COLD_DATA_FILE));
+		let mut swap = {
+			let mut src = File::open(&compact_file)?;
+			self.hashsum_file.prepare_rewrite(
+				&mut src,
+				cold_file.as_ref().map(|path| &path[..]),
+				self.hashsum_file.cold_threshold_bytes,
+			)?
+		};
+		swap.remove(compact_file);
+		swap.commit(&format!("{}/{}", self.data_dir, PMMR_SWAP_FILE))?;
+		self.reopen_data_file()
+	}
+
+	// Opens the data file again, after its files got replaced.
+	fn reopen_data_file(&mut self) -> io::Result<()> {
+		let data_path = format!("{}/{}", self.data_dir, PMMR_DATA_FILE);
+		let segment_size = self.hashsum_file.segment_size;
+		let checksummed = self.hashsum_file.checksummed;
+		self.hashsum_file = match self.cold_dir {
+			Some(ref cold_dir) => AppendOnlyFile::open_tiered(
+				data_path,
+				format!("{}/{}", cold_dir, PMMR_COLD_DATA_FILE),
+				segment_size,
+				checksummed,
+			)?,
+			None => AppendOnlyFile::with_segment_size(data_path, segment_size, checksummed)?,
+		};
+		Ok(())
+	}
+
+	// Deals with a compaction that got interrupted. Once the prune list is
+	// saved (step 2 of check_compact) it covers the whole remove log and only
+	// matches a compacted data file: the compaction is completed by moving
+	// its compact copy in place, if not done already, and truncating the
+	// remove log. Before that, the data file and prune list are still
+	// consistent with the remove log and the compact copy is just deleted.
+	fn recover_compaction(&mut self) -> io::Result<()> {
+		let compacted = self.remove_log.len() > 0 &&
+			self.remove_log.iter().all(|pos| self.pruned_nodes.pruned_pos(pos).is_none());
+		if !compacted {
+			return remove_stale_prune_files(&self.data_dir);
+		}
+		warn!("Completing interrupted compaction in {}", self.data_dir);
+		let prune_file = format!("{}/{}.prune", self.data_dir, PMMR_DATA_FILE);
+		if Path::new(&prune_file).exists() {
+			self.replace_data_file(prune_file)?;
+		}
+		self.remove_log.truncate()
+	}
+
+	/// HashSum archived at the provided position when it got pruned, if the
+	/// backend archives pruned data and it was compacted since.
+	pub fn get_archived(&self, position: u64) -> io::Result<Option<HashSum<T>>> {
+		let data = match self.cold_store {
+			Some(ref cold_store) => cold_store.retrieve(position)?,
+			None => None,
+		};
+		match data {
+			Some(data) => ser::deserialize(&mut &data[..]).map(Some).map_err(|e| {
+				io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+			}),
+			None => Ok(None),
+		}
+	}
+
+	// Length of a record in the data file, checksum included.
+	fn stored_len(&self) -> u64 {
+		stored_record_len::<T>(self.hashsum_file.checksummed)
+	}
 }
 
-// Read an ordered vector of scalars from a file.
+/// Either PMMR backend, as selected by the backend type of the options it's
+/// opened with.
+pub enum AnyBackend<T>
+where
+	T: Summable + Clone,
+{
+	/// Backend storing append-only files
+	FileBased(PMMRBackend<T>),
+	/// Backend storing a RocksDB database
+	RocksDb(RocksDbBackend<T>),
+}
+
+impl<T> Backend<T> for AnyBackend<T>
+where
+	T: Summable + Clone,
+{
+	fn append(&mut self, position: u64, data: Vec<HashSum<T>>) -> Result<(), String> {
+		match *self {
+			AnyBackend::FileBased(ref mut b) => b.append(position, data),
+			AnyBackend::RocksDb(ref mut b) => b.append(position, data),
+		}
+	}
+
+	fn get(&self, position: u64) -> Option<HashSum<T>> {
+		match *self {
+			AnyBackend::FileBased(ref b) => b.get(position),
+			AnyBackend::RocksDb(ref b) => b.get(position),
+		}
+	}
+
+	fn remove(&mut self, positions: Vec<u64>) -> Result<(), String> {
+		match *self {
+			AnyBackend::FileBased(ref mut b) => b.remove(positions),
+			AnyBackend::RocksDb(ref mut b) => b.remove(positions),
+		}
+	}
+
+	fn rewind(&mut self, position: u64) -> Result<(), String> {
+		match *self {
+			AnyBackend::FileBased(ref mut b) => b.rewind(position),
+			AnyBackend::RocksDb(ref mut b) => b.rewind(position),
+		}
+	}
+
+	fn current_size(&self) -> u64 {
+		match *self {
+			AnyBackend::FileBased(ref b) => b.current_size(),
+			AnyBackend::RocksDb(ref b) => b.current_size(),
+		}
+	}
+}
+
+impl<T> AnyBackend<T>
+where
+	T: Summable + Clone,
+{
+	/// Opens the backend of the type selected by the options in the provided
+	/// directory.
+	pub fn open(data_dir: String, opts: PMMROptions) -> io::Result<AnyBackend<T>> {
+		match opts.backend_type {
+			BackendType::FileBased => {
+				PMMRBackend::new_with_opts(data_dir, opts).map(AnyBackend::FileBased)
+			}
+			BackendType::RocksDb => RocksDbBackend::new(data_dir).map(AnyBackend::RocksDb),
+		}
+	}
+
+	/// Syncs the backend to disk.
+	pub fn sync(&mut self) -> io::Result<()> {
+		match *self {
+			AnyBackend::FileBased(ref mut b) => b.sync(),
+			AnyBackend::RocksDb(ref mut b) => b.sync(),
+		}
+	}
+}
+
+/// Description of a PMMR snapshot, stored along with its files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+	/// Size of the MMR, as its last position
+	pub mmr_size: u64,
+	/// Height of the chain tip the snapshot was taken at
+	pub height: u64,
+	/// Root hash of the MMR
+	pub root: Hash,
+}
+
+impl<T> PMMRBackend<T>
+where
+	T: Summable + Writeable + Debug + Clone,
+{
+	/// Exports a snapshot of the backend to the provided directory, which
+	/// shouldn't exist yet. Copies the data file (cold tier and segments
+	/// included), the remove log and the pruned list, along with a
+	/// snapshot_meta.json describing the MMR and the chain height it was
+	/// taken at. Everything is written aside first and only moved to the
+	/// destination once complete. All data must have been synced beforehand.
+	pub fn export_snapshot(&self, dest_dir: &str, height: u64) -> io::Result<()> {
+		if self.buffer.len() > 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				"Unsynced data, can't export a snapshot.",
+			));
+		}
+		let tmp_dir = format!("{}.tmp", dest_dir);
+		if Path::new(&tmp_dir).exists() {
+			fs::remove_dir_all(&tmp_dir)?;
+		}
+		fs::create_dir_all(&tmp_dir)?;
+
+		let mut data_file = File::create(format!("{}/{}", tmp_dir, PMMR_DATA_FILE))?;
+		io::copy(&mut self.hashsum_file.reader()?, &mut data_file)?;
+		data_file.sync_all()?;
+		for name in &[PMMR_RM_LOG_FILE, PMMR_PRUNED_FILE, PMMR_FORMAT_FILE] {
+			copy_synced(&format!("{}/{}", self.data_dir, name), &format!("{}/{}", tmp_dir, name))?;
+		}
+
+		let mmr_size = self.current_size();
+		let meta = SnapshotMeta {
+			mmr_size: mmr_size,
+			height: height,
+			root: PMMRReader::at(self, mmr_size).root().hash,
+		};
+		let meta_file = File::create(format!("{}/{}", tmp_dir, SNAPSHOT_META_FILE))?;
+		serde_json::to_writer_pretty(&meta_file, &meta)
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+		meta_file.sync_all()?;
+
+		fs::rename(tmp_dir, dest_dir)
+	}
+
+	/// Instantiates a backend in the provided data directory from a snapshot
+	/// previously exported. The snapshot files are copied over and the
+	/// resulting backend checked against the snapshot metadata.
+	pub fn from_snapshot(snapshot_dir: &str, data_dir: &str) -> io::Result<PMMRBackend<T>> {
+		let meta_file = File::open(format!("{}/{}", snapshot_dir, SNAPSHOT_META_FILE))?;
+		let meta: SnapshotMeta = serde_json::from_reader(meta_file)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+		fs::create_dir_all(data_dir)?;
+		for name in &[PMMR_DATA_FILE, PMMR_RM_LOG_FILE, PMMR_PRUNED_FILE, PMMR_FORMAT_FILE] {
+			copy_synced(&format!("{}/{}", snapshot_dir, name), &format!("{}/{}", data_dir, name))?;
+		}
+
+		let backend = PMMRBackend::new(data_dir.to_string(), true)?;
+		let size = backend.current_size();
+		if size != meta.mmr_size || PMMRReader::at(&backend, size).root().hash != meta.root {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("Snapshot in {} doesn't match its metadata", snapshot_dir),
+			));
+		}
+		Ok(backend)
+	}
+}
+
+// Deals with the temporary files left over by interrupted writes to any of
+// the files in data_dir, see recover_tmp_file.
+fn recover_tmp_files(data_dir: &str) -> io::Result<()> {
+	for entry in fs::read_dir(data_dir)? {
+		let entry = entry?;
+		let tmp_path = entry.path();
+		if entry.file_type()?.is_file() && tmp_path.extension().map(|ext| ext == "tmp").unwrap_or(false) {
+			let path = tmp_path.with_extension("");
+			recover_tmp_file(&path.to_string_lossy(), &tmp_path.to_string_lossy())?;
+		}
+	}
+	Ok(())
+}
+
+// Takes an exclusive lock on the file at the provided path, through a lock
+// file next to it. Fails with WouldBlock if the lock is already held.
+fn lock_exclusive(path: &str) -> io::Result<FileLock> {
+	let lock_path = format!("{}.lock", path);
+	let file = OpenOptions::new().read(true).write(true).create(true).open(&lock_path)?;
+	file.try_lock_exclusive().map_err(|e| {
+		if e.kind() == ErrorKind::WouldBlock {
+			io::Error::new(ErrorKind::WouldBlock, format!("{} is locked by another writer", path))
+		} else {
+			e
+		}
+	})?;
+	Ok(FileLock {
+		path: lock_path,
+		file: file,
+	})
+}
+
+/// Renames and removals of files, applied as a whole. The swap is recorded
+/// in a marker file before any file is touched and the marker removed once
+/// done. Every step being a no-op once done, an interrupted swap is
+/// completed by applying it again (see recover_swap).
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileSwap {
+	renames: Vec<(String, String)>,
+	removals: Vec<String>,
+}
+
+impl FileSwap {
+	fn rename(&mut self, from: String, to: String) {
+		self.renames.push((from, to));
+	}
+
+	fn remove(&mut self, path: String) {
+		self.removals.push(path);
+	}
+
+	// Records the swap in the provided marker file, applies it and removes
+	// the marker.
+	fn commit(&self, marker_path: &str) -> io::Result<()> {
+		let data = serde_json::to_vec(self).map_err(|e| {
+			io::Error::new(io::ErrorKind::Other, format!("Could not encode file swap: {:?}", e))
+		})?;
+		write_through_tmp(marker_path, &data)?;
+		self.apply()?;
+		fs::remove_file(marker_path)
+	}
+
+	fn apply(&self) -> io::Result<()> {
+		for &(ref from, ref to) in &self.renames {
+			if Path::new(from).exists() {
+				fs::rename(from, to)?;
+			}
+		}
+		for path in &self.removals {
+			if Path::new(path).exists() {
+				fs::remove_file(path)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+// Completes the file swap recorded in the data directory, if one got
+// interrupted. Without a recorded swap, the new versions of the data files
+// that were being prepared are just removed, the current ones being intact.
+fn recover_swap(data_dir: &str) -> io::Result<()> {
+	let marker_path = format!("{}/{}", data_dir, PMMR_SWAP_FILE);
+	if Path::new(&marker_path).exists() {
+		warn!("Completing interrupted file swap in {}", data_dir);
+		let swap: FileSwap = serde_json::from_reader(File::open(&marker_path)?).map_err(|e| {
+			io::Error::new(io::ErrorKind::InvalidData, format!("Corrupted file swap: {:?}", e))
+		})?;
+		swap.apply()?;
+		return fs::remove_file(marker_path);
+	}
+
+	let mut dirs = vec![data_dir.to_string()];
+	if let Some(cold_dir) = read_cold_dir(data_dir)? {
+		dirs.push(cold_dir);
+	}
+	for dir in dirs {
+		if !Path::new(&dir).exists() {
+			continue;
+		}
+		for entry in fs::read_dir(&dir)? {
+			let path = entry?.path();
+			if path.extension().map(|ext| ext == "tier").unwrap_or(false) {
+				warn!("Removing {}, left over by an interrupted file swap", path.display());
+				fs::remove_file(path)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+// Deletes the compacted copies of the data file left over by a compaction
+// that got interrupted before saving the prune list. The data file and the
+// prune list are still consistent with the remove log in that case, the
+// compaction will just be done again.
+fn remove_stale_prune_files(data_dir: &str) -> io::Result<()> {
+	for entry in fs::read_dir(data_dir)? {
+		let path = entry?.path();
+		if path.extension().map(|ext| ext == "prune").unwrap_or(false) {
+			warn!("Removing {}, left over by an interrupted compaction", path.display());
+			fs::remove_file(path)?;
+		}
+	}
+	Ok(())
+}
+
+// Copies a file, if it exists, making sure the copy is on disk.
+fn copy_synced(src: &str, dest: &str) -> io::Result<()> {
+	if !Path::new(src).exists() {
+		return Ok(());
+	}
+	fs::copy(src, dest)?;
+	File::open(dest)?.sync_all()
+}
+
+// Decodes the write-ahead log entry at the start of the provided data,
+// returning the MMR position of the batch and its records. An entry is made
+// of the position and the number of records as 2 u64, followed by the
+// records and the checksum of all of it. None if the entry is incomplete or
+// corrupted.
+fn read_wal_entry(data: &[u8], record_len: usize) -> Option<(u64, &[u8])> {
+	if data.len() < 16 {
+		return None;
+	}
+	let position = LittleEndian::read_u64(&data[0..8]);
+	let count = LittleEndian::read_u64(&data[8..16]) as usize;
+	let end = match count.checked_mul(record_len).and_then(|len| len.checked_add(16)) {
+		Some(end) => end,
+		None => return None,
+	};
+	if position == 0 || data.len() < end + CHECKSUM_LEN {
+		return None;
+	}
+	let checksum = LittleEndian::read_u32(&data[end..(end + CHECKSUM_LEN)]);
+	if checksum != crc32::checksum_castagnoli(&data[..end]) {
+		return None;
+	}
+	Some((position, &data[16..end]))
+}
+
+// Length of a record in the data file, with or without checksum.
+fn stored_record_len<T>(checksummed: bool) -> u64
+	where T: Summable {
+
+	let record_len = (32 + T::sum_len()) as u64;
+	if checksummed {
+		record_len + CHECKSUM_LEN as u64
+	} else {
+		record_len
+	}
+}
+
+// Read the version of the data file format without creating anything, None
+// if there's no data at all.
+fn read_format_version_readonly(data_dir: &str) -> io::Result<Option<u32>> {
+	if Path::new(&format!("{}/{}", data_dir, PMMR_FORMAT_FILE)).exists() ||
+		Path::new(&format!("{}/{}", data_dir, PMMR_DATA_FILE)).exists() {
+		read_format_version(data_dir).map(Some)
+	} else {
+		Ok(None)
+	}
+}
+
+// Read the version of the data file format. Data files predating the format
+// file are version 1, brand new ones get the current version.
+fn read_format_version(data_dir: &str) -> io::Result<u32> {
+	let format_path = format!("{}/{}", data_dir, PMMR_FORMAT_FILE);
+	if Path::new(&format_path).exists() {
+		let mut version = String::new();
+		File::open(format_path)?.read_to_string(&mut version)?;
+		return match version.trim().parse() {
+			Ok(v) if v <= PMMR_FORMAT_VERSION => Ok(v),
+			_ => Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("Unsupported PMMR format version {}", version),
+			)),
+		};
+	}
+	if Path::new(&format!("{}/{}", data_dir, PMMR_DATA_FILE)).exists() {
+		return Ok(1);
+	}
+	let mut format_file = File::create(format_path)?;
+	format_file.write_all(PMMR_FORMAT_VERSION.to_string().as_bytes())?;
+	format_file.sync_all()?;
+	Ok(PMMR_FORMAT_VERSION)
+}
+
+// Read the cold tier directory, if one has been set.
+fn read_cold_dir(data_dir: &str) -> io::Result<Option<String>> {
+	let tier_path = format!("{}/{}", data_dir, PMMR_COLD_TIER_FILE);
+	if !Path::new(&tier_path).exists() {
+		return Ok(None);
+	}
+	let mut cold_dir = String::new();
+	File::open(tier_path)?.read_to_string(&mut cold_dir)?;
+	Ok(Some(cold_dir))
+}
+
+// Read an ordered vector of scalars from a file. Fails if the file holds
+// anything that doesn't decode as a whole number of elements.
 fn read_ordered_vec<T>(path: String) -> io::Result<Vec<T>>
+	where T: ser::Readable + ser::Writeable + cmp::Ord {
+
+	read_ordered_vec_with(path, false)
+}
+
+// Read an ordered vector of scalars from a file, stopping at the last
+// element successfully decoded instead of failing. For files where losing
+// the last few entries written before an unclean shutdown is recoverable.
+fn read_ordered_vec_tolerant<T>(path: String) -> io::Result<Vec<T>>
+	where T: ser::Readable + ser::Writeable + cmp::Ord {
+
+	read_ordered_vec_with(path, true)
+}
+
+fn read_ordered_vec_with<T>(path: String, tolerant: bool) -> io::Result<Vec<T>>
+	where T: ser::Readable + ser::Writeable + cmp::Ord {
+
+	let tmp_path = format!("{}.tmp", path);
+	if Path::new(&tmp_path).exists() {
+		recover_tmp_file(&path, &tmp_path)?;
+	}
+	decode_ordered_vec(&path, tolerant)
+}
+
+// Decode the ordered vector held by a file, leaving any temporary file left
+// over by write_vec alone.
+fn decode_ordered_vec<T>(path: &str, tolerant: bool) -> io::Result<Vec<T>>
 	where T: ser::Readable + cmp::Ord {
 
-	let file_path = Path::new(&path);
 	let mut ovec = Vec::with_capacity(1000);
-	if file_path.exists() {
-		let mut file = BufReader::with_capacity(8 * 1000, File::open(path.clone())?);
-		loop {
-			// need a block to end mutable borrow before consume
-			let buf_len = {
-				let buf = file.fill_buf()?;
-				if buf.len() == 0 {
-					break;
-				}
-				let elmts_res: Result<Vec<T>, ser::Error> = ser::deserialize(&mut &buf[..]);
-				match elmts_res {
-					Ok(elmts) => {
-						for elmt in elmts {
-							if let Err(idx) = ovec.binary_search(&elmt) {
-								ovec.insert(idx, elmt);
-							}
-						}
-					}
-					Err(_) => {
-						return Err(io::Error::new(
-							io::ErrorKind::InvalidData,
-							format!("Corrupted storage, could not read file at {}", path),
-						));
-					}
+	if !Path::new(&path).exists() {
+		return Ok(ovec);
+	}
+	let reader = BufReader::new(File::open(path)?);
+	for elmt_res in ser::StreamingDeserializer::<_, T>::new(reader) {
+		match elmt_res {
+			Ok(elmt) => {
+				if let Err(idx) = ovec.binary_search(&elmt) {
+					ovec.insert(idx, elmt);
 				}
-				buf.len()
-			};
-			file.consume(buf_len);
+			}
+			Err(_) if tolerant => {
+				warn!("Skipping the end of {}, not a full entry", path);
+				break;
+			}
+			Err(_) => {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Corrupted storage, could not read file at {}", path),
+				));
+			}
 		}
 	}
 	Ok(ovec)
 }
 
-fn write_vec<T>(path: String, v: &Vec<T>) -> io::Result<()>
+// Read the prune list from a file, in its compact form or as the plain
+// ordered vector older versions wrote, completing or discarding an
+// interrupted write first.
+fn read_prune_list(path: String) -> io::Result<pmmr::PruneList> {
+	let tmp_path = format!("{}.tmp", path);
+	if Path::new(&tmp_path).exists() {
+		recover_tmp_file(&path, &tmp_path)?;
+	}
+	if is_compact_prune_list(&path)? {
+		decode_prune_list(&path)
+	} else {
+		read_ordered_vec(path).map(pmmr::PruneList::with_nodes)
+	}
+}
+
+// Decode the prune list held by a file, leaving any temporary file alone.
+fn decode_prune_list(path: &str) -> io::Result<pmmr::PruneList> {
+	if !is_compact_prune_list(path)? {
+		return decode_ordered_vec(path, false).map(pmmr::PruneList::with_nodes);
+	}
+	pmmr::PruneList::read_compact(&mut File::open(path)?).map_err(|e| {
+		io::Error::new(e.kind(), format!("Corrupted storage, could not read file at {}: {}", path, e))
+	})
+}
+
+// Whether the file holds a prune list in its compact form, starting with its
+// magic number.
+fn is_compact_prune_list(path: &str) -> io::Result<bool> {
+	if !Path::new(path).exists() {
+		return Ok(false);
+	}
+	let mut magic = vec![];
+	File::open(path)?.take(4).read_to_end(&mut magic)?;
+	Ok(magic[..] == pmmr::PRUNE_LIST_MAGIC[..])
+}
+
+// Writes the prune list in its compact form, through a temporary file like
+// write_vec.
+fn write_prune_list(path: String, prune_list: &pmmr::PruneList) -> io::Result<()> {
+	let mut data = vec![];
+	prune_list.write_compact(&mut data)?;
+	write_through_tmp(&path, &data)
+}
+
+/// Writes the vector to a temporary file first, only moving it over the
+/// final path once fully written and synced. A crash midway leaves the
+/// previous version of the file intact.
+pub fn write_vec<T>(path: String, v: &Vec<T>) -> io::Result<()>
 	where T: ser::Writeable {
-	
-	let mut file_path = File::create(&path)?;
-	ser::serialize(&mut file_path, v).map_err(|_| {
+
+	let data = ser::ser_vec(v).map_err(|_| {
 		io::Error::new(
 			io::ErrorKind::InvalidInput,
 			format!("Failed to serialize data when writing to {}", path))
 	})?;
-	Ok(())
+	write_through_tmp(&path, &data)
+}
+
+// Writes the data to {path}.tmp followed by a footer holding its length and
+// checksum, so a leftover temporary file can be told complete or not by
+// recover_tmp_file. Once synced, the footer is cut and the file moved in
+// place.
+fn write_through_tmp(path: &str, data: &[u8]) -> io::Result<()> {
+	let tmp_path = format!("{}.tmp", path);
+	let mut file = File::create(&tmp_path)?;
+	file.write_all(data)?;
+	file.write_all(&tmp_footer(data))?;
+	file.sync_all()?;
+	file.set_len(data.len() as u64)?;
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+// Footer closing a temporary file: the length of the data (u64) and its
+// CRC32C checksum.
+fn tmp_footer(data: &[u8]) -> [u8; TMP_FOOTER_LEN] {
+	let mut footer = [0; TMP_FOOTER_LEN];
+	LittleEndian::write_u64(&mut footer[0..8], data.len() as u64);
+	LittleEndian::write_u32(&mut footer[8..], crc32::checksum_castagnoli(data));
+	footer
+}
+
+/// Deals with a temporary file left over by a write that didn't complete. If
+/// the temporary file ends with a footer matching its content, only the
+/// rename was missed and it's completed. Otherwise the temporary file is
+/// incomplete (or already cut, which can't be told apart from a truncation)
+/// and just deleted, leaving the previous version of the file in place.
+fn recover_tmp_file(path: &str, tmp_path: &str) -> io::Result<()> {
+	let mut data = vec![];
+	File::open(tmp_path)?.read_to_end(&mut data)?;
+	let intact = data.len() >= TMP_FOOTER_LEN && {
+		let len = data.len() - TMP_FOOTER_LEN;
+		data[len..] == tmp_footer(&data[..len])[..]
+	};
+	if intact {
+		warn!("Completing interrupted write of {} from {}", path, tmp_path);
+		let file = OpenOptions::new().write(true).open(tmp_path)?;
+		file.set_len((data.len() - TMP_FOOTER_LEN) as u64)?;
+		file.sync_all()?;
+		fs::rename(tmp_path, path)
+	} else {
+		warn!("Removing {}, left over by an interrupted write of {}", tmp_path, path);
+		fs::remove_file(tmp_path)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn aof_read_into() {
+		let data_dir = "./target/aof_read_into";
+		let _ = fs::remove_dir_all(data_dir);
+		fs::create_dir_all(data_dir).unwrap();
+
+		let mut aof = AppendOnlyFile::with_segment_size(format!("{}/data.bin", data_dir), 0, false).unwrap();
+		aof.append(&(0..16).collect::<Vec<u8>>()).unwrap();
+		// nothing readable until synced
+		assert_eq!(aof.slice(0, 4), None);
+		aof.sync().unwrap();
+
+		assert_eq!(aof.slice(4, 4), Some(&[4, 5, 6, 7][..]));
+		let mut buf = [0; 4];
+		aof.read_into(12, &mut buf).unwrap();
+		assert_eq!(buf, [12, 13, 14, 15]);
+
+		// past the end
+		assert_eq!(aof.slice(14, 4), None);
+		assert!(aof.read_into(14, &mut buf).is_err());
+	}
+
+	#[test]
+	fn aof_positions() {
+		let data_dir = "./target/aof_positions";
+		let _ = fs::remove_dir_all(data_dir);
+		fs::create_dir_all(data_dir).unwrap();
+		let path = format!("{}/data.bin", data_dir);
+
+		let mut aof = AppendOnlyFile::with_segment_size(path.clone(), 12, true).unwrap();
+		aof.append(&[1; 4]).unwrap();
+		aof.append(&[2; 4]).unwrap();
+		assert_eq!(aof.buffered_bytes(), 16);
+		assert_eq!(aof.synced_size(), 0);
+		aof.sync().unwrap();
+		assert_eq!(aof.buffered_bytes(), 0);
+		assert_eq!(aof.synced_size(), 16);
+		assert_eq!(aof.synced_size(), aof.size().unwrap());
+
+		aof.rewind(8).unwrap();
+		assert_eq!(aof.buffered_bytes(), 0);
+		assert_eq!(aof.synced_size(), 8);
+
+		// positions are picked up again when reopening
+		aof.append(&[3; 4]).unwrap();
+		aof.sync().unwrap();
+		let reopened = AppendOnlyFile::with_segment_size(path, 12, true).unwrap();
+		assert_eq!(reopened.synced_size(), 16);
+
+		// rewinding within unsynced data keeps what's before it unsynced, and
+		// the synced data readable
+		let mut aof = AppendOnlyFile::with_segment_size(format!("{}/unsynced.bin", data_dir), 0, false)
+			.unwrap();
+		aof.append(&[1; 4]).unwrap();
+		aof.sync().unwrap();
+		aof.append(&[2; 4]).unwrap();
+		aof.append(&[3; 4]).unwrap();
+		aof.rewind(8).unwrap();
+		assert_eq!(aof.buffered_bytes(), 4);
+		assert_eq!(aof.synced_size(), 4);
+		let mut buf = [0; 4];
+		aof.read_into(0, &mut buf).unwrap();
+		assert_eq!(buf, [1; 4]);
+		assert!(aof.rewind(12).is_err());
+
+		aof.append(&[4; 4]).unwrap();
+		aof.sync().unwrap();
+		assert_eq!(aof.synced_size(), 12);
+		aof.read_into(4, &mut buf).unwrap();
+		assert_eq!(buf, [2; 4]);
+		aof.read_into(8, &mut buf).unwrap();
+		assert_eq!(buf, [4; 4]);
+	}
+
+	#[test]
+	fn rm_log_merge() {
+		let data_dir = "./target/rm_log_merge";
+		let _ = fs::remove_dir_all(data_dir);
+		fs::create_dir_all(data_dir).unwrap();
+
+		let mut a = RemoveLog::open(format!("{}/a.bin", data_dir)).unwrap();
+		a.append(vec![1, 4, 5, 9]).unwrap();
+		let mut b = RemoveLog::open(format!("{}/b.bin", data_dir)).unwrap();
+		b.append(vec![2, 4, 9, 12]).unwrap();
+
+		let mut merged = RemoveLog::merge(&a, &b).unwrap();
+		assert_eq!(merged.as_slice(), &[1, 2, 4, 5, 9, 12]);
+
+		// only persisted once synced
+		merged.sync().unwrap();
+		let mut reopened = RemoveLog::open(format!("{}/a.bin", data_dir)).unwrap();
+		assert_eq!(reopened.iter().collect::<Vec<_>>(), vec![1, 2, 4, 5, 9, 12]);
+
+		reopened.clear_positions_above(5).unwrap();
+		let reopened = RemoveLog::open(format!("{}/a.bin", data_dir)).unwrap();
+		assert_eq!(reopened.as_slice(), &[1, 2, 4, 5]);
+	}
+
+	#[test]
+	fn read_truncated_vec() {
+		let data_dir = "./target/read_truncated_vec";
+		let _ = fs::remove_dir_all(data_dir);
+		fs::create_dir_all(data_dir).unwrap();
+		let path = format!("{}/vec.bin", data_dir);
+
+		let mut data = ser::ser_vec(&vec![3u64, 5, 8]).unwrap();
+		data.extend_from_slice(&[0, 0, 1]);
+		File::create(&path).unwrap().write_all(&data).unwrap();
+
+		assert!(read_ordered_vec::<u64>(path.clone()).is_err());
+		assert_eq!(read_ordered_vec_tolerant::<u64>(path.clone()).unwrap(), vec![3, 5, 8]);
+
+		// the remove log drops the partial entry and keeps appending cleanly
+		let mut rm_log = RemoveLog::open(path.clone()).unwrap();
+		rm_log.append(vec![13]).unwrap();
+		assert_eq!(read_ordered_vec::<u64>(path.clone()).unwrap(), vec![3, 5, 8, 13]);
+	}
+
+	#[test]
+	fn write_vec_recovery() {
+		let data_dir = "./target/write_vec_recovery";
+		let _ = fs::remove_dir_all(data_dir);
+		fs::create_dir_all(data_dir).unwrap();
+		let path = format!("{}/vec.bin", data_dir);
+		let tmp_path = format!("{}.tmp", path);
+
+		write_vec(path.clone(), &vec![3u64, 5, 8]).unwrap();
+		assert!(!Path::new(&tmp_path).exists());
+		assert_eq!(read_ordered_vec::<u64>(path.clone()).unwrap(), vec![3, 5, 8]);
+
+		// a temp file truncated on an element boundary still decodes, it gets
+		// discarded all the same
+		let data = ser::ser_vec(&vec![3u64, 5, 8, 13]).unwrap();
+		File::create(&tmp_path).unwrap().write_all(&data[..16]).unwrap();
+		assert_eq!(read_ordered_vec::<u64>(path.clone()).unwrap(), vec![3, 5, 8]);
+		assert!(!Path::new(&tmp_path).exists());
+
+		// so does one with a footer not matching its content
+		let mut tmp_data = data.clone();
+		tmp_data.extend_from_slice(&tmp_footer(&data[..24]));
+		File::create(&tmp_path).unwrap().write_all(&tmp_data).unwrap();
+		assert_eq!(read_ordered_vec::<u64>(path.clone()).unwrap(), vec![3, 5, 8]);
+		assert!(!Path::new(&tmp_path).exists());
+
+		// an intact one only missed its rename
+		let mut tmp_data = data.clone();
+		tmp_data.extend_from_slice(&tmp_footer(&data));
+		File::create(&tmp_path).unwrap().write_all(&tmp_data).unwrap();
+		assert_eq!(read_ordered_vec::<u64>(path.clone()).unwrap(), vec![3, 5, 8, 13]);
+		assert!(!Path::new(&tmp_path).exists());
+	}
+
+	#[test]
+	fn interrupted_file_swap() {
+		let data_dir = "./target/interrupted_file_swap";
+		let _ = fs::remove_dir_all(data_dir);
+		fs::create_dir_all(data_dir).unwrap();
+		let path = format!("{}/{}", data_dir, PMMR_DATA_FILE);
+		let cold_path = format!("{}/{}", data_dir, PMMR_COLD_DATA_FILE);
+		let marker_path = format!("{}/{}", data_dir, PMMR_SWAP_FILE);
+		let content = (0..40).collect::<Vec<u8>>();
+
+		// moving the first 8 bytes to the cold tier, crashing right after the
+		// new cold file is in place, the hot segments still holding everything
+		{
+			let mut aof = AppendOnlyFile::with_segment_size(path.clone(), 12, false).unwrap();
+			for chunk in content.chunks(4) {
+				aof.append(chunk).unwrap();
+			}
+			aof.sync().unwrap();
+			let swap = aof.prepare_rewrite(&mut aof.reader().unwrap(), Some(&cold_path[..]), 8).unwrap();
+			write_through_tmp(&marker_path, &serde_json::to_vec(&swap).unwrap()).unwrap();
+			fs::rename(&swap.renames[0].0, &swap.renames[0].1).unwrap();
+		}
+		recover_swap(data_dir).unwrap();
+		assert!(!Path::new(&marker_path).exists());
+		assert!(!Path::new(&segment_path(&path, 3)).exists());
+
+		let aof = AppendOnlyFile::open_tiered(path.clone(), cold_path.clone(), 12, false).unwrap();
+		assert_eq!(aof.cold_threshold_bytes, 8);
+		let mut data = vec![];
+		aof.reader().unwrap().read_to_end(&mut data).unwrap();
+		assert_eq!(data, content);
+
+		// a swap prepared but not recorded yet is just dropped
+		let swap = aof.prepare_rewrite(&mut aof.reader().unwrap(), Some(&cold_path[..]), 16).unwrap();
+		recover_swap(data_dir).unwrap();
+		for &(ref from, _) in &swap.renames {
+			assert!(!Path::new(from).exists());
+		}
+		let aof = AppendOnlyFile::open_tiered(path, cold_path, 12, false).unwrap();
+		assert_eq!(aof.cold_threshold_bytes, 8);
+		let mut data = vec![];
+		aof.reader().unwrap().read_to_end(&mut data).unwrap();
+		assert_eq!(data, content);
+	}
 }
@@ -17,7 +17,9 @@ extern crate grin_core as core;
 extern crate grin_store as store;
 extern crate time;
 
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
 
 use core::ser::*;
 use core::core::pmmr::{PMMR, Summable, HashSum, Backend};
@@ -26,7 +28,7 @@ use core::core::hash::Hashed;
 #[test]
 fn sumtree_append() {
 	let (data_dir, elems) = setup();
-	let mut backend = store::sumtree::PMMRBackend::new(data_dir).unwrap();
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
 
 	// adding first set of 4 elements and sync
 	let mut mmr_size = load(0, &elems[0..4], &mut backend);
@@ -66,7 +68,7 @@ fn sumtree_prune_compact() {
 	let (data_dir, elems) = setup();
 
 	// setup the mmr store with all elements
-	let mut backend = store::sumtree::PMMRBackend::new(data_dir).unwrap();
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
 	let mmr_size = load(0, &elems[..], &mut backend);
 	backend.sync().unwrap();
 	
@@ -102,6 +104,286 @@ fn sumtree_prune_compact() {
 	}
 }
 
+#[test]
+fn sumtree_compact_opts() {
+	let (data_dir, elems) = setup();
+
+	let opts = store::sumtree::PMMROptions {
+		rm_log_max_nodes: 3,
+		..Default::default()
+	};
+	let mut backend = store::sumtree::PMMRBackend::new_with_opts(data_dir.clone(), opts).unwrap();
+	let mmr_size = load(0, &elems[..], &mut backend);
+	backend.sync().unwrap();
+	let root: HashSum<TestElem>;
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		root = pmmr.root();
+		pmmr.prune(1);
+		pmmr.prune(2);
+	}
+	backend.sync().unwrap();
+
+	// below the configured threshold, nothing gets compacted
+	let pruned_path = format!("{}/pmmr_pruned.bin", data_dir);
+	backend.check_compact(0).unwrap();
+	assert!(!Path::new(&pruned_path).exists());
+
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.prune(4);
+		pmmr.prune(5);
+	}
+	backend.sync().unwrap();
+	backend.check_compact(0).unwrap();
+	assert!(Path::new(&pruned_path).exists());
+	{
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+}
+
+#[test]
+fn sumtree_archive_pruned() {
+	use store::sumtree::{PMMRBackend, PMMROptions};
+
+	let (data_dir, elems) = setup();
+	let opts = PMMROptions {
+		archive_pruned: true,
+		..Default::default()
+	};
+	let leaf1 = HashSum::from_summable(1, &elems[0]);
+	let leaf2 = HashSum::from_summable(2, &elems[1]);
+	{
+		let mut backend = PMMRBackend::new_with_opts(data_dir.clone(), opts.clone()).unwrap();
+		let mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			pmmr.prune(1);
+			pmmr.prune(2);
+		}
+		backend.sync().unwrap();
+
+		// nothing archived until compacted
+		assert_eq!(backend.get_archived(1).unwrap(), None);
+		backend.check_compact(1).unwrap();
+		assert_eq!(backend.get(1), None);
+		assert_eq!(backend.get_archived(1).unwrap(), Some(leaf1.clone()));
+		assert_eq!(backend.get_archived(2).unwrap(), Some(leaf2.clone()));
+		assert_eq!(backend.get_archived(4).unwrap(), None);
+	}
+
+	// the archive survives a restart, only read when enabled
+	{
+		let backend: PMMRBackend<TestElem> = PMMRBackend::new_with_opts(data_dir.clone(), opts)
+			.unwrap();
+		assert_eq!(backend.get_archived(2).unwrap(), Some(leaf2));
+	}
+	let backend: PMMRBackend<TestElem> = PMMRBackend::new(data_dir, false).unwrap();
+	assert_eq!(backend.get_archived(1).unwrap(), None);
+}
+
+#[test]
+fn sumtree_hot_tier() {
+	use store::sumtree::{PMMRBackend, PMMROptions};
+
+	let (data_dir, elems) = setup();
+	let opts = PMMROptions {
+		hot_threshold: 4,
+		..Default::default()
+	};
+	let mut backend = PMMRBackend::new_with_opts(data_dir.clone(), opts).unwrap();
+	let mut mmr_size = load(0, &elems[0..4], &mut backend);
+	backend.sync().unwrap();
+	let root7 = {
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.root()
+	};
+
+	// the same data whichever tier it comes from
+	mmr_size = load(mmr_size, &elems[4..9], &mut backend);
+	backend.sync().unwrap();
+	assert_eq!(backend.get(1), Some(HashSum::from_summable(1, &elems[0])));
+	assert_eq!(backend.get(12), Some(HashSum::from_summable(12, &elems[7])));
+	assert_eq!(backend.get(16), Some(HashSum::from_summable(16, &elems[8])));
+	let root16 = {
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.root()
+	};
+
+	// rewinding past the hot tier, then appending again
+	backend.rewind(7).unwrap();
+	assert_eq!(backend.get(8), None);
+	{
+		let pmmr = PMMR::at(&mut backend, 7);
+		assert_eq!(root7, pmmr.root());
+	}
+	mmr_size = load(7, &elems[4..9], &mut backend);
+	backend.sync().unwrap();
+	assert_eq!(backend.get(16), Some(HashSum::from_summable(16, &elems[8])));
+	{
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root16, pmmr.root());
+	}
+
+	// shrinking, disabling and enabling the hot tier again
+	backend.set_hot_threshold(1);
+	assert_eq!(backend.get(12), Some(HashSum::from_summable(12, &elems[7])));
+	backend.set_hot_threshold(0);
+	assert_eq!(backend.get(16), Some(HashSum::from_summable(16, &elems[8])));
+	backend.set_hot_threshold(4);
+	load(mmr_size, &elems[0..1], &mut backend);
+	backend.sync().unwrap();
+	assert_eq!(backend.get(17), Some(HashSum::from_summable(17, &elems[0])));
+	backend.rewind(16).unwrap();
+	{
+		let pmmr = PMMR::at(&mut backend, 16);
+		assert_eq!(root16, pmmr.root());
+	}
+
+	// pruned data isn't served from the hot tier
+	{
+		let mut pmmr = PMMR::at(&mut backend, 16);
+		pmmr.prune(16);
+	}
+	backend.sync().unwrap();
+	assert_eq!(backend.get(16), None);
+}
+
+#[test]
+fn sumtree_consistency() {
+	use store::sumtree::{ConsistencyError, PMMRBackend, PMMROptions};
+
+	let (data_dir, elems) = setup();
+	let data_path = format!("{}/pmmr_dat.bin", data_dir);
+	let mmr_size: u64;
+	{
+		let mut backend = PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		assert_eq!(backend.verify_consistency(), Ok(()));
+
+		// the data file grows behind the backend's back
+		OpenOptions::new().append(true).open(&data_path).unwrap().write_all(&[0; 44]).unwrap();
+		backend.sync().unwrap();
+		assert_eq!(
+			backend.verify_consistency(),
+			Err(ConsistencyError::BufferIndexMismatch {
+				computed: mmr_size as usize + 1,
+				stored: mmr_size as usize,
+			})
+		);
+	}
+
+	// a partial record at the end of the data file
+	OpenOptions::new().append(true).open(&data_path).unwrap().write_all(&[0; 3]).unwrap();
+	{
+		let backend: PMMRBackend<TestElem> = PMMRBackend::new(data_dir.clone(), false).unwrap();
+		assert_eq!(
+			backend.verify_consistency(),
+			Err(ConsistencyError::FileSizeMismatch { remainder: 3 })
+		);
+	}
+	let opts = PMMROptions {
+		enable_consistency_check: true,
+		..Default::default()
+	};
+	assert!(PMMRBackend::<TestElem>::new_with_opts(data_dir.clone(), opts.clone()).is_err());
+
+	// a pruned position left in the remove log
+	let (data_dir, elems) = setup();
+	{
+		let mut backend = PMMRBackend::new(data_dir.clone(), false).unwrap();
+		let mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			pmmr.prune(1);
+			pmmr.prune(2);
+		}
+		backend.sync().unwrap();
+		backend.check_compact(1).unwrap();
+	}
+	File::create(format!("{}/pmmr_rm_log.bin", data_dir)).unwrap()
+		.write_all(&ser_vec(&vec![1u64]).unwrap()).unwrap();
+	{
+		let backend: PMMRBackend<TestElem> = PMMRBackend::new(data_dir.clone(), false).unwrap();
+		assert_eq!(
+			backend.verify_consistency(),
+			Err(ConsistencyError::RemoveLogPrunedOverlap { position: 1 })
+		);
+	}
+	assert!(PMMRBackend::<TestElem>::new_with_opts(data_dir, opts).is_err());
+}
+
+#[test]
+fn sumtree_transaction_rollback() {
+	let (data_dir, _) = setup();
+	let elems = (0..150).map(|n| TestElem([0, 0, 1, n])).collect::<Vec<_>>();
+
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
+	let mmr_size = load(0, &elems[0..100], &mut backend);
+	backend.sync().unwrap();
+	let root: HashSum<TestElem>;
+	{
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		root = pmmr.root();
+	}
+
+	let savepoint = backend.begin_transaction();
+	assert_eq!(savepoint.mmr_size(), mmr_size);
+	let new_size = load(mmr_size, &elems[100..150], &mut backend);
+	{
+		let mut pmmr = PMMR::at(&mut backend, new_size);
+		pmmr.prune(1);
+	}
+	backend.rollback(savepoint.clone()).unwrap();
+
+	assert_eq!(backend.current_size(), mmr_size);
+	assert_eq!(backend.get(mmr_size + 1), None);
+	assert!(backend.get(1).is_some());
+	{
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+
+	// once committed, there's no going back
+	let savepoint = backend.begin_transaction();
+	load(mmr_size, &elems[100..150], &mut backend);
+	backend.commit().unwrap();
+	assert!(backend.rollback(savepoint).is_err());
+}
+
+#[test]
+fn sumtree_rollback_reopen() {
+	let (data_dir, elems) = setup();
+
+	// appends before the savepoint are kept, the ones after it are gone
+	// after syncing and reopening, the write-ahead log included
+	let mmr_size: u64;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[0..4], &mut backend);
+		let savepoint = backend.begin_transaction();
+		load(mmr_size, &elems[4..9], &mut backend);
+		backend.rollback(savepoint).unwrap();
+		backend.sync().unwrap();
+	}
+	assert_eq!(fs::metadata(format!("{}/pmmr_wal.bin", data_dir)).unwrap().len(), 0);
+
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
+	assert_eq!(backend.current_size(), mmr_size);
+	assert_eq!(backend.get(5), Some(HashSum::from_summable(5, &elems[3])));
+	assert_eq!(backend.get(mmr_size + 1), None);
+
+	// appending again after the rollback lands at the same positions
+	let new_size = load(mmr_size, &elems[4..6], &mut backend);
+	backend.sync().unwrap();
+	assert_eq!(backend.get(mmr_size + 1), Some(HashSum::from_summable(mmr_size + 1, &elems[4])));
+	assert_eq!(backend.current_size(), new_size);
+}
+
 #[test]
 fn sumtree_reload() {
 	let (data_dir, elems) = setup();
@@ -110,7 +392,7 @@ fn sumtree_reload() {
 	let mmr_size: u64;
 	let root: HashSum<TestElem>;
 	{
-		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone()).unwrap();
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
 		mmr_size = load(0, &elems[..], &mut backend);
 		backend.sync().unwrap();
 		
@@ -135,7 +417,7 @@ fn sumtree_reload() {
 
 	// create a new backend and check everything is kosher
 	{
-		let mut backend = store::sumtree::PMMRBackend::new(data_dir).unwrap();
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
 		{
 			let pmmr = PMMR::at(&mut backend, mmr_size);
 			assert_eq!(root, pmmr.root());
@@ -144,6 +426,633 @@ fn sumtree_reload() {
 	}
 }
 
+#[test]
+fn sumtree_interrupted_write() {
+	let (data_dir, elems) = setup();
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			root = pmmr.root();
+			pmmr.prune(1);
+			pmmr.prune(4);
+		}
+		backend.sync().unwrap();
+		backend.check_compact(1).unwrap();
+	}
+
+	// a crash while rewriting the prune list leaves half a temp file behind,
+	// one rewriting the remove log a complete one missing its footer
+	let tmp_path = format!("{}/pmmr_pruned.bin.tmp", data_dir);
+	File::create(&tmp_path).unwrap().write_all(&[0, 0, 0, 0, 0, 1]).unwrap();
+	let rm_tmp_path = format!("{}/pmmr_rm_log.bin.tmp", data_dir);
+	File::create(&rm_tmp_path).unwrap().write_all(&ser_vec(&vec![2u64]).unwrap()).unwrap();
+
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+	assert!(!Path::new(&tmp_path).exists());
+	assert!(!Path::new(&rm_tmp_path).exists());
+}
+
+#[test]
+fn sumtree_prune_list_format() {
+	let (data_dir, elems) = setup();
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			root = pmmr.root();
+			pmmr.prune(1);
+			pmmr.prune(2);
+			pmmr.prune(4);
+		}
+		backend.sync().unwrap();
+		backend.check_compact(1).unwrap();
+	}
+
+	// the prune list is always written compact
+	let pruned_path = format!("{}/pmmr_pruned.bin", data_dir);
+	let mut data = vec![];
+	File::open(&pruned_path).unwrap().read_to_end(&mut data).unwrap();
+	assert_eq!(&data[0..4], b"PRNL");
+
+	// the plain form written by older versions is still read
+	File::create(&pruned_path).unwrap().write_all(&ser_vec(&vec![3u64, 4]).unwrap()).unwrap();
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+		}
+		assert_eq!(backend.get(4), None);
+		assert!(backend.get(5).is_some());
+	}
+}
+
+#[test]
+fn sumtree_stale_prune_file() {
+	let (data_dir, elems) = setup();
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		root = pmmr.root();
+	}
+
+	// a compaction interrupted before moving its copy of the data file
+	let prune_path = format!("{}/pmmr_dat.bin.prune", data_dir);
+	File::create(&prune_path).unwrap().write_all(&[0; 10]).unwrap();
+
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+	assert!(!Path::new(&prune_path).exists());
+}
+
+#[test]
+fn sumtree_interrupted_compaction() {
+	let (data_dir, elems) = setup();
+	let data_path = format!("{}/pmmr_dat.bin", data_dir);
+	let rm_log_path = format!("{}/pmmr_rm_log.bin", data_dir);
+	let prune_path = format!("{}/pmmr_dat.bin.prune", data_dir);
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			root = pmmr.root();
+			pmmr.prune(1);
+			pmmr.prune(4);
+		}
+		backend.sync().unwrap();
+		fs::copy(&data_path, format!("{}.orig", data_path)).unwrap();
+		fs::copy(&rm_log_path, format!("{}.orig", rm_log_path)).unwrap();
+		backend.check_compact(1).unwrap();
+	}
+
+	// a crash right after saving the prune list leaves the compact copy and
+	// the original data file and remove log behind
+	fs::rename(&data_path, &prune_path).unwrap();
+	fs::copy(format!("{}.orig", data_path), &data_path).unwrap();
+	fs::copy(format!("{}.orig", rm_log_path), &rm_log_path).unwrap();
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+		}
+		assert_eq!(backend.get(4), None);
+		assert!(backend.get(5).is_some());
+	}
+	assert!(!Path::new(&prune_path).exists());
+	assert_eq!(fs::metadata(&rm_log_path).unwrap().len(), 0);
+
+	// a crash right after moving the compact copy only misses the remove log
+	// truncation
+	fs::copy(format!("{}.orig", rm_log_path), &rm_log_path).unwrap();
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+		}
+		assert!(backend.get(5).is_some());
+	}
+	assert_eq!(fs::metadata(&rm_log_path).unwrap().len(), 0);
+}
+
+#[test]
+fn sumtree_wal_replay() {
+	let (data_dir, elems) = setup();
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		let synced_size = load(0, &elems[0..4], &mut backend);
+		backend.sync().unwrap();
+		mmr_size = load(synced_size, &elems[4..9], &mut backend);
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			root = pmmr.root();
+		}
+		// nothing gets logged until the sync, which crashes right after the
+		// log is on disk
+		let wal_path = format!("{}/pmmr_wal.bin", data_dir);
+		assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+		backend.flush_wal().unwrap();
+	}
+
+	// only part of the unsynced data made it to disk, the last record being
+	// half written, and the last write-ahead log write didn't complete either
+	let data_path = format!("{}/pmmr_dat.bin", data_dir);
+	let data_len = fs::metadata(&data_path).unwrap().len();
+	OpenOptions::new().write(true).open(&data_path).unwrap().set_len(data_len - 3 * 44 - 10).unwrap();
+	let wal_path = format!("{}/pmmr_wal.bin", data_dir);
+	OpenOptions::new().append(true).open(&wal_path).unwrap().write_all(&[1, 0, 0]).unwrap();
+
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), true).unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+	assert_eq!(fs::metadata(&data_path).unwrap().len(), data_len);
+	assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+}
+
+#[test]
+fn sumtree_snapshot() {
+	let (data_dir, elems) = setup();
+	let snapshot_dir = format!("{}/snapshot", data_dir);
+	let import_dir = format!("{}/import", data_dir);
+
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+	let mmr_size = load(0, &elems[..], &mut backend);
+	backend.sync().unwrap();
+	// get both pruned list and remove log data
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.prune(1);
+		pmmr.prune(2);
+	}
+	backend.sync().unwrap();
+	backend.check_compact(1).unwrap();
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.prune(4);
+	}
+	backend.sync().unwrap();
+
+	backend.export_snapshot(&snapshot_dir, 12).unwrap();
+	assert!(!Path::new(&format!("{}.tmp", snapshot_dir)).exists());
+
+	let imported = store::sumtree::PMMRBackend::<TestElem>::from_snapshot(&snapshot_dir, &import_dir)
+		.unwrap();
+	assert_eq!(imported.current_size(), mmr_size);
+	for pos in 1..(mmr_size + 1) {
+		assert_eq!(imported.get(pos), backend.get(pos));
+	}
+}
+
+#[test]
+fn sumtree_readonly() {
+	let (data_dir, elems) = setup();
+
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+	let mmr_size = load(0, &elems[0..4], &mut backend);
+	backend.sync().unwrap();
+
+	let mut readonly = store::sumtree::PMMRBackend::<TestElem>::open_readonly(&data_dir).unwrap();
+	for pos in 1..(mmr_size + 1) {
+		assert_eq!(readonly.get(pos), backend.get(pos));
+	}
+	assert!(readonly.append(mmr_size + 1, vec![HashSum::from_summable(mmr_size + 1, &elems[4])])
+		.is_err());
+	assert!(readonly.remove(vec![1]).is_err());
+	assert!(readonly.check_compact(1).is_err());
+
+	// writes through the read-write backend are seen once synced
+	let new_size = load(mmr_size, &elems[4..9], &mut backend);
+	backend.sync().unwrap();
+	readonly.sync().unwrap();
+	assert_eq!(readonly.get(new_size), backend.get(new_size));
+	assert_eq!(readonly.get(1), Some(HashSum::from_summable(1, &elems[0])));
+
+	// and so are removals
+	{
+		let mut pmmr = PMMR::at(&mut backend, new_size);
+		pmmr.prune(1);
+		pmmr.prune(2);
+		pmmr.prune(4);
+	}
+	backend.sync().unwrap();
+	readonly.sync().unwrap();
+	assert_eq!(readonly.get(1), None);
+	assert_eq!(readonly.get(4), None);
+
+	// as well as compactions, replacing the data file
+	backend.check_compact(1).unwrap();
+	readonly.sync().unwrap();
+	for pos in 1..(new_size + 1) {
+		assert_eq!(readonly.get(pos), backend.get(pos));
+	}
+	assert_eq!(readonly.get(5), Some(HashSum::from_summable(5, &elems[3])));
+	assert_eq!(readonly.current_size(), new_size);
+}
+
+#[test]
+fn sumtree_exclusive_lock() {
+	use store::sumtree::PMMRBackend;
+
+	let (data_dir, elems) = setup();
+	{
+		let mut backend = PMMRBackend::new(data_dir.clone(), false).unwrap();
+		let mmr_size = load(0, &elems[0..4], &mut backend);
+		backend.sync().unwrap();
+
+		// a second writer is refused while the first is around
+		match PMMRBackend::<TestElem>::new(data_dir.clone(), false) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+			Ok(_) => panic!("two backends writing to {}", data_dir),
+		}
+		// readers aren't
+		let readonly = PMMRBackend::<TestElem>::open_readonly(&data_dir).unwrap();
+		assert_eq!(readonly.get(mmr_size), backend.get(mmr_size));
+	}
+
+	// the lock goes away with the backend
+	let backend = PMMRBackend::<TestElem>::new(data_dir, false).unwrap();
+	assert_eq!(backend.get(1), Some(HashSum::from_summable(1, &elems[0])));
+}
+
+#[test]
+fn sumtree_cold_tier() {
+	let (data_dir, elems) = setup();
+	let cold_dir = format!("{}/cold", data_dir);
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			root = pmmr.root();
+		}
+
+		// move the first 5 records (and a bit) to the cold tier
+		backend.set_cold_tier(Path::new(&cold_dir), 5 * 44 + 12).unwrap();
+		assert_eq!(backend.get(1), Some(HashSum::from_summable(1, &elems[0])));
+		assert_eq!(backend.get(16), Some(HashSum::from_summable(16, &elems[8])));
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+		}
+	}
+	assert_eq!(fs::metadata(format!("{}/pmmr_dat_cold.bin", cold_dir)).unwrap().len(), 220);
+
+	// the cold tier is picked up again on reload, and survives compaction
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+			pmmr.prune(1);
+			pmmr.prune(4);
+		}
+		backend.sync().unwrap();
+		backend.check_compact(1).unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+		}
+		assert_eq!(backend.get(2), Some(HashSum::from_summable(2, &elems[1])));
+	}
+}
+
+#[test]
+fn sumtree_segments() {
+	let (data_dir, elems) = setup();
+
+	let mmr_size: u64;
+	let root: HashSum<TestElem>;
+	{
+		// segments of 4 records (and a bit, rounded down)
+		let mut backend = store::sumtree::PMMRBackend::with_segment_size(data_dir.clone(), 4 * 44 + 12)
+			.unwrap();
+		mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			root = pmmr.root();
+		}
+		assert_eq!(backend.get(1), Some(HashSum::from_summable(1, &elems[0])));
+		assert_eq!(backend.get(16), Some(HashSum::from_summable(16, &elems[8])));
+	}
+
+	// 16 records of 44 bytes (with checksum) make 4 full segments
+	for seg in &["pmmr_dat.bin", "pmmr_dat.bin_001", "pmmr_dat.bin_002", "pmmr_dat.bin_003"] {
+		assert_eq!(fs::metadata(format!("{}/{}", data_dir, seg)).unwrap().len(), 176);
+	}
+
+	// segments are picked up again on reload, and survive compaction
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		{
+			let mut pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+			pmmr.prune(1);
+			pmmr.prune(4);
+			pmmr.prune(5);
+		}
+		backend.sync().unwrap();
+		backend.check_compact(2).unwrap();
+		{
+			let pmmr = PMMR::at(&mut backend, mmr_size);
+			assert_eq!(root, pmmr.root());
+		}
+		assert_eq!(backend.get(2), Some(HashSum::from_summable(2, &elems[1])));
+		assert_eq!(backend.get(16), Some(HashSum::from_summable(16, &elems[8])));
+	}
+	assert!(!Path::new(&format!("{}/pmmr_dat.bin_004", data_dir)).exists());
+}
+
+#[test]
+fn sumtree_rewind() {
+	let (data_dir, elems) = setup();
+	for sub in &["full", "batch", "segments"] {
+		fs::create_dir_all(format!("{}/{}", data_dir, sub)).unwrap();
+	}
+
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(format!("{}/full", data_dir), false).unwrap();
+		let mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		root = pmmr.root();
+	}
+
+	// rewinding mid-batch, dropping part of the unsynced data, and then back
+	// to the synced data
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(format!("{}/batch", data_dir), false).unwrap();
+		let synced_size = load(0, &elems[0..4], &mut backend);
+		backend.sync().unwrap();
+		load(synced_size, &elems[4..7], &mut backend);
+		backend.rewind(8).unwrap();
+		assert_eq!(backend.current_size(), 8);
+		assert_eq!(backend.get(7), Some(HashSum::from_summable(7, &elems[3])));
+		assert_eq!(backend.get(8), Some(HashSum::from_summable(8, &elems[4])));
+		assert_eq!(backend.get(9), None);
+
+		load(8, &elems[5..7], &mut backend);
+		backend.rewind(synced_size).unwrap();
+		assert_eq!(backend.current_size(), synced_size);
+		assert_eq!(backend.get(8), None);
+
+		load(synced_size, &elems[4..6], &mut backend);
+		backend.rewind(8).unwrap();
+		let mmr_size = load(8, &elems[5..9], &mut backend);
+		backend.sync().unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+
+	// the data kept pending by the rewind gets logged and synced as expected
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(format!("{}/batch", data_dir), false).unwrap();
+		let pmmr = PMMR::at(&mut backend, 16);
+		assert_eq!(root, pmmr.root());
+	}
+
+	// rewinding across segments of 4 records
+	{
+		let seg_dir = format!("{}/segments", data_dir);
+		let mut backend = store::sumtree::PMMRBackend::with_segment_size(seg_dir.clone(), 4 * 44)
+			.unwrap();
+		load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		backend.rewind(7).unwrap();
+		assert!(!Path::new(&format!("{}/pmmr_dat.bin_002", seg_dir)).exists());
+		assert_eq!(fs::metadata(format!("{}/pmmr_dat.bin_001", seg_dir)).unwrap().len(), 132);
+
+		let mmr_size = load(7, &elems[4..9], &mut backend);
+		backend.sync().unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+	}
+}
+
+#[test]
+fn sumtree_rewind_remove_log() {
+	let (data_dir, _) = setup();
+	let elems = (0..1000).map(|n| TestElem([0, 0, n / 16, n % 16])).collect::<Vec<_>>();
+
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
+	let mut positions = vec![];
+	let mut rewind_pos = 0;
+	let mmr_size: u64;
+	{
+		let mut pmmr = PMMR::new(&mut backend);
+		for (n, elem) in elems.iter().enumerate() {
+			positions.push(pmmr.push(elem.clone()));
+			if n == 499 {
+				rewind_pos = pmmr.unpruned_size();
+			}
+		}
+		mmr_size = pmmr.unpruned_size();
+	}
+	backend.sync().unwrap();
+
+	// removing one element in 5
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		for pos in positions.iter().enumerate().filter(|&(n, _)| n % 5 == 0).map(|(_, p)| p) {
+			pmmr.prune(*pos);
+		}
+	}
+	backend.sync().unwrap();
+
+	backend.rewind(rewind_pos).unwrap();
+	for pos in (rewind_pos + 1)..(mmr_size + 1) {
+		assert_eq!(backend.get(pos), None);
+	}
+
+	// removals before the rewind position are kept, the ones after are
+	// forgotten once the same elements get appended again
+	{
+		let mut pmmr = PMMR::at(&mut backend, rewind_pos);
+		for elem in &elems[500..] {
+			pmmr.push(elem.clone());
+		}
+	}
+	backend.sync().unwrap();
+	assert_eq!(backend.get(positions[495]), None);
+	assert_eq!(
+		backend.get(positions[500]),
+		Some(HashSum::from_summable(positions[500], &elems[500]))
+	);
+}
+
+#[test]
+fn sumtree_unremove() {
+	let (data_dir, elems) = setup();
+	let mut backend = store::sumtree::PMMRBackend::new(data_dir, false).unwrap();
+	let mmr_size = load(0, &elems[..], &mut backend);
+	backend.sync().unwrap();
+
+	// pruning both leaves also removes their parent
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.prune(1);
+		pmmr.prune(2);
+	}
+	assert_eq!(backend.get(3), None);
+
+	backend.unremove(vec![1, 3]).unwrap();
+	assert_eq!(backend.get(1), Some(HashSum::from_summable(1, &elems[0])));
+	assert!(backend.get(3).is_some());
+	assert_eq!(backend.get(2), None);
+
+	// once compacted, removals are final
+	{
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		pmmr.prune(4);
+		pmmr.prune(5);
+	}
+	backend.check_compact(1).unwrap();
+	assert!(backend.unremove(vec![4]).is_err());
+}
+
+#[test]
+fn sumtree_integrity() {
+	let (data_dir, elems) = setup();
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), true).unwrap();
+		load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+	}
+	assert!(store::sumtree::PMMRBackend::<TestElem>::new(data_dir.clone(), true).is_ok());
+
+	// flip a byte in the middle of the data file
+	let data_path = format!("{}/pmmr_dat.bin", data_dir);
+	let mut data = vec![];
+	File::open(&data_path).unwrap().read_to_end(&mut data).unwrap();
+	data[100] ^= 1;
+	File::create(&data_path).unwrap().write_all(&data).unwrap();
+
+	match store::sumtree::PMMRBackend::<TestElem>::new(data_dir.clone(), true) {
+		Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+		Ok(_) => panic!("corrupted data file should fail the integrity check"),
+	}
+	// only checked on demand
+	assert!(store::sumtree::PMMRBackend::<TestElem>::new(data_dir.clone(), false).is_ok());
+
+	// a truncated last record is caught as well
+	data[100] ^= 1;
+	let len = data.len();
+	File::create(&data_path).unwrap().write_all(&data[..(len - 2)]).unwrap();
+	assert!(store::sumtree::PMMRBackend::<TestElem>::new(data_dir, true).is_err());
+}
+
+#[test]
+fn sumtree_rocksdb() {
+	use store::sumtree::{AnyBackend, BackendType, PMMROptions};
+
+	let (data_dir, elems) = setup();
+	let root: HashSum<TestElem>;
+	{
+		let mut backend = store::sumtree::PMMRBackend::new(data_dir.clone(), false).unwrap();
+		let mmr_size = load(0, &elems[..], &mut backend);
+		backend.sync().unwrap();
+		let pmmr = PMMR::at(&mut backend, mmr_size);
+		root = pmmr.root();
+	}
+
+	let opts = PMMROptions {
+		backend_type: BackendType::RocksDb,
+		..Default::default()
+	};
+	let rocks_dir = format!("{}/rocksdb", data_dir);
+	{
+		let mut backend = AnyBackend::open(rocks_dir.clone(), opts.clone()).unwrap();
+		match backend {
+			AnyBackend::RocksDb(_) => {}
+			_ => panic!("Expected a RocksDB backend"),
+		}
+
+		// same root as the file based backend, even after a rewind
+		let mut mmr_size = load(0, &elems[0..4], &mut backend);
+		load(mmr_size, &elems[4..7], &mut backend);
+		backend.rewind(mmr_size).unwrap();
+		assert_eq!(backend.get(mmr_size + 1), None);
+		mmr_size = load(mmr_size, &elems[4..9], &mut backend);
+		backend.sync().unwrap();
+		assert_eq!(backend.current_size(), 16);
+		let mut pmmr = PMMR::at(&mut backend, mmr_size);
+		assert_eq!(root, pmmr.root());
+
+		// pruning deletes the nodes, the root stays the same
+		pmmr.prune(1);
+		pmmr.prune(2);
+		assert_eq!(root, pmmr.root());
+	}
+
+	// sizes and nodes are still there when reopened
+	let mut backend = AnyBackend::<TestElem>::open(rocks_dir, opts).unwrap();
+	assert_eq!(backend.current_size(), 16);
+	assert_eq!(backend.get(1), None);
+	assert_eq!(backend.get(2), None);
+	assert!(backend.get(4).is_some());
+	let pmmr = PMMR::at(&mut backend, 16);
+	assert_eq!(root, pmmr.root());
+}
+
 fn setup() -> (String, Vec<TestElem>) {
 	let _ = env_logger::init();
 	let t = time::get_time();
@@ -164,8 +1073,9 @@ fn setup() -> (String, Vec<TestElem>) {
 	(data_dir, elems)
 }
 
-fn load(pos: u64, elems: &[TestElem],
-				backend: &mut store::sumtree::PMMRBackend<TestElem>) -> u64 {
+fn load<B>(pos: u64, elems: &[TestElem], backend: &mut B) -> u64
+	where B: Backend<TestElem>
+{
 
 	let mut pmmr = PMMR::at(backend, pos);
 	for elem in elems {
@@ -0,0 +1,79 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cross-checks the outputs the wallet data tracks against the UTXO set of a
+//! node, to detect the wallet data diverging from the chain after a crash
+//! or a node outage. Nothing is modified, refreshing the outputs takes care
+//! of that.
+
+use checker;
+use extkey::ExtendedKey;
+use secp;
+use secp::pedersen::Commitment;
+use types::*;
+
+/// Differences found between the wallet data and the chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditReport {
+	/// Outputs the wallet and the chain agree are unspent
+	pub confirmed_match: u64,
+	/// Outputs the wallet considers unspent but aren't in the UTXO set
+	pub missing_from_chain: Vec<Commitment>,
+	/// Outputs the wallet doesn't consider unspent but are in the UTXO set
+	pub extra_in_chain: Vec<Commitment>,
+	/// Balance according to the chain minus the balance according to the
+	/// wallet
+	pub balance_discrepancy: i64,
+}
+
+/// Checks all the outputs of the wallet against the UTXO set of the node
+/// configured in the wallet config.
+pub fn audit(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<AuditReport, Error> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+	// no need to hold the wallet lock during the network round trips
+	let outputs = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.outputs.clone()
+	})?;
+
+	let mut report = AuditReport {
+		confirmed_match: 0,
+		missing_from_chain: vec![],
+		extra_in_chain: vec![],
+		balance_discrepancy: 0,
+	};
+	for out in outputs {
+		let key = ext_key.derive(&secp, out.n_child)?;
+		let commit = secp.commit(out.value, key.key)?;
+		let in_chain = checker::get_output_by_commitment(config, commit)?.is_some();
+		let unspent = match out.status {
+			OutputStatus::Unspent | OutputStatus::Immature | OutputStatus::Locked => true,
+			OutputStatus::Unconfirmed | OutputStatus::Spent => false,
+		};
+
+		match (unspent, in_chain) {
+			(true, true) => report.confirmed_match += 1,
+			(true, false) => {
+				report.missing_from_chain.push(commit);
+				report.balance_discrepancy -= out.value as i64;
+			}
+			(false, true) => {
+				report.extra_in_chain.push(commit);
+				report.balance_discrepancy += out.value as i64;
+			}
+			(false, false) => {}
+		}
+	}
+	Ok(report)
+}
@@ -0,0 +1,133 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export and import of the outputs tracked by the wallet, independently of
+//! the keychain. Allows moving a wallet between machines or restoring the
+//! outputs from a backup without rescanning the chain.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use types::*;
+
+/// Summary of an import of outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportReport {
+	/// Outputs that weren't in the wallet data and have been added
+	pub imported: usize,
+	/// Outputs the wallet data already knew about
+	pub skipped: usize,
+}
+
+/// Writes all the outputs tracked by the wallet to the provided file, as a
+/// JSON array. Returns the number of outputs exported.
+pub fn export_outputs(config: &WalletConfig, path: &Path) -> io::Result<usize> {
+	let outputs = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.outputs.clone()
+	}).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+
+	let file = File::create(path)?;
+	serde_json::to_writer_pretty(file, &outputs)
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+	Ok(outputs.len())
+}
+
+/// Reads the outputs previously exported to the provided file and adds the
+/// ones the wallet doesn't track yet to its data. Outputs are identified by
+/// their key fingerprint and child index.
+pub fn import_outputs(config: &WalletConfig, path: &Path) -> Result<ImportReport, Error> {
+	let file = File::open(path).map_err(|e| {
+		Error::WalletData(format!("Could not open {}: {}", path.display(), e))
+	})?;
+	let outputs: Vec<OutputData> = serde_json::from_reader(file)?;
+
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let mut report = ImportReport {
+			imported: 0,
+			skipped: 0,
+		};
+		for out in outputs {
+			let known = wallet_data.outputs.iter().any(|known| {
+				known.n_child == out.n_child && known.fingerprint == out.fingerprint
+			});
+			if known {
+				report.skipped += 1;
+			} else {
+				wallet_data.append_output(out);
+				report.imported += 1;
+			}
+		}
+		report
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use std::fs;
+
+	use secp::Secp256k1;
+	use extkey::ExtendedKey;
+	use super::*;
+
+	fn wallet_config(dir: &str) -> WalletConfig {
+		let _ = fs::remove_dir_all(dir);
+		WalletConfig {
+			data_file_dir: dir.to_string(),
+			..Default::default()
+		}
+	}
+
+	fn add_outputs(config: &WalletConfig, ext_key: &ExtendedKey, children: Vec<u32>) {
+		let secp = Secp256k1::new();
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for n in children {
+				let key = ext_key.derive(&secp, n).unwrap();
+				wallet_data.append_output(OutputData {
+					fingerprint: key.fingerprint,
+					n_child: key.n_child,
+					value: 10 * n as u64,
+					status: OutputStatus::Unspent,
+					height: 1,
+					lock_height: 0,
+					is_coinbase: false,
+					confirmations: 0,
+				});
+			}
+		}).unwrap();
+	}
+
+	#[test]
+	fn export_import_outputs() {
+		let secp = Secp256k1::new();
+		let ext_key = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap();
+
+		let source = wallet_config("target/backup_source");
+		add_outputs(&source, &ext_key, vec![1, 2, 3]);
+		let backup = Path::new("target/outputs_backup.json");
+		assert_eq!(export_outputs(&source, backup).unwrap(), 3);
+
+		// the destination already knows about one of them
+		let dest = wallet_config("target/backup_dest");
+		add_outputs(&dest, &ext_key, vec![2]);
+		let report = import_outputs(&dest, backup).unwrap();
+		assert_eq!(report, ImportReport { imported: 2, skipped: 1 });
+
+		// importing again doesn't duplicate anything
+		let report = import_outputs(&dest, backup).unwrap();
+		assert_eq!(report, ImportReport { imported: 0, skipped: 3 });
+	}
+}
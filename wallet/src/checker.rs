@@ -18,6 +18,7 @@
 use api;
 use extkey::ExtendedKey;
 use secp::{self, pedersen};
+use secp::pedersen::Commitment;
 use types::*;
 use util;
 
@@ -30,6 +31,7 @@ fn refresh_output(
 	if let Some(api_out) = api_out {
 		out.height = api_out.height;
 		out.lock_height = api_out.lock_height;
+		out.is_coinbase = is_coinbase(&api_out);
 
 		if out.status == OutputStatus::Locked {
 			// leave it Locked locally for now
@@ -46,10 +48,29 @@ fn refresh_output(
 	}
 }
 
+/// Whether the output the node returned is a coinbase reward.
+pub fn is_coinbase(api_out: &api::Output) -> bool {
+	match api_out.output_type {
+		api::OutputType::Coinbase => true,
+		api::OutputType::Transaction => false,
+	}
+}
+
 /// Goes through the list of outputs that haven't been spent yet and check
 /// with a node whether their status has changed.
 pub fn refresh_outputs(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<(), Error>{
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	refresh_outputs_with(config, |out| {
+		let key = ext_key.derive(&secp, out.n_child)?;
+		Ok(secp.commit(out.value, key.key)?)
+	})
+}
+
+/// Same as refresh_outputs, the commitment of each output being rebuilt by
+/// the provided function.
+pub fn refresh_outputs_with<F>(config: &WalletConfig, commit_fn: F) -> Result<(), Error>
+	where F: Fn(&OutputData) -> Result<Commitment, Error>
+{
 	let tip = get_tip(config)?;
 
 	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
@@ -60,8 +81,13 @@ pub fn refresh_outputs(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<(
 
 			// figure out the commitment
 			// TODO check the pool for unconfirmed
-			let key = ext_key.derive(&secp, out.n_child).unwrap();
-			let commitment = secp.commit(out.value, key.key).unwrap();
+			let commitment = match commit_fn(out) {
+				Ok(commitment) => commitment,
+				Err(e) => {
+					error!("Could not rebuild the commitment of output {}: {:?}", out.n_child, e);
+					continue;
+				}
+			};
 
 			match get_output_by_commitment(config, commitment) {
 				Ok(api_out) => refresh_output(&mut out, api_out, &tip),
@@ -71,17 +97,20 @@ pub fn refresh_outputs(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<(
 				}
 			}
 		}
+		wallet_data.resolve_replaced();
+		wallet_data.update_outputs(tip.height);
 	})
 }
 
-fn get_tip(config: &WalletConfig) -> Result<api::Tip, Error> {
+/// Current tip of the chain of the node the wallet checks against.
+pub fn get_tip(config: &WalletConfig) -> Result<api::Tip, Error> {
 	let url = format!("{}/v1/chain/1", config.check_node_api_http_addr);
 	api::client::get::<api::Tip>(url.as_str())
 		.map_err(|e| Error::Node(e))
 }
 
 // queries a reachable node for a given output, checking whether it's been confirmed
-fn get_output_by_commitment(
+pub fn get_output_by_commitment(
 	config: &WalletConfig,
 	commit: pedersen::Commitment
 ) -> Result<Option<api::Output>, Error> {
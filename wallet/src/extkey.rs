@@ -28,6 +28,9 @@ use crypto::digest::Digest;
 use secp::Secp256k1;
 use secp::key::SecretKey;
 
+use keychain::{HARDENED, KeyDerivation, Keychain, SEED_SIZE};
+use types::KeyScheme;
+
 /// An ExtKey error
 #[derive(Copy, PartialEq, Eq, Clone, Debug)]
 pub enum Error {
@@ -144,6 +147,8 @@ pub struct ExtendedKey {
 	pub chaincode: [u8; 32],
 	/// Actual private key
 	pub key: SecretKey,
+	/// How children get derived
+	pub scheme: KeyScheme,
 }
 
 impl ExtendedKey {
@@ -169,6 +174,7 @@ impl ExtendedKey {
 			n_child: n_child,
 			chaincode: chaincode,
 			key: secret_key,
+			scheme: KeyScheme::Legacy,
 		})
 	}
 
@@ -195,6 +201,7 @@ impl ExtendedKey {
 			n_child: 0,
 			chaincode: chaincode,
 			key: secret_key,
+			scheme: KeyScheme::Legacy,
 		};
 
 		ext_key.fingerprint = ext_key.identifier().fingerprint();
@@ -202,6 +209,40 @@ impl ExtendedKey {
 		Ok(ext_key)
 	}
 
+	/// Creates the root key of a wallet from its seed, following the
+	/// provided key scheme. The BIP32 scheme needs a seed of 256 bits.
+	pub fn from_seed_with_scheme(secp: &Secp256k1, seed: &[u8], scheme: KeyScheme) -> Result<ExtendedKey, Error> {
+		match scheme {
+			KeyScheme::Legacy => ExtendedKey::from_seed(secp, seed),
+			KeyScheme::Bip32 => {
+				if seed.len() != SEED_SIZE {
+					return Err(Error::InvalidSeedSize);
+				}
+				let mut keychain_seed = [0; SEED_SIZE];
+				keychain_seed.copy_from_slice(seed);
+				Keychain::from_seed(keychain_seed)
+					.and_then(|keychain| keychain.account_key())
+					.map_err(|_| Error::InvalidExtendedKey)
+			}
+		}
+	}
+
+	/// Creates the root key of a wallet following the BIP32 key scheme from
+	/// the node of its account, m/0'. The keys it derives are the ones of the
+	/// Keychain at m/0'/n_child.
+	pub fn from_account(account: KeyDerivation) -> ExtendedKey {
+		let mut ext_key = ExtendedKey {
+			depth: account.depth,
+			fingerprint: Fingerprint::zero(),
+			n_child: HARDENED,
+			chaincode: account.chaincode,
+			key: account.key,
+			scheme: KeyScheme::Bip32,
+		};
+		ext_key.fingerprint = ext_key.identifier().fingerprint();
+		ext_key
+	}
+
 	/// Return the identifier of the key, which is the
 	/// Hash160 of the private key
 	pub fn identifier(&self) -> Identifier {
@@ -221,6 +262,9 @@ impl ExtendedKey {
 
 	/// Derive an extended key from an extended key
 	pub fn derive(&self, secp: &Secp256k1, n: u32) -> Result<ExtendedKey, Error> {
+		if self.scheme == KeyScheme::Bip32 {
+			return self.derive_bip32(secp, n);
+		}
 		let mut hmac = Hmac::new(Sha512::new(), &self.chaincode[..]);
 		let mut n_bytes: [u8; 4] = [0; 4];
 		BigEndian::write_u32(&mut n_bytes, n);
@@ -246,6 +290,25 @@ impl ExtendedKey {
 			n_child: n,
 			chaincode: chain_code,
 			key: secret_key,
+			scheme: KeyScheme::Legacy,
+		})
+	}
+
+	// Derives the child the way the Keychain does, through BIP32.
+	fn derive_bip32(&self, secp: &Secp256k1, n: u32) -> Result<ExtendedKey, Error> {
+		let node = KeyDerivation {
+			depth: self.depth,
+			chaincode: self.chaincode,
+			key: self.key,
+		};
+		let child = node.child(secp, n).map_err(|_| Error::InvalidExtendedKey)?;
+		Ok(ExtendedKey {
+			depth: child.depth,
+			fingerprint: self.identifier().fingerprint(),
+			n_child: n,
+			chaincode: child.chaincode,
+			key: child.key,
+			scheme: KeyScheme::Bip32,
 		})
 	}
 }
@@ -255,6 +318,8 @@ mod test {
 	use secp::Secp256k1;
 	use secp::key::SecretKey;
 	use super::{ExtendedKey, Fingerprint};
+	use keychain::{Keychain, SEED_SIZE};
+	use types::KeyScheme;
 	use util;
 
 	fn from_hex(hex_str: &str) -> Vec<u8> {
@@ -303,4 +368,27 @@ mod test {
 		assert_eq!(derived.depth, depth);
 		assert_eq!(derived.n_child, n_child);
 	}
+
+	#[test]
+	fn extkey_bip32_scheme() {
+		let s = Secp256k1::with_caps(::secp::ContextFlag::Commit);
+		let seed = [7; SEED_SIZE];
+		let keychain = Keychain::from_seed(seed).unwrap();
+		let extk = ExtendedKey::from_seed_with_scheme(&s, &seed, KeyScheme::Bip32).unwrap();
+
+		// sequential children are the keys of the keychain at m/0'/n_child,
+		// all under the fingerprint of the root key
+		for n in 0..10 {
+			let derived = extk.derive(&s, n).unwrap();
+			assert_eq!(derived.key, keychain.derive_index(n).unwrap());
+			assert_eq!(derived.n_child, n);
+			assert_eq!(derived.fingerprint, extk.fingerprint);
+		}
+
+		// and distinct from the legacy ones
+		let legacy = ExtendedKey::from_seed_with_scheme(&s, &seed, KeyScheme::Legacy).unwrap();
+		assert!(legacy.fingerprint != extk.fingerprint);
+		assert!(legacy.derive(&s, 1).unwrap().key != extk.derive(&s, 1).unwrap().key);
+		assert!(ExtendedKey::from_seed_with_scheme(&s, &[7; 16], KeyScheme::Bip32).is_err());
+	}
 }
@@ -25,17 +25,18 @@ pub fn show_info(config: &WalletConfig, ext_key: &ExtendedKey) {
 	let _ = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
 
 		println!("Outputs - ");
-		println!("fingerprint, n_child, height, lock_height, status, value");
+		println!("fingerprint, n_child, height, lock_height, confirmations, status, value");
 		println!("----------------------------------");
 		for out in &mut wallet_data.outputs {
 			let key = ext_key.derive(&secp, out.n_child).unwrap();
 
 			println!(
-				"{}, {}, {}, {}, {:?}, {}",
+				"{}, {}, {}, {}, {}, {:?}, {}",
 				key.identifier().fingerprint(),
 				out.n_child,
 				out.height,
 				out.lock_height,
+				out.confirmations,
 				out.status,
 				out.value
 			);
@@ -0,0 +1,503 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hierarchical deterministic derivation of the wallet keys from a single
+//! 256 bits master seed, following BIP32 paths like m/0'/1/2'. The seed can
+//! be backed up as a 24 words BIP39 mnemonic.
+//!
+//! Public keys are handled as commitments to a zero value, k*G, which is
+//! what outputs are checked against. Non-hardened children hash the
+//! commitment of their parent key (instead of its BIP32 serialization), so
+//! they can be derived from an extended public key as well.
+
+use std::{error, fmt};
+use std::str::FromStr;
+
+use byteorder::{ByteOrder, BigEndian};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::{Sha256, Sha512};
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+use secp::pedersen::Commitment;
+
+use extkey::ExtendedKey;
+use util;
+
+/// Size of the master seed, in bytes.
+pub const SEED_SIZE: usize = 32;
+
+/// Offset of hardened child numbers, written with a ' in derivation paths.
+pub const HARDENED: u32 = 1 << 31;
+
+// Number of words of a mnemonic, 11 bits each for the 256 bits of the seed
+// and the 8 bits of its checksum.
+const MNEMONIC_WORDS: usize = 24;
+
+// The BIP39 english word list, sorted.
+const WORDS: &'static str = include_str!("wordlist/english.txt");
+
+/// A Keychain error
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum KeychainError {
+	/// The derivation path can't be parsed
+	InvalidPath(String),
+	/// The mnemonic isn't made of 24 words from the list, with a valid
+	/// checksum
+	InvalidMnemonic(String),
+	/// The derived key is out of range, the path has to be changed (happens
+	/// with a probability lower than 1 in 2^127)
+	InvalidKey,
+	/// The extended public key can't be parsed
+	InvalidXpub(String),
+	/// Hardened children can't be derived from an extended public key
+	HardenedFromPublic,
+}
+
+impl fmt::Display for KeychainError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match *self {
+			KeychainError::InvalidPath(ref s) => write!(f, "{}: {}", error::Error::description(self), s),
+			KeychainError::InvalidMnemonic(ref s) => write!(f, "{}: {}", error::Error::description(self), s),
+			KeychainError::InvalidXpub(ref s) => write!(f, "{}: {}", error::Error::description(self), s),
+			_ => f.write_str(error::Error::description(self)),
+		}
+	}
+}
+
+impl error::Error for KeychainError {
+	fn cause(&self) -> Option<&error::Error> {
+		None
+	}
+
+	fn description(&self) -> &str {
+		match *self {
+			KeychainError::InvalidPath(_) => "keychain: invalid derivation path",
+			KeychainError::InvalidMnemonic(_) => "keychain: invalid mnemonic",
+			KeychainError::InvalidKey => "keychain: derived key out of range",
+			KeychainError::InvalidXpub(_) => "keychain: invalid extended public key",
+			KeychainError::HardenedFromPublic => "keychain: can't derive a hardened child from a public key",
+		}
+	}
+}
+
+impl From<secp::Error> for KeychainError {
+	fn from(_: secp::Error) -> KeychainError {
+		KeychainError::InvalidKey
+	}
+}
+
+/// Path of a key in the tree of keys derived from the master seed, as the
+/// child number at each level. Hardened child numbers are offset by
+/// HARDENED.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl DerivationPath {
+	/// Path of the account all the sequential keys are derived from, m/0'.
+	/// Its extended public key derives their public keys.
+	pub fn account() -> DerivationPath {
+		DerivationPath(vec![HARDENED])
+	}
+
+	/// Path of the key with the provided sequential index, like the n_child
+	/// of the wallet outputs: m/0'/index.
+	pub fn from_index(index: u32) -> DerivationPath {
+		DerivationPath(vec![HARDENED, index])
+	}
+}
+
+impl FromStr for DerivationPath {
+	type Err = KeychainError;
+
+	/// Parses a path like m/0'/1/2', where ' marks hardened child numbers.
+	fn from_str(s: &str) -> Result<DerivationPath, KeychainError> {
+		let invalid = || KeychainError::InvalidPath(s.to_string());
+		let mut parts = s.split('/');
+		if parts.next() != Some("m") {
+			return Err(invalid());
+		}
+		let mut path = vec![];
+		for part in parts {
+			let (num, hardened) = if part.ends_with('\'') {
+				(&part[..part.len() - 1], true)
+			} else {
+				(part, false)
+			};
+			let n = num.parse::<u32>().map_err(|_| invalid())?;
+			if n >= HARDENED {
+				return Err(invalid());
+			}
+			path.push(if hardened { n + HARDENED } else { n });
+		}
+		Ok(DerivationPath(path))
+	}
+}
+
+impl fmt::Display for DerivationPath {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "m"));
+		for n in &self.0 {
+			if *n >= HARDENED {
+				try!(write!(f, "/{}'", n - HARDENED));
+			} else {
+				try!(write!(f, "/{}", n));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A node of the key tree: a private key and the chain code its children
+/// get derived with, through HMAC-SHA512 as in BIP32.
+#[derive(Clone)]
+pub struct KeyDerivation {
+	/// Depth in the tree, 0 for the master key
+	pub depth: u8,
+	/// Code of the derivation chain
+	pub chaincode: [u8; 32],
+	/// Actual private key
+	pub key: SecretKey,
+}
+
+impl KeyDerivation {
+	/// The master key of the tree derived from the provided seed.
+	pub fn master(secp: &Secp256k1, seed: &[u8; SEED_SIZE]) -> Result<KeyDerivation, KeychainError> {
+		let mut hmac = Hmac::new(Sha512::new(), b"Mimble seed");
+		hmac.input(&seed[..]);
+		let mut derived = [0; 64];
+		hmac.raw_result(&mut derived);
+		let mut chaincode = [0; 32];
+		chaincode.copy_from_slice(&derived[32..]);
+		Ok(KeyDerivation {
+			depth: 0,
+			chaincode: chaincode,
+			key: SecretKey::from_slice(secp, &derived[..32])?,
+		})
+	}
+
+	/// Derives the child with the provided number. Hardened children are
+	/// derived from the private key, others from the public key.
+	pub fn child(&self, secp: &Secp256k1, n: u32) -> Result<KeyDerivation, KeychainError> {
+		let mut hmac = Hmac::new(Sha512::new(), &self.chaincode[..]);
+		if n >= HARDENED {
+			hmac.input(&[0u8]);
+			hmac.input(&self.key[..]);
+		} else {
+			hmac.input(secp.commit(0, self.key)?.as_ref());
+		}
+		let (tweak, chaincode) = split_hmac(secp, hmac, n)?;
+		let mut key = tweak;
+		key.add_assign(secp, &self.key)?;
+		Ok(KeyDerivation {
+			depth: self.depth + 1,
+			chaincode: chaincode,
+			key: key,
+		})
+	}
+
+	/// The extended public key of this node, deriving the public keys of its
+	/// non-hardened children.
+	pub fn public(&self, secp: &Secp256k1) -> Result<ExtendedPubKey, KeychainError> {
+		Ok(ExtendedPubKey {
+			depth: self.depth,
+			chaincode: self.chaincode,
+			point: secp.commit(0, self.key)?,
+		})
+	}
+}
+
+/// The public side of a node of the key tree: its public key, as a
+/// commitment to a zero value, and its chain code. Derives the public keys of
+/// the non-hardened children of the node, which match the ones of the
+/// private keys derived by a Keychain. Serialized as the hex of its depth,
+/// chain code and public key.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExtendedPubKey {
+	/// Depth in the tree, 0 for the master key
+	pub depth: u8,
+	/// Code of the derivation chain
+	pub chaincode: [u8; 32],
+	/// Public key, as a commitment to a zero value
+	pub point: Commitment,
+}
+
+impl ExtendedPubKey {
+	/// Derives the public key of the child with the provided number, which
+	/// can't be hardened.
+	pub fn child(&self, secp: &Secp256k1, n: u32) -> Result<ExtendedPubKey, KeychainError> {
+		if n >= HARDENED {
+			return Err(KeychainError::HardenedFromPublic);
+		}
+		let mut hmac = Hmac::new(Sha512::new(), &self.chaincode[..]);
+		hmac.input(self.point.as_ref());
+		let (tweak, chaincode) = split_hmac(secp, hmac, n)?;
+		Ok(ExtendedPubKey {
+			depth: self.depth + 1,
+			chaincode: chaincode,
+			point: secp.commit_sum(vec![self.point, secp.commit(0, tweak)?], vec![])?,
+		})
+	}
+}
+
+impl FromStr for ExtendedPubKey {
+	type Err = KeychainError;
+
+	fn from_str(s: &str) -> Result<ExtendedPubKey, KeychainError> {
+		let invalid = || KeychainError::InvalidXpub(s.to_string());
+		let bytes = util::from_hex(s.to_string()).map_err(|_| invalid())?;
+		if bytes.len() != 1 + 32 + 33 {
+			return Err(invalid());
+		}
+		let mut chaincode = [0; 32];
+		chaincode.copy_from_slice(&bytes[1..33]);
+		Ok(ExtendedPubKey {
+			depth: bytes[0],
+			chaincode: chaincode,
+			point: Commitment::from_vec(bytes[33..].to_vec()),
+		})
+	}
+}
+
+impl fmt::Display for ExtendedPubKey {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut bytes = vec![self.depth];
+		bytes.extend_from_slice(&self.chaincode[..]);
+		bytes.extend_from_slice(self.point.as_ref());
+		write!(f, "{}", util::to_hex(bytes))
+	}
+}
+
+// Appends the child number to the HMAC-SHA512 input and splits the result in
+// a key tweak and a chain code.
+fn split_hmac(secp: &Secp256k1, mut hmac: Hmac<Sha512>, n: u32) -> Result<(SecretKey, [u8; 32]), KeychainError> {
+	let mut n_bytes = [0; 4];
+	BigEndian::write_u32(&mut n_bytes, n);
+	hmac.input(&n_bytes[..]);
+
+	let mut derived = [0; 64];
+	hmac.raw_result(&mut derived);
+	let mut chaincode = [0; 32];
+	chaincode.copy_from_slice(&derived[32..]);
+	Ok((SecretKey::from_slice(secp, &derived[..32])?, chaincode))
+}
+
+/// Derives all the keys of a wallet from its master seed.
+pub struct Keychain {
+	secp: Secp256k1,
+	seed: [u8; SEED_SIZE],
+	master: KeyDerivation,
+}
+
+impl Keychain {
+	/// Keychain of the provided master seed.
+	pub fn from_seed(seed: [u8; SEED_SIZE]) -> Result<Keychain, KeychainError> {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let master = KeyDerivation::master(&secp, &seed)?;
+		Ok(Keychain {
+			secp: secp,
+			seed: seed,
+			master: master,
+		})
+	}
+
+	/// Keychain of the master seed encoded by the provided mnemonic.
+	pub fn from_mnemonic(mnemonic: &str) -> Result<Keychain, KeychainError> {
+		Keychain::from_seed(mnemonic_to_seed(mnemonic)?)
+	}
+
+	/// The master seed, as a 24 words mnemonic for backups.
+	pub fn mnemonic(&self) -> String {
+		seed_to_mnemonic(&self.seed)
+	}
+
+	/// Derives the private key at the provided path.
+	pub fn derive_child(&self, path: &DerivationPath) -> Result<SecretKey, KeychainError> {
+		Ok(self.node(path)?.key)
+	}
+
+	/// Derives the private key of the provided sequential key index, at
+	/// m/0'/index.
+	pub fn derive_index(&self, index: u32) -> Result<SecretKey, KeychainError> {
+		self.derive_child(&DerivationPath::from_index(index))
+	}
+
+	/// The key of the account m/0' as the root key of a wallet, deriving the
+	/// keys of its outputs at m/0'/n_child.
+	pub fn account_key(&self) -> Result<ExtendedKey, KeychainError> {
+		Ok(ExtendedKey::from_account(self.node(&DerivationPath::account())?))
+	}
+
+	/// The extended public key at the provided path, typically the account
+	/// one, for a watch-only wallet.
+	pub fn xpub(&self, path: &DerivationPath) -> Result<ExtendedPubKey, KeychainError> {
+		self.node(path)?.public(&self.secp)
+	}
+
+	// The node of the key tree at the provided path.
+	fn node(&self, path: &DerivationPath) -> Result<KeyDerivation, KeychainError> {
+		let mut node = self.master.clone();
+		for n in &path.0 {
+			node = node.child(&self.secp, *n)?;
+		}
+		Ok(node)
+	}
+}
+
+/// Encodes a seed as a BIP39 mnemonic, the seed being the entropy. Each
+/// word holds 11 bits of the seed followed by the first byte of its SHA256.
+pub fn seed_to_mnemonic(seed: &[u8; SEED_SIZE]) -> String {
+	let mut data = seed.to_vec();
+	data.push(checksum(seed));
+
+	let words = WORDS.lines().collect::<Vec<_>>();
+	let mut mnemonic = Vec::with_capacity(MNEMONIC_WORDS);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for b in data {
+		acc = (acc << 8) | b as u32;
+		bits += 8;
+		if bits >= 11 {
+			bits -= 11;
+			mnemonic.push(words[((acc >> bits) & 0x7ff) as usize]);
+		}
+	}
+	mnemonic.join(" ")
+}
+
+/// Decodes the seed of a BIP39 mnemonic of 24 words, checking its
+/// checksum.
+pub fn mnemonic_to_seed(mnemonic: &str) -> Result<[u8; SEED_SIZE], KeychainError> {
+	let words = WORDS.lines().collect::<Vec<_>>();
+	let mnemonic = mnemonic.split_whitespace().collect::<Vec<_>>();
+	if mnemonic.len() != MNEMONIC_WORDS {
+		return Err(KeychainError::InvalidMnemonic(
+			format!("{} words instead of {}", mnemonic.len(), MNEMONIC_WORDS),
+		));
+	}
+
+	let mut data = Vec::with_capacity(SEED_SIZE + 1);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for word in mnemonic {
+		let idx = words.binary_search(&word).map_err(|_| {
+			KeychainError::InvalidMnemonic(format!("unknown word {}", word))
+		})?;
+		acc = (acc << 11) | idx as u32;
+		bits += 11;
+		while bits >= 8 {
+			bits -= 8;
+			data.push((acc >> bits) as u8);
+		}
+	}
+
+	let mut seed = [0; SEED_SIZE];
+	seed.copy_from_slice(&data[..SEED_SIZE]);
+	if data[SEED_SIZE] != checksum(&seed) {
+		return Err(KeychainError::InvalidMnemonic("invalid checksum".to_string()));
+	}
+	Ok(seed)
+}
+
+// First byte of the SHA256 of the seed.
+fn checksum(seed: &[u8; SEED_SIZE]) -> u8 {
+	let mut sha = Sha256::new();
+	sha.input(&seed[..]);
+	let mut hash = [0; 32];
+	sha.result(&mut hash);
+	hash[0]
+}
+
+#[cfg(test)]
+mod test {
+	use rand::{self, Rng};
+	use super::*;
+
+	#[test]
+	fn derive_keys_from_seed() {
+		let mut seed = [0; SEED_SIZE];
+		rand::thread_rng().fill_bytes(&mut seed);
+
+		let keychain = Keychain::from_seed(seed).unwrap();
+		let keys = (0..100).map(|n| keychain.derive_index(n).unwrap()).collect::<Vec<_>>();
+
+		// same keys from the same seed, directly or through its mnemonic
+		let same_seed = Keychain::from_seed(seed).unwrap();
+		let from_mnemonic = Keychain::from_mnemonic(&keychain.mnemonic()).unwrap();
+		for n in 0..100 {
+			assert_eq!(same_seed.derive_index(n).unwrap(), keys[n as usize]);
+			assert_eq!(from_mnemonic.derive_index(n).unwrap(), keys[n as usize]);
+		}
+		// all different
+		for n in 1..100 {
+			assert!(keys[..n].iter().all(|k| *k != keys[n]));
+		}
+
+		let path = "m/0'/7".parse::<DerivationPath>().unwrap();
+		assert_eq!(keychain.derive_child(&path).unwrap(), keys[7]);
+
+		// hardened or not makes a different key
+		let hardened = "m/0'/7'".parse::<DerivationPath>().unwrap();
+		assert!(keychain.derive_child(&hardened).unwrap() != keys[7]);
+	}
+
+	#[test]
+	fn derive_public_keys() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let keychain = Keychain::from_seed([3; SEED_SIZE]).unwrap();
+		let xpub = keychain.xpub(&DerivationPath::account()).unwrap();
+
+		// an xpub derives the public keys of the private ones
+		for n in 0..20 {
+			let key = keychain.derive_index(n).unwrap();
+			assert_eq!(xpub.child(&secp, n).unwrap().point, secp.commit(0, key).unwrap());
+		}
+		assert_eq!(xpub.child(&secp, HARDENED), Err(KeychainError::HardenedFromPublic));
+
+		let parsed = xpub.to_string().parse::<ExtendedPubKey>().unwrap();
+		assert_eq!(parsed, xpub);
+		assert!("00ff".parse::<ExtendedPubKey>().is_err());
+		assert!("xpub".parse::<ExtendedPubKey>().is_err());
+	}
+
+	#[test]
+	fn derivation_paths() {
+		let path = "m/0'/1/2'".parse::<DerivationPath>().unwrap();
+		assert_eq!(path, DerivationPath(vec![HARDENED, 1, HARDENED + 2]));
+		assert_eq!(path.to_string(), "m/0'/1/2'");
+		assert_eq!("m".parse::<DerivationPath>().unwrap(), DerivationPath(vec![]));
+		assert_eq!(DerivationPath::from_index(5).to_string(), "m/0'/5");
+
+		for invalid in &["", "0/1", "m/", "m/a", "m/1''", "m/-1", "m/2147483648"] {
+			assert!(invalid.parse::<DerivationPath>().is_err(), "{} parsed", invalid);
+		}
+	}
+
+	#[test]
+	fn mnemonic_vectors() {
+		// BIP39 test vectors
+		let zero = vec!["abandon"; 23].join(" ") + " art";
+		assert_eq!(seed_to_mnemonic(&[0; SEED_SIZE]), zero);
+		assert_eq!(mnemonic_to_seed(&zero).unwrap(), [0; SEED_SIZE]);
+		let ones = vec!["zoo"; 23].join(" ") + " vote";
+		assert_eq!(seed_to_mnemonic(&[0xff; SEED_SIZE]), ones);
+		assert_eq!(mnemonic_to_seed(&ones).unwrap(), [0xff; SEED_SIZE]);
+
+		// wrong checksum, unknown word, wrong length
+		assert!(mnemonic_to_seed(&(vec!["abandon"; 24].join(" "))).is_err());
+		assert!(mnemonic_to_seed(&(vec!["abandon"; 23].join(" ") + " grin")).is_err());
+		assert!(mnemonic_to_seed(&(vec!["abandon"; 11].join(" ") + " about")).is_err());
+	}
+}
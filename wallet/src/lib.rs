@@ -14,6 +14,7 @@
 
 //! Library module for the main wallet functionalities provided by Grin.
 
+extern crate blake2_rfc as blake2;
 extern crate byteorder;
 extern crate crypto;
 #[macro_use]
@@ -23,21 +24,38 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate uuid;
 
 extern crate grin_api as api;
 extern crate grin_core as core;
 extern crate grin_util as util;
 extern crate secp256k1zkp as secp;
 
+mod audit;
+mod backup;
 mod checker;
 mod extkey;
 mod info;
+mod keychain;
+mod payment;
 mod receiver;
+mod restore;
+mod selection;
 mod sender;
+mod slate;
 mod types;
+mod watch;
 
+pub use audit::{AuditReport, audit};
+pub use backup::{ImportReport, export_outputs, import_outputs};
 pub use extkey::ExtendedKey;
 pub use info::show_info;
-pub use receiver::{WalletReceiver, receive_json_tx};
-pub use sender::issue_send_tx;
-pub use types::{WalletConfig, WalletReceiveRequest, CbAmount, CbData};
+pub use keychain::{DerivationPath, ExtendedPubKey, KeyDerivation, Keychain, KeychainError};
+pub use payment::{PaymentProof, generate_payment_proof};
+pub use receiver::{WalletReceiver, add_receiver_data, receive_json_tx};
+pub use restore::{RescanReport, rescan_from_height};
+pub use selection::CoinSelectionPolicy;
+pub use sender::{MIN_FEE_BUMP_INCREMENT, bump_fee, create_slate, issue_fee_bump, issue_send_tx};
+pub use slate::{ParticipantData, Slate, finalize_slate};
+pub use types::{WalletConfig, WalletReceiveRequest, CbAmount, CbData, KeyScheme, COINBASE_MIN_CONFIRMATIONS};
+pub use watch::WatchOnlyWallet;
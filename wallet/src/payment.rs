@@ -0,0 +1,112 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proofs that a sender made a given payment to a given recipient. Once the
+//! transaction is confirmed, the sender signs the excess of its kernel along
+//! with the public key of the recipient. Anyone knowing the sender's public
+//! key can then check the proof against the kernel found on chain.
+
+use blake2::blake2b::blake2b;
+
+use core::core::TxKernel;
+use extkey::ExtendedKey;
+use secp::{self, Message, Secp256k1, Signature};
+use secp::key::PublicKey;
+use types::*;
+
+/// Proof, signed by the sender, that the transaction a kernel belongs to
+/// paid the recipient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentProof {
+	/// Public key of the recipient of the payment
+	pub recipient: PublicKey,
+	/// Signature of the kernel excess and recipient key by the sender
+	pub sig: Signature,
+}
+
+impl PaymentProof {
+	/// Whether the proof is a valid signature by the provided sender of the
+	/// kernel excess and the recipient key.
+	pub fn verify(&self, kernel: &TxKernel, sender_pub: &PublicKey) -> bool {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::VerifyOnly);
+		match message(&secp, kernel, &self.recipient) {
+			Ok(msg) => secp.verify(&msg, &self.sig, sender_pub).is_ok(),
+			Err(_) => false,
+		}
+	}
+}
+
+/// Generates a proof that the transaction the provided kernel belongs to
+/// paid the recipient. Signed with the wallet's root key, whose public key
+/// the proof is verified against.
+pub fn generate_payment_proof(
+	ext_key: &ExtendedKey,
+	kernel: &TxKernel,
+	recipient_pub: &PublicKey,
+) -> Result<PaymentProof, Error> {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::SignOnly);
+	let msg = message(&secp, kernel, recipient_pub)?;
+	Ok(PaymentProof {
+		recipient: recipient_pub.clone(),
+		sig: secp.sign(&msg, &ext_key.key)?,
+	})
+}
+
+// The signed message, BLAKE2b(kernel_excess || recipient_pub)
+fn message(
+	secp: &Secp256k1,
+	kernel: &TxKernel,
+	recipient_pub: &PublicKey,
+) -> Result<Message, secp::Error> {
+	let mut data = kernel.excess.0.to_vec();
+	data.extend_from_slice(&recipient_pub.serialize_vec(secp, true));
+	Message::from_slice(blake2b(32, &[], &data).as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+	use core::core::DEFAULT_KERNEL;
+	use secp::key::SecretKey;
+	use super::*;
+
+	fn kernel(secp: &Secp256k1, blinding: u8) -> TxKernel {
+		let key = SecretKey::from_slice(secp, &[blinding; 32]).unwrap();
+		TxKernel {
+			features: DEFAULT_KERNEL,
+			excess: secp.commit(0, key).unwrap(),
+			excess_sig: vec![],
+			fee: 1,
+		}
+	}
+
+	#[test]
+	fn payment_proof() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let sender = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap();
+		let sender_pub = PublicKey::from_secret_key(&secp, &sender.key).unwrap();
+		let recipient = ExtendedKey::from_seed(&secp, &[2; 16]).unwrap();
+		let recipient_pub = PublicKey::from_secret_key(&secp, &recipient.key).unwrap();
+
+		let paid = kernel(&secp, 3);
+		let proof = generate_payment_proof(&sender, &paid, &recipient_pub).unwrap();
+		assert!(proof.verify(&paid, &sender_pub));
+
+		// another kernel, another sender or another recipient don't check out
+		assert!(!proof.verify(&kernel(&secp, 4), &sender_pub));
+		assert!(!proof.verify(&paid, &recipient_pub));
+		let mut other_recipient = proof.clone();
+		other_recipient.recipient = sender_pub;
+		assert!(!other_recipient.verify(&paid, &sender_pub));
+	}
+}
@@ -20,9 +20,13 @@
 //!
 //! POST /v1/wallet/receive
 //! > {
+//! >   "id": "5c8e2ab0-...",
 //! >   "amount": 10,
-//! >   "blind_sum": "a12b7f...",
+//! >   "fee": 4,
+//! >   "height": 1200,
+//! >   "lock_height": 0,
 //! >   "tx": "f083de...",
+//! >   "participant_data": [{ "id": 0, "blind_sum": "a12b7f..." }],
 //! > }
 //!
 //! < {
@@ -57,6 +61,7 @@ use core::core::{Block, Transaction, TxKernel, Output, build};
 use core::ser;
 use api::{self, ApiEndpoint, Operation, ApiResult};
 use extkey::ExtendedKey;
+use slate::{self, ParticipantData, Slate};
 use types::*;
 use util;
 
@@ -66,16 +71,17 @@ struct TxWrapper {
 	tx_hex: String,
 }
 
-/// Receive an already well formed JSON transaction issuance and finalize the
+/// Receive the JSON slate of a transaction sent to us and finalize the
 /// transaction, adding our receiving output, to broadcast to the rest of the
 /// network.
 pub fn receive_json_tx(
 	config: &WalletConfig,
 	ext_key: &ExtendedKey,
-	partial_tx_str: &str
+	slate_str: &str
 ) -> Result<(), Error> {
-	let (amount, blinding, partial_tx) = partial_tx_from_json(partial_tx_str)?;
-	let final_tx = receive_transaction(&config, ext_key, amount, blinding, partial_tx)?;
+	let mut slate = Slate::from_json(slate_str)?;
+	add_receiver_data(config, ext_key, &mut slate)?;
+	let final_tx = slate::finalize_slate(slate)?;
 	let tx_hex = util::to_hex(ser::ser_vec(&final_tx).unwrap());
 
 	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
@@ -176,6 +182,8 @@ fn receive_coinbase(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -
 			status: OutputStatus::Unconfirmed,
 			height: 0,
 			lock_height: 0,
+			is_coinbase: true,
+			confirmations: 0,
 		});
 		debug!("Using child {} for a new coinbase output.",
 		       coinbase_key.n_child);
@@ -184,6 +192,29 @@ fn receive_coinbase(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -
 	})?
 }
 
+/// Adds our receiving output to the slate of a transaction sent to us,
+/// building the full transaction from the sender's blinding factor.
+pub fn add_receiver_data(config: &WalletConfig, ext_key: &ExtendedKey, slate: &mut Slate) -> Result<(), Error> {
+	if slate.participant(slate::RECEIVER_ID).is_some() {
+		return Err(Error::Format(format!("Slate {} was already received", slate.id)));
+	}
+	let blinding = match slate.participant(slate::SENDER_ID).and_then(|p| p.blind_sum) {
+		Some(blinding) => blinding,
+		None => {
+			return Err(Error::Format(
+				format!("Slate {} is missing the sender's blinding factor", slate.id),
+			))
+		}
+	};
+	let tx = receive_transaction(config, ext_key, slate.amount, blinding, slate.tx.clone())?;
+	slate.tx = tx;
+	slate.participant_data.push(ParticipantData {
+		id: slate::RECEIVER_ID,
+		blind_sum: None,
+	});
+	Ok(())
+}
+
 /// Builds a full transaction from the partial one sent to us for transfer
 fn receive_transaction(config: &WalletConfig,
 					   ext_key: &ExtendedKey,
@@ -216,6 +247,8 @@ fn receive_transaction(config: &WalletConfig,
 			status: OutputStatus::Unconfirmed,
 			height: 0,
 			lock_height: 0,
+			is_coinbase: false,
+			confirmations: 0,
 		});
 
 		debug!("Using child {} for a new transaction output.",
@@ -0,0 +1,146 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Incremental rescan of the chain, starting at a given height, to recover
+//! outputs the wallet data doesn't know about (after a failure or a gap in
+//! syncing for example). Each output found on chain gets its range proof
+//! rewound with our derived keys, which only succeeds for outputs we built.
+
+use api;
+use checker;
+use extkey::ExtendedKey;
+use secp;
+use types::*;
+
+/// Number of derivations past the highest known child index that are tried
+/// when rewinding proofs, to find outputs the wallet data has lost track of.
+const CHILD_LOOKAHEAD: u32 = 100;
+
+/// Summary of what a rescan went through and found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RescanReport {
+	/// First height that was scanned
+	pub start_height: u64,
+	/// Last height that was scanned
+	pub end_height: u64,
+	/// Total number of outputs inspected
+	pub outputs_scanned: u64,
+	/// Outputs that belong to us and weren't in the wallet data yet
+	pub outputs_found: u64,
+	/// Outputs that belong to us but the wallet data already knew about
+	pub outputs_known: u64,
+}
+
+/// Scans the chain from the provided height up to the current tip, trying to
+/// rewind the range proof of every output with the keys derived from our
+/// extended key. Newly discovered outputs are merged into the wallet data,
+/// outputs already tracked are left untouched. Outputs found are then
+/// refreshed against the node to catch the ones that have been spent since.
+pub fn rescan_from_height(
+	config: &WalletConfig,
+	ext_key: &ExtendedKey,
+	start_height: u64,
+) -> Result<RescanReport, Error> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let tip = get_tip(config)?;
+
+	let mut report = RescanReport {
+		start_height: start_height,
+		end_height: tip.height,
+		outputs_scanned: 0,
+		outputs_found: 0,
+		outputs_known: 0,
+	};
+	if start_height > tip.height {
+		return Ok(report);
+	}
+
+	// download the outputs first, no need to hold the wallet lock during the
+	// network round trips
+	let mut chain_outputs = vec![];
+	for height in start_height..(tip.height + 1) {
+		let block_outs = get_outputs_at_height(config, height)?;
+		chain_outputs.extend(block_outs.outputs);
+	}
+
+	let report = WalletData::with_wallet(&config.data_file_dir, |wallet_data| -> Result<RescanReport, Error> {
+		let max_child = wallet_data.next_child(&ext_key.fingerprint) + CHILD_LOOKAHEAD;
+		let mut keys = vec![];
+		for n in 1..max_child {
+			keys.push(ext_key.derive(&secp, n)?);
+		}
+
+		for api_out in chain_outputs {
+			report.outputs_scanned += 1;
+
+			let owned = keys.iter().filter_map(|key| {
+				let info = secp.rewind_range_proof(api_out.commit, api_out.proof, key.key);
+				if !info.success {
+					return None;
+				}
+				match secp.commit(info.value, key.key) {
+					Ok(commit) if commit == api_out.commit => Some((key, info.value)),
+					_ => None,
+				}
+			}).next();
+
+			if let Some((key, value)) = owned {
+				let known = wallet_data.outputs.iter().any(|out| {
+					out.n_child == key.n_child && out.fingerprint == key.fingerprint
+				});
+				if known {
+					report.outputs_known += 1;
+					continue;
+				}
+
+				let status = if api_out.lock_height >= tip.height {
+					OutputStatus::Immature
+				} else {
+					OutputStatus::Unspent
+				};
+				wallet_data.append_output(OutputData {
+					fingerprint: key.fingerprint.clone(),
+					n_child: key.n_child,
+					value: value,
+					status: status,
+					height: api_out.height,
+					lock_height: api_out.lock_height,
+					is_coinbase: checker::is_coinbase(&api_out),
+					confirmations: tip.height.saturating_sub(api_out.height),
+				});
+				report.outputs_found += 1;
+			}
+		}
+		Ok(report)
+	})??;
+
+	// outputs we just found may have been spent since, refreshing sorts
+	// that out
+	checker::refresh_outputs(config, ext_key)?;
+	Ok(report)
+}
+
+fn get_tip(config: &WalletConfig) -> Result<api::Tip, Error> {
+	let url = format!("{}/v1/chain/1", config.check_node_api_http_addr);
+	api::client::get::<api::Tip>(url.as_str()).map_err(|e| Error::Node(e))
+}
+
+fn get_outputs_at_height(config: &WalletConfig, height: u64) -> Result<api::BlockOutputs, Error> {
+	let url = format!(
+		"{}/v1/chain/outputs/{}",
+		config.check_node_api_http_addr,
+		height
+	);
+	api::client::get::<api::BlockOutputs>(url.as_str()).map_err(|e| Error::Node(e))
+}
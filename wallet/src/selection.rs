@@ -0,0 +1,214 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selection of the outputs a transaction spends, giving users control over
+//! which of their coins get spent.
+
+use std::str::FromStr;
+
+use secp::pedersen::Commitment;
+
+use extkey;
+use types::*;
+
+/// How the outputs a transaction spends get picked among the unspent outputs
+/// of the wallet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoinSelectionPolicy {
+	/// Outputs in the order the wallet received them
+	Automatic,
+	/// Exactly the outputs with the provided commitments
+	Manual(Vec<Commitment>),
+	/// Outputs with the largest values first, spending fewer outputs
+	LargestFirst,
+	/// Outputs with the smallest values first, consolidating dust
+	SmallestFirst,
+	/// Outputs mined at the lowest heights first
+	OldestFirst,
+}
+
+impl Default for CoinSelectionPolicy {
+	fn default() -> CoinSelectionPolicy {
+		CoinSelectionPolicy::Automatic
+	}
+}
+
+impl FromStr for CoinSelectionPolicy {
+	type Err = Error;
+
+	/// Parses the policies that don't need a list of outputs: automatic,
+	/// largest, smallest or oldest.
+	fn from_str(s: &str) -> Result<CoinSelectionPolicy, Error> {
+		match s {
+			"automatic" => Ok(CoinSelectionPolicy::Automatic),
+			"largest" => Ok(CoinSelectionPolicy::LargestFirst),
+			"smallest" => Ok(CoinSelectionPolicy::SmallestFirst),
+			"oldest" => Ok(CoinSelectionPolicy::OldestFirst),
+			_ => Err(Error::Format(format!("Unknown coin selection policy {}", s))),
+		}
+	}
+}
+
+/// Selects unspent outputs with the provided fingerprint covering the
+/// provided amount, following the policy. The commitment of an output is
+/// built by commit_fn, only needed for manual selection. Returns the outputs
+/// and the change left over, or NotEnoughFunds with the missing amount.
+pub fn select_coins<F>(
+	outputs: &[OutputData],
+	fingerprint: &extkey::Fingerprint,
+	amount: u64,
+	policy: &CoinSelectionPolicy,
+	commit_fn: F,
+) -> Result<(Vec<OutputData>, u64), Error>
+where
+	F: Fn(&OutputData) -> Result<Commitment, Error>,
+{
+	let mut eligible = outputs.iter()
+		.filter(|out| out.status == OutputStatus::Unspent && out.fingerprint == *fingerprint)
+		.cloned()
+		.collect::<Vec<_>>();
+
+	let to_spend = match *policy {
+		CoinSelectionPolicy::Manual(ref commits) => {
+			let mut candidates = vec![];
+			for out in eligible {
+				candidates.push((commit_fn(&out)?, out));
+			}
+			let mut selected = vec![];
+			for commit in commits {
+				match candidates.iter().position(|c| c.0 == *commit) {
+					Some(idx) => selected.push(candidates.remove(idx).1),
+					None => {
+						return Err(Error::WalletData(
+							format!("{:?} isn't an unspent output of the wallet", commit),
+						))
+					}
+				}
+			}
+			selected
+		}
+		_ => {
+			match *policy {
+				CoinSelectionPolicy::LargestFirst => eligible.sort_by(|a, b| b.value.cmp(&a.value)),
+				CoinSelectionPolicy::SmallestFirst => eligible.sort_by_key(|out| out.value),
+				CoinSelectionPolicy::OldestFirst => eligible.sort_by_key(|out| out.height),
+				_ => {}
+			}
+			let mut selected = vec![];
+			let mut total = 0;
+			for out in eligible {
+				if total >= amount {
+					break;
+				}
+				total += out.value;
+				selected.push(out);
+			}
+			selected
+		}
+	};
+
+	let total = to_spend.iter().map(|out| out.value).sum::<u64>();
+	if total < amount {
+		return Err(Error::NotEnoughFunds(amount - total));
+	}
+	Ok((to_spend, total - amount))
+}
+
+#[cfg(test)]
+mod test {
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+
+	use extkey::ExtendedKey;
+	use super::*;
+
+	// Unspent outputs of values 50, 10, 30, 20 and 40 mined at heights 3, 5,
+	// 1, 2 and 4, along with a spent one.
+	fn outputs(fingerprint: &extkey::Fingerprint) -> Vec<OutputData> {
+		let out = |n_child: u32, value: u64, height: u64, status: OutputStatus| OutputData {
+			fingerprint: fingerprint.clone(),
+			n_child: n_child,
+			value: value,
+			status: status,
+			height: height,
+			lock_height: 0,
+			is_coinbase: false,
+			confirmations: 0,
+		};
+		vec![
+			out(1, 50, 3, OutputStatus::Unspent),
+			out(2, 10, 5, OutputStatus::Unspent),
+			out(3, 100, 0, OutputStatus::Spent),
+			out(4, 30, 1, OutputStatus::Unspent),
+			out(5, 20, 2, OutputStatus::Unspent),
+			out(6, 40, 4, OutputStatus::Unspent),
+		]
+	}
+
+	// Commitment of an output, with a key standing for the derived one.
+	fn commit(out: &OutputData) -> Result<Commitment, Error> {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let key = SecretKey::from_slice(&secp, &[out.n_child as u8; 32])?;
+		Ok(secp.commit(out.value, key)?)
+	}
+
+	fn selected(policy: CoinSelectionPolicy, amount: u64) -> Result<(Vec<u32>, u64), Error> {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let fingerprint = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap().fingerprint;
+		let (outs, change) = select_coins(&outputs(&fingerprint), &fingerprint, amount, &policy, commit)?;
+		Ok((outs.iter().map(|out| out.n_child).collect(), change))
+	}
+
+	#[test]
+	fn select_by_policy() {
+		assert_eq!(selected(CoinSelectionPolicy::Automatic, 55).unwrap(), (vec![1, 2], 5));
+		assert_eq!(selected(CoinSelectionPolicy::LargestFirst, 55).unwrap(), (vec![1, 6], 35));
+		assert_eq!(selected(CoinSelectionPolicy::SmallestFirst, 55).unwrap(), (vec![2, 5, 4], 5));
+		assert_eq!(selected(CoinSelectionPolicy::OldestFirst, 55).unwrap(), (vec![4, 5, 1], 45));
+		assert_eq!(selected(CoinSelectionPolicy::LargestFirst, 150).unwrap(), (vec![1, 6, 4, 5, 2], 0));
+
+		// the spent output is never selected
+		match selected(CoinSelectionPolicy::SmallestFirst, 151) {
+			Err(Error::NotEnoughFunds(1)) => {}
+			res => panic!("unexpected selection {:?}", res),
+		}
+	}
+
+	#[test]
+	fn select_manually() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let fingerprint = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap().fingerprint;
+		let outs = outputs(&fingerprint);
+		let commits = vec![commit(&outs[3]).unwrap(), commit(&outs[1]).unwrap()];
+
+		let policy = CoinSelectionPolicy::Manual(commits.clone());
+		assert_eq!(selected(policy.clone(), 35).unwrap(), (vec![4, 2], 5));
+		assert_eq!(selected(policy.clone(), 40).unwrap(), (vec![4, 2], 0));
+		match selected(policy, 41) {
+			Err(Error::NotEnoughFunds(1)) => {}
+			res => panic!("unexpected selection {:?}", res),
+		}
+
+		// spent or unknown outputs can't be selected
+		let spent = CoinSelectionPolicy::Manual(vec![commit(&outs[2]).unwrap()]);
+		assert!(selected(spent, 1).is_err());
+	}
+
+	#[test]
+	fn parse_policies() {
+		assert_eq!("largest".parse::<CoinSelectionPolicy>().unwrap(), CoinSelectionPolicy::LargestFirst);
+		assert_eq!("oldest".parse::<CoinSelectionPolicy>().unwrap(), CoinSelectionPolicy::OldestFirst);
+		assert!("manual".parse::<CoinSelectionPolicy>().is_err());
+	}
+}
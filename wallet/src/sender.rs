@@ -17,51 +17,148 @@ use secp::{self};
 use secp::key::SecretKey;
 
 use checker;
+use core::consensus::{INPUT_WEIGHT, KERNEL_WEIGHT, OUTPUT_WEIGHT};
 use core::core::{Transaction, build};
 use extkey::ExtendedKey;
+use selection::{self, CoinSelectionPolicy};
+use slate::{self, ParticipantData, Slate};
 use types::*;
+use uuid::Uuid;
 
 use api;
 
+/// Number of blocks we'd like our transactions to be mined within, which
+/// the fees we pay are estimated for.
+const FEE_TARGET_BLOCKS: u64 = 3;
+
+/// Minimum amount by which bumping the fee of a transaction has to raise it,
+/// in the smallest denomination.
+pub const MIN_FEE_BUMP_INCREMENT: u64 = 1_000;
+
 /// Issue a new transaction to the provided sender by spending some of our
 /// wallet
 /// UTXOs. The destination can be "stdout" (for command line) or a URL to the
-/// recipients wallet receiver (to be implemented).
-pub fn issue_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64, dest: String) -> Result<(), Error> {
+/// recipients wallet receiver (to be implemented). The outputs spent are
+/// picked following the provided policy.
+pub fn issue_send_tx(
+	config: &WalletConfig,
+	ext_key: &ExtendedKey,
+	amount: u64,
+	dest: String,
+	policy: CoinSelectionPolicy,
+) -> Result<(), Error> {
 	let _ = checker::refresh_outputs(&config, ext_key);
+	// without a node to ask, the height of the slate stays unknown
+	let height = checker::get_tip(config).map(|tip| tip.height).unwrap_or(0);
 
-	let (tx, blind_sum) = build_send_tx(config, ext_key, amount)?;
-	let json_tx = partial_tx_to_json(amount, blind_sum, tx);
+	let slate = create_slate(config, ext_key, amount, height, &policy)?;
+	send_slate(&slate, dest);
+	Ok(())
+}
+
+/// Issue again the transaction of the slate with the provided id, paying the
+/// provided higher fee. The new slate still requires the receiver to add its
+/// output, it's sent to the provided destination like by issue_send_tx.
+pub fn issue_fee_bump(
+	config: &WalletConfig,
+	ext_key: &ExtendedKey,
+	slate_id: &str,
+	new_fee: u64,
+	dest: String,
+) -> Result<(), Error> {
+	let slate_id = Uuid::parse_str(slate_id).map_err(|_| {
+		Error::Format(format!("Invalid slate id {}", slate_id))
+	})?;
+	let height = checker::get_tip(config).map(|tip| tip.height).unwrap_or(0);
+	let slate = bump_fee(config, ext_key, &slate_id, new_fee, height)?;
+	send_slate(&slate, dest);
+	Ok(())
+}
 
+// Prints the slate or posts it to the receiver at the provided URL.
+fn send_slate(slate: &Slate, dest: String) {
+	let json_slate = slate.to_json();
 	if dest == "stdout" {
-		println!("{}", json_tx);
+		println!("{}", json_slate);
 	} else if &dest[..4] == "http" {
 		let url = format!("{}/v1/receive/receive_json_tx", &dest);
-		debug!("Posting partial transaction to {}", url);
-		let request = WalletReceiveRequest::PartialTransaction(json_tx);
+		debug!("Posting slate {} to {}", slate.id, url);
+		let request = WalletReceiveRequest::PartialTransaction(json_slate);
 		let _: CbData = api::client::post(url.as_str(), &request)
 			.expect(&format!("Wallet receiver at {} unreachable, could not send transaction. Is it running?", url));
 	}
-	Ok(())
+}
+
+/// Creates the slate of a transaction sending the provided amount, with the
+/// inputs and change output of the sender, at the provided chain height. The
+/// outputs spent are picked following the provided policy.
+pub fn create_slate(
+	config: &WalletConfig,
+	ext_key: &ExtendedKey,
+	amount: u64,
+	height: u64,
+	policy: &CoinSelectionPolicy,
+) -> Result<Slate, Error> {
+	let id = Uuid::new_v4();
+	let (tx, blind_sum) = build_send_tx(config, ext_key, &id, amount, policy)?;
+	Ok(Slate {
+		id: id,
+		amount: amount,
+		fee: tx.fee,
+		height: height,
+		lock_height: 0,
+		tx: tx,
+		participant_data: vec![
+			ParticipantData {
+				id: slate::SENDER_ID,
+				blind_sum: Some(blind_sum),
+			},
+		],
+	})
 }
 
 /// Builds a transaction to send to someone from the HD seed associated with the
 /// wallet and the amount to send. Handles reading through the wallet data file,
 /// selecting outputs to spend and building the change.
-fn build_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -> Result<(Transaction, SecretKey), Error> {
+fn build_send_tx(
+	config: &WalletConfig,
+	ext_key: &ExtendedKey,
+	slate_id: &Uuid,
+	amount: u64,
+	policy: &CoinSelectionPolicy,
+) -> Result<(Transaction, SecretKey), Error> {
 	// first, rebuild the private key from the seed
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let fee_rate = estimate_fee_rate(config);
 
 	// operate within a lock on wallet data
 	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
 
-		// second, check from our local wallet data for outputs to spend
-		let (coins, change) = wallet_data.select(&ext_key.fingerprint, amount);
-		if change < 0 {
-			return Err(Error::NotEnoughFunds((-change) as u64));
+		// second, check from our local wallet data for outputs to spend, the
+		// fee depending on how many we spend
+		let mut fee = 0;
+		let mut selected;
+		let spendable = wallet_data.spendable_outputs(config.min_confirmations);
+		loop {
+			selected = selection::select_coins(
+				&spendable,
+				&ext_key.fingerprint,
+				amount + fee,
+				policy,
+				|out| {
+					let key = ext_key.derive(&secp, out.n_child)?;
+					Ok(secp.commit(out.value, key.key)?)
+				},
+			)?;
+			// our change and the recipient's output
+			let weight = selected.0.len() as u64 * INPUT_WEIGHT + 2 * OUTPUT_WEIGHT +
+			             KERNEL_WEIGHT;
+			if weight * fee_rate <= fee {
+				break;
+			}
+			fee = weight * fee_rate;
 		}
-
-		// TODO add fees, which is likely going to make this iterative
+		let (coins, change) = selected;
 
 		// third, build inputs using the appropriate key
 		let mut parts = vec![];
@@ -73,17 +170,29 @@ fn build_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -> R
 		// fourth, derive a new private for change and build the change output
 		let next_child = wallet_data.next_child(&ext_key.fingerprint);
 		let change_key = ext_key.derive(&secp, next_child).map_err(|e| Error::Key(e))?;
-		parts.push(build::output(change as u64, change_key.key));
+		parts.push(build::output(change, change_key.key));
+		parts.push(build::with_fee(fee));
 
 		// we got that far, time to start tracking the new output, finalize tx
 		// and lock the outputs used
 		wallet_data.append_output(OutputData {
 			fingerprint: change_key.fingerprint,
 			n_child: change_key.n_child,
-			value: change as u64,
+			value: change,
 			status: OutputStatus::Unconfirmed,
 			height: 0,
 			lock_height: 0,
+			is_coinbase: false,
+			confirmations: 0,
+		});
+		wallet_data.append_sent(SentTxData {
+			id: slate_id.hyphenated().to_string(),
+			amount: amount,
+			fee: fee,
+			fingerprint: ext_key.fingerprint.clone(),
+			inputs: coins.iter().map(|coin| coin.n_child).collect(),
+			change: change_key.n_child,
+			replaced_changes: vec![],
 		});
 		for coin in coins {
 			wallet_data.lock_output(&coin);
@@ -93,13 +202,131 @@ fn build_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -> R
 	})?
 }
 
+/// Builds again the transaction of the slate with the provided id, which we
+/// sent, spending the same outputs and paying the provided fee, taken out of
+/// our change. The fee has to be raised by at least MIN_FEE_BUMP_INCREMENT.
+/// Returns a slate with the same id, created at the provided chain height,
+/// for the receiver to add its output again.
+pub fn bump_fee(
+	config: &WalletConfig,
+	ext_key: &ExtendedKey,
+	slate_id: &Uuid,
+	new_fee: u64,
+	height: u64,
+) -> Result<Slate, Error> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let id = slate_id.hyphenated().to_string();
+
+	let (amount, tx, blind_sum) = WalletData::with_wallet(&config.data_file_dir, |wallet_data| -> Result<(u64, Transaction, SecretKey), Error> {
+		let sent = match wallet_data.sent.iter().find(|sent| sent.id == id) {
+			Some(sent) => sent.clone(),
+			None => return Err(Error::WalletData(format!("No transaction sent with slate {}", id))),
+		};
+		let min_fee = sent.fee + MIN_FEE_BUMP_INCREMENT;
+		if new_fee < min_fee {
+			return Err(Error::FeeBelowMinimumBump(min_fee));
+		}
+
+		// the outputs spent must still be locked, otherwise the transaction
+		// was already mined (or cancelled)
+		let mut parts = vec![];
+		for n_child in &sent.inputs {
+			let value = match wallet_data.outputs.iter().find(|out| {
+				out.n_child == *n_child && out.fingerprint == sent.fingerprint
+			}) {
+				Some(out) if out.status == OutputStatus::Locked => out.value,
+				_ => {
+					return Err(Error::WalletData(
+						format!("Output {} spent by slate {} isn't locked anymore", n_child, id),
+					))
+				}
+			};
+			let in_key = ext_key.derive(&secp, *n_child)?;
+			parts.push(build::input(value, in_key.key));
+		}
+
+		// the higher fee comes out of the change, which goes to a new key:
+		// until either version of the transaction confirms, we keep track of
+		// both change outputs
+		let increase = new_fee - sent.fee;
+		let is_change = |out: &OutputData| out.n_child == sent.change && out.fingerprint == sent.fingerprint;
+		let change = match wallet_data.outputs.iter().find(|out| is_change(out)) {
+			Some(out) if out.value >= increase => out.value - increase,
+			Some(out) => return Err(Error::NotEnoughFunds(increase - out.value)),
+			None => {
+				return Err(Error::WalletData(
+					format!("Change output {} of slate {} is missing", sent.change, id),
+				))
+			}
+		};
+		let next_child = wallet_data.next_child(&sent.fingerprint);
+		let change_key = ext_key.derive(&secp, next_child)?;
+		parts.push(build::output(change, change_key.key));
+		parts.push(build::with_fee(new_fee));
+		let (tx, blind_sum) = build::transaction(parts)?;
+
+		// only track the new fee and change once the transaction is built
+		wallet_data.append_output(OutputData {
+			fingerprint: change_key.fingerprint,
+			n_child: change_key.n_child,
+			value: change,
+			status: OutputStatus::Unconfirmed,
+			height: 0,
+			lock_height: 0,
+			is_coinbase: false,
+			confirmations: 0,
+		});
+		for entry in wallet_data.sent.iter_mut().filter(|entry| entry.id == id) {
+			entry.fee = new_fee;
+			entry.replaced_changes.push(entry.change);
+			entry.change = change_key.n_child;
+		}
+		Ok((sent.amount, tx, blind_sum))
+	})??;
+
+	Ok(Slate {
+		id: *slate_id,
+		amount: amount,
+		fee: new_fee,
+		height: height,
+		lock_height: 0,
+		tx: tx,
+		participant_data: vec![
+			ParticipantData {
+				id: slate::SENDER_ID,
+				blind_sum: Some(blind_sum),
+			},
+		],
+	})
+}
+
+// Fee rate the node we're connected to estimates our transactions should
+// pay. When the node can't be reached, transactions don't pay any fee.
+fn estimate_fee_rate(config: &WalletConfig) -> u64 {
+	let url = format!(
+		"{}/v1/pool/fee_estimate/{}",
+		config.check_node_api_http_addr,
+		FEE_TARGET_BLOCKS
+	);
+	match api::client::get::<api::FeeEstimate>(url.as_str()) {
+		Ok(estimate) => estimate.fee_rate,
+		Err(e) => {
+			warn!("Could not get a fee estimate from {}, not paying any fee: {:?}", url, e);
+			0
+		}
+	}
+}
+
 #[cfg(test)]
 mod test {
+	use std::fs;
+
 	use core::core::build::{input, output, transaction};
+	use receiver::add_receiver_data;
 	use types::{OutputData, OutputStatus};
 
 	use secp::Secp256k1;
-	use super::ExtendedKey;
+	use super::*;
 	use util;
 
 	fn from_hex(hex_str: &str) -> Vec<u8> {
@@ -124,6 +351,8 @@ mod test {
 			status: OutputStatus::Unconfirmed,
 			height: 0,
 			lock_height: 0,
+			is_coinbase: false,
+			confirmations: 0,
 		};
 
 		let (tx, _) = transaction(vec![output(coin.value, out_key.key)]).unwrap();
@@ -135,4 +364,70 @@ mod test {
 		assert_eq!(in_key.key, out_key.key);
 		assert_eq!(tx.outputs[0].commitment(), tx2.inputs[0].commitment());
 	}
+
+	fn wallet_config(dir: &str) -> WalletConfig {
+		let _ = fs::remove_dir_all(dir);
+		WalletConfig {
+			data_file_dir: dir.to_string(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn bump_fee_of_sent_tx() {
+		let secp = Secp256k1::new();
+		let sender_key = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap();
+		let receiver_key = ExtendedKey::from_seed(&secp, &[2; 16]).unwrap();
+
+		let sender = wallet_config("target/bump_sender");
+		WalletData::with_wallet(&sender.data_file_dir, |wallet_data| {
+			let key = sender_key.derive(&secp, 1).unwrap();
+			wallet_data.append_output(OutputData {
+				fingerprint: key.fingerprint,
+				n_child: key.n_child,
+				value: 10_000,
+				status: OutputStatus::Unspent,
+				height: 1,
+				lock_height: 0,
+				is_coinbase: false,
+				confirmations: 10,
+			});
+		}).unwrap();
+		let slate = create_slate(&sender, &sender_key, 6_000, 5, &CoinSelectionPolicy::Automatic).unwrap();
+		let fee = slate.fee;
+
+		match bump_fee(&sender, &sender_key, &slate.id, fee + 1, 5) {
+			Err(Error::FeeBelowMinimumBump(min)) => assert_eq!(min, fee + MIN_FEE_BUMP_INCREMENT),
+			res => panic!("unexpected fee bump {:?}", res),
+		}
+		assert!(bump_fee(&sender, &sender_key, &Uuid::new_v4(), fee + 2_000, 5).is_err());
+
+		// same inputs, higher fee and lower change
+		let new_fee = fee + 2_000;
+		let mut bumped = bump_fee(&sender, &sender_key, &slate.id, new_fee, 6).unwrap();
+		assert_eq!((bumped.id, bumped.fee, bumped.height), (slate.id, new_fee, 6));
+		add_receiver_data(&wallet_config("target/bump_receiver"), &receiver_key, &mut bumped).unwrap();
+		let tx = slate::finalize_slate(bumped).unwrap();
+		assert_eq!(tx.fee, new_fee);
+		assert_eq!(tx.inputs[0].commitment(), slate.tx.inputs[0].commitment());
+
+		// both change outputs are tracked until one confirms
+		let changes = |sender: &WalletConfig| WalletData::with_wallet(&sender.data_file_dir, |wallet_data| {
+			wallet_data.outputs[1..].iter().map(|out| (out.value, out.status.clone())).collect::<Vec<_>>()
+		}).unwrap();
+		assert_eq!(changes(&sender), vec![
+			(4_000 - fee, OutputStatus::Unconfirmed),
+			(4_000 - new_fee, OutputStatus::Unconfirmed),
+		]);
+
+		// bumping again needs to go over the new fee
+		assert!(bump_fee(&sender, &sender_key, &slate.id, new_fee + 500, 6).is_err());
+
+		// once the bumped version confirms, the first change is dropped
+		WalletData::with_wallet(&sender.data_file_dir, |wallet_data| {
+			wallet_data.outputs[2].status = OutputStatus::Unspent;
+			wallet_data.resolve_replaced();
+		}).unwrap();
+		assert_eq!(changes(&sender), vec![(4_000 - new_fee, OutputStatus::Unspent)]);
+	}
 }
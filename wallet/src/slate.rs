@@ -0,0 +1,342 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The slate, passed around between the parties of a transaction while they
+//! each add their part to it. Building a transaction in MimbleWimble being
+//! interactive, the slate is serialized to JSON so it can be exchanged over
+//! the wallet API or out of band (email, QR code).
+//!
+//! The exchange currently goes:
+//!
+//! * the sender creates the slate with its inputs and change output
+//! (sender::create_slate)
+//! * the receiver adds its output and builds the kernel
+//! (receiver::add_receiver_data)
+//! * either party checks the slate is complete and gets the final transaction
+//! out of it (finalize_slate)
+
+use secp;
+use secp::key::SecretKey;
+use secp::pedersen::{Commitment, RangeProof};
+use serde_json;
+use uuid::Uuid;
+
+use core::core::{build, OutputFeatures, Transaction};
+use core::ser;
+use types::*;
+use util;
+
+/// Id of the participant initiating the transaction.
+pub const SENDER_ID: u64 = 0;
+/// Id of the participant receiving the funds.
+pub const RECEIVER_ID: u64 = 1;
+
+/// Data a participant adds to the slate.
+#[derive(Debug, Clone)]
+pub struct ParticipantData {
+	/// Id of the participant, SENDER_ID or RECEIVER_ID
+	pub id: u64,
+	/// Sum of the blinding factors of the inputs and outputs the participant
+	/// added. Until the kernel gets signed interactively, the sender has to
+	/// share it with the receiver for the latter to build the kernel.
+	pub blind_sum: Option<SecretKey>,
+}
+
+/// A transaction in the making, along with everything the parties agreed
+/// on.
+#[derive(Debug, Clone)]
+pub struct Slate {
+	/// Unique id of the transaction, for the parties to keep track of it
+	pub id: Uuid,
+	/// Amount sent
+	pub amount: u64,
+	/// Fee paid by the transaction
+	pub fee: u64,
+	/// Height of the chain when the slate was created
+	pub height: u64,
+	/// Height before which the transaction can't be mined, 0 if none
+	pub lock_height: u64,
+	/// The transaction, as built so far
+	pub tx: Transaction,
+	/// Data each participant added so far
+	pub participant_data: Vec<ParticipantData>,
+}
+
+/// Helper in serializing a slate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct JSONSlate {
+	id: String,
+	amount: u64,
+	fee: u64,
+	height: u64,
+	lock_height: u64,
+	tx: String,
+	participant_data: Vec<JSONParticipantData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct JSONParticipantData {
+	id: u64,
+	blind_sum: Option<String>,
+}
+
+impl Slate {
+	/// Data the provided participant added, if any.
+	pub fn participant(&self, id: u64) -> Option<&ParticipantData> {
+		self.participant_data.iter().find(|p| p.id == id)
+	}
+
+	/// Adds an output which commitment and range proof have been built
+	/// elsewhere, typically by a hardware signing device computing range
+	/// proofs in firmware, to the slate transaction. The proof is checked but
+	/// never generated again, finalizing the slate only validates it. The
+	/// blinding factor staying on the device, it has to be counted in the
+	/// sender's blinding sum already, and the output added before the
+	/// receiver builds the kernel.
+	pub fn add_output_with_prebuilt_proof(&mut self,
+	                                      commit: Commitment,
+	                                      proof: RangeProof,
+	                                      features: OutputFeatures)
+	                                      -> Result<(), Error> {
+		if self.participant(RECEIVER_ID).is_some() {
+			return Err(Error::Format(
+				format!("Slate {} was already received, no output can be added", self.id),
+			));
+		}
+		let blind_sum = match self.participant(SENDER_ID).and_then(|p| p.blind_sum) {
+			Some(blind_sum) => blind_sum,
+			None => {
+				return Err(Error::Format(
+					format!("Slate {} is missing the sender's blinding factor", self.id),
+				))
+			}
+		};
+
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		secp.verify_range_proof(commit, proof)?;
+		let (tx, _) = build::transaction(vec![build::initial_tx(self.tx.clone()),
+		                                      build::with_excess(blind_sum),
+		                                      build::output_with_prebuilt_proof(commit,
+		                                                                        proof,
+		                                                                        features)])?;
+		self.tx = tx;
+		Ok(())
+	}
+
+	/// Encodes the slate into JSON.
+	pub fn to_json(&self) -> String {
+		let slate = JSONSlate {
+			id: self.id.hyphenated().to_string(),
+			amount: self.amount,
+			fee: self.fee,
+			height: self.height,
+			lock_height: self.lock_height,
+			tx: util::to_hex(ser::ser_vec(&self.tx).unwrap()),
+			participant_data: self.participant_data
+				.iter()
+				.map(|p| {
+					JSONParticipantData {
+						id: p.id,
+						blind_sum: p.blind_sum.map(|b| util::to_hex(b.as_ref().to_vec())),
+					}
+				})
+				.collect(),
+		};
+		serde_json::to_string_pretty(&slate).unwrap()
+	}
+
+	/// Reads a slate encoded as JSON.
+	pub fn from_json(json_str: &str) -> Result<Slate, Error> {
+		let slate: JSONSlate = serde_json::from_str(json_str)?;
+
+		let id = Uuid::parse_str(&slate.id).map_err(|_| {
+			Error::Format(format!("Invalid slate id {}", slate.id))
+		})?;
+		let tx_bin = util::from_hex(slate.tx)?;
+		let tx = ser::deserialize(&mut &tx_bin[..]).map_err(|_| {
+			Error::Format("Could not deserialize transaction, invalid format.".to_string())
+		})?;
+
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let mut participant_data = vec![];
+		for p in slate.participant_data {
+			let blind_sum = match p.blind_sum {
+				Some(hex) => Some(SecretKey::from_slice(&secp, &util::from_hex(hex)?[..])?),
+				None => None,
+			};
+			participant_data.push(ParticipantData {
+				id: p.id,
+				blind_sum: blind_sum,
+			});
+		}
+
+		Ok(Slate {
+			id: id,
+			amount: slate.amount,
+			fee: slate.fee,
+			height: slate.height,
+			lock_height: slate.lock_height,
+			tx: tx,
+			participant_data: participant_data,
+		})
+	}
+}
+
+/// Checks both parties added their data to the slate and that the resulting
+/// transaction is valid and pays the agreed fee, returning it ready to be
+/// broadcast.
+pub fn finalize_slate(slate: Slate) -> Result<Transaction, Error> {
+	if slate.participant(SENDER_ID).is_none() || slate.participant(RECEIVER_ID).is_none() {
+		return Err(Error::Format(
+			format!("Slate {} is missing the data of a participant", slate.id),
+		));
+	}
+	if slate.tx.fee != slate.fee {
+		return Err(Error::Format(format!(
+			"Slate {} transaction pays a fee of {} instead of {}",
+			slate.id,
+			slate.tx.fee,
+			slate.fee
+		)));
+	}
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	slate.tx.validate(&secp)?;
+	Ok(slate.tx)
+}
+
+#[cfg(test)]
+mod test {
+	use std::fs;
+
+	use rand;
+	use secp::Secp256k1;
+	use secp::key;
+
+	use core::core::DEFAULT_OUTPUT;
+	use extkey::ExtendedKey;
+	use receiver::add_receiver_data;
+	use selection::CoinSelectionPolicy;
+	use sender::create_slate;
+	use super::*;
+
+	fn wallet_config(dir: &str) -> WalletConfig {
+		let _ = fs::remove_dir_all(dir);
+		WalletConfig {
+			data_file_dir: dir.to_string(),
+			..Default::default()
+		}
+	}
+
+	fn outputs(config: &WalletConfig) -> Vec<OutputData> {
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.outputs.clone()
+		}).unwrap()
+	}
+
+	#[test]
+	fn two_party_transaction() {
+		let secp = Secp256k1::new();
+		let sender_key = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap();
+		let receiver_key = ExtendedKey::from_seed(&secp, &[2; 16]).unwrap();
+
+		// the sender owns a single output of 100
+		let sender = wallet_config("target/slate_sender");
+		WalletData::with_wallet(&sender.data_file_dir, |wallet_data| {
+			let key = sender_key.derive(&secp, 1).unwrap();
+			wallet_data.append_output(OutputData {
+				fingerprint: key.fingerprint,
+				n_child: key.n_child,
+				value: 100,
+				status: OutputStatus::Unspent,
+				height: 1,
+				lock_height: 0,
+				is_coinbase: false,
+				confirmations: 10,
+			});
+		}).unwrap();
+
+		let slate = create_slate(&sender, &sender_key, 60, 5, &CoinSelectionPolicy::Automatic).unwrap();
+		assert_eq!((slate.amount, slate.height), (60, 5));
+		assert!(slate.participant(SENDER_ID).unwrap().blind_sum.is_some());
+		assert!(slate.participant(RECEIVER_ID).is_none());
+		assert!(finalize_slate(slate.clone()).is_err());
+
+		// sent out of band to the receiver, who adds its output
+		let mut slate = Slate::from_json(&slate.to_json()).unwrap();
+		let receiver = wallet_config("target/slate_receiver");
+		add_receiver_data(&receiver, &receiver_key, &mut slate).unwrap();
+		assert!(slate.participant(RECEIVER_ID).unwrap().blind_sum.is_none());
+
+		// and back to the sender
+		let slate = Slate::from_json(&slate.to_json()).unwrap();
+		let fee = slate.fee;
+		let tx = finalize_slate(slate).unwrap();
+		assert_eq!(tx.inputs.len(), 1);
+		assert_eq!(tx.outputs.len(), 2);
+		assert_eq!(tx.fee, fee);
+
+		// both wallets track their new output
+		let change = outputs(&sender);
+		assert_eq!(change[0].status, OutputStatus::Locked);
+		assert_eq!((change[1].value, &change[1].status), (40 - fee, &OutputStatus::Unconfirmed));
+		let received = outputs(&receiver);
+		assert_eq!((received[0].value, &received[0].status), (60, &OutputStatus::Unconfirmed));
+	}
+
+	#[test]
+	fn prebuilt_proof_output() {
+		let secp = Secp256k1::new();
+		let sender_key = ExtendedKey::from_seed(&secp, &[3; 16]).unwrap();
+		let receiver_key = ExtendedKey::from_seed(&secp, &[4; 16]).unwrap();
+
+		let sender = wallet_config("target/slate_prebuilt_sender");
+		WalletData::with_wallet(&sender.data_file_dir, |wallet_data| {
+			let key = sender_key.derive(&secp, 1).unwrap();
+			wallet_data.append_output(OutputData {
+				fingerprint: key.fingerprint,
+				n_child: key.n_child,
+				value: 100,
+				status: OutputStatus::Unspent,
+				height: 1,
+				lock_height: 0,
+				is_coinbase: false,
+				confirmations: 10,
+			});
+		}).unwrap();
+		let mut slate = create_slate(&sender, &sender_key, 60, 5, &CoinSelectionPolicy::Automatic)
+			.unwrap();
+
+		// an output built by a device, which blinding factor the sender's sum
+		// accounts for
+		let device_key = SecretKey::new(&secp, &mut rand::thread_rng());
+		let commit = secp.commit(0, device_key).unwrap();
+		let proof = secp.range_proof(0, 0, device_key, commit, device_key);
+		let blind_sum = slate.participant(SENDER_ID).unwrap().blind_sum.unwrap();
+		slate.participant_data[0].blind_sum =
+			Some(secp.blind_sum(vec![blind_sum, device_key], vec![]).unwrap());
+
+		// a proof that isn't for the commitment is refused
+		let other = secp.commit(0, key::ONE_KEY).unwrap();
+		assert!(slate.add_output_with_prebuilt_proof(other, proof, DEFAULT_OUTPUT).is_err());
+		slate.add_output_with_prebuilt_proof(commit, proof, DEFAULT_OUTPUT).unwrap();
+
+		let receiver = wallet_config("target/slate_prebuilt_receiver");
+		add_receiver_data(&receiver, &receiver_key, &mut slate).unwrap();
+		assert!(slate.add_output_with_prebuilt_proof(commit, proof, DEFAULT_OUTPUT).is_err());
+		let tx = finalize_slate(slate).unwrap();
+		assert_eq!(tx.outputs.len(), 3);
+		assert!(tx.outputs.iter().any(|out| out.commit == commit));
+	}
+}
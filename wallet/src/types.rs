@@ -13,27 +13,31 @@
 // limitations under the License.
 
 use std::{num, thread, time};
+use std::cmp::max;
 use std::convert::From;
 use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::path::Path;
 use std::path::MAIN_SEPARATOR;
+use std::str::FromStr;
 
 
 use serde_json;
 
 use secp;
-use secp::key::SecretKey;
 
 use api;
-use core::core::Transaction;
-use core::ser;
+use core::core::TxError;
 use extkey;
-use util;
+use keychain;
 
 const DAT_FILE: &'static str = "wallet.dat";
 const LOCK_FILE: &'static str = "wallet.lock";
 
+/// Number of confirmations a coinbase output needs before the wallet spends
+/// it, whatever the configured minimum.
+pub const COINBASE_MIN_CONFIRMATIONS: u64 = 1440;
+
 /// Wallet errors, mostly wrappers around underlying crypto or I/O errors.
 #[derive(Debug)]
 pub enum Error {
@@ -45,6 +49,14 @@ pub enum Error {
 	Format(String),
 	/// Error when contacting a node through its API
 	Node(api::Error),
+	/// A transaction we built or were sent is invalid
+	Transaction(TxError),
+	/// Error deriving a key through the keychain
+	Keychain(keychain::KeychainError),
+	/// The wallet is watch-only, it can't sign
+	ReadOnly,
+	/// A fee bump doesn't raise the fee enough, the minimum fee is provided
+	FeeBelowMinimumBump(u64),
 }
 
 impl From<secp::Error> for Error {
@@ -53,12 +65,24 @@ impl From<secp::Error> for Error {
 	}
 }
 
+impl From<TxError> for Error {
+	fn from(e: TxError) -> Error {
+		Error::Transaction(e)
+	}
+}
+
 impl From<extkey::Error> for Error {
 	fn from(e: extkey::Error) -> Error {
 		Error::Key(e)
 	}
 }
 
+impl From<keychain::KeychainError> for Error {
+	fn from(e: keychain::KeychainError) -> Error {
+		Error::Keychain(e)
+	}
+}
+
 impl From<serde_json::Error> for Error {
 	fn from(e: serde_json::Error) -> Error {
 		Error::Format(e.to_string())
@@ -88,6 +112,16 @@ pub struct WalletConfig {
 	pub check_node_api_http_addr: String,
 	//The directory in which wallet files are stored
 	pub data_file_dir: String,
+	//The number of confirmations an output needs before it can be spent
+	#[serde(default = "default_min_confirmations")]
+	pub min_confirmations: u64,
+	//How the keys of the wallet are derived from its seed
+	#[serde(default)]
+	pub key_scheme: KeyScheme,
+}
+
+fn default_min_confirmations() -> u64 {
+	1
 }
 
 impl Default for WalletConfig {
@@ -97,6 +131,40 @@ impl Default for WalletConfig {
 			api_http_addr: "127.0.0.1:13416".to_string(),
 			check_node_api_http_addr: "http://127.0.0.1:13413".to_string(),
 			data_file_dir: ".".to_string(),
+			min_confirmations: default_min_confirmations(),
+			key_scheme: KeyScheme::default(),
+		}
+	}
+}
+
+/// How the keys of a wallet are derived from its seed. Both derive the key of
+/// an output from its n_child, the sequential index kept in the wallet data,
+/// but to different keys: the scheme of a wallet can't change once it has
+/// outputs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyScheme {
+	/// Children derived straight from the master key by ExtendedKey
+	Legacy,
+	/// BIP32 derivation of the Keychain, n_child being the index in the
+	/// account m/0', which a watch-only wallet can follow from its xpub
+	Bip32,
+}
+
+impl Default for KeyScheme {
+	fn default() -> KeyScheme {
+		KeyScheme::Legacy
+	}
+}
+
+impl FromStr for KeyScheme {
+	type Err = Error;
+
+	/// Parses legacy or bip32.
+	fn from_str(s: &str) -> Result<KeyScheme, Error> {
+		match s {
+			"legacy" => Ok(KeyScheme::Legacy),
+			"bip32" => Ok(KeyScheme::Bip32),
+			_ => Err(Error::Format(format!("Unknown key scheme {}", s))),
 		}
 	}
 }
@@ -130,6 +198,13 @@ pub struct OutputData {
 	/// Height of the output
 	pub height: u64,
 	pub lock_height: u64,
+	/// Whether the output is a coinbase reward
+	#[serde(default)]
+	pub is_coinbase: bool,
+	/// Number of blocks mined on top of the one with the output, as of the
+	/// last refresh
+	#[serde(default)]
+	pub confirmations: u64,
 }
 
 impl OutputData {
@@ -137,6 +212,38 @@ impl OutputData {
 	pub fn lock(&mut self) {
 		self.status = OutputStatus::Locked;
 	}
+
+	/// Whether the output is unspent with enough confirmations to be spent,
+	/// coinbase outputs always requiring at least COINBASE_MIN_CONFIRMATIONS.
+	pub fn is_spendable(&self, min_confirmations: u64) -> bool {
+		let min_confirmations = if self.is_coinbase {
+			max(min_confirmations, COINBASE_MIN_CONFIRMATIONS)
+		} else {
+			min_confirmations
+		};
+		self.status == OutputStatus::Unspent && self.confirmations >= min_confirmations
+	}
+}
+
+/// Information about a transaction sent by the wallet, enough to build it
+/// again with a different fee.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SentTxData {
+	/// Id of the slate the transaction was sent with
+	pub id: String,
+	/// Amount sent
+	pub amount: u64,
+	/// Fee paid by the transaction
+	pub fee: u64,
+	/// Private key fingerprint of the inputs and change output
+	pub fingerprint: extkey::Fingerprint,
+	/// Child indexes of the outputs spent
+	pub inputs: Vec<u32>,
+	/// Child index of the change output
+	pub change: u32,
+	/// Child indexes of the change outputs of the versions of the transaction
+	/// replaced by a fee bump, tracked until one of the versions confirms
+	pub replaced_changes: Vec<u32>,
 }
 
 /// Wallet information tracking all our outputs. Based on HD derivation and
@@ -150,6 +257,8 @@ impl OutputData {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WalletData {
 	pub outputs: Vec<OutputData>,
+	#[serde(default)]
+	pub sent: Vec<SentTxData>,
 }
 
 impl WalletData {
@@ -217,7 +326,7 @@ impl WalletData {
 			WalletData::read(data_file_path)
 		} else {
 			// just create a new instance, it will get written afterward
-			Ok(WalletData { outputs: vec![] })
+			Ok(WalletData { outputs: vec![], sent: vec![] })
 		}
 	}
 
@@ -259,24 +368,54 @@ impl WalletData {
 		}
 	}
 
-	/// Select a subset of unspent outputs to spend in a transaction
-	/// transferring
-	/// the provided amount.
-	pub fn select(&self, fingerprint: &extkey::Fingerprint, amount: u64) -> (Vec<OutputData>, i64) {
-		let mut to_spend = vec![];
-		let mut input_total = 0;
-		// TODO very naive impl for now, there's definitely better coin selection
-		// algos available
-		for out in &self.outputs {
-			if out.status == OutputStatus::Unspent && out.fingerprint == *fingerprint {
-				to_spend.push(out.clone());
-				input_total += out.value;
-				if input_total >= amount {
-					break;
-				}
+	/// Updates the number of confirmations of the outputs that made it into
+	/// a block, given the height of the tip of the chain.
+	pub fn update_outputs(&mut self, tip_height: u64) {
+		for out in &mut self.outputs {
+			out.confirmations = match out.status {
+				OutputStatus::Unconfirmed => 0,
+				_ => tip_height.saturating_sub(out.height),
+			};
+		}
+	}
+
+	/// Outputs with enough confirmations to be spent.
+	pub fn spendable_outputs(&self, min_confirmations: u64) -> Vec<OutputData> {
+		self.outputs
+			.iter()
+			.filter(|out| out.is_spendable(min_confirmations))
+			.cloned()
+			.collect()
+	}
+
+	/// Keeps track of a transaction we sent.
+	pub fn append_sent(&mut self, sent: SentTxData) {
+		self.sent.push(sent);
+	}
+
+	/// Once a version of a transaction we sent bumping the fee of another
+	/// gets confirmed, stops tracking the change outputs of the other
+	/// versions, which will never confirm.
+	pub fn resolve_replaced(&mut self) {
+		let outputs = &mut self.outputs;
+		for sent in self.sent.iter_mut().filter(|sent| sent.replaced_changes.len() > 0) {
+			let mut changes = sent.replaced_changes.clone();
+			changes.push(sent.change);
+			let confirmed = changes.iter().cloned().find(|n_child| {
+				outputs.iter().any(|out| {
+					out.n_child == *n_child && out.fingerprint == sent.fingerprint &&
+						out.status != OutputStatus::Unconfirmed
+				})
+			});
+			if let Some(confirmed) = confirmed {
+				outputs.retain(|out| {
+					out.n_child == confirmed || out.fingerprint != sent.fingerprint ||
+						!changes.contains(&out.n_child)
+				});
+				sent.change = confirmed;
+				sent.replaced_changes.clear();
 			}
 		}
-		(to_spend, (input_total as i64) - (amount as i64))
 	}
 
 	/// Next child index when we want to create a new output.
@@ -291,42 +430,6 @@ impl WalletData {
 	}
 }
 
-/// Helper in serializing the information a receiver requires to build a
-/// transaction.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct JSONPartialTx {
-	amount: u64,
-	blind_sum: String,
-	tx: String,
-}
-
-/// Encodes the information for a partial transaction (not yet completed by the
-/// receiver) into JSON.
-pub fn partial_tx_to_json(receive_amount: u64, blind_sum: SecretKey, tx: Transaction) -> String {
-	let partial_tx = JSONPartialTx {
-		amount: receive_amount,
-		blind_sum: util::to_hex(blind_sum.as_ref().to_vec()),
-		tx: util::to_hex(ser::ser_vec(&tx).unwrap()),
-	};
-	serde_json::to_string_pretty(&partial_tx).unwrap()
-}
-
-/// Reads a partial transaction encoded as JSON into the amount, sum of blinding
-/// factors and the transaction itself.
-pub fn partial_tx_from_json(json_str: &str) -> Result<(u64, SecretKey, Transaction), Error> {
-	let partial_tx: JSONPartialTx = serde_json::from_str(json_str)?;
-
-	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	let blind_bin = util::from_hex(partial_tx.blind_sum)?;
-	let blinding = SecretKey::from_slice(&secp, &blind_bin[..])?;
-	let tx_bin = util::from_hex(partial_tx.tx)?;
-	let tx = ser::deserialize(&mut &tx_bin[..]).map_err(|_| {
-		Error::Format("Could not deserialize transaction, invalid format.".to_string())
-	})?;
-
-	Ok((partial_tx.amount, blinding, tx))
-}
-
 /// Amount in request to build a coinbase output.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum WalletReceiveRequest {
@@ -347,3 +450,49 @@ pub struct CbData {
 	pub output: String,
 	pub kernel: String,
 }
+
+#[cfg(test)]
+mod test {
+	use secp::Secp256k1;
+
+	use extkey::ExtendedKey;
+	use super::*;
+
+	#[test]
+	fn spendable_outputs() {
+		let secp = Secp256k1::new();
+		let fingerprint = ExtendedKey::from_seed(&secp, &[1; 16]).unwrap().fingerprint;
+		let output = |n_child: u32, height: u64, status: OutputStatus, is_coinbase: bool| OutputData {
+			fingerprint: fingerprint.clone(),
+			n_child: n_child,
+			value: 10,
+			status: status,
+			height: height,
+			lock_height: 0,
+			is_coinbase: is_coinbase,
+			confirmations: 0,
+		};
+		let mut wallet_data = WalletData {
+			outputs: vec![
+				output(1, 10, OutputStatus::Unspent, false),
+				output(2, 1000, OutputStatus::Unspent, false),
+				output(3, 10, OutputStatus::Unspent, true),
+				output(4, 1000, OutputStatus::Unspent, true),
+				output(5, 10, OutputStatus::Spent, false),
+				output(6, 0, OutputStatus::Unconfirmed, false),
+			],
+			sent: vec![],
+		};
+		wallet_data.update_outputs(2000);
+		let confirmations = wallet_data.outputs.iter().map(|out| out.confirmations).collect::<Vec<_>>();
+		assert_eq!(confirmations, vec![1990, 1000, 1990, 1000, 1990, 0]);
+
+		let spendable = |min: u64| {
+			wallet_data.spendable_outputs(min).iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+		assert_eq!(spendable(0), vec![1, 2, 3]);
+		assert_eq!(spendable(1000), vec![1, 2, 3]);
+		assert_eq!(spendable(1001), vec![1, 3]);
+		assert_eq!(spendable(2000), Vec::<u32>::new());
+	}
+}
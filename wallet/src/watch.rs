@@ -0,0 +1,126 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watch-only wallet, following the outputs of a wallet from the extended
+//! public key of its account, on a device that never sees the private keys.
+
+use checker;
+use keychain::ExtendedPubKey;
+use secp::{self, Secp256k1};
+use secp::pedersen::Commitment;
+use types::*;
+
+/// Wallet holding only the extended public key of the account its keys are
+/// derived from (m/0', see Keychain::xpub). The commitment of an output
+/// being the public key of its index plus its value, the outputs listed in
+/// the wallet data (typically a copy of the data of the signing wallet) can
+/// be followed on chain. Finding outputs the wallet data doesn't know about
+/// requires rewinding their range proofs, with the private keys.
+pub struct WatchOnlyWallet {
+	config: WalletConfig,
+	xpub: ExtendedPubKey,
+	secp: Secp256k1,
+}
+
+impl WatchOnlyWallet {
+	/// Watch-only wallet of the provided serialized extended public key,
+	/// with its data in the directory of the provided config.
+	pub fn from_xpub(xpub: &str, config: &WalletConfig) -> Result<WatchOnlyWallet, Error> {
+		Ok(WatchOnlyWallet {
+			config: config.clone(),
+			xpub: xpub.parse::<ExtendedPubKey>()?,
+			secp: Secp256k1::with_caps(secp::ContextFlag::Commit),
+		})
+	}
+
+	/// Commitment of the provided output, from the public key of its index
+	/// and its value.
+	pub fn commitment(&self, out: &OutputData) -> Result<Commitment, Error> {
+		let pub_key = self.xpub.child(&self.secp, out.n_child)?;
+		let value = self.secp.commit_value(out.value)?;
+		Ok(self.secp.commit_sum(vec![pub_key.point, value], vec![])?)
+	}
+
+	/// Checks with the node whether the status of the outputs of the wallet
+	/// has changed.
+	pub fn refresh(&self) -> Result<(), Error> {
+		checker::refresh_outputs_with(&self.config, |out| self.commitment(out))
+	}
+
+	/// All the outputs of the wallet, refreshed against the node first.
+	pub fn list_outputs(&self) -> Result<Vec<OutputData>, Error> {
+		self.refresh()?;
+		WalletData::with_wallet(&self.config.data_file_dir, |wallet_data| {
+			wallet_data.outputs.clone()
+		})
+	}
+
+	/// Total value of the unspent outputs of the wallet, refreshed against the
+	/// node first. When provided a minimum number of confirmations, only the
+	/// outputs that can be spent with it are counted.
+	pub fn get_balance(&self, min_confirmations: Option<u64>) -> Result<u64, Error> {
+		let outputs = self.list_outputs()?;
+		Ok(outputs.iter()
+			.filter(|out| match min_confirmations {
+				Some(min) => out.is_spendable(min),
+				None => out.status == OutputStatus::Unspent,
+			})
+			.map(|out| out.value)
+			.sum())
+	}
+
+	/// Always fails, a watch-only wallet can't sign transactions.
+	pub fn send(&self, _amount: u64, _dest: String) -> Result<(), Error> {
+		Err(Error::ReadOnly)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use extkey::ExtendedKey;
+	use keychain::{DerivationPath, Keychain, SEED_SIZE};
+	use super::*;
+
+	#[test]
+	fn watch_only_commitments() {
+		let config = WalletConfig::default();
+		let keychain = Keychain::from_seed([5; SEED_SIZE]).unwrap();
+		let xpub = keychain.xpub(&DerivationPath::account()).unwrap().to_string();
+		let wallet = WatchOnlyWallet::from_xpub(&xpub, &config).unwrap();
+
+		// same commitments as the ones built from the private keys
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let fingerprint = ExtendedKey::from_seed(&secp, &[5; 16]).unwrap().fingerprint;
+		for n in 1..10 {
+			let out = OutputData {
+				fingerprint: fingerprint.clone(),
+				n_child: n,
+				value: 1000 * n as u64,
+				status: OutputStatus::Unspent,
+				height: 0,
+				lock_height: 0,
+				is_coinbase: false,
+				confirmations: 0,
+			};
+			let key = keychain.derive_index(n).unwrap();
+			assert_eq!(wallet.commitment(&out).unwrap(), secp.commit(out.value, key).unwrap());
+		}
+
+		match wallet.send(10, "stdout".to_string()) {
+			Err(Error::ReadOnly) => {}
+			_ => panic!("a watch-only wallet sent a transaction"),
+		}
+		assert!(WatchOnlyWallet::from_xpub("00", &config).is_err());
+	}
+}